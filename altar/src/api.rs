@@ -1,18 +1,44 @@
 use hv::{
+    elastic::{ElasticMut, ElasticRef},
     prelude::*,
+    resources::Resources,
     script::api::{Module, ModuleBuilder},
 };
 use parry3d::shape::SharedShape;
 
 lazy_static::lazy_static! {
     pub static ref ALTAR: Module = Module::new("altar", "altar", altar_module);
+    pub static ref LATTICE: Module = Module::new("lattice", "altar.lattice", lattice_module);
     pub static ref PHYSICS: Module = Module::new("physics", "altar.physics", physics_module);
     pub static ref TYPES: Module = Module::new("types", "altar.types", types_module);
 }
 
 fn altar_module(lua: &Lua) -> Result<ModuleBuilder> {
     let mut builder = ModuleBuilder::new(lua)?;
-    builder.submodule(&*PHYSICS)?.submodule(&*TYPES)?;
+    builder
+        .submodule(&*LATTICE)?
+        .submodule(&*PHYSICS)?
+        .submodule(&*TYPES)?;
+
+    Ok(builder)
+}
+
+fn lattice_module(lua: &Lua) -> Result<ModuleBuilder> {
+    use crate::lattice::*;
+    let mut builder = ModuleBuilder::new(lua)?;
+    builder
+        .userdata_type::<ChunkCoords>("ChunkCoords")?
+        .userdata_type::<SubCoords>("SubCoords")?
+        .userdata_type::<tracked_map::TrackedMap<soft_edge::Atom>>("TrackedMap")?
+        .userdata_type::<atom_map::AtomMap>("AtomMap")?
+        .userdata_type::<atom_map::LatticeEventReader>("EventReader")?
+        .userdata_type::<navigation::NavConfig>("NavConfig")?
+        .userdata_type::<navigation::FlowField>("FlowField")?
+        .userdata_type::<collider_map::Neighbor>("Neighbor")?;
+
+    atom_map::register_functions(&mut builder)?;
+    collider_map::register_functions(&mut builder)?;
+    navigation::register_functions(&mut builder)?;
 
     Ok(builder)
 }
@@ -26,10 +52,21 @@ fn physics_module(lua: &Lua) -> Result<ModuleBuilder> {
         .userdata_type::<CompositePosition3>("CompositePosition3")?
         .userdata_type::<CompositeVelocity3>("CompositeVelocity3")?
         .userdata_type::<Physics>("Physics")?
+        .userdata_type::<ColliderPart>("ColliderPart")?
+        .userdata_type::<Material>("Material")?
         .userdata_type::<CcdEnabled>("CcdEnabled")?
         .userdata_type::<KinematicMarker>("KinematicMarker")?
+        .userdata_type::<SensorMarker>("SensorMarker")?
+        .userdata_type::<Sleeping>("Sleeping")?
+        .userdata_type::<joint::Joint>("Joint")?
+        .userdata_type::<character::CharacterController>("CharacterController")?
+        .userdata_type::<hooks::ContactModification>("ContactModification")?
         .userdata_type::<SharedShape>("Shape")?;
 
+    query::register_functions(&mut builder)?;
+    joint::register_functions(&mut builder)?;
+    hooks::register_functions(&mut builder)?;
+
     Ok(builder)
 }
 
@@ -45,6 +82,53 @@ fn types_module(lua: &Lua) -> Result<ModuleBuilder> {
     Ok(builder)
 }
 
+/// Run a closure with a shared reference to some `T` loaned to the Lua state. The `T` may be loaned
+/// directly (as an `ElasticMut<T>` or `ElasticRef<T>`) or be present in a loaned `Resources`.
+pub(crate) fn with_loaned<T, R>(lua: &Lua, f: impl FnOnce(&T) -> LuaResult<R>) -> LuaResult<R>
+where
+    T: Send + Sync + 'static,
+{
+    if let Some(elastic) = lua.app_data_ref::<ElasticMut<T>>() {
+        return f(&elastic.borrow());
+    } else if let Some(elastic) = lua.app_data_ref::<ElasticRef<T>>() {
+        return f(&elastic.borrow());
+    } else if let Some(resources) = lua.app_data_ref::<ElasticRef<Resources>>() {
+        let resources = resources.borrow();
+        let t = resources.get::<T>().to_lua_err()?;
+        return f(&t);
+    }
+
+    Err(anyhow!(
+        "no {} loaned to Lua state!",
+        std::any::type_name::<T>()
+    ))
+    .to_lua_err()
+}
+
+/// Run a closure with a mutable reference to some `T` loaned to the Lua state. The `T` may be
+/// loaned directly (as an `ElasticMut<T>`) or be present in a loaned `Resources`.
+pub(crate) fn with_loaned_mut<T, R>(
+    lua: &Lua,
+    f: impl FnOnce(&mut T) -> LuaResult<R>,
+) -> LuaResult<R>
+where
+    T: Send + Sync + 'static,
+{
+    if let Some(elastic) = lua.app_data_ref::<ElasticMut<T>>() {
+        return f(&mut elastic.borrow_mut());
+    } else if let Some(resources) = lua.app_data_ref::<ElasticRef<Resources>>() {
+        let resources = resources.borrow();
+        let mut t = resources.get_mut::<T>().to_lua_err()?;
+        return f(&mut t);
+    }
+
+    Err(anyhow!(
+        "no {} mutably loaned to Lua state!",
+        std::any::type_name::<T>()
+    ))
+    .to_lua_err()
+}
+
 pub fn create_lua_context() -> Result<Lua> {
     Ok(Lua::new())
 }
@@ -3,12 +3,17 @@ use std::{collections::HashMap, sync::Arc};
 
 use hv::prelude::*;
 use parry3d::{
-    bounding_volume::BoundingVolume,
+    bounding_volume::{BoundingSphere, BoundingVolume, AABB},
+    mass_properties::MassProperties,
     query::{
-        visitors::BoundingVolumeIntersectionsVisitor, Contact, DefaultQueryDispatcher, PointQuery,
-        QueryDispatcher, TOI,
+        visitors::{BoundingVolumeIntersectionsVisitor, RayIntersectionsVisitor},
+        Contact, DefaultQueryDispatcher, PointProjection, PointQuery, QueryDispatcher, Ray,
+        RayCast, RayIntersection, TOI,
+    },
+    shape::{
+        FeatureId, PolygonalFeature, Segment, Shape, ShapeType, SimdCompositeShape, TriMesh,
+        TypedShape,
     },
-    shape::{FeatureId, Segment, Shape, TriMesh},
 };
 use slab::Slab;
 use soft_edge::{CompoundHull, EdgeFilter, Exact, HullFacet, SortedPair, VertexFilter};
@@ -63,6 +68,9 @@ impl CompoundHullFeature {
 pub struct CompoundHullShape {
     mesh: TriMesh,
     features: HashMap<(u32, FeatureId), CompoundHullFeature>,
+    // The index of the first triangle of the facet each triangle belongs to. Rectangular facets
+    // are split into two triangles.
+    facets: Vec<u32>,
 }
 
 impl fmt::Debug for CompoundHullShape {
@@ -77,6 +85,7 @@ impl CompoundHullShape {
         let mut vertices: Vec<Point3<f32>> = Vec::new();
         let mut indices: Vec<[u32; 3]> = Vec::new();
         let mut features = HashMap::new();
+        let mut facets = Vec::new();
 
         let mut v = |exact: Exact| -> u32 {
             *index_map.entry(exact).or_insert_with(|| {
@@ -110,6 +119,7 @@ impl CompoundHullShape {
                         CompoundHullFeature::Face(facet),
                     );
 
+                    facets.push(subshape_id);
                     indices.push([v(a), v(b), v(c)]);
                 }
                 HullFacet::Rectangle([a, b, c, d]) => {
@@ -155,6 +165,7 @@ impl CompoundHullShape {
                         CompoundHullFeature::Face(facet),
                     );
 
+                    facets.extend([subshape_id; 2]);
                     indices.push([v(a), v(b), v(c)]);
                     indices.push([v(a), v(c), v(d)]);
                 }
@@ -163,7 +174,11 @@ impl CompoundHullShape {
 
         let mesh = TriMesh::new(vertices, indices);
 
-        Self { mesh, features }
+        Self {
+            mesh,
+            features,
+            facets,
+        }
     }
 
     /// Perform a contact check by doing a roundabout SAT-like construction.
@@ -177,7 +192,8 @@ impl CompoundHullShape {
     /// original contact normal of the feature.
     ///
     /// This filtering is done through the soft-edge [`EdgeFilter`] and [`VertexFilter`], which
-    /// should be generated by an [`AtomMap`](crate::lattice::atom_map::AtomMap).
+    /// should be the ones [`AtomMap::filters`](crate::lattice::atom_map::AtomMap::filters) gives
+    /// for `coords`.
     #[allow(clippy::too_many_arguments)]
     pub fn contact(
         &self,
@@ -369,6 +385,68 @@ impl CompoundHullShape {
         self.mesh.qbvh().traverse_depth_first(&mut visitor);
     }
 
+    /// Generate contact manifolds between this hull placed at `coords` and another shape, w/ up to
+    /// four points per facet of the hull.
+    ///
+    /// Contacts are first found w/ [`CompoundHullShape::contact`]. Whenever a contact lies on the
+    /// face of a facet (or has been filtered onto it) and the other shape has polygonal features,
+    /// the support feature of the other shape facing the facet is clipped against the edges of the
+    /// facet, and every clipped point within `prediction` of the facet becomes a point of the
+    /// manifold. Other contacts are kept as-is, as single-point manifolds.
+    ///
+    /// Each contact is output along w/ the index of the first triangle of its facet and the ID of
+    /// the point within the manifold. Point IDs are derived from the features which produced them,
+    /// so that they stay the same from one update to the next as long as the shapes stay in
+    /// roughly the same configuration, which makes them suitable for warm starting. Single-point
+    /// manifolds are output w/ the index of the triangle the contact was found on and the ID
+    /// [`SINGLE_POINT`].
+    #[allow(clippy::too_many_arguments)]
+    pub fn contact_manifolds(
+        &self,
+        coords: &Vector3<i32>,
+        s2: &dyn Shape,
+        pos2: &Isometry3<f32>,
+        prediction: f32,
+        edge_filter: &EdgeFilter,
+        vertex_filter: &VertexFilter,
+        out: &mut Vec<(Contact, u32, u32)>,
+    ) {
+        let mut contacts = Vec::new();
+        self.contact(
+            coords,
+            s2,
+            pos2,
+            prediction,
+            edge_filter,
+            vertex_filter,
+            &mut contacts,
+        );
+
+        let mut clipped_facets = Vec::new();
+        let mut points = Vec::new();
+        for (c, i) in contacts {
+            let facet_id = self.facets[i as usize];
+            if clipped_facets.contains(&facet_id) {
+                continue;
+            }
+
+            let facet = self.features[&(i, FeatureId::Face(0))].unwrap_face();
+            let normal = facet.normal();
+            if c.normal1.dot(&*normal) >= FACE_CONTACT_COS {
+                points.clear();
+                clip_facet(coords, facet, &normal, s2, pos2, prediction, &mut points);
+                if !points.is_empty() {
+                    clipped_facets.push(facet_id);
+                    reduce_manifold(&normal, &mut points);
+                    out.extend(points.iter().map(|&(c, id)| (c, facet_id, id)));
+                    continue;
+                }
+            }
+
+            out.push((c, i, SINGLE_POINT));
+        }
+    }
+
     pub fn time_of_impact(
         &self,
         coords: &Vector3<i32>,
@@ -388,4 +466,469 @@ impl CompoundHullShape {
             max_toi,
         )
     }
+
+    /// Compute the world-space AABB of this hull when placed at `coords`.
+    pub fn compute_aabb(&self, coords: &Vector3<i32>) -> AABB {
+        self.mesh
+            .compute_aabb(&Isometry3::from(coords.cast::<f32>()))
+    }
+
+    /// Cast a ray against the facets of this hull placed at `coords`, returning the nearest
+    /// intersection and the index of the triangle which was hit.
+    ///
+    /// Hulls are not closed meshes (faces shared between joined atoms are removed) so both sides of
+    /// every facet are tested. The returned normal is always the outward-facing normal of the facet
+    /// which was hit, which makes it possible to tell whether the ray was entering or leaving.
+    pub fn cast_ray(
+        &self,
+        coords: &Vector3<i32>,
+        ray: &Ray,
+        max_toi: f32,
+    ) -> Option<(RayIntersection, u32)> {
+        let pos1 = Isometry3::from(coords.cast::<f32>());
+        let local_ray = ray.inverse_transform_by(&pos1);
+        self.cast_local_ray_on_facets(&local_ray, max_toi)
+            .map(|(hit, i)| (hit.transform_by(&pos1), i))
+    }
+
+    fn cast_local_ray_on_facets(&self, ray: &Ray, max_toi: f32) -> Option<(RayIntersection, u32)> {
+        let mut best = None::<(RayIntersection, u32)>;
+
+        let mut leaf_callback = |&i: &u32| {
+            let triangle = self.mesh.triangle(i);
+            if let Some(hit) = triangle.cast_local_ray_and_get_normal(ray, max_toi, false) {
+                if best.map_or(true, |(prev, _)| hit.toi < prev.toi) {
+                    best = Some((hit, i));
+                }
+            }
+
+            true
+        };
+
+        let mut visitor = RayIntersectionsVisitor::new(ray, max_toi, &mut leaf_callback);
+        self.mesh.qbvh().traverse_depth_first(&mut visitor);
+
+        best.map(|(mut hit, i)| {
+            hit.normal = self.features[&(i, FeatureId::Face(0))]
+                .unwrap_face()
+                .normal()
+                .into_inner();
+            hit.feature = FeatureId::Face(i);
+            (hit, i)
+        })
+    }
+}
+
+// A `CompoundHullShape` is a first-class parry shape in the local space of its cell, so it can be
+// put in a `SharedShape` and used w/ any of parry's generic queries, placed w/ an isometry
+// translating it to its cell's coordinates. Anything which isn't specific to hulls is delegated to
+// the underlying triangle mesh; in particular, generic contact and distance queries treat it as a
+// composite shape of triangles and don't do any of the edge and vertex filtering done by
+// `CompoundHullShape::contact`.
+
+impl Shape for CompoundHullShape {
+    fn compute_local_aabb(&self) -> AABB {
+        self.mesh.local_aabb()
+    }
+
+    fn compute_local_bounding_sphere(&self) -> BoundingSphere {
+        self.mesh.local_bounding_sphere()
+    }
+
+    fn mass_properties(&self, density: f32) -> MassProperties {
+        self.mesh.mass_properties(density)
+    }
+
+    fn shape_type(&self) -> ShapeType {
+        ShapeType::Custom
+    }
+
+    fn as_typed_shape(&self) -> TypedShape {
+        TypedShape::Custom(COMPOUND_HULL_SHAPE_TYPE_ID)
+    }
+
+    fn ccd_thickness(&self) -> f32 {
+        self.mesh.ccd_thickness()
+    }
+
+    fn ccd_angular_thickness(&self) -> f32 {
+        self.mesh.ccd_angular_thickness()
+    }
+
+    fn as_composite_shape(&self) -> Option<&dyn SimdCompositeShape> {
+        Some(&self.mesh)
+    }
+
+    fn feature_normal_at_point(
+        &self,
+        feature: FeatureId,
+        point: &Point3<f32>,
+    ) -> Option<UnitVector3<f32>> {
+        self.mesh.feature_normal_at_point(feature, point)
+    }
+}
+
+impl RayCast for CompoundHullShape {
+    /// Cast a ray against both sides of every facet of this hull. Hulls aren't closed, so `solid`
+    /// is ignored; the returned normal is always the outward-facing normal of the facet hit.
+    fn cast_local_ray_and_get_normal(
+        &self,
+        ray: &Ray,
+        max_toi: f32,
+        _solid: bool,
+    ) -> Option<RayIntersection> {
+        self.cast_local_ray_on_facets(ray, max_toi)
+            .map(|(hit, _)| hit)
+    }
+}
+
+impl PointQuery for CompoundHullShape {
+    fn project_local_point(&self, point: &Point3<f32>, solid: bool) -> PointProjection {
+        self.mesh.project_local_point(point, solid)
+    }
+
+    fn project_local_point_and_get_feature(
+        &self,
+        point: &Point3<f32>,
+    ) -> (PointProjection, FeatureId) {
+        self.mesh.project_local_point_and_get_feature(point)
+    }
+}
+
+/// The ID given to [`CompoundHullShape`]s by [`TypedShape::Custom`], for telling them apart from
+/// other custom shapes.
+pub const COMPOUND_HULL_SHAPE_TYPE_ID: u32 = u32::from_be_bytes(*b"hull");
+
+/// The point ID of a contact which isn't part of a clipped manifold.
+pub const SINGLE_POINT: u32 = u32::MAX;
+
+/// The minimum cosine between a contact normal and a facet normal for the contact to be considered
+/// to lie on the face of the facet.
+const FACE_CONTACT_COS: f32 = 0.9999;
+
+/// Clip the support feature of `s2` facing a hull facet against the edges of the facet, collecting
+/// the points of the resulting manifold w/ their IDs. Collects nothing if `s2` doesn't have
+/// polygonal features, or if its support feature is a single vertex.
+fn clip_facet(
+    coords: &Vector3<i32>,
+    facet: &HullFacet,
+    normal: &UnitVector3<f32>,
+    s2: &dyn Shape,
+    pos2: &Isometry3<f32>,
+    prediction: f32,
+    out: &mut Vec<(Contact, u32)>,
+) {
+    let (pfm, border_radius) = match s2.as_polygonal_feature_map() {
+        Some(pfm) => pfm,
+        None => return,
+    };
+
+    let mut feature = PolygonalFeature::default();
+    pfm.local_support_feature(&pos2.inverse_transform_unit_vector(&-*normal), &mut feature);
+    if feature.num_vertices < 2 {
+        return;
+    }
+
+    let offset = coords.cast::<f32>();
+    let reference = match *facet {
+        HullFacet::Triangle(vs) => vs.iter().map(|v| v.to_f32() + offset).collect::<Vec<_>>(),
+        HullFacet::Rectangle(vs) => vs.iter().map(|v| v.to_f32() + offset).collect::<Vec<_>>(),
+    };
+
+    // Incident points, offset onto the surface of the shape if it's rounded, w/ their IDs and the
+    // line the edge from each point to the next lies on. A two-point polygon is a segment, and
+    // only has the one line.
+    let num_vertices = feature.num_vertices;
+    let mut polygon = feature.vertices[..num_vertices]
+        .iter()
+        .enumerate()
+        .map(|(k, v)| {
+            let line = if num_vertices == 2 { 0 } else { k as u32 };
+            (
+                pos2 * v - normal.into_inner() * border_radius,
+                k as u32,
+                line,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let centroid = reference
+        .iter()
+        .fold(Point3::origin(), |acc, p| acc + p.coords)
+        / reference.len() as f32;
+    let mut clipped = Vec::with_capacity(8);
+    for j in 0..reference.len() {
+        let a = reference[j];
+        let b = reference[(j + 1) % reference.len()];
+        let mut side = (b - a).cross(&normal.into_inner());
+        if side.dot(&(centroid - a)) > 0. {
+            side = -side;
+        }
+
+        let reference_line = REFERENCE_LINES + j as u32;
+        let edges = if polygon.len() == 2 { 1 } else { polygon.len() };
+        clipped.clear();
+        for k in 0..edges {
+            let (p, p_id, p_line) = polygon[k];
+            let (q, q_id, q_line) = polygon[(k + 1) % polygon.len()];
+            let dp = side.dot(&(p - a));
+            let dq = side.dot(&(q - a));
+
+            // Where the polygon leaves the facet, the edge to the next point kept (where it comes
+            // back in) lies along the edge of the facet.
+            if dp <= 0. {
+                let line = if dp == 0. && dq > 0. {
+                    reference_line
+                } else {
+                    p_line
+                };
+                clipped.push((p, p_id, line));
+            }
+
+            if (dp < 0. && dq > 0.) || (dp > 0. && dq < 0.) {
+                let t = dp / (dp - dq);
+                let line = if dp < 0. { reference_line } else { p_line };
+                clipped.push((p + (q - p) * t, clip_id(p_line, reference_line), line));
+            }
+
+            if edges == 1 && dq <= 0. {
+                clipped.push((q, q_id, q_line));
+            }
+        }
+
+        std::mem::swap(&mut polygon, &mut clipped);
+        if polygon.is_empty() {
+            return;
+        }
+    }
+
+    for (p, id, _) in polygon {
+        let dist = (p - reference[0]).dot(&normal.into_inner());
+        if dist <= prediction {
+            let on_facet = p - normal.into_inner() * dist;
+            out.push((Contact::new(on_facet, p, *normal, -*normal, dist), id));
+        }
+    }
+}
+
+/// Polygonal features have at most four edges, as do facets, so the lines which clipped points lie
+/// on are numbered w/ the edges of the incident feature first and then the edges of the facet.
+const REFERENCE_LINES: u32 = 4;
+
+/// The ID of the point where two lines cross, which is the same no matter which of the lines was
+/// clipped against the other. Clipped points lie on one line of each polygon, so their IDs never
+/// collide w/ each other, w/ the IDs of incident vertices, or w/ [`SINGLE_POINT`].
+fn clip_id(line1: u32, line2: u32) -> u32 {
+    debug_assert!(line1 < 2 * REFERENCE_LINES && line2 < 2 * REFERENCE_LINES);
+    0x8000_0000 | line1.min(line2) << 3 | line1.max(line2)
+}
+
+/// Reduce a manifold to at most four points: the deepest point, the point farthest from it, and
+/// the two points on either side of the line between them which span the largest area.
+fn reduce_manifold(normal: &UnitVector3<f32>, points: &mut Vec<(Contact, u32)>) {
+    if points.len() <= 4 {
+        return;
+    }
+
+    let position = |i: usize| points[i].0.point2;
+    let max_by = |f: &dyn Fn(usize) -> f32| {
+        (0..points.len())
+            .max_by(|&a, &b| f(a).total_cmp(&f(b)))
+            .unwrap()
+    };
+
+    let i0 = max_by(&|i| -points[i].0.dist);
+    let i1 = max_by(&|i| (position(i) - position(i0)).norm_squared());
+    let signed_area = |i: usize| {
+        (position(i1) - position(i0))
+            .cross(&(position(i) - position(i0)))
+            .dot(&normal.into_inner())
+    };
+    let i2 = max_by(&signed_area);
+    let i3 = max_by(&|i| -signed_area(i));
+
+    let mut keep = vec![i0, i1];
+    for i in [i2, i3] {
+        if !keep.contains(&i) {
+            keep.push(i);
+        }
+    }
+    keep.sort_unstable();
+
+    let mut index = 0;
+    points.retain(|_| {
+        let kept = keep.contains(&index);
+        index += 1;
+        kept
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use parry3d::shape::{Ball, Cuboid, SharedShape};
+    use soft_edge::Atom;
+
+    use crate::lattice::atom_map::AtomMap;
+
+    /// A map w/ a single full cube at the origin, whose top facet is the square from `(0, 0, 1)`
+    /// to `(1, 1, 1)`.
+    fn cube_map() -> AtomMap {
+        let cube = Atom::generator()
+            .find(|atom| {
+                atom.compound_hull()
+                    .facets()
+                    .filter(|facet| matches!(facet, HullFacet::Rectangle(_)))
+                    .count()
+                    == 6
+            })
+            .unwrap();
+
+        let mut map = AtomMap::new();
+        map.atoms_mut().insert(Vector3::zeros(), cube);
+        map.calculate_hulls();
+        map
+    }
+
+    /// The points of the manifolds between the cube and a shape which were clipped onto a facet,
+    /// sorted by ID.
+    fn clipped_points(map: &AtomMap, s2: &dyn Shape, pos2: &Isometry3<f32>) -> Vec<(Contact, u32)> {
+        let coords = Vector3::zeros();
+        let (edge_filter, vertex_filter) = map.filters(coords);
+        let mut out = Vec::new();
+        map.shapes().get(coords).unwrap().contact_manifolds(
+            &coords,
+            s2,
+            pos2,
+            0.1,
+            edge_filter,
+            vertex_filter,
+            &mut out,
+        );
+
+        let mut points = out
+            .into_iter()
+            .filter(|&(_, _, id)| id != SINGLE_POINT)
+            .map(|(c, _, id)| (c, id))
+            .collect::<Vec<_>>();
+        points.sort_by_key(|&(_, id)| id);
+        points
+    }
+
+    fn ids(points: &[(Contact, u32)]) -> Vec<u32> {
+        points.iter().map(|&(_, id)| id).collect()
+    }
+
+    #[test]
+    fn box_resting_on_a_facet_has_four_stable_points() {
+        let map = cube_map();
+        let small = Cuboid::new(Vector3::repeat(0.2));
+        let pos = Isometry3::translation(0.5, 0.5, 1.19);
+        let points = clipped_points(&map, &small, &pos);
+
+        assert_eq!(points.len(), 4);
+        for (c, _) in &points {
+            assert!(c.normal1.z > 0.99);
+            assert!((c.dist + 0.01).abs() < 1e-4);
+        }
+        let mut distinct = ids(&points);
+        distinct.dedup();
+        assert_eq!(distinct.len(), 4);
+
+        // Sliding and turning a little doesn't change which features the points come from.
+        let moved = Isometry3::new(Vector3::new(0.55, 0.48, 1.185), Vector3::z() * 0.05);
+        assert_eq!(ids(&clipped_points(&map, &small, &moved)), ids(&points));
+
+        // A box overhanging the whole facet is clipped to its corners, which all have their own
+        // IDs, and keep them as it moves.
+        let large = Cuboid::new(Vector3::new(1., 1., 0.2));
+        let points = clipped_points(&map, &large, &pos);
+        assert_eq!(points.len(), 4);
+        for (c, _) in &points {
+            let corner = c.point1.map(|t| t.round());
+            assert!((c.point1 - corner).norm() < 1e-4);
+        }
+        let mut distinct = ids(&points);
+        distinct.dedup();
+        assert_eq!(distinct.len(), 4);
+        assert_eq!(ids(&clipped_points(&map, &large, &moved)), ids(&points));
+    }
+
+    #[test]
+    fn box_resting_on_an_edge_has_two_points() {
+        let map = cube_map();
+        let cuboid = Cuboid::new(Vector3::repeat(0.2));
+        // Tipped over onto one of its edges, which runs along the y axis.
+        let height = 0.2 * std::f32::consts::SQRT_2;
+        let pos = Isometry3::new(
+            Vector3::new(0.5, 0.5, 1. + height - 0.01),
+            Vector3::y() * std::f32::consts::FRAC_PI_4,
+        );
+        let points = clipped_points(&map, &cuboid, &pos);
+
+        assert_eq!(points.len(), 2);
+        assert_ne!(points[0].1, points[1].1);
+        for (c, _) in &points {
+            assert!(c.normal1.z > 0.99);
+            assert!((c.point1.x - 0.5).abs() < 1e-4);
+            assert!((c.dist + 0.01).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn clip_ids_are_distinct() {
+        let mut ids = Vec::new();
+        for line1 in 0..REFERENCE_LINES {
+            for j in 0..4 {
+                let line2 = REFERENCE_LINES + j;
+                assert_eq!(clip_id(line1, line2), clip_id(line2, line1));
+                ids.push(clip_id(line1, line2));
+            }
+        }
+        for j1 in 0..4 {
+            for j2 in j1 + 1..4 {
+                ids.push(clip_id(REFERENCE_LINES + j1, REFERENCE_LINES + j2));
+            }
+        }
+
+        let count = ids.len();
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), count);
+        assert!(ids.iter().all(|&id| id >= 4 && id != SINGLE_POINT));
+    }
+
+    #[test]
+    fn shared_shape_queries() {
+        let map = cube_map();
+        let shape = SharedShape(map.shapes().get(Vector3::zeros()).unwrap().clone());
+        // Hull shapes are in the local space of their cell, so they can be placed anywhere.
+        let pos = Isometry3::translation(10., -3., 2.);
+
+        let ray = Ray::new(Point3::new(10.5, -2.5, 5.), -Vector3::z());
+        let hit = shape
+            .cast_ray_and_get_normal(&pos, &ray, 10., true)
+            .unwrap();
+        assert!((hit.toi - 2.).abs() < 1e-4);
+        assert!((hit.normal - Vector3::z()).norm() < 1e-4);
+        assert!(shape.cast_ray(&pos, &ray, 1.5, true).is_none());
+
+        let projection = shape.project_point(&pos, &Point3::new(10.25, -2.5, 4.), false);
+        assert!((projection.point - Point3::new(10.25, -2.5, 3.)).norm() < 1e-4);
+
+        let ball = Ball::new(0.25);
+        let ball_pos = Isometry3::translation(10.5, -2.5, 3.2);
+        let contact = parry3d::query::contact(&pos, &*shape, &ball_pos, &ball, 0.1)
+            .unwrap()
+            .unwrap();
+        assert!((contact.dist + 0.05).abs() < 1e-4);
+        assert!((contact.normal1.into_inner() - Vector3::z()).norm() < 1e-4);
+        assert!((contact.point1 - Point3::new(10.5, -2.5, 3.)).norm() < 1e-4);
+
+        let far = Isometry3::translation(10.5, -2.5, 4.);
+        assert!(parry3d::query::contact(&pos, &*shape, &far, &ball, 0.1)
+            .unwrap()
+            .is_none());
+    }
 }
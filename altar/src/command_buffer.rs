@@ -149,6 +149,12 @@ pub struct CommandPool {
 unsafe impl Send for CommandPool {}
 unsafe impl Sync for CommandPool {}
 
+impl Default for CommandPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct CommandPoolScope<'a> {
     command_pool: &'a CommandPool,
     buf: ManuallyDrop<Arc<Mutex<Vec<Chunk<'a, Command<'a>>>>>>,
@@ -157,6 +163,15 @@ pub struct CommandPoolScope<'a> {
 }
 
 impl CommandPool {
+    pub fn new() -> Self {
+        Self {
+            stampede: BumpPool::new(),
+            raw_chunk_bufs: Mutex::new(Vec::new()),
+            raw_elastic_bufs: Mutex::new(Vec::new()),
+            raw_guard_bufs: Mutex::new(Vec::new()),
+        }
+    }
+
     pub fn scope(&'_ self) -> CommandPoolScope<'_> {
         CommandPoolScope {
             command_pool: self,
@@ -324,6 +339,22 @@ impl CommandPoolResource {
     pub fn get_buffer(&self) -> CommandBuffer {
         self.borrow().get()
     }
+
+    /// Loan a new scope of `pool` to this resource while `f` runs, then flush every command which
+    /// was buffered through it into the world.
+    pub fn run_scoped<R>(
+        &self,
+        pool: &CommandPool,
+        world: &mut World,
+        resources: &mut Resources,
+        f: impl FnOnce(&World) -> R,
+    ) -> R {
+        // Safety: the guard is either taken back below, or dropped while unwinding if `f` panics.
+        let guard = unsafe { self.inner.loan(pool.scope()) };
+        let out = f(world);
+        guard.take().flush(world, resources);
+        out
+    }
 }
 
 impl LuaUserData for CommandPoolResource {
@@ -2,6 +2,12 @@ pub mod atom_map;
 pub mod chunk_map;
 pub mod collider_map;
 pub mod event;
+pub mod format;
+pub mod history;
+pub mod navigation;
+pub mod region;
+pub mod streaming;
 pub mod tracked_map;
+pub mod traversal;
 
 pub use chunk_map::{ChunkCoords, SubCoords};
@@ -1,14 +1,34 @@
-use std::{fmt, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 
-use hv::prelude::*;
+use hv::{prelude::*, script::api::ModuleBuilder};
 use parry3d::bounding_volume::AABB;
+use shrev::ReaderId;
 use soft_edge::{Atom, Axis, CompoundHull, EdgeFilter, Face, VertexFilter, VertexSet};
 
 use crate::{
+    api::{with_loaned, with_loaned_mut},
     collision::{CompoundHullShape, CompoundHullShapeCache},
-    lattice::{chunk_map::ChunkMap, tracked_map::TrackedMap},
+    lattice::{
+        chunk_map::{Chunk, ChunkMap, DividedCoords, CHUNK_SIDE_LENGTH},
+        event::{
+            ChunkEventKind, LatticeEvent, LatticeEventDebouncer, LayerEventKind, SlotEventKind,
+        },
+        tracked_map::TrackedMap,
+        ChunkCoords,
+    },
 };
 
+/// The next generation of changes to the hulls of any atom map. Generations are shared between
+/// maps, so that a generation seen in one map is never reused by another.
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(1);
+
 #[derive(Debug, Clone, Copy)]
 pub struct Intersection<'a> {
     pub coords: Vector3<i32>,
@@ -16,14 +36,31 @@ pub struct Intersection<'a> {
 }
 
 /// A 3D layered map where the cells are [`Atom`]s.
-#[derive(Default)]
+///
+/// The hulls and shapes of the map are kept up to date w/ its atoms by listening to the events of
+/// the underlying [`TrackedMap`]; after editing atoms through [`AtomMap::atoms_mut`], call
+/// [`AtomMap::update_hulls`] to recalculate only the hulls around the cells which changed. Changes
+/// made without going through the tracked map (for example through
+/// [`TrackedMap::as_chunk_map_mut`]) aren't seen, and require a full [`AtomMap::calculate_hulls`].
 pub struct AtomMap {
     shape_cache: CompoundHullShapeCache,
-    edge_filter: EdgeFilter,
-    vertex_filter: VertexFilter,
-    pub atoms: TrackedMap<Atom>,
+    /// Edge and vertex filters for every chunk column w/ hulls in it; see [`AtomMap::filters`].
+    filters: HashMap<ChunkCoords, (EdgeFilter, VertexFilter)>,
+    empty_filters: (EdgeFilter, VertexFilter),
+    atoms: TrackedMap<Atom>,
+    reader_id: ReaderId<LatticeEvent<Atom>>,
+    debouncer: LatticeEventDebouncer<Atom>,
     hulls: ChunkMap<CompoundHull>,
     shapes: ChunkMap<Arc<CompoundHullShape>>,
+    generation: u64,
+    /// The generation in which the hulls of each chunk, keyed by `(layer, chunk)`, last changed.
+    changed_chunks: HashMap<(i32, ChunkCoords), u64>,
+}
+
+impl Default for AtomMap {
+    fn default() -> Self {
+        Self::from_atoms(TrackedMap::new())
+    }
 }
 
 impl fmt::Debug for AtomMap {
@@ -40,15 +77,48 @@ impl AtomMap {
         Self::default()
     }
 
+    /// Create a map from existing atoms, and calculate its hulls.
+    pub fn from_atoms(mut atoms: TrackedMap<Atom>) -> Self {
+        let reader_id = atoms.events_mut().register_reader();
+        let mut this = Self {
+            shape_cache: CompoundHullShapeCache::new(),
+            filters: HashMap::new(),
+            empty_filters: Default::default(),
+            atoms,
+            reader_id,
+            debouncer: LatticeEventDebouncer::new(),
+            hulls: ChunkMap::new(),
+            shapes: ChunkMap::new(),
+            generation: 0,
+            changed_chunks: HashMap::new(),
+        };
+        this.calculate_hulls();
+        this
+    }
+
+    pub fn atoms(&self) -> &TrackedMap<Atom> {
+        &self.atoms
+    }
+
+    pub fn atoms_mut(&mut self) -> &mut TrackedMap<Atom> {
+        &mut self.atoms
+    }
+
     /// Clear and calculate from scratch the hulls of the entire map.
     pub fn calculate_hulls(&mut self) {
         use Axis::*;
 
-        // Phase 1. Reset all hulls to their unjoined state, and clear all shapes.
+        // Everything is about to be recalculated, so any pending changes can be ignored.
+        self.atoms
+            .events()
+            .read(&mut self.reader_id)
+            .for_each(|_| ());
+
+        // Phase 1. Reset all hulls to their unjoined state, and clear all shapes. Every chunk which
+        // had hulls before or has them after counts as changed.
+        let mut changed = layer_chunks(&self.hulls).collect::<HashSet<_>>();
         self.hulls.clear();
         self.shapes.clear();
-        self.edge_filter.clear();
-        self.vertex_filter.clear();
         for (coords, &a0) in self.atoms.as_chunk_map().iter() {
             self.hulls.insert(coords, a0.compound_hull());
         }
@@ -82,15 +152,232 @@ impl AtomMap {
             self.shapes.insert(coords, self.shape_cache.get_shape(hull));
         }
 
-        // Phase 4. Populate edge filter.
-        self.edge_filter
-            .extend(self.hulls.iter().flat_map(|(coords, hull)| {
-                hull.facets()
-                    .map(move |facet| (coords, facet.translated_by(coords)))
-            }));
+        // Phases 4 and 5. Populate edge and vertex filters.
+        self.filters.clear();
+        changed.extend(layer_chunks(&self.hulls));
+        let chunks = changed
+            .iter()
+            .map(|&(_, coords)| coords)
+            .collect::<HashSet<_>>();
+        for chunk in chunks {
+            self.rebuild_filters(chunk);
+        }
+
+        // Everything was inserted and joined through dense storage; compress it again.
+        self.atoms.as_chunk_map_mut().compact();
+        self.hulls.compact();
+        self.shapes.compact_by(Arc::ptr_eq);
+
+        self.mark_changed(changed);
+    }
+
+    /// Recalculate the hulls, shapes, and filters affected by changes to the atoms of the map since
+    /// the last time they were calculated.
+    ///
+    /// Changed slots, chunks, and layers are read from the events of the tracked atom map and
+    /// debounced, so a slot changed several times (or changed and then changed back) is only
+    /// handled once. Only the hulls and shapes of changed cells and their immediate neighbors are
+    /// recalculated, along w/ the edge and vertex filters of the chunks around them.
+    pub fn update_hulls(&mut self) {
+        self.debouncer
+            .extend(self.atoms.events().read(&mut self.reader_id).copied());
+
+        let mut dirty = HashSet::new();
+        for event in self.debouncer.drain() {
+            match event {
+                LatticeEvent::Slot(slot_event) => {
+                    let divided = DividedCoords {
+                        chunk_coords: slot_event.chunk,
+                        sub_coords: slot_event.sub,
+                    };
+                    dirty.insert(divided.to_world_coords().push(slot_event.layer));
+                }
+                LatticeEvent::Chunk(chunk_event) => {
+                    let layer = chunk_event.layer;
+                    dirty.extend(chunk_event.chunk.world_coords().map(|xy| xy.push(layer)));
+                }
+                LatticeEvent::Layer(layer_event) => {
+                    // A removed layer is no longer in the atom map, but its hulls are still around
+                    // to tell us which chunks it had.
+                    let index = layer_event.layer;
+                    let atom_chunks = self.atoms.get_layer(index).into_iter();
+                    let hull_chunks = self.hulls.get_layer(index).into_iter();
+                    let chunks = atom_chunks
+                        .flat_map(|layer| layer.chunks().map(|(coords, _)| coords))
+                        .chain(
+                            hull_chunks.flat_map(|layer| layer.chunks().map(|(coords, _)| coords)),
+                        )
+                        .collect::<HashSet<_>>();
+                    for chunk in chunks {
+                        dirty.extend(chunk.world_coords().map(|xy| xy.push(index)));
+                    }
+                }
+            }
+        }
 
-        // Phase 5. Populate vertex filter.
-        self.vertex_filter.extend(self.edge_filter.iter());
+        if !dirty.is_empty() {
+            self.recalculate_cells(&dirty);
+        }
+    }
+
+    fn recalculate_cells(&mut self, dirty: &HashSet<Vector3<i32>>) {
+        use Axis::*;
+
+        // Phase 1. Find every cell whose hull may change: the changed cells, and their neighbors,
+        // which may have been joined w/ them.
+        let mut affected = dirty.clone();
+        for coords in dirty {
+            affected.extend(Axis::generator().map(|axis| coords + axis.to_offset()));
+        }
+
+        // Phase 2. Reset affected hulls to their unjoined state, and clear their shapes.
+        for &coords in &affected {
+            self.shapes.remove(coords);
+            match self.atoms.get(coords) {
+                Some(atom) => self.hulls.insert(coords, atom.compound_hull()),
+                None => self.hulls.remove(coords),
+            };
+        }
+
+        // Phase 3. Rejoin affected hulls. Joins between two affected cells are done exactly as in
+        // `calculate_hulls`. Unaffected neighbors keep their hulls, so affected cells are joined
+        // against fresh, unjoined copies of them instead, always w/ the lower cell joining the
+        // higher one on a positive axis.
+        for &coords in &affected {
+            for axis in [PosX, PosY, PosZ] {
+                let upper = coords + axis.to_offset();
+                if affected.contains(&upper) {
+                    if let Some([h0, h1]) = self.hulls.get_all_n_mut([coords, upper]) {
+                        h0.join_exteriors(axis, h1);
+                    }
+                } else if let (Some(h0), Some(a1)) =
+                    (self.hulls.get_mut(coords), self.atoms.get(upper))
+                {
+                    h0.join_exteriors(axis, &mut a1.compound_hull());
+                }
+
+                let lower = coords - axis.to_offset();
+                if !affected.contains(&lower) {
+                    if let (Some(a1), Some(h0)) =
+                        (self.atoms.get(lower), self.hulls.get_mut(coords))
+                    {
+                        a1.compound_hull().join_exteriors(axis, h0);
+                    }
+                }
+            }
+        }
+
+        // Phase 4. Recalculate affected shapes.
+        for &coords in &affected {
+            if let Some(hull) = self.hulls.get(coords) {
+                self.shapes.insert(coords, self.shape_cache.get_shape(hull));
+            }
+        }
+
+        // Phases 5 and 6. Rebuild the edge and vertex filters of every chunk which borders an
+        // affected cell. Facets can't be removed from soft-edge's filters, so each of these chunks
+        // has its filters cleared and refilled, but the rest of the map is left alone.
+        let chunks = affected
+            .iter()
+            .flat_map(|coords| {
+                (-1..=1).flat_map(move |dy| {
+                    (-1..=1).map(move |dx| {
+                        let xy = coords.xy() + Vector2::new(dx, dy);
+                        DividedCoords::from_world_coords(xy).chunk_coords
+                    })
+                })
+            })
+            .collect::<HashSet<_>>();
+        for chunk in chunks {
+            self.rebuild_filters(chunk);
+        }
+
+        // Phase 7. Editing and joining leave the touched chunks dense; compress them again.
+        let chunks = affected
+            .iter()
+            .map(|coords| {
+                let divided = DividedCoords::from_world_coords(coords.xy());
+                (coords.z, divided.chunk_coords)
+            })
+            .collect::<HashSet<_>>();
+        for &(layer, coords) in &chunks {
+            if let Some(chunk) = chunk_mut(self.atoms.as_chunk_map_mut(), layer, coords) {
+                chunk.compact();
+            }
+            if let Some(chunk) = chunk_mut(&mut self.hulls, layer, coords) {
+                chunk.compact();
+            }
+            if let Some(chunk) = chunk_mut(&mut self.shapes, layer, coords) {
+                chunk.compact_by(Arc::ptr_eq);
+            }
+        }
+
+        self.mark_changed(chunks);
+    }
+
+    fn mark_changed(&mut self, chunks: impl IntoIterator<Item = (i32, ChunkCoords)>) {
+        self.generation = NEXT_GENERATION.fetch_add(1, Ordering::Relaxed);
+        for chunk in chunks {
+            self.changed_chunks.insert(chunk, self.generation);
+        }
+    }
+
+    /// The generation of the last change to the hulls of the map. Generations always increase,
+    /// and are never shared between maps.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// The bounds of every chunk whose hulls changed after the given generation, as seen through
+    /// [`AtomMap::generation`]. These include the cells around any changed cell, since their hulls
+    /// may have been rejoined.
+    pub fn chunks_changed_since(&self, generation: u64) -> impl Iterator<Item = AABB> + '_ {
+        let side = CHUNK_SIDE_LENGTH as f32;
+        self.changed_chunks
+            .iter()
+            .filter(move |&(_, &changed)| changed > generation)
+            .map(move |(&(layer, coords), _)| {
+                let mins =
+                    Point3::new(coords.x as f32 * side, coords.y as f32 * side, layer as f32);
+                AABB::new(mins, mins + Vector3::new(side, side, 1.))
+            })
+    }
+
+    /// Rebuild the edge and vertex filters of a chunk column from the hulls in it and in the cells
+    /// bordering it, on every layer. Whether an edge or vertex is filtered only depends on the
+    /// facets touching it, so this is all the filters of a chunk need to know about.
+    fn rebuild_filters(&mut self, chunk: ChunkCoords) {
+        let has_hulls = self.hulls.layers().any(|(_, layer)| {
+            layer
+                .get_chunk(chunk)
+                .map_or(false, |hulls| !hulls.is_empty())
+        });
+
+        if !has_hulls {
+            self.filters.remove(&chunk);
+            return;
+        }
+
+        let side = CHUNK_SIDE_LENGTH as i32;
+        let origin = *chunk * side;
+        let hulls = self.hulls.layers().flat_map(move |(z, layer)| {
+            (origin.y - 1..=origin.y + side).flat_map(move |y| {
+                (origin.x - 1..=origin.x + side).filter_map(move |x| {
+                    Some((Vector3::new(x, y, z), layer.get(Vector2::new(x, y))?))
+                })
+            })
+        });
+
+        let (edge_filter, vertex_filter) = self.filters.entry(chunk).or_default();
+        edge_filter.clear();
+        vertex_filter.clear();
+
+        edge_filter.extend(hulls.flat_map(|(coords, hull)| {
+            hull.facets()
+                .map(move |facet| (coords, facet.translated_by(coords)))
+        }));
+
+        vertex_filter.extend(edge_filter.iter());
     }
 
     /// Recalculate the join on all axes of this cell.
@@ -169,11 +456,529 @@ impl AtomMap {
         &self.shapes
     }
 
-    pub fn edge_filter(&self) -> &EdgeFilter {
-        &self.edge_filter
+    /// The edge and vertex filters to collide against the hull at `coords` w/. They're kept per
+    /// chunk, so they only know about the hulls in and right around the chunk containing `coords`.
+    pub fn filters(&self, coords: Vector3<i32>) -> (&EdgeFilter, &VertexFilter) {
+        let chunk = DividedCoords::from_world_coords(coords.xy()).chunk_coords;
+        let (edge_filter, vertex_filter) = self.filters.get(&chunk).unwrap_or(&self.empty_filters);
+        (edge_filter, vertex_filter)
+    }
+}
+
+fn layer_chunks<T>(map: &ChunkMap<T>) -> impl Iterator<Item = (i32, ChunkCoords)> + '_ {
+    map.layers()
+        .flat_map(|(z, layer)| layer.chunks().map(move |(coords, _)| (z, coords)))
+}
+
+fn chunk_mut<T>(map: &mut ChunkMap<T>, layer: i32, coords: ChunkCoords) -> Option<&mut Chunk<T>> {
+    map.get_layer_mut(layer)?.get_chunk_mut(coords)
+}
+
+lazy_static::lazy_static! {
+    static ref ATOMS: Vec<Atom> = Atom::generator().collect();
+}
+
+/// An [`Atom`] as seen from Lua, where atoms are represented by their index in the order of
+/// [`Atom::generator`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LuaAtom(pub Atom);
+
+impl<'lua> ToLua<'lua> for LuaAtom {
+    fn to_lua(self, lua: &'lua Lua) -> LuaResult<LuaValue<'lua>> {
+        let index = ATOMS
+            .iter()
+            .position(|&atom| atom == self.0)
+            .ok_or_else(|| anyhow!("atom {:?} not in generator", self.0))
+            .to_lua_err()?;
+        (index as u32).to_lua(lua)
+    }
+}
+
+impl<'lua> FromLua<'lua> for LuaAtom {
+    fn from_lua(lua_value: LuaValue<'lua>, lua: &'lua Lua) -> LuaResult<Self> {
+        let index = u32::from_lua(lua_value, lua)?;
+        ATOMS
+            .get(index as usize)
+            .copied()
+            .map(Self)
+            .ok_or_else(|| anyhow!("no atom w/ index {}", index))
+            .to_lua_err()
+    }
+}
+
+/// A reader registered w/ the event channel of a map of atoms from Lua.
+pub struct LatticeEventReader(ReaderId<LatticeEvent<Atom>>);
+
+impl LuaUserData for LatticeEventReader {
+    fn on_metatable_init(table: Type<Self>) {
+        table.add_send().add_sync();
     }
+}
 
-    pub fn vertex_filter(&self) -> &VertexFilter {
-        &self.vertex_filter
+// Convert an event into a table w/ a `type` ("slot", "chunk" or "layer") and a `kind` ("insert" or
+// "remove"), along w/ the fields of the event.
+fn event_to_lua<'lua>(lua: &'lua Lua, event: &LatticeEvent<Atom>) -> LuaResult<LuaTable<'lua>> {
+    let table = lua.create_table()?;
+    let kind = |insert| if insert { "insert" } else { "remove" };
+    match event {
+        LatticeEvent::Slot(slot_event) => {
+            let coords = DividedCoords {
+                chunk_coords: slot_event.chunk,
+                sub_coords: slot_event.sub,
+            }
+            .to_world_coords()
+            .push(slot_event.layer);
+            table.set("type", "slot")?;
+            table.set("layer", slot_event.layer)?;
+            table.set("chunk", slot_event.chunk)?;
+            table.set("sub", slot_event.sub)?;
+            table.set("coords", coords)?;
+            match slot_event.kind {
+                SlotEventKind::Insert { new, prev } => {
+                    table.set("kind", kind(true))?;
+                    table.set("new", LuaAtom(new))?;
+                    table.set("prev", prev.map(LuaAtom))?;
+                }
+                SlotEventKind::Remove { prev } => {
+                    table.set("kind", kind(false))?;
+                    table.set("prev", LuaAtom(prev))?;
+                }
+            }
+        }
+        LatticeEvent::Chunk(chunk_event) => {
+            table.set("type", "chunk")?;
+            table.set("layer", chunk_event.layer)?;
+            table.set("chunk", chunk_event.chunk)?;
+            table.set(
+                "kind",
+                kind(matches!(chunk_event.kind, ChunkEventKind::Insert)),
+            )?;
+        }
+        LatticeEvent::Layer(layer_event) => {
+            table.set("type", "layer")?;
+            table.set("layer", layer_event.layer)?;
+            table.set(
+                "kind",
+                kind(matches!(layer_event.kind, LayerEventKind::Insert)),
+            )?;
+        }
+    }
+    Ok(table)
+}
+
+// The Lua bindings of `TrackedMap<Atom>` and `AtomMap` share their methods for editing atoms.
+trait LuaAtomStorage: LuaUserData + Send + Sync + 'static {
+    fn lua_atoms(&self) -> &TrackedMap<Atom>;
+    fn lua_atoms_mut(&mut self) -> &mut TrackedMap<Atom>;
+}
+
+impl LuaAtomStorage for TrackedMap<Atom> {
+    fn lua_atoms(&self) -> &TrackedMap<Atom> {
+        self
+    }
+
+    fn lua_atoms_mut(&mut self) -> &mut TrackedMap<Atom> {
+        self
+    }
+}
+
+impl LuaAtomStorage for AtomMap {
+    fn lua_atoms(&self) -> &TrackedMap<Atom> {
+        &self.atoms
+    }
+
+    fn lua_atoms_mut(&mut self) -> &mut TrackedMap<Atom> {
+        &mut self.atoms
+    }
+}
+
+fn add_atom_methods<'lua, U: LuaAtomStorage, M: LuaUserDataMethods<'lua, U>>(methods: &mut M) {
+    methods.add_method("get", |_, this, coords: Vector3<i32>| {
+        Ok(this.lua_atoms().get(coords).copied().map(LuaAtom))
+    });
+    methods.add_method_mut(
+        "insert",
+        |_, this, (coords, atom): (Vector3<i32>, LuaAtom)| {
+            Ok(this.lua_atoms_mut().insert(coords, atom.0).map(LuaAtom))
+        },
+    );
+    methods.add_method_mut("remove", |_, this, coords: Vector3<i32>| {
+        Ok(this.lua_atoms_mut().remove(coords).map(LuaAtom))
+    });
+    methods.add_method_mut(
+        "remove_chunk",
+        |_, this, (layer, coords): (i32, ChunkCoords)| {
+            Ok(this.lua_atoms_mut().remove_chunk(layer, coords).is_some())
+        },
+    );
+    methods.add_method_mut("remove_layer", |_, this, layer: i32| {
+        Ok(this.lua_atoms_mut().remove_layer(layer).is_some())
+    });
+
+    // Returns an iterator function over the coordinates and atoms of all occupied cells in the
+    // inclusive box between `mins` and `maxs`, for use in a generic `for` loop.
+    methods.add_method(
+        "region",
+        |lua, this, (mins, maxs): (Vector3<i32>, Vector3<i32>)| {
+            let mut cells = Vec::new();
+            for z in mins.z..=maxs.z {
+                let layer = match this.lua_atoms().get_layer(z) {
+                    Some(layer) => layer,
+                    None => continue,
+                };
+
+                for y in mins.y..=maxs.y {
+                    for x in mins.x..=maxs.x {
+                        if let Some(&atom) = layer.get(Vector2::new(x, y)) {
+                            cells.push((Vector3::new(x, y, z), LuaAtom(atom)));
+                        }
+                    }
+                }
+            }
+
+            let mut cells = cells.into_iter();
+            lua.create_function_mut(move |_, ()| match cells.next() {
+                Some((coords, atom)) => Ok((Some(coords), Some(atom))),
+                None => Ok((None, None)),
+            })
+        },
+    );
+
+    methods.add_method_mut("register_reader", |_, this, ()| {
+        Ok(LatticeEventReader(
+            this.lua_atoms_mut().events_mut().register_reader(),
+        ))
+    });
+    methods.add_method("read_events", |lua, this, reader: LuaAnyUserData| {
+        let mut reader = reader.borrow_mut::<LatticeEventReader>()?;
+        this.lua_atoms()
+            .events()
+            .read(&mut reader.0)
+            .map(|event| event_to_lua(lua, event))
+            .collect::<LuaResult<Vec<_>>>()
+    });
+}
+
+impl LuaUserData for TrackedMap<Atom> {
+    fn on_metatable_init(table: Type<Self>) {
+        table.add_send().add_sync();
+    }
+
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        add_atom_methods(methods);
+    }
+
+    fn add_type_methods<'lua, M: LuaUserDataMethods<'lua, Type<Self>>>(methods: &mut M) {
+        methods.add_function("new", |_, ()| Ok(Self::new()));
+    }
+}
+
+impl LuaUserData for AtomMap {
+    fn on_metatable_init(table: Type<Self>) {
+        table.add_send().add_sync().add::<dyn std::fmt::Debug>();
+    }
+
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        add_atom_methods(methods);
+        methods.add_method_mut("calculate_hulls", |_, this, ()| {
+            this.calculate_hulls();
+            Ok(())
+        });
+        methods.add_method_mut("update_hulls", |_, this, ()| {
+            this.update_hulls();
+            Ok(())
+        });
+        methods.add_method_mut("rejoin_cell", |_, this, coords: Vector3<i32>| {
+            this.rejoin_cell(&Point3::from(coords));
+            Ok(())
+        });
+        methods.add_method_mut(
+            "rejoin_on_axis",
+            |_, this, (c0, c1): (Vector3<i32>, Vector3<i32>)| {
+                let (p0, p1) = (Point3::from(c0), Point3::from(c1));
+                if Axis::from_adjacent_coords(&p0, &p1).is_none() {
+                    return Err(anyhow!("cells {} and {} are not adjacent", c0, c1)).to_lua_err();
+                }
+                this.rejoin_on_axis(&p0, &p1);
+                Ok(())
+            },
+        );
+    }
+
+    fn add_type_methods<'lua, M: LuaUserDataMethods<'lua, Type<Self>>>(methods: &mut M) {
+        methods.add_function("new", |_, ()| Ok(Self::new()));
+    }
+}
+
+pub(crate) fn register_functions(builder: &mut ModuleBuilder) -> Result<()> {
+    builder
+        .function("num_atoms", |_, ()| Ok(ATOMS.len()))?
+        .function("get_atom", |lua, coords: Vector3<i32>| {
+            with_loaned(lua, |atom_map: &AtomMap| {
+                Ok(atom_map.atoms().get(coords).copied().map(LuaAtom))
+            })
+        })?
+        .function(
+            "set_atom",
+            |lua, (coords, atom): (Vector3<i32>, Option<LuaAtom>)| {
+                with_loaned_mut(lua, |atom_map: &mut AtomMap| {
+                    let atoms = atom_map.atoms_mut();
+                    Ok(match atom {
+                        Some(atom) => atoms.insert(coords, atom.0),
+                        None => atoms.remove(coords),
+                    }
+                    .map(LuaAtom))
+                })
+            },
+        )?
+        .function("update_hulls", |lua, ()| {
+            with_loaned_mut(lua, |atom_map: &mut AtomMap| {
+                atom_map.update_hulls();
+                Ok(())
+            })
+        })?
+        .function("calculate_hulls", |lua, ()| {
+            with_loaned_mut(lua, |atom_map: &mut AtomMap| {
+                atom_map.calculate_hulls();
+                Ok(())
+            })
+        })?
+        .function("rejoin_cell", |lua, coords: Vector3<i32>| {
+            with_loaned_mut(lua, |atom_map: &mut AtomMap| {
+                atom_map.rejoin_cell(&Point3::from(coords));
+                Ok(())
+            })
+        })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use soft_edge::{HullFacet, SortedPair};
+
+    use hv::fs::Filesystem;
+
+    use super::*;
+    use crate::lattice::{
+        chunk_map::{CHUNK_AREA, CHUNK_SIDE_LENGTH},
+        format::Compression,
+        SubCoords,
+    };
+
+    fn atom(atoms: &[Atom], coords: Vector3<i32>) -> Option<Atom> {
+        let i = (coords.x * 7 + coords.y * 13 + coords.z * 5).rem_euclid(atoms.len() as i32 + 3);
+        atoms.get(i as usize).copied()
+    }
+
+    fn assert_matches_full_calculation(incremental: &AtomMap) {
+        let full = AtomMap::from_atoms(TrackedMap::from_chunk_map(
+            incremental.atoms().as_chunk_map().clone(),
+        ));
+        assert_same_hulls(incremental, &full);
+    }
+
+    fn assert_same_hulls(incremental: &AtomMap, full: &AtomMap) {
+        let incremental_hulls = incremental.hulls().iter().collect::<HashMap<_, _>>();
+        let full_hulls = full.hulls().iter().collect::<HashMap<_, _>>();
+        assert_eq!(incremental_hulls.len(), full_hulls.len());
+        for (coords, &hull) in &full_hulls {
+            assert!(
+                incremental_hulls.get(coords) == Some(&hull),
+                "hulls differ at {}",
+                coords
+            );
+            assert!(incremental.shapes().get(*coords).is_some());
+        }
+        assert_eq!(
+            incremental.shapes().iter().count(),
+            full.shapes().iter().count()
+        );
+
+        for (coords, hull) in &full_hulls {
+            for facet in hull.facets() {
+                let vertices = match facet.translated_by(*coords) {
+                    HullFacet::Triangle(vs) => vs.to_vec(),
+                    HullFacet::Rectangle(vs) => vs.to_vec(),
+                };
+
+                let (incremental_edges, incremental_vertices) = incremental.filters(*coords);
+                let (full_edges, full_vertices) = full.filters(*coords);
+                for (i, &v) in vertices.iter().enumerate() {
+                    let w = vertices[(i + 1) % vertices.len()];
+                    assert_eq!(
+                        incremental_edges.edge_exists(SortedPair::new(v, w)),
+                        full_edges.edge_exists(SortedPair::new(v, w))
+                    );
+                    assert_eq!(
+                        incremental_vertices.vertex_exists(v),
+                        full_vertices.vertex_exists(v)
+                    );
+                }
+            }
+        }
+    }
+
+    fn test_map(atoms: &[Atom]) -> AtomMap {
+        let mut map = AtomMap::new();
+        for z in 0..3 {
+            for y in -4..20 {
+                for x in 10..22 {
+                    let coords = Vector3::new(x, y, z);
+                    if let Some(a) = atom(atoms, coords) {
+                        map.atoms_mut().insert(coords, a);
+                    }
+                }
+            }
+        }
+        map.calculate_hulls();
+        map
+    }
+
+    #[test]
+    fn chunk_filters_match_whole_map_filters() {
+        let atoms = Atom::generator().collect::<Vec<_>>();
+        let map = test_map(&atoms);
+
+        let mut edge_filter = EdgeFilter::default();
+        edge_filter.extend(map.hulls().iter().flat_map(|(coords, hull)| {
+            hull.facets()
+                .map(move |facet| (coords, facet.translated_by(coords)))
+        }));
+        let mut vertex_filter = VertexFilter::default();
+        vertex_filter.extend(edge_filter.iter());
+
+        for (coords, hull) in map.hulls().iter() {
+            let (chunk_edges, chunk_vertices) = map.filters(coords);
+            for facet in hull.facets() {
+                let vertices = match facet.translated_by(coords) {
+                    HullFacet::Triangle(vs) => vs.to_vec(),
+                    HullFacet::Rectangle(vs) => vs.to_vec(),
+                };
+
+                for (i, &v) in vertices.iter().enumerate() {
+                    let w = vertices[(i + 1) % vertices.len()];
+                    assert_eq!(
+                        chunk_edges.edge_exists(SortedPair::new(v, w)),
+                        edge_filter.edge_exists(SortedPair::new(v, w)),
+                        "edge filters differ at {}",
+                        coords
+                    );
+                    assert_eq!(
+                        chunk_vertices.vertex_exists(v),
+                        vertex_filter.vertex_exists(v),
+                        "vertex filters differ at {}",
+                        coords
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn incremental_slot_updates() {
+        let atoms = Atom::generator().collect::<Vec<_>>();
+        let mut map = test_map(&atoms);
+
+        // Changes on and across chunk boundaries, and between layers.
+        for (i, coords) in [
+            Vector3::new(15, 3, 1),
+            Vector3::new(16, 3, 1),
+            Vector3::new(12, -1, 0),
+            Vector3::new(12, 0, 2),
+            Vector3::new(30, 30, 3),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            map.atoms_mut().insert(coords, atoms[i * 3 % atoms.len()]);
+        }
+        map.atoms_mut()
+            .get_or_insert_layer(1)
+            .remove(Vector2::new(17, 3));
+        map.update_hulls();
+        assert_matches_full_calculation(&map);
+
+        // Set a slot and then put it back; the debounced event should still be handled.
+        let coords = Vector3::new(14, 8, 1);
+        let prev = *map.atoms().get(coords).unwrap_or(&atoms[0]);
+        map.atoms_mut().insert(coords, atoms[1]);
+        map.atoms_mut().insert(coords, prev);
+        map.update_hulls();
+        assert_matches_full_calculation(&map);
+    }
+
+    #[test]
+    fn incremental_chunk_and_layer_updates() {
+        let atoms = Atom::generator().collect::<Vec<_>>();
+        let mut map = test_map(&atoms);
+
+        let mut chunk = Chunk::default();
+        for linear in 0..CHUNK_AREA {
+            chunk.insert(SubCoords::from_linear(linear), atoms[linear % atoms.len()]);
+        }
+        map.atoms_mut()
+            .insert_chunk(1, ChunkCoords::new(1, 1), chunk);
+        map.atoms_mut().remove_chunk(2, ChunkCoords::new(0, -1));
+        map.update_hulls();
+        assert_matches_full_calculation(&map);
+
+        map.atoms_mut().remove_layer(1);
+        map.update_hulls();
+        assert_matches_full_calculation(&map);
+
+        map.atoms_mut().insert(Vector3::new(11, 2, 1), atoms[0]);
+        map.update_hulls();
+        assert_matches_full_calculation(&map);
+    }
+
+    #[test]
+    fn uniform_map_is_compacted() {
+        use std::mem::size_of;
+
+        let atom = Atom::generator().next().unwrap();
+        let mut map = AtomMap::new();
+        for z in 0..2 {
+            for y in 0..CHUNK_SIDE_LENGTH as i32 {
+                for x in 0..CHUNK_SIDE_LENGTH as i32 {
+                    map.atoms_mut().insert(Vector3::new(x, y, z), atom);
+                }
+            }
+        }
+
+        // Edits through the tracked map keep the atoms uniform as they're inserted.
+        let dense =
+            2 * CHUNK_AREA * (size_of::<CompoundHull>() + size_of::<Arc<CompoundHullShape>>());
+        assert_eq!(map.atoms().as_chunk_map().heap_size(), 0);
+
+        map.update_hulls();
+        assert_eq!(map.atoms().as_chunk_map().heap_size(), 0);
+        let heap_size = map.hulls().heap_size() + map.shapes().heap_size();
+        assert!(heap_size < dense);
+        assert_matches_full_calculation(&map);
+    }
+
+    #[test]
+    fn loading_rebuilds_hulls_and_filters() {
+        let atoms = Atom::generator().collect::<Vec<_>>();
+        let map = test_map(&atoms);
+
+        let mut fs = Filesystem::new();
+        fs.mount_memory();
+        map.save(&mut fs, "/atoms.map", Compression::RunLength)
+            .unwrap();
+        let loaded = AtomMap::load(&mut fs, "/atoms.map").unwrap();
+
+        assert_eq!(
+            loaded
+                .atoms()
+                .as_chunk_map()
+                .iter()
+                .collect::<HashMap<_, _>>(),
+            map.atoms().as_chunk_map().iter().collect::<HashMap<_, _>>()
+        );
+        assert_same_hulls(&loaded, &map);
     }
 }
@@ -1,5 +1,6 @@
 use std::{
     collections::{BTreeMap, HashMap},
+    fmt,
     mem::MaybeUninit,
     ops::{Deref, Index, IndexMut, RangeBounds},
     ptr::NonNull,
@@ -26,6 +27,17 @@ impl ChunkCoords {
     pub fn new(x: i32, y: i32) -> Self {
         Self(Vector2::new(x, y))
     }
+
+    /// Iterate over the world coordinates of every slot in this chunk.
+    pub fn world_coords(self) -> impl Iterator<Item = Vector2<i32>> {
+        (0..CHUNK_AREA).map(move |linear| {
+            DividedCoords {
+                chunk_coords: self,
+                sub_coords: SubCoords::from_linear(linear),
+            }
+            .to_world_coords()
+        })
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Hash)]
@@ -98,20 +110,112 @@ impl DividedCoords {
 // Can't #[derive] on something w/ a type macro in it. (?? what?)
 type ValidBits = BitArr!(for 256);
 
+/// The largest number of distinct values a palette-compressed chunk can hold.
+pub const MAX_PALETTE_LEN: usize = u8::MAX as usize + 1;
+
+/// How the values of a [`Chunk`] are currently stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChunkStorageKind {
+    /// Every occupied slot holds the same value, which is stored once. Empty chunks are uniform,
+    /// and allocate nothing.
+    Uniform,
+    /// Occupied slots hold a byte indexing into a palette of distinct values.
+    Palette,
+    /// Every slot has space for its own value.
+    Dense,
+}
+
+// Compressed storage shares values between slots, and has to clone them when slots are split off
+// of it again. Keeping the clone function in the storage means only compressing a chunk requires
+// `T: Clone`, rather than every method which might have to decompress it.
+struct Cloner<T>(fn(&T) -> T);
+
+impl<T> Clone for Cloner<T> {
+    fn clone(&self) -> Self {
+        Cloner(self.0)
+    }
+}
+
+impl<T> Copy for Cloner<T> {}
+
+impl<T> fmt::Debug for Cloner<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Cloner")
+    }
+}
+
+#[derive(Debug)]
+enum Storage<T> {
+    // No slots are occupied, and nothing is allocated.
+    Empty,
+    // Every occupied slot holds `value`.
+    Uniform {
+        value: T,
+        cloner: Cloner<T>,
+    },
+    // The palette may hold values which are no longer used by any slot, until the chunk is
+    // compacted.
+    Palette {
+        palette: Vec<T>,
+        indices: Box<[u8; CHUNK_AREA]>,
+        cloner: Cloner<T>,
+    },
+    Dense(Box<[MaybeUninit<T>; CHUNK_AREA]>),
+}
+
+/// A square of `CHUNK_SIDE_LENGTH` by `CHUNK_SIDE_LENGTH` optionally occupied slots.
+///
+/// Values are stored densely, uniformly (one value shared by every occupied slot) or
+/// palette-compressed (a byte per slot, indexing a few distinct values). [`Chunk::insert`] and
+/// mutable borrows work for any `T`, and store values densely; an empty chunk allocates nothing,
+/// and a chunk frees its storage when its last slot is removed. [`Chunk::compact`] switches the
+/// chunk to whichever representation uses the least memory, and [`Chunk::insert_dedup`] and
+/// [`Chunk::remove_compact`] (or their `_by` variants, for types w/o a suitable `PartialEq`) keep
+/// it that way as it is edited. [`TrackedMap`](crate::lattice::tracked_map::TrackedMap) edits
+/// chunks through the latter, and chunks read from disk are compacted as they are loaded.
 #[derive(Debug)]
 pub struct Chunk<T> {
-    data: Box<[MaybeUninit<T>; CHUNK_AREA]>,
+    storage: Storage<T>,
     valid: ValidBits,
 }
 
 impl<T: Clone> Clone for Chunk<T> {
     fn clone(&self) -> Self {
-        let mut new_chunk = Chunk::default();
-        for index in self.valid.iter_ones() {
-            new_chunk.data[index].write(unsafe { self.data[index].assume_init_ref() }.clone());
+        let storage = match &self.storage {
+            Storage::Empty => Storage::Empty,
+            Storage::Uniform { value, cloner } => Storage::Uniform {
+                value: value.clone(),
+                cloner: *cloner,
+            },
+            Storage::Palette {
+                palette,
+                indices,
+                cloner,
+            } => Storage::Palette {
+                palette: palette.clone(),
+                indices: indices.clone(),
+                cloner: *cloner,
+            },
+            Storage::Dense(data) => {
+                let mut new_data: Box<[MaybeUninit<T>; CHUNK_AREA]> =
+                    Box::new(MaybeUninit::uninit_array());
+                for index in self.valid.iter_ones() {
+                    new_data[index].write(unsafe { data[index].assume_init_ref() }.clone());
+                }
+                Storage::Dense(new_data)
+            }
+        };
+
+        Chunk {
+            storage,
+            valid: self.valid,
         }
-        new_chunk.valid = self.valid;
-        new_chunk
+    }
+}
+
+impl<T> Drop for Chunk<T> {
+    fn drop(&mut self) {
+        self.drop_dense_values();
     }
 }
 
@@ -121,7 +225,7 @@ impl<T> Index<SubCoords> for Chunk<T> {
     fn index(&self, index: SubCoords) -> &Self::Output {
         let linear = index.to_linear();
         assert!(self.valid[linear], "no initialized data at index!");
-        unsafe { self.data[linear].assume_init_ref() }
+        self.slot(linear)
     }
 }
 
@@ -129,40 +233,99 @@ impl<T> IndexMut<SubCoords> for Chunk<T> {
     fn index_mut(&mut self, index: SubCoords) -> &mut Self::Output {
         let linear = index.to_linear();
         assert!(self.valid[linear], "no initialized data at index!");
-        unsafe { self.data[linear].assume_init_mut() }
+        unsafe { self.make_dense()[linear].assume_init_mut() }
     }
 }
 
 impl<T> Default for Chunk<T> {
     fn default() -> Self {
         Chunk {
-            data: Box::new(MaybeUninit::uninit_array()),
+            storage: Storage::Empty,
             valid: BitArray::zeroed(),
         }
     }
 }
 
 impl<T> Chunk<T> {
+    // Get the value of a slot which is known to be occupied.
+    fn slot(&self, linear: usize) -> &T {
+        match &self.storage {
+            Storage::Empty => unreachable!("occupied slot in empty chunk!"),
+            Storage::Uniform { value, .. } => value,
+            Storage::Palette {
+                palette, indices, ..
+            } => &palette[indices[linear] as usize],
+            Storage::Dense(data) => unsafe { data[linear].assume_init_ref() },
+        }
+    }
+
+    // Run the destructors of the values in a dense chunk, without marking their slots unoccupied.
+    fn drop_dense_values(&mut self) {
+        if let Storage::Dense(data) = &mut self.storage {
+            for index in self.valid.iter_ones() {
+                unsafe { data[index].assume_init_drop() };
+            }
+        }
+    }
+
+    // Whether palette compression w/ a palette of some length uses less memory than dense storage.
+    fn palette_is_smaller(len: usize) -> bool {
+        len <= MAX_PALETTE_LEN
+            && CHUNK_AREA + len * std::mem::size_of::<T>() < CHUNK_AREA * std::mem::size_of::<T>()
+    }
+
+    // Switch to dense storage, if the chunk isn't dense already.
+    fn make_dense(&mut self) -> &mut [MaybeUninit<T>; CHUNK_AREA] {
+        if !matches!(self.storage, Storage::Dense(_)) {
+            let mut data: Box<[MaybeUninit<T>; CHUNK_AREA]> = Box::new(MaybeUninit::uninit_array());
+            match &self.storage {
+                Storage::Uniform { value, cloner } => {
+                    for index in self.valid.iter_ones() {
+                        data[index].write((cloner.0)(value));
+                    }
+                }
+                Storage::Palette {
+                    palette,
+                    indices,
+                    cloner,
+                } => {
+                    for index in self.valid.iter_ones() {
+                        data[index].write((cloner.0)(&palette[indices[index] as usize]));
+                    }
+                }
+                Storage::Empty | Storage::Dense(_) => {}
+            }
+            self.storage = Storage::Dense(data);
+        }
+
+        match &mut self.storage {
+            Storage::Dense(data) => data,
+            _ => unreachable!(),
+        }
+    }
+
     pub fn get(&self, index: SubCoords) -> Option<&T> {
         let linear = index.to_linear();
-        self.valid[linear].then(|| unsafe { self.data[linear].assume_init_ref() })
+        self.valid[linear].then(|| self.slot(linear))
     }
 
     pub fn get_mut(&mut self, index: SubCoords) -> Option<&mut T> {
         let linear = index.to_linear();
-        self.valid[linear].then(|| unsafe { self.data[linear].assume_init_mut() })
+        self.valid[linear].then(move || unsafe { self.make_dense()[linear].assume_init_mut() })
     }
 
     pub fn insert(&mut self, index: SubCoords, val: T) -> Option<T> {
         let linear = index.to_linear();
-        match self.valid[linear] {
+        let occupied = self.valid[linear];
+        let data = self.make_dense();
+        match occupied {
             true => Some(std::mem::replace(
-                unsafe { self.data[linear].assume_init_mut() },
+                unsafe { data[linear].assume_init_mut() },
                 val,
             )),
             false => {
+                data[linear].write(val);
                 self.valid.set(linear, true);
-                self.data[linear].write(val);
                 None
             }
         }
@@ -170,39 +333,228 @@ impl<T> Chunk<T> {
 
     pub fn remove(&mut self, index: SubCoords) -> Option<T> {
         let linear = index.to_linear();
-        self.valid[linear].then(|| {
-            self.valid.set(linear, false);
-            unsafe { std::ptr::read(&self.data[linear]).assume_init() }
-        })
+        if !self.valid[linear] {
+            return None;
+        }
+
+        let removed = match &self.storage {
+            Storage::Empty => unreachable!("occupied slot in empty chunk!"),
+            Storage::Uniform { value, cloner } => (cloner.0)(value),
+            Storage::Palette {
+                palette,
+                indices,
+                cloner,
+            } => (cloner.0)(&palette[indices[linear] as usize]),
+            Storage::Dense(data) => unsafe { std::ptr::read(&data[linear]).assume_init() },
+        };
+        self.valid.set(linear, false);
+
+        // Free the storage once the chunk is empty; any dense values have been moved out by now.
+        if self.is_empty() {
+            self.storage = Storage::Empty;
+        }
+
+        Some(removed)
     }
 
     pub fn iter(&self) -> impl Iterator<Item = (SubCoords, &T)> + ExactSizeIterator {
-        self.valid.iter_ones().map(|i| {
-            (SubCoords::from_linear(i), unsafe {
-                self.data[i].assume_init_ref()
-            })
-        })
+        self.valid
+            .iter_ones()
+            .map(|i| (SubCoords::from_linear(i), self.slot(i)))
     }
 
     pub fn iter_mut(&mut self) -> impl Iterator<Item = (SubCoords, &mut T)> + ExactSizeIterator {
-        self.valid.iter_ones().map(|i| {
+        // Don't allocate just to iterate over nothing; the pointer is never read if there are no
+        // occupied slots.
+        let data = match self.is_empty() {
+            true => std::ptr::null_mut(),
+            false => self.make_dense().as_mut_ptr(),
+        };
+
+        self.valid.iter_ones().map(move |i| {
             // safety: we're continuously borrowing different elements, and we have mutable access;
             // so no one else is going to try to access the same two, and we're assured we won't try
             // to access the same two since our valid bits won't repeat.
             (SubCoords::from_linear(i), unsafe {
-                (*(&mut self.data[i] as *mut MaybeUninit<_>)).assume_init_mut()
+                (*data.add(i)).assume_init_mut()
             })
         })
     }
 
-    /// Clear the chunk.
+    /// The number of occupied slots.
+    pub fn len(&self) -> usize {
+        self.valid.count_ones()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.valid.not_any()
+    }
+
+    pub fn storage_kind(&self) -> ChunkStorageKind {
+        match self.storage {
+            Storage::Empty | Storage::Uniform { .. } => ChunkStorageKind::Uniform,
+            Storage::Palette { .. } => ChunkStorageKind::Palette,
+            Storage::Dense(_) => ChunkStorageKind::Dense,
+        }
+    }
+
+    /// The number of bytes allocated by this chunk to store its values, not counting any memory
+    /// owned by the values themselves.
+    pub fn heap_size(&self) -> usize {
+        match &self.storage {
+            Storage::Empty | Storage::Uniform { .. } => 0,
+            Storage::Palette { palette, .. } => {
+                CHUNK_AREA + palette.capacity() * std::mem::size_of::<T>()
+            }
+            Storage::Dense(_) => CHUNK_AREA * std::mem::size_of::<T>(),
+        }
+    }
+
+    /// Clear the chunk, freeing any storage it allocated.
     pub fn clear(&mut self) {
-        // TODO: most cases should not need to run a destructor on the elements, but, for
-        // completeness and correctness, it should be done.
+        self.drop_dense_values();
+        self.storage = Storage::Empty;
         self.valid.set_all(false);
     }
 }
 
+impl<T: Clone> Chunk<T> {
+    /// Switch to whichever storage uses the least memory for the values currently in the chunk,
+    /// w/ `eq` deciding which values can share storage. Unused values are dropped from the palette
+    /// of a palette-compressed chunk.
+    pub fn compact_by(&mut self, mut eq: impl FnMut(&T, &T) -> bool) {
+        let mut palette: Vec<T> = Vec::new();
+        let mut indices = Box::new([0; CHUNK_AREA]);
+        for index in self.valid.iter_ones() {
+            let value = self.slot(index);
+            indices[index] = match palette.iter().position(|v| eq(v, value)) {
+                Some(i) => i as u8,
+                None if palette.is_empty() || Self::palette_is_smaller(palette.len() + 1) => {
+                    palette.push(value.clone());
+                    (palette.len() - 1) as u8
+                }
+                None => {
+                    self.make_dense();
+                    return;
+                }
+            };
+        }
+
+        let cloner = Cloner(T::clone);
+        self.drop_dense_values();
+        self.storage = match palette.pop() {
+            None => Storage::Empty,
+            Some(value) if palette.is_empty() => Storage::Uniform { value, cloner },
+            Some(value) => {
+                palette.push(value);
+                palette.shrink_to_fit();
+                Storage::Palette {
+                    palette,
+                    indices,
+                    cloner,
+                }
+            }
+        };
+    }
+
+    /// Insert a value like [`Chunk::insert`], but share its storage w/ any value already in the
+    /// chunk which `eq` considers equal to it, so that uniform and palette-compressed chunks stay
+    /// compressed as long as they can.
+    pub fn insert_dedup_by(
+        &mut self,
+        index: SubCoords,
+        val: T,
+        mut eq: impl FnMut(&T, &T) -> bool,
+    ) -> Option<T> {
+        if let Storage::Dense(_) = self.storage {
+            return self.insert(index, val);
+        }
+
+        let linear = index.to_linear();
+        let occupied = self.valid[linear];
+        let prev = occupied.then(|| self.slot(linear).clone());
+        let only_slot = occupied && self.len() == 1;
+        let cloner = Cloner(T::clone);
+
+        match &mut self.storage {
+            Storage::Empty => self.storage = Storage::Uniform { value: val, cloner },
+            Storage::Uniform { value, .. } => {
+                if only_slot || eq(value, &val) {
+                    *value = val;
+                } else if Self::palette_is_smaller(2) {
+                    let mut indices = Box::new([0; CHUNK_AREA]);
+                    indices[linear] = 1;
+                    let palette = vec![value.clone(), val];
+                    self.storage = Storage::Palette {
+                        palette,
+                        indices,
+                        cloner,
+                    };
+                } else {
+                    return self.insert(index, val);
+                }
+            }
+            Storage::Palette {
+                palette, indices, ..
+            } => match palette.iter().position(|v| eq(v, &val)) {
+                Some(i) => indices[linear] = i as u8,
+                None if Self::palette_is_smaller(palette.len() + 1) => {
+                    indices[linear] = palette.len() as u8;
+                    palette.push(val);
+                }
+                // Try to make room by dropping unused values from the palette, and fall back to
+                // dense storage if that doesn't help.
+                None => {
+                    self.compact_by(&mut eq);
+                    if let Storage::Palette { palette, .. } = &self.storage {
+                        if !Self::palette_is_smaller(palette.len() + 1) {
+                            self.make_dense();
+                        }
+                    }
+                    return self.insert_dedup_by(index, val, eq);
+                }
+            },
+            Storage::Dense(_) => unreachable!(),
+        }
+
+        self.valid.set(linear, true);
+        prev
+    }
+
+    /// Remove a value like [`Chunk::remove`], and compact a dense chunk w/ `eq` each time the
+    /// number of occupied slots falls to a power of two, so that a chunk which has been
+    /// decompressed by mutable access returns to a compressed representation as it empties out.
+    /// Compacting at halving occupancies keeps the cost of removals amortized constant.
+    pub fn remove_compact_by(
+        &mut self,
+        index: SubCoords,
+        eq: impl FnMut(&T, &T) -> bool,
+    ) -> Option<T> {
+        let removed = self.remove(index)?;
+        if matches!(self.storage, Storage::Dense(_)) && self.len().is_power_of_two() {
+            self.compact_by(eq);
+        }
+        Some(removed)
+    }
+}
+
+impl<T: Clone + PartialEq> Chunk<T> {
+    /// Compact the chunk, sharing storage between equal values. See [`Chunk::compact_by`].
+    pub fn compact(&mut self) {
+        self.compact_by(T::eq);
+    }
+
+    /// Insert a value, sharing storage w/ equal values. See [`Chunk::insert_dedup_by`].
+    pub fn insert_dedup(&mut self, index: SubCoords, val: T) -> Option<T> {
+        self.insert_dedup_by(index, val, T::eq)
+    }
+
+    /// Remove a value, compacting the chunk as it empties out. See [`Chunk::remove_compact_by`].
+    pub fn remove_compact(&mut self, index: SubCoords) -> Option<T> {
+        self.remove_compact_by(index, T::eq)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ChunkLayer<T> {
     chunks: HashMap<ChunkCoords, Chunk<T>>,
@@ -266,7 +618,7 @@ impl<T> ChunkLayer<T> {
     pub fn get_n_mut<const N: usize>(&mut self, coords: [Vector2<i32>; N]) -> [Option<&mut T>; N] {
         // Fast if N is small.
         for i in 0..N {
-            for j in i..N {
+            for j in i + 1..N {
                 assert_ne!(
                     coords[i], coords[j],
                     "cannot mutably borrow the same slot twice!"
@@ -344,6 +696,27 @@ impl<T> ChunkLayer<T> {
     pub fn clear(&mut self) {
         self.chunks.values_mut().for_each(Chunk::clear);
     }
+
+    /// The number of bytes allocated by the chunks of this layer to store their values.
+    pub fn heap_size(&self) -> usize {
+        self.chunks.values().map(Chunk::heap_size).sum()
+    }
+}
+
+impl<T: Clone> ChunkLayer<T> {
+    /// Compact the storage of every chunk in this layer. See [`Chunk::compact_by`].
+    pub fn compact_by(&mut self, mut eq: impl FnMut(&T, &T) -> bool) {
+        for chunk in self.chunks.values_mut() {
+            chunk.compact_by(&mut eq);
+        }
+    }
+}
+
+impl<T: Clone + PartialEq> ChunkLayer<T> {
+    /// Compact the storage of every chunk in this layer. See [`Chunk::compact`].
+    pub fn compact(&mut self) {
+        self.compact_by(T::eq);
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -481,8 +854,304 @@ impl<T> ChunkMap<T> {
             .flat_map(|(z, layer)| layer.iter_mut().map(move |(xy, value)| (xy.push(z), value)))
     }
 
-    /// Clear the map while retaining the allocated memory.
+    /// Clear the map, keeping its layers and chunks but freeing their storage.
     pub fn clear(&mut self) {
         self.layers.values_mut().for_each(ChunkLayer::clear);
     }
+
+    /// The number of bytes allocated by the chunks of this map to store their values.
+    pub fn heap_size(&self) -> usize {
+        self.layers.values().map(ChunkLayer::heap_size).sum()
+    }
+}
+
+impl<T: Clone> ChunkMap<T> {
+    /// Compact the storage of every chunk in this map. See [`Chunk::compact_by`].
+    pub fn compact_by(&mut self, mut eq: impl FnMut(&T, &T) -> bool) {
+        for layer in self.layers.values_mut() {
+            layer.compact_by(&mut eq);
+        }
+    }
+}
+
+impl<T: Clone + PartialEq> ChunkMap<T> {
+    /// Compact the storage of every chunk in this map. See [`Chunk::compact`].
+    pub fn compact(&mut self) {
+        self.compact_by(T::eq);
+    }
+}
+
+impl LuaUserData for ChunkCoords {
+    fn on_metatable_init(table: Type<Self>) {
+        table
+            .add_clone()
+            .add_copy()
+            .add_send()
+            .add_sync()
+            .add::<dyn std::fmt::Debug>();
+    }
+
+    fn add_fields<'lua, F: LuaUserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("x", |_, this| Ok(this.x));
+        fields.add_field_method_get("y", |_, this| Ok(this.y));
+    }
+
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("to_world_coords", |_, this, sub: Option<SubCoords>| {
+            let sub_coords = sub.unwrap_or_else(|| SubCoords::new(Vector2::zeros()));
+            Ok(DividedCoords {
+                chunk_coords: *this,
+                sub_coords,
+            }
+            .to_world_coords())
+        });
+        methods.add_meta_method(LuaMetaMethod::Eq, |_, this, other: Self| Ok(*this == other));
+    }
+
+    fn add_type_methods<'lua, M: LuaUserDataMethods<'lua, Type<Self>>>(methods: &mut M) {
+        methods.add_function("new", |_, (x, y)| Ok(Self::new(x, y)));
+        methods.add_function("from_world_coords", |_, coords: Vector2<i32>| {
+            let divided = DividedCoords::from_world_coords(coords);
+            Ok((divided.chunk_coords, divided.sub_coords))
+        });
+    }
+}
+
+impl LuaUserData for SubCoords {
+    fn on_metatable_init(table: Type<Self>) {
+        table
+            .add_clone()
+            .add_copy()
+            .add_send()
+            .add_sync()
+            .add::<dyn std::fmt::Debug>();
+    }
+
+    fn add_fields<'lua, F: LuaUserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("x", |_, this| Ok(this.x));
+        fields.add_field_method_get("y", |_, this| Ok(this.y));
+    }
+
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("to_linear", |_, this, ()| Ok(this.to_linear()));
+        methods.add_meta_method(LuaMetaMethod::Eq, |_, this, other: Self| Ok(*this == other));
+    }
+
+    fn add_type_methods<'lua, M: LuaUserDataMethods<'lua, Type<Self>>>(methods: &mut M) {
+        // Check the bounds here rather than letting `SubCoords::new` panic on bad script input.
+        methods.add_function("new", |_, (x, y): (u32, u32)| {
+            if x >= CHUNK_SIDE_LENGTH as u32 || y >= CHUNK_SIDE_LENGTH as u32 {
+                return Err(anyhow!("sub-coordinates ({}, {}) out of bounds", x, y)).to_lua_err();
+            }
+            Ok(Self::new(Vector2::new(x, y)))
+        });
+        methods.add_function("from_linear", |_, linear: usize| {
+            if linear >= CHUNK_AREA {
+                return Err(anyhow!("linear sub-coordinate {} out of bounds", linear)).to_lua_err();
+            }
+            Ok(Self::from_linear(linear))
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+
+    fn sub(linear: usize) -> SubCoords {
+        SubCoords::from_linear(linear)
+    }
+
+    #[test]
+    fn storage_follows_contents() {
+        let mut chunk = Chunk::default();
+        assert_eq!(chunk.storage_kind(), ChunkStorageKind::Uniform);
+        assert_eq!(chunk.heap_size(), 0);
+
+        for i in 0..CHUNK_AREA {
+            chunk.insert_dedup(sub(i), 7u32);
+        }
+        assert_eq!(chunk.storage_kind(), ChunkStorageKind::Uniform);
+        assert_eq!(chunk.heap_size(), 0);
+
+        assert_eq!(chunk.insert_dedup(sub(3), 8), Some(7));
+        assert_eq!(chunk.storage_kind(), ChunkStorageKind::Palette);
+        assert_eq!(chunk.get(sub(3)), Some(&8));
+        assert_eq!(chunk.get(sub(4)), Some(&7));
+
+        for i in 0..CHUNK_AREA {
+            chunk.insert_dedup(sub(i), i as u32);
+        }
+        assert_eq!(chunk.storage_kind(), ChunkStorageKind::Dense);
+        assert!(chunk
+            .iter()
+            .all(|(sub, &value)| value == sub.to_linear() as u32));
+
+        let remaining = CHUNK_AREA / 4;
+        for i in remaining..CHUNK_AREA {
+            assert_eq!(chunk.remove(sub(i)), Some(i as u32));
+        }
+        assert_eq!(chunk.storage_kind(), ChunkStorageKind::Dense);
+        chunk.compact();
+        assert_eq!(chunk.storage_kind(), ChunkStorageKind::Palette);
+        assert_eq!(chunk.len(), remaining);
+        assert!(chunk
+            .iter()
+            .all(|(sub, &value)| value == sub.to_linear() as u32));
+
+        for i in 0..remaining {
+            chunk.remove(sub(i));
+        }
+        assert!(chunk.is_empty());
+        assert_eq!(chunk.storage_kind(), ChunkStorageKind::Uniform);
+        assert_eq!(chunk.heap_size(), 0);
+    }
+
+    #[test]
+    fn plain_insert_goes_dense() {
+        let mut chunk = Chunk::default();
+        chunk.insert(sub(0), 1u32);
+        chunk.insert(sub(1), 1);
+        assert_eq!(chunk.storage_kind(), ChunkStorageKind::Dense);
+
+        chunk.compact();
+        assert_eq!(chunk.storage_kind(), ChunkStorageKind::Uniform);
+        assert_eq!(chunk.heap_size(), 0);
+
+        assert_eq!(chunk.insert(sub(1), 2), Some(1));
+        assert_eq!(chunk.storage_kind(), ChunkStorageKind::Dense);
+        assert_eq!(chunk.get(sub(0)), Some(&1));
+        assert_eq!(chunk.get(sub(1)), Some(&2));
+    }
+
+    #[test]
+    fn mutable_access_goes_dense() {
+        let mut chunk = Chunk::default();
+        for i in 0..CHUNK_AREA {
+            chunk.insert_dedup(sub(i), (i % 3) as u32);
+        }
+        assert_eq!(chunk.storage_kind(), ChunkStorageKind::Palette);
+
+        *chunk.get_mut(sub(5)).unwrap() += 10;
+        assert_eq!(chunk.storage_kind(), ChunkStorageKind::Dense);
+        chunk[sub(6)] += 10;
+        for (_, value) in chunk.iter_mut() {
+            *value += 1;
+        }
+        assert_eq!(chunk[sub(4)], 2);
+        assert_eq!(chunk[sub(5)], 13);
+        assert_eq!(chunk[sub(6)], 11);
+
+        let clone = chunk.clone();
+        chunk.compact();
+        assert_eq!(chunk.storage_kind(), ChunkStorageKind::Palette);
+        assert!(chunk.iter().eq(clone.iter()));
+
+        let mut empty = Chunk::<u32>::default();
+        assert_eq!(empty.iter_mut().count(), 0);
+        assert_eq!(empty.heap_size(), 0);
+    }
+
+    #[test]
+    fn layer_get_n_mut() {
+        let mut layer = ChunkLayer::new();
+        layer.insert(Vector2::new(0, 0), 1);
+        layer.insert(Vector2::new(-1, 0), 2);
+
+        let [a, b] = layer
+            .get_all_n_mut([Vector2::new(0, 0), Vector2::new(-1, 0)])
+            .unwrap();
+        std::mem::swap(a, b);
+        assert_eq!(layer.get(Vector2::new(0, 0)), Some(&2));
+        assert_eq!(layer.get(Vector2::new(-1, 0)), Some(&1));
+        assert!(layer
+            .get_all_n_mut([Vector2::new(0, 0), Vector2::new(1, 0)])
+            .is_none());
+    }
+
+    #[test]
+    #[should_panic]
+    fn layer_get_n_mut_aliasing() {
+        let mut layer = ChunkLayer::<u32>::new();
+        layer.get_n_mut([Vector2::new(3, 3), Vector2::new(3, 3)]);
+    }
+
+    #[test]
+    fn values_are_dropped() {
+        let value = Rc::new(0);
+        let mut chunk = Chunk::default();
+        for i in 0..CHUNK_AREA {
+            chunk.insert(sub(i), Rc::new(i));
+        }
+        chunk.insert(sub(0), value.clone());
+        chunk.insert(sub(1), value.clone());
+        assert_eq!(Rc::strong_count(&value), 3);
+
+        let clone = chunk.clone();
+        assert_eq!(Rc::strong_count(&value), 5);
+        drop(clone);
+        chunk.remove(sub(0));
+        assert_eq!(Rc::strong_count(&value), 2);
+        chunk.clear();
+        assert_eq!(Rc::strong_count(&value), 1);
+
+        // Uniform storage holds a single clone, and hands out clones of it when split up.
+        for i in 0..4 {
+            chunk.insert_dedup_by(sub(i), value.clone(), Rc::ptr_eq);
+        }
+        assert_eq!(chunk.storage_kind(), ChunkStorageKind::Uniform);
+        assert_eq!(Rc::strong_count(&value), 2);
+        chunk.get_mut(sub(2));
+        assert_eq!(Rc::strong_count(&value), 5);
+        chunk.compact_by(Rc::ptr_eq);
+        assert_eq!(Rc::strong_count(&value), 2);
+
+        drop(chunk);
+        assert_eq!(Rc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn removal_compacts_dense_chunks() {
+        let mut chunk = Chunk::default();
+        for i in 0..CHUNK_AREA {
+            chunk.insert(sub(i), (i % 2) as u32);
+        }
+        *chunk.get_mut(sub(0)).unwrap() += 2;
+        assert_eq!(chunk.storage_kind(), ChunkStorageKind::Dense);
+
+        // Nothing is compacted until the occupancy halves.
+        for i in 0..CHUNK_AREA / 2 - 1 {
+            assert!(chunk.remove_compact(sub(i)).is_some());
+            assert_eq!(chunk.storage_kind(), ChunkStorageKind::Dense);
+        }
+        chunk.remove_compact(sub(CHUNK_AREA / 2 - 1));
+        assert_eq!(chunk.storage_kind(), ChunkStorageKind::Palette);
+
+        // Leave only the odd slots of the last quarter, which all hold the same value.
+        for i in CHUNK_AREA / 2..CHUNK_AREA * 3 / 4 {
+            chunk.remove_compact(sub(i));
+        }
+        for i in (CHUNK_AREA * 3 / 4..CHUNK_AREA).step_by(2) {
+            chunk.remove_compact(sub(i));
+        }
+        assert!(chunk.iter().all(|(_, &value)| value == 1));
+
+        // Mutable access decompresses the chunk again, and removal compacts it back down to a
+        // single shared value, and then to nothing.
+        chunk.iter_mut().for_each(|(_, value)| *value += 1);
+        assert_eq!(chunk.storage_kind(), ChunkStorageKind::Dense);
+        let remaining = chunk.iter().map(|(index, _)| index).collect::<Vec<_>>();
+        for index in remaining {
+            chunk.remove_compact(index);
+            if chunk.len() == 1 {
+                assert_eq!(chunk.storage_kind(), ChunkStorageKind::Uniform);
+                assert_eq!(chunk.heap_size(), 0);
+            }
+        }
+        assert!(chunk.is_empty());
+        assert_eq!(chunk.storage_kind(), ChunkStorageKind::Uniform);
+        assert_eq!(chunk.heap_size(), 0);
+    }
 }
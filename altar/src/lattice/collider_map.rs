@@ -1,16 +1,26 @@
 use hv::{
-    ecs::{Entity, QueryMarker, SystemContext},
+    ecs::{Entity, Query, QueryMarker, SystemContext},
     prelude::*,
+    script::api::ModuleBuilder,
+};
+use parry3d::{
+    bounding_volume::{BoundingVolume, AABB},
+    partitioning::QBVH,
+    query::PointQuery,
 };
-use parry3d::{bounding_volume::AABB, partitioning::QBVH};
 use shrev::ReaderId;
 use std::collections::HashMap;
 
 use crate::{
+    api::with_loaned,
     lattice::{event::LatticeEvent, tracked_map::TrackedMap},
     physics::Collider,
 };
 
+// How far the box of a cell is shrunk before testing it against colliders, so that colliders which
+// only touch the cell's boundary aren't counted as occupying it.
+const CELL_EPSILON: f32 = 1e-3;
+
 // Pack three i32 coordinates into 3 bytes, 3 bytes, and 2 bytes.
 fn pack_coords(coords: Vector3<i32>) -> u64 {
     assert!(coords.x.abs() < (1 << 23) - 1);
@@ -55,13 +65,46 @@ impl Intersections {
     }
 }
 
+/// An entity found by a neighbourhood query on a [`ColliderMap`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Neighbor {
+    pub entity: Entity,
+    /// The lattice cell the entity occupies.
+    pub coords: Vector3<i32>,
+    /// Distance from the query point to the AABB of the entity's collider; zero if the point is
+    /// inside it.
+    pub distance: f32,
+}
+
+/// Build a filter for [`ColliderMap`] queries which only accepts entities matching the query `Q`,
+/// for use from inside a yaks system.
+pub fn matching<'a, Q>(
+    context: &'a SystemContext<'a>,
+    query: QueryMarker<Q>,
+) -> impl FnMut(Entity) -> bool + 'a
+where
+    Q: Query + Send + Sync + 'a,
+{
+    move |entity| {
+        context
+            .query_one(query, entity)
+            .map_or(false, |mut one| one.get().is_some())
+    }
+}
+
 pub struct ColliderMap {
+    // The AABB of each entity's collider in the cell at the origin, to be translated to every
+    // cell the entity occupies.
     entities_to_aabbs: HashMap<Entity, AABB>,
+    // Packed coordinates of every occupied cell, mapped to the entity in it and its AABB.
+    cells: HashMap<u64, (Entity, AABB)>,
+    // The union of the AABBs of all cells, or `None` if the map is empty.
+    bounds: Option<AABB>,
 
     // QBVH ids are encoded coordinates: x/y/z i32s cut down to 24 bit/24 bit/16 bit, using
     // pack_coords and unpack_coords.
     qbvh: QBVH<u64>,
-    buf: Vec<(u64, AABB)>,
+    buf: Vec<(u64, Entity, AABB)>,
 
     reader_id: ReaderId<LatticeEvent<Entity>>,
 }
@@ -70,6 +113,8 @@ impl ColliderMap {
     pub fn new(map: &mut TrackedMap<Entity>) -> Self {
         Self {
             entities_to_aabbs: HashMap::new(),
+            cells: HashMap::new(),
+            bounds: None,
             qbvh: QBVH::new(),
             buf: Vec::new(),
             reader_id: map.events_mut().register_reader(),
@@ -107,37 +152,446 @@ impl ColliderMap {
             let qbvh_generator = map.as_chunk_map().iter().filter_map(|(coords, &entity)| {
                 use std::collections::hash_map::Entry::*;
 
-                let aabb = match self.entities_to_aabbs.entry(entity) {
+                let local_aabb = match self.entities_to_aabbs.entry(entity) {
                     Occupied(occupied) => *occupied.get(),
                     Vacant(vacant) => {
                         let res = context
                             .query_one(query, entity)
                             .ok()?
                             .get()?
-                            .compute_aabb(&Isometry3::from(coords.cast::<f32>()));
+                            .compute_aabb(&Isometry3::identity());
                         vacant.insert(res);
                         res
                     }
                 };
 
-                Some((pack_coords(coords), aabb))
+                let offset = coords.cast::<f32>();
+                let aabb = AABB::new(local_aabb.mins + offset, local_aabb.maxs + offset);
+                Some((pack_coords(coords), entity, aabb))
             });
 
             self.buf.clear();
             self.buf.extend(qbvh_generator);
-            self.qbvh.clear_and_rebuild(self.buf.drain(..), 0.05);
+            self.rebuild();
         }
     }
 
+    // Rebuild the QBVH and the cell lookup from the cells in `self.buf`.
+    fn rebuild(&mut self) {
+        self.cells.clear();
+        self.cells.extend(
+            self.buf
+                .iter()
+                .map(|&(packed, entity, aabb)| (packed, (entity, aabb))),
+        );
+        self.bounds = self
+            .buf
+            .iter()
+            .map(|&(_, _, aabb)| aabb)
+            .reduce(|a, b| a.merged(&b));
+        self.qbvh.clear_and_rebuild(
+            self.buf.drain(..).map(|(packed, _, aabb)| (packed, aabb)),
+            0.05,
+        );
+    }
+
     pub fn intersect(&self, aabb: &AABB, out: &mut Intersections) {
         self.qbvh.intersect_aabb(aabb, &mut out.buf);
     }
+
+    /// Find the entities whose colliders overlap the cell at `coords`, which spans from `coords`
+    /// to `coords + 1`. Only entities accepted by `filter` are pushed to `out`.
+    pub fn entities_in_cell(
+        &self,
+        coords: Vector3<i32>,
+        mut filter: impl FnMut(Entity) -> bool,
+        out: &mut Vec<Entity>,
+    ) {
+        let mins = Point3::from(coords.cast::<f32>());
+        let cell = AABB::new(mins, mins + Vector3::repeat(1.)).tightened(CELL_EPSILON);
+        let mut hits = Vec::new();
+        self.qbvh.intersect_aabb(&cell, &mut hits);
+
+        let start = out.len();
+        for packed in hits {
+            let (entity, aabb) = self.cells[&packed];
+            if aabb.intersects(&cell) && !out[start..].contains(&entity) && filter(entity) {
+                out.push(entity);
+            }
+        }
+    }
+
+    /// Find the entities whose colliders' AABBs are within `radius` of `center`, sorted nearest
+    /// first. Only entities accepted by `filter` are pushed to `out`; an entity occupying several
+    /// cells is reported once, at its nearest cell.
+    pub fn entities_within_radius(
+        &self,
+        center: &Point3<f32>,
+        radius: f32,
+        filter: impl FnMut(Entity) -> bool,
+        out: &mut Vec<Neighbor>,
+    ) {
+        let start = out.len();
+        self.collect_within_radius(center, radius, filter, out);
+        out[start..].sort_by(|a, b| a.distance.total_cmp(&b.distance));
+    }
+
+    /// Find the `k` entities nearest to `center`, sorted nearest first. Only entities accepted by
+    /// `filter` are considered; fewer than `k` are pushed to `out` if there aren't enough of them.
+    /// `filter` is called at most once per entity.
+    pub fn k_nearest(
+        &self,
+        center: &Point3<f32>,
+        k: usize,
+        mut filter: impl FnMut(Entity) -> bool,
+        out: &mut Vec<Neighbor>,
+    ) {
+        let bounds = match self.bounds {
+            Some(bounds) if k > 0 => bounds,
+            _ => return,
+        };
+
+        // Nothing in the map is farther away than the farthest corner of its bounds. This is also
+        // where a non-finite center (e.g. from a script) is caught, since the search would never
+        // end otherwise.
+        let farthest = (center - bounds.mins)
+            .abs()
+            .sup(&(center - bounds.maxs).abs())
+            .norm();
+        if !farthest.is_finite() {
+            return;
+        }
+
+        // Each search sees the entities of the last one again, so remember which were accepted
+        // rather than asking the filter (which may be a Lua function) again.
+        let mut accepted = HashMap::new();
+        let mut memoized =
+            |entity: Entity| *accepted.entry(entity).or_insert_with(|| filter(entity));
+
+        // Search w/ a doubling radius until either we've found enough entities or the search
+        // sphere reaches every corner of the map. Everything within the radius is found, so once
+        // there are at least `k` results the nearest `k` are among them.
+        let start = out.len();
+        let mut radius = 1.;
+        loop {
+            out.truncate(start);
+            self.collect_within_radius(center, radius, &mut memoized, out);
+
+            if out.len() - start >= k || radius >= farthest {
+                break;
+            }
+
+            radius *= 2.;
+        }
+
+        out[start..].sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        out.truncate(start + k);
+    }
+
+    // Push every accepted entity within `radius` of `center` to `out`, deduplicated but unsorted.
+    fn collect_within_radius(
+        &self,
+        center: &Point3<f32>,
+        radius: f32,
+        mut filter: impl FnMut(Entity) -> bool,
+        out: &mut Vec<Neighbor>,
+    ) {
+        let query = AABB::from_half_extents(*center, Vector3::repeat(radius));
+        let mut hits = Vec::new();
+        self.qbvh.intersect_aabb(&query, &mut hits);
+
+        let start = out.len();
+        for packed in hits {
+            let (entity, aabb) = self.cells[&packed];
+            let distance = aabb.distance_to_local_point(center, true);
+            if distance > radius {
+                continue;
+            }
+
+            match out[start..].iter_mut().find(|n| n.entity == entity) {
+                Some(existing) if existing.distance > distance => {
+                    existing.coords = unpack_coords(packed);
+                    existing.distance = distance;
+                }
+                Some(_) => {}
+                None if filter(entity) => out.push(Neighbor {
+                    entity,
+                    coords: unpack_coords(packed),
+                    distance,
+                }),
+                None => {}
+            }
+        }
+    }
+}
+
+// Wrap an optional Lua predicate as a query filter. The first error raised by the predicate is
+// stored in `error`, and rejects the entity it was raised for.
+fn lua_filter<'a>(
+    predicate: &'a Option<LuaFunction<'a>>,
+    error: &'a mut Option<LuaError>,
+) -> impl FnMut(Entity) -> bool + 'a {
+    move |entity| match predicate {
+        None => true,
+        Some(predicate) => match predicate.call(entity) {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                error.get_or_insert(err);
+                false
+            }
+        },
+    }
+}
+
+pub(crate) fn register_functions(builder: &mut ModuleBuilder) -> Result<()> {
+    builder
+        .function(
+            "entities_in_cell",
+            |lua, (coords, predicate): (Vector3<i32>, Option<LuaFunction>)| {
+                let mut out = Vec::new();
+                let mut error = None;
+                with_loaned(lua, |map: &ColliderMap| {
+                    let filter = lua_filter(&predicate, &mut error);
+                    map.entities_in_cell(coords, filter, &mut out);
+                    Ok(())
+                })?;
+                error.map_or(Ok(out), Err)
+            },
+        )?
+        .function(
+            "entities_within_radius",
+            |lua, (center, radius, predicate): (Vector3<f32>, f32, Option<LuaFunction>)| {
+                let mut out = Vec::new();
+                let mut error = None;
+                with_loaned(lua, |map: &ColliderMap| {
+                    let filter = lua_filter(&predicate, &mut error);
+                    map.entities_within_radius(&Point3::from(center), radius, filter, &mut out);
+                    Ok(())
+                })?;
+                error.map_or(Ok(out), Err)
+            },
+        )?
+        .function(
+            "k_nearest",
+            |lua, (center, k, predicate): (Vector3<f32>, usize, Option<LuaFunction>)| {
+                let mut out = Vec::new();
+                let mut error = None;
+                with_loaned(lua, |map: &ColliderMap| {
+                    let filter = lua_filter(&predicate, &mut error);
+                    map.k_nearest(&Point3::from(center), k, filter, &mut out);
+                    Ok(())
+                })?;
+                error.map_or(Ok(out), Err)
+            },
+        )?;
+
+    Ok(())
+}
+
+impl LuaUserData for Neighbor {
+    fn on_metatable_init(table: Type<Self>) {
+        table
+            .add_clone()
+            .add_copy()
+            .add_send()
+            .add_sync()
+            .add::<dyn std::fmt::Debug>();
+    }
+
+    fn add_fields<'lua, F: LuaUserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("entity", |_, this| Ok(this.entity));
+        fields.add_field_method_get("coords", |_, this| Ok(this.coords));
+        fields.add_field_method_get("distance", |_, this| Ok(this.distance));
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use hv::ecs::{System, World};
+    use parry3d::shape::SharedShape;
+
     use super::*;
 
+    fn update_colliders(
+        context: SystemContext,
+        (tracked, map): (&TrackedMap<Entity>, &mut ColliderMap),
+        query: &mut QueryMarker<&'static Collider>,
+    ) {
+        map.update(tracked, context, *query);
+    }
+
+    // An entity whose collider fills exactly the unit cube of any cell it's in.
+    fn spawn_unit_cube(world: &mut World) -> Entity {
+        world.spawn((Collider::new(
+            Isometry3::translation(0.5, 0.5, 0.5),
+            SharedShape::cuboid(0.5, 0.5, 0.5),
+        ),))
+    }
+
+    // Build a collider map by putting entities in a tracked map and updating from it.
+    fn map_of(world: &World, cells: &[(Vector3<i32>, Entity)]) -> ColliderMap {
+        let mut tracked = TrackedMap::new();
+        let mut map = ColliderMap::new(&mut tracked);
+        for &(coords, entity) in cells {
+            tracked.insert(coords, entity);
+        }
+        update_colliders.run(world, (&tracked, &mut map));
+        map
+    }
+
+    #[test]
+    fn entities_in_cell() {
+        let mut world = World::new();
+        let (a, b) = (spawn_unit_cube(&mut world), spawn_unit_cube(&mut world));
+        let map = map_of(
+            &world,
+            &[(Vector3::new(0, 0, 0), a), (Vector3::new(1, 0, 0), b)],
+        );
+
+        let mut out = Vec::new();
+        map.entities_in_cell(Vector3::new(0, 0, 0), |_| true, &mut out);
+        assert_eq!(out, [a]);
+
+        out.clear();
+        map.entities_in_cell(Vector3::new(1, 0, 0), |e| e != b, &mut out);
+        assert!(out.is_empty());
+
+        map.entities_in_cell(Vector3::new(5, 5, 5), |_| true, &mut out);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn entities_within_radius() {
+        let mut world = World::new();
+        let [a, b, c] = [(); 3].map(|()| spawn_unit_cube(&mut world));
+        let map = map_of(
+            &world,
+            &[
+                (Vector3::new(0, 0, 0), a),
+                (Vector3::new(1, 0, 0), a),
+                (Vector3::new(3, 0, 0), b),
+                (Vector3::new(10, 0, 0), c),
+            ],
+        );
+
+        let center = Point3::new(-1., 0.5, 0.5);
+        let mut out = Vec::new();
+        map.entities_within_radius(&center, 4.5, |_| true, &mut out);
+        let found = out.iter().map(|n| (n.entity, n.coords)).collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            [(a, Vector3::new(0, 0, 0)), (b, Vector3::new(3, 0, 0))]
+        );
+        assert_eq!(out[0].distance, 1.);
+        assert_eq!(out[1].distance, 4.);
+
+        out.clear();
+        map.entities_within_radius(&center, 4.5, |e| e != a, &mut out);
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].entity, b);
+    }
+
+    #[test]
+    fn k_nearest() {
+        let mut world = World::new();
+        let [a, b, c] = [(); 3].map(|()| spawn_unit_cube(&mut world));
+        let map = map_of(
+            &world,
+            &[
+                (Vector3::new(0, 0, 0), a),
+                (Vector3::new(20, 0, 0), b),
+                (Vector3::new(-40, 0, 0), c),
+            ],
+        );
+
+        let center = Point3::new(0.5, 0.5, 0.5);
+        let mut out = Vec::new();
+        map.k_nearest(&center, 2, |_| true, &mut out);
+        assert_eq!(out.iter().map(|n| n.entity).collect::<Vec<_>>(), [a, b]);
+
+        out.clear();
+        map.k_nearest(&center, 5, |e| e != b, &mut out);
+        assert_eq!(out.iter().map(|n| n.entity).collect::<Vec<_>>(), [a, c]);
+
+        out.clear();
+        map_of(&world, &[]).k_nearest(&center, 3, |_| true, &mut out);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn k_nearest_filters_each_entity_once() {
+        let mut world = World::new();
+        let [a, b, c] = [(); 3].map(|()| spawn_unit_cube(&mut world));
+        let map = map_of(
+            &world,
+            &[
+                (Vector3::new(0, 0, 0), a),
+                (Vector3::new(1, 0, 0), a),
+                (Vector3::new(5, 0, 0), b),
+                (Vector3::new(30, 0, 0), c),
+            ],
+        );
+
+        // Finding `c` takes several searches, each of which sees `a` and `b` again.
+        let mut calls = HashMap::new();
+        let mut out = Vec::new();
+        map.k_nearest(
+            &Point3::new(0.5, 0.5, 0.5),
+            3,
+            |e| {
+                *calls.entry(e).or_insert(0) += 1;
+                e != b
+            },
+            &mut out,
+        );
+        assert_eq!(out.iter().map(|n| n.entity).collect::<Vec<_>>(), [a, c]);
+        assert_eq!(calls.len(), 3);
+        assert!(calls.values().all(|&n| n == 1), "{:?}", calls);
+    }
+
+    #[test]
+    fn entities_spanning_several_cells() {
+        let mut world = World::new();
+        let wide = spawn_unit_cube(&mut world);
+        let cells = [0, 5, 9].map(|x| Vector3::new(x, 2, 0));
+        let map = map_of(&world, &cells.map(|coords| (coords, wide)));
+
+        let mut out = Vec::new();
+        for coords in cells {
+            out.clear();
+            map.entities_in_cell(coords, |_| true, &mut out);
+            assert_eq!(out, [wide], "{:?}", coords);
+        }
+
+        // Each cell has its own box, so the entity is found at whichever cell is nearest.
+        let mut neighbors = Vec::new();
+        map.entities_within_radius(&Point3::new(9.5, 2.5, 0.5), 1., |_| true, &mut neighbors);
+        assert_eq!(neighbors.len(), 1);
+        assert_eq!(neighbors[0].coords, cells[2]);
+        assert_eq!(neighbors[0].distance, 0.);
+    }
+
+    #[test]
+    fn k_nearest_diagonal() {
+        let mut world = World::new();
+        let (a, b) = (spawn_unit_cube(&mut world), spawn_unit_cube(&mut world));
+        let map = map_of(
+            &world,
+            &[(Vector3::new(0, 0, 0), a), (Vector3::new(10, 10, 10), b)],
+        );
+
+        // `b` is farther away than the half-width of the smallest cube around the center which
+        // contains the whole map, so it's only found by searching out to the farthest corner.
+        let mut out = Vec::new();
+        map.k_nearest(&Point3::new(0.5, 0.5, 0.5), 2, |_| true, &mut out);
+        assert_eq!(out.iter().map(|n| n.entity).collect::<Vec<_>>(), [a, b]);
+
+        out.clear();
+        map.k_nearest(&Point3::new(f32::NAN, 0., 0.), 2, |_| true, &mut out);
+        map.k_nearest(&Point3::new(f32::INFINITY, 0., 0.), 2, |_| true, &mut out);
+        assert!(out.is_empty());
+    }
+
     #[test]
     fn pack_unpack_ok() {
         fn roundtrip(v: Vector3<i32>) {
@@ -0,0 +1,539 @@
+//! A versioned on-disk format for chunk maps.
+//!
+//! Maps are stored sparsely: only layers which exist and chunks which exist in them are written,
+//! and within a chunk only the slots which are occupied. Each chunk is stored as a bitmask of its
+//! occupied slots followed by the values in those slots, in linear order, compressed according to
+//! the [`Compression`] chosen when the map was written. The compression used is recorded per chunk,
+//! so files can be read back without knowing how they were written.
+//!
+//! The values themselves are encoded w/ `bincode`, so any `T: Serialize + DeserializeOwned` can be
+//! stored; reading chunks back also takes `T: Clone + PartialEq`, so that they can be compacted as
+//! they are loaded. [`ChunkMap`], [`TrackedMap`], and [`AtomMap`] can all be saved to and loaded
+//! from a [`Filesystem`]; loading an `AtomMap` recalculates its hulls from the loaded atoms. Single
+//! chunks can also be written and read on their own, w/ [`write_chunk`] and [`read_chunk`].
+//! [`Region`]s are saved as prefabs in a format of their own, w/ [`write_region`] and
+//! [`read_region`].
+
+use std::{
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder};
+use hv::{fs::Filesystem, prelude::*};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::lattice::{
+    atom_map::AtomMap,
+    chunk_map::{Chunk, ChunkLayer, ChunkMap, CHUNK_AREA},
+    region::Region,
+    tracked_map::TrackedMap,
+    ChunkCoords, SubCoords,
+};
+
+/// The magic bytes every chunk map file starts with.
+pub const MAGIC: [u8; 4] = *b"ALCM";
+
+/// The magic bytes every single-chunk file starts with.
+pub const CHUNK_MAGIC: [u8; 4] = *b"ALCK";
+
+/// The magic bytes every region prefab file starts with.
+pub const REGION_MAGIC: [u8; 4] = *b"ALRG";
+
+/// The version of the format written by this version of `altar`. Files w/ a later version are
+/// rejected when read.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// How the contents of each chunk are compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    /// Store values as-is.
+    None,
+    /// Collapse runs of consecutive occupied slots w/ identical values. Cheap, and effective for
+    /// chunks mostly filled w/ the same value.
+    RunLength,
+    /// Compress values w/ DEFLATE. Slower, but effective on any repetitive data.
+    Deflate,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::RunLength
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    magic: [u8; 4],
+    version: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LayerRecord {
+    index: i32,
+    chunks: Vec<ChunkRecord>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ChunkRecord {
+    coords: [i32; 2],
+    occupied: [u64; CHUNK_AREA / 64],
+    compression: Compression,
+    data: Vec<u8>,
+}
+
+impl ChunkRecord {
+    fn encode<T: Serialize>(
+        coords: ChunkCoords,
+        chunk: &Chunk<T>,
+        compression: Compression,
+    ) -> Result<Self> {
+        let mut occupied = [0; CHUNK_AREA / 64];
+        for (sub, _) in chunk.iter() {
+            let linear = sub.to_linear();
+            occupied[linear / 64] |= 1 << (linear % 64);
+        }
+
+        let data = match compression {
+            Compression::None => {
+                bincode::serialize(&chunk.iter().map(|(_, v)| v).collect::<Vec<_>>())?
+            }
+            Compression::RunLength => {
+                let mut runs: Vec<(u16, Vec<u8>)> = Vec::new();
+                for (_, value) in chunk.iter() {
+                    let bytes = bincode::serialize(value)?;
+                    match runs.last_mut() {
+                        Some((count, run)) if *run == bytes => *count += 1,
+                        _ => runs.push((1, bytes)),
+                    }
+                }
+                bincode::serialize(&runs)?
+            }
+            Compression::Deflate => {
+                let values = chunk.iter().map(|(_, v)| v).collect::<Vec<_>>();
+                let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                bincode::serialize_into(&mut encoder, &values)?;
+                encoder.finish()?
+            }
+        };
+
+        Ok(Self {
+            coords: [coords.x, coords.y],
+            occupied,
+            compression,
+            data,
+        })
+    }
+
+    fn decode<T: DeserializeOwned + Clone + PartialEq>(&self) -> Result<(ChunkCoords, Chunk<T>)> {
+        let coords = ChunkCoords::new(self.coords[0], self.coords[1]);
+        let values: Vec<T> = match self.compression {
+            Compression::None => bincode::deserialize(&self.data)?,
+            Compression::RunLength => {
+                let runs: Vec<(u16, Vec<u8>)> = bincode::deserialize(&self.data)?;
+                let mut values = Vec::new();
+                for (count, bytes) in runs {
+                    for _ in 0..count {
+                        values.push(bincode::deserialize(&bytes)?);
+                    }
+                }
+                values
+            }
+            Compression::Deflate => bincode::deserialize_from(DeflateDecoder::new(&*self.data))?,
+        };
+
+        let slots = (0..CHUNK_AREA).filter(|&i| self.occupied[i / 64] & (1 << (i % 64)) != 0);
+        let num_slots = slots.clone().count();
+        ensure!(
+            num_slots == values.len(),
+            "chunk at {:?} has {} occupied slots but {} values",
+            coords,
+            num_slots,
+            values.len()
+        );
+
+        let mut chunk = Chunk::default();
+        for (linear, value) in slots.zip(values) {
+            chunk.insert_dedup(SubCoords::from_linear(linear), value);
+        }
+
+        Ok((coords, chunk))
+    }
+}
+
+fn write_header(mut writer: impl Write, magic: [u8; 4]) -> Result<()> {
+    let header = Header {
+        magic,
+        version: FORMAT_VERSION,
+    };
+    bincode::serialize_into(&mut writer, &header)?;
+    Ok(())
+}
+
+fn read_header(mut reader: impl Read, magic: [u8; 4], what: &str) -> Result<()> {
+    let header: Header = bincode::deserialize_from(&mut reader)
+        .with_context(|| format!("while reading {} header", what))?;
+    ensure!(header.magic == magic, "not a {} (bad magic bytes)", what);
+    ensure!(
+        header.version <= FORMAT_VERSION,
+        "{} format version {} is newer than the latest supported version {}",
+        what,
+        header.version,
+        FORMAT_VERSION
+    );
+    Ok(())
+}
+
+/// Write a chunk map, compressing each chunk w/ the given compression. Chunks are written in a
+/// fixed order, so identical maps produce identical output.
+pub fn write_chunk_map<T: Serialize>(
+    map: &ChunkMap<T>,
+    mut writer: impl Write,
+    compression: Compression,
+) -> Result<()> {
+    let mut layers = Vec::new();
+    for (index, layer) in map.layers() {
+        let mut chunks = layer
+            .chunks()
+            .filter(|(_, chunk)| chunk.iter().next().is_some())
+            .map(|(coords, chunk)| ChunkRecord::encode(coords, chunk, compression))
+            .collect::<Result<Vec<_>>>()?;
+        chunks.sort_unstable_by_key(|record| (record.coords[1], record.coords[0]));
+        layers.push(LayerRecord { index, chunks });
+    }
+
+    write_header(&mut writer, MAGIC)?;
+    bincode::serialize_into(&mut writer, &layers)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Read a chunk map written w/ [`write_chunk_map`].
+pub fn read_chunk_map<T: DeserializeOwned + Clone + PartialEq>(
+    mut reader: impl Read,
+) -> Result<ChunkMap<T>> {
+    read_header(&mut reader, MAGIC, "chunk map")?;
+    let records: Vec<LayerRecord> =
+        bincode::deserialize_from(&mut reader).context("while reading chunk map layers")?;
+    let mut map = ChunkMap::new();
+    for record in records {
+        let mut layer = ChunkLayer::new();
+        for chunk in &record.chunks {
+            let (coords, chunk) = chunk
+                .decode()
+                .with_context(|| format!("while reading chunk in layer {}", record.index))?;
+            layer.insert_chunk(coords, chunk);
+        }
+        map.insert_layer(record.index, layer);
+    }
+
+    Ok(map)
+}
+
+/// Write a single chunk, in the same format as the chunks of a chunk map.
+pub fn write_chunk<T: Serialize>(
+    coords: ChunkCoords,
+    chunk: &Chunk<T>,
+    mut writer: impl Write,
+    compression: Compression,
+) -> Result<()> {
+    write_header(&mut writer, CHUNK_MAGIC)?;
+    bincode::serialize_into(
+        &mut writer,
+        &ChunkRecord::encode(coords, chunk, compression)?,
+    )?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Read a single chunk written w/ [`write_chunk`], along w/ its coordinates.
+pub fn read_chunk<T: DeserializeOwned + Clone + PartialEq>(
+    mut reader: impl Read,
+) -> Result<(ChunkCoords, Chunk<T>)> {
+    read_header(&mut reader, CHUNK_MAGIC, "chunk")?;
+    let record: ChunkRecord =
+        bincode::deserialize_from(&mut reader).context("while reading chunk")?;
+    record.decode()
+}
+
+/// Write a region as a prefab. Regions are dense, so the whole region is always compressed w/
+/// DEFLATE.
+pub fn write_region<T: Serialize>(region: &Region<T>, mut writer: impl Write) -> Result<()> {
+    write_header(&mut writer, REGION_MAGIC)?;
+    let mut encoder = DeflateEncoder::new(&mut writer, flate2::Compression::default());
+    bincode::serialize_into(&mut encoder, region)?;
+    encoder.finish()?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Read a region prefab written w/ [`write_region`].
+pub fn read_region<T: DeserializeOwned>(mut reader: impl Read) -> Result<Region<T>> {
+    read_header(&mut reader, REGION_MAGIC, "region")?;
+    bincode::deserialize_from(DeflateDecoder::new(reader)).context("while reading region")
+}
+
+impl<T: Serialize> ChunkMap<T> {
+    /// Save this map to a file in the user directory of the filesystem.
+    pub fn save(
+        &self,
+        fs: &mut Filesystem,
+        path: impl AsRef<Path>,
+        compression: Compression,
+    ) -> Result<()> {
+        let file = fs.create(path.as_ref())?;
+        write_chunk_map(self, BufWriter::new(file), compression)
+            .with_context(|| format!("while saving chunk map to {:?}", path.as_ref()))
+    }
+}
+
+impl<T: DeserializeOwned + Clone + PartialEq> ChunkMap<T> {
+    /// Load a map saved w/ [`ChunkMap::save`].
+    pub fn load(fs: &mut Filesystem, path: impl AsRef<Path>) -> Result<Self> {
+        let file = fs.open(path.as_ref())?;
+        read_chunk_map(BufReader::new(file))
+            .with_context(|| format!("while loading chunk map from {:?}", path.as_ref()))
+    }
+}
+
+impl<T: Copy + PartialEq + Send + Sync + Serialize + 'static> TrackedMap<T> {
+    /// Save the contents of this map. Events are not saved.
+    pub fn save(
+        &self,
+        fs: &mut Filesystem,
+        path: impl AsRef<Path>,
+        compression: Compression,
+    ) -> Result<()> {
+        self.as_chunk_map().save(fs, path, compression)
+    }
+}
+
+impl<T: Copy + PartialEq + Send + Sync + DeserializeOwned + 'static> TrackedMap<T> {
+    /// Load a map saved w/ [`TrackedMap::save`] or [`ChunkMap::save`]. The loaded map starts out
+    /// w/ no events.
+    pub fn load(fs: &mut Filesystem, path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::from_chunk_map(ChunkMap::load(fs, path)?))
+    }
+}
+
+impl AtomMap {
+    /// Save the atoms of this map. Hulls and shapes are not saved, since they're recalculated on
+    /// load.
+    pub fn save(
+        &self,
+        fs: &mut Filesystem,
+        path: impl AsRef<Path>,
+        compression: Compression,
+    ) -> Result<()> {
+        self.atoms().save(fs, path, compression)
+    }
+
+    /// Load a map saved w/ [`AtomMap::save`] and calculate its hulls.
+    pub fn load(fs: &mut Filesystem, path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::from_atoms(TrackedMap::load(fs, path)?))
+    }
+}
+
+impl<T: Serialize> Region<T> {
+    /// Save this region as a prefab to a file in the user directory of the filesystem.
+    pub fn save(&self, fs: &mut Filesystem, path: impl AsRef<Path>) -> Result<()> {
+        let file = fs.create(path.as_ref())?;
+        write_region(self, BufWriter::new(file))
+            .with_context(|| format!("while saving region to {:?}", path.as_ref()))
+    }
+}
+
+impl<T: DeserializeOwned> Region<T> {
+    /// Load a region prefab saved w/ [`Region::save`].
+    pub fn load(fs: &mut Filesystem, path: impl AsRef<Path>) -> Result<Self> {
+        let file = fs.open(path.as_ref())?;
+        read_region(BufReader::new(file))
+            .with_context(|| format!("while loading region from {:?}", path.as_ref()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lattice::chunk_map::ChunkStorageKind;
+
+    use super::*;
+
+    fn test_map() -> ChunkMap<u32> {
+        let mut map = ChunkMap::new();
+        for x in -20..20 {
+            for y in -3..5 {
+                map.insert(Vector3::new(x, y, 0), 7);
+            }
+        }
+        map.insert(Vector3::new(3, 3, 0), 9);
+        map.insert(Vector3::new(-100, 40, -2), 1);
+        map.insert(Vector3::new(0, 0, 5), 2);
+        map.get_or_insert_layer(6);
+        map
+    }
+
+    fn assert_same(a: &ChunkMap<u32>, b: &ChunkMap<u32>) {
+        let mut a_values = a
+            .iter()
+            .map(|(c, &v)| ((c.x, c.y, c.z), v))
+            .collect::<Vec<_>>();
+        let mut b_values = b
+            .iter()
+            .map(|(c, &v)| ((c.x, c.y, c.z), v))
+            .collect::<Vec<_>>();
+        a_values.sort_unstable();
+        b_values.sort_unstable();
+        assert_eq!(a_values, b_values);
+    }
+
+    #[test]
+    fn round_trip() {
+        let map = test_map();
+        for compression in [
+            Compression::None,
+            Compression::RunLength,
+            Compression::Deflate,
+        ] {
+            let mut bytes = Vec::new();
+            write_chunk_map(&map, &mut bytes, compression).unwrap();
+            let loaded = read_chunk_map::<u32>(&*bytes).unwrap();
+            assert_same(&map, &loaded);
+            assert!(loaded.get_layer(6).is_some());
+        }
+    }
+
+    #[test]
+    fn run_length_is_smaller() {
+        let map = test_map();
+        let mut raw = Vec::new();
+        let mut rle = Vec::new();
+        write_chunk_map(&map, &mut raw, Compression::None).unwrap();
+        write_chunk_map(&map, &mut rle, Compression::RunLength).unwrap();
+        assert!(rle.len() < raw.len());
+    }
+
+    #[test]
+    fn output_is_deterministic() {
+        let map = test_map();
+        let mut a = Vec::new();
+        let mut b = Vec::new();
+        write_chunk_map(&map, &mut a, Compression::Deflate).unwrap();
+        write_chunk_map(&map.clone(), &mut b, Compression::Deflate).unwrap();
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let mut bytes = Vec::new();
+        bincode::serialize_into(
+            &mut bytes,
+            &Header {
+                magic: MAGIC,
+                version: FORMAT_VERSION + 1,
+            },
+        )
+        .unwrap();
+        bincode::serialize_into(&mut bytes, &Vec::<LayerRecord>::new()).unwrap();
+        assert!(read_chunk_map::<u32>(&*bytes).is_err());
+    }
+
+    #[test]
+    fn region_round_trip() {
+        let region = test_map().copy_region(Vector3::new(-3, -5, -1), Vector3::new(4, 4, 6));
+        let mut bytes = Vec::new();
+        write_region(&region, &mut bytes).unwrap();
+        assert_eq!(read_region::<u32>(&*bytes).unwrap(), region);
+        assert!(read_chunk_map::<u32>(&*bytes).is_err());
+    }
+
+    #[test]
+    fn loaded_chunks_are_compacted() {
+        let mut bytes = Vec::new();
+        write_chunk_map(&test_map(), &mut bytes, Compression::RunLength).unwrap();
+        let loaded = read_chunk_map::<u32>(&*bytes).unwrap();
+        let kind = |z, x, y| {
+            let layer = loaded.get_layer(z).unwrap();
+            let chunk = layer.get_chunk(ChunkCoords::new(x, y)).unwrap();
+            chunk.storage_kind()
+        };
+        assert_eq!(kind(0, -2, 0), ChunkStorageKind::Uniform);
+        assert_eq!(kind(0, 0, 0), ChunkStorageKind::Palette);
+        assert_eq!(kind(-2, -7, 2), ChunkStorageKind::Uniform);
+    }
+
+    fn memory_fs() -> Filesystem {
+        let mut fs = Filesystem::new();
+        fs.mount_memory();
+        fs
+    }
+
+    #[test]
+    fn save_and_load_through_the_filesystem() {
+        let mut fs = memory_fs();
+        let map = test_map();
+        map.save(&mut fs, "/map", Compression::Deflate).unwrap();
+        assert_same(&map, &ChunkMap::load(&mut fs, "/map").unwrap());
+
+        let tracked = TrackedMap::from_chunk_map(map.clone());
+        tracked
+            .save(&mut fs, "/tracked", Compression::RunLength)
+            .unwrap();
+        let loaded = TrackedMap::<u32>::load(&mut fs, "/tracked").unwrap();
+        assert_same(&map, loaded.as_chunk_map());
+
+        // Tracked and untracked maps share a format.
+        assert_same(&map, &ChunkMap::load(&mut fs, "/tracked").unwrap());
+        assert_same(
+            &map,
+            TrackedMap::load(&mut fs, "/map").unwrap().as_chunk_map(),
+        );
+    }
+
+    #[test]
+    fn bad_files_are_errors() {
+        let mut fs = memory_fs();
+        let write = |fs: &mut Filesystem, path, bytes: &[u8]| {
+            fs.create(path).unwrap().write_all(bytes).unwrap();
+        };
+
+        // Another kind of file altogether.
+        test_map()
+            .copy_region(Vector3::new(0, 0, 0), Vector3::new(2, 2, 1))
+            .save(&mut fs, "/region")
+            .unwrap();
+        write(&mut fs, "/garbage", b"definitely not a chunk map");
+
+        let mut newer = Vec::new();
+        write_chunk_map(&test_map(), &mut newer, Compression::None).unwrap();
+        let header = bincode::serialize(&Header {
+            magic: MAGIC,
+            version: FORMAT_VERSION + 1,
+        })
+        .unwrap();
+        newer[..header.len()].copy_from_slice(&header);
+        write(&mut fs, "/newer", &newer);
+
+        let mut truncated = Vec::new();
+        write_chunk_map(&test_map(), &mut truncated, Compression::Deflate).unwrap();
+        truncated.truncate(truncated.len() / 2);
+        write(&mut fs, "/truncated", &truncated);
+
+        for path in ["/region", "/garbage", "/newer", "/truncated", "/missing"] {
+            assert!(ChunkMap::<u32>::load(&mut fs, path).is_err(), "{}", path);
+            assert!(TrackedMap::<u32>::load(&mut fs, path).is_err(), "{}", path);
+            assert!(AtomMap::load(&mut fs, path).is_err(), "{}", path);
+        }
+
+        let err = ChunkMap::<u32>::load(&mut fs, "/region").unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("bad magic bytes"),
+            "{:#}",
+            err
+        );
+        let err = ChunkMap::<u32>::load(&mut fs, "/newer").unwrap_err();
+        assert!(format!("{:#}", err).contains("is newer than"), "{:#}", err);
+    }
+}
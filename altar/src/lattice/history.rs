@@ -0,0 +1,527 @@
+//! Undo and redo for edits made to a [`TrackedMap`].
+//!
+//! An [`EditHistory`] records edits made through it as a log of transactions. Each edit stores the
+//! state of whatever it touched before and after the edit - for slots, the same values a
+//! [`SlotEventKind`] carries; for chunks and layers, their entire contents - so that undoing or
+//! redoing a transaction is just a matter of writing back one side or the other. Edits are replayed
+//! through the tracked map, so undo and redo emit the same [`LatticeEvent`]s as any other edit, and
+//! everything listening to the map follows along.
+//!
+//! Edits made to the map without going through the history aren't recorded, and undoing edits
+//! which have since been overwritten by unrecorded ones will clobber the unrecorded changes.
+//!
+//! [`SlotEventKind`]: crate::lattice::event::SlotEventKind
+//! [`LatticeEvent`]: crate::lattice::event::LatticeEvent
+
+use std::collections::VecDeque;
+
+use hv::prelude::*;
+
+use crate::lattice::{
+    chunk_map::{Chunk, ChunkLayer},
+    tracked_map::TrackedMap,
+    ChunkCoords,
+};
+
+/// A single recorded edit, w/ the state of the edited slot, chunk or layer before and after.
+#[derive(Debug, Clone)]
+pub enum Edit<T> {
+    Slot {
+        coords: Vector3<i32>,
+        before: Option<T>,
+        after: Option<T>,
+    },
+    Chunk {
+        layer: i32,
+        coords: ChunkCoords,
+        before: Option<Chunk<T>>,
+        after: Option<Chunk<T>>,
+    },
+    Layer {
+        index: i32,
+        before: Option<ChunkLayer<T>>,
+        after: Option<ChunkLayer<T>>,
+    },
+}
+
+impl<T: Copy + PartialEq + Send + Sync + 'static> Edit<T> {
+    fn apply(&self, map: &mut TrackedMap<T>, undo: bool) {
+        match self {
+            Self::Slot {
+                coords,
+                before,
+                after,
+            } => match if undo { before } else { after } {
+                Some(value) => {
+                    map.insert(*coords, *value);
+                }
+                None => {
+                    map.remove(*coords);
+                }
+            },
+            Self::Chunk {
+                layer,
+                coords,
+                before,
+                after,
+            } => match if undo { before } else { after } {
+                Some(chunk) => {
+                    map.get_or_insert_layer(*layer)
+                        .insert_chunk(*coords, chunk.clone());
+                }
+                None => {
+                    map.remove_chunk(*layer, *coords);
+                }
+            },
+            Self::Layer {
+                index,
+                before,
+                after,
+            } => match if undo { before } else { after } {
+                Some(layer) => {
+                    map.insert_layer(*index, layer.clone());
+                }
+                None => {
+                    map.remove_layer(*index);
+                }
+            },
+        }
+    }
+}
+
+/// A group of edits which are undone and redone together.
+#[derive(Debug, Clone)]
+pub struct Transaction<T> {
+    edits: Vec<Edit<T>>,
+}
+
+impl<T> Default for Transaction<T> {
+    fn default() -> Self {
+        Self { edits: Vec::new() }
+    }
+}
+
+impl<T> Transaction<T> {
+    pub fn edits(&self) -> &[Edit<T>] {
+        &self.edits
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.edits.is_empty()
+    }
+}
+
+impl<T: Copy + PartialEq + Send + Sync + 'static> Transaction<T> {
+    fn undo(&self, map: &mut TrackedMap<T>) {
+        for edit in self.edits.iter().rev() {
+            edit.apply(map, true);
+        }
+    }
+
+    fn redo(&self, map: &mut TrackedMap<T>) {
+        for edit in &self.edits {
+            edit.apply(map, false);
+        }
+    }
+}
+
+/// A bounded undo/redo log of edits to a [`TrackedMap`]. See the [module-level docs](self).
+///
+/// Edits are grouped into transactions w/ [`EditHistory::begin`] and [`EditHistory::commit`];
+/// transactions may be nested, in which case the edits are grouped into the outermost one. Edits
+/// made outside of any transaction are committed as transactions of their own.
+#[derive(Debug, Clone)]
+pub struct EditHistory<T> {
+    undo: VecDeque<Transaction<T>>,
+    redo: Vec<Transaction<T>>,
+    current: Transaction<T>,
+    depth: usize,
+    max_transactions: usize,
+}
+
+impl<T: Copy + PartialEq + Send + Sync + 'static> Default for EditHistory<T> {
+    fn default() -> Self {
+        Self::new(256)
+    }
+}
+
+impl<T: Copy + PartialEq + Send + Sync + 'static> EditHistory<T> {
+    /// Create an empty history which keeps at most `max_transactions` transactions to undo.
+    pub fn new(max_transactions: usize) -> Self {
+        Self {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            current: Transaction::default(),
+            depth: 0,
+            max_transactions,
+        }
+    }
+
+    pub fn max_transactions(&self) -> usize {
+        self.max_transactions
+    }
+
+    /// Change the bound on the history, discarding the oldest transactions if there are too many.
+    pub fn set_max_transactions(&mut self, max_transactions: usize) {
+        self.max_transactions = max_transactions;
+        self.trim();
+    }
+
+    /// Begin a transaction. Every edit until the matching [`EditHistory::commit`] is undone and
+    /// redone as one.
+    pub fn begin(&mut self) {
+        self.depth += 1;
+    }
+
+    /// Commit the current transaction. Committing a non-empty transaction clears the redo
+    /// history.
+    pub fn commit(&mut self) -> Result<()> {
+        ensure!(self.depth > 0, "no transaction to commit");
+        self.depth -= 1;
+
+        if self.depth == 0 && !self.current.is_empty() {
+            let transaction = std::mem::take(&mut self.current);
+            self.undo.push_back(transaction);
+            self.redo.clear();
+            self.trim();
+        }
+
+        Ok(())
+    }
+
+    /// Undo every edit made in the current transaction (including any enclosing transactions) and
+    /// abandon it, without recording it in the history.
+    pub fn rollback(&mut self, map: &mut TrackedMap<T>) -> Result<()> {
+        ensure!(self.depth > 0, "no transaction to roll back");
+        self.depth = 0;
+        std::mem::take(&mut self.current).undo(map);
+        Ok(())
+    }
+
+    /// Whether a transaction is currently open.
+    pub fn in_transaction(&self) -> bool {
+        self.depth > 0
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Undo the most recently committed transaction. Returns `false` if there was nothing to undo.
+    pub fn undo(&mut self, map: &mut TrackedMap<T>) -> Result<bool> {
+        ensure!(self.depth == 0, "can't undo while a transaction is open");
+        match self.undo.pop_back() {
+            Some(transaction) => {
+                transaction.undo(map);
+                self.redo.push(transaction);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Redo the most recently undone transaction. Returns `false` if there was nothing to redo.
+    pub fn redo(&mut self, map: &mut TrackedMap<T>) -> Result<bool> {
+        ensure!(self.depth == 0, "can't redo while a transaction is open");
+        match self.redo.pop() {
+            Some(transaction) => {
+                transaction.redo(map);
+                self.undo.push_back(transaction);
+                self.trim();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Forget every recorded transaction. An open transaction stays open, but loses its edits.
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.current = Transaction::default();
+    }
+
+    /// The committed transactions which can be undone, from oldest to newest.
+    pub fn undo_transactions(&self) -> impl Iterator<Item = &Transaction<T>> {
+        self.undo.iter()
+    }
+
+    /// The undone transactions which can be redone, from newest to oldest.
+    pub fn redo_transactions(&self) -> impl Iterator<Item = &Transaction<T>> {
+        self.redo.iter().rev()
+    }
+
+    pub fn insert(&mut self, map: &mut TrackedMap<T>, coords: Vector3<i32>, value: T) -> Option<T> {
+        self.begin();
+        self.insert_missing_layer(map, coords.z);
+        let before = map.insert(coords, value);
+        self.record(Edit::Slot {
+            coords,
+            before,
+            after: Some(value),
+        });
+        self.commit().unwrap();
+        before
+    }
+
+    pub fn remove(&mut self, map: &mut TrackedMap<T>, coords: Vector3<i32>) -> Option<T> {
+        let before = map.remove(coords);
+        if before.is_some() {
+            self.record(Edit::Slot {
+                coords,
+                before,
+                after: None,
+            });
+        }
+        before
+    }
+
+    pub fn insert_chunk(
+        &mut self,
+        map: &mut TrackedMap<T>,
+        layer: i32,
+        coords: ChunkCoords,
+        chunk: Chunk<T>,
+    ) -> Option<Chunk<T>> {
+        self.begin();
+        self.insert_missing_layer(map, layer);
+        let before = map
+            .get_or_insert_layer(layer)
+            .insert_chunk(coords, chunk.clone());
+        self.record(Edit::Chunk {
+            layer,
+            coords,
+            before: before.clone(),
+            after: Some(chunk),
+        });
+        self.commit().unwrap();
+        before
+    }
+
+    pub fn remove_chunk(
+        &mut self,
+        map: &mut TrackedMap<T>,
+        layer: i32,
+        coords: ChunkCoords,
+    ) -> Option<Chunk<T>> {
+        let before = map.remove_chunk(layer, coords);
+        if before.is_some() {
+            self.record(Edit::Chunk {
+                layer,
+                coords,
+                before: before.clone(),
+                after: None,
+            });
+        }
+        before
+    }
+
+    pub fn insert_layer(
+        &mut self,
+        map: &mut TrackedMap<T>,
+        index: i32,
+        layer: ChunkLayer<T>,
+    ) -> Option<ChunkLayer<T>> {
+        let before = map.insert_layer(index, layer.clone());
+        self.record(Edit::Layer {
+            index,
+            before: before.clone(),
+            after: Some(layer),
+        });
+        before
+    }
+
+    pub fn remove_layer(&mut self, map: &mut TrackedMap<T>, index: i32) -> Option<ChunkLayer<T>> {
+        let before = map.remove_layer(index);
+        if before.is_some() {
+            self.record(Edit::Layer {
+                index,
+                before: before.clone(),
+                after: None,
+            });
+        }
+        before
+    }
+
+    // Edits which would otherwise create a layer implicitly record its creation first, in the same
+    // transaction, so that undoing them doesn't leave an empty layer behind.
+    fn insert_missing_layer(&mut self, map: &mut TrackedMap<T>, index: i32) {
+        if map.get_layer(index).is_none() {
+            self.insert_layer(map, index, ChunkLayer::new());
+        }
+    }
+
+    fn record(&mut self, edit: Edit<T>) {
+        self.current.edits.push(edit);
+
+        // Edits outside of a transaction are transactions of their own.
+        if self.depth == 0 {
+            self.depth = 1;
+            self.commit().unwrap();
+        }
+    }
+
+    fn trim(&mut self) {
+        while self.undo.len() > self.max_transactions {
+            self.undo.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lattice::{
+        event::{LatticeEvent, LayerEventKind},
+        SubCoords,
+    };
+
+    fn contents(map: &TrackedMap<u32>) -> Vec<(Vector3<i32>, u32)> {
+        let mut contents = map
+            .as_chunk_map()
+            .iter()
+            .map(|(coords, &value)| (coords, value))
+            .collect::<Vec<_>>();
+        contents.sort_by_key(|&(coords, _)| (coords.z, coords.y, coords.x));
+        contents
+    }
+
+    #[test]
+    fn undo_and_redo_slots() {
+        let mut map = TrackedMap::new();
+        let mut history = EditHistory::default();
+
+        history.insert(&mut map, Vector3::new(1, 2, 0), 1);
+        let first = contents(&map);
+
+        history.begin();
+        history.insert(&mut map, Vector3::new(1, 2, 0), 2);
+        history.insert(&mut map, Vector3::new(-20, 5, 3), 3);
+        history.remove(&mut map, Vector3::new(1, 2, 0));
+        history.commit().unwrap();
+        let second = contents(&map);
+
+        assert!(history.undo(&mut map).unwrap());
+        assert_eq!(contents(&map), first);
+        assert!(history.undo(&mut map).unwrap());
+        assert!(contents(&map).is_empty());
+        assert!(!history.undo(&mut map).unwrap());
+
+        assert!(history.redo(&mut map).unwrap());
+        assert_eq!(contents(&map), first);
+        assert!(history.redo(&mut map).unwrap());
+        assert_eq!(contents(&map), second);
+        assert!(!history.redo(&mut map).unwrap());
+
+        // A new edit after undoing discards the redo history.
+        history.undo(&mut map).unwrap();
+        history.insert(&mut map, Vector3::new(0, 0, 0), 4);
+        assert!(!history.can_redo());
+    }
+
+    #[test]
+    fn undo_chunks_and_layers() {
+        let mut map = TrackedMap::new();
+        let mut history = EditHistory::default();
+        for x in 0..40 {
+            history.insert(&mut map, Vector3::new(x, x / 2, x % 3), x as u32);
+        }
+        let before = contents(&map);
+
+        let mut chunk = Chunk::default();
+        chunk.insert(SubCoords::from_linear(7), 99);
+        history.begin();
+        history.insert_chunk(&mut map, 1, ChunkCoords::new(0, 0), chunk);
+        history.remove_chunk(&mut map, 2, ChunkCoords::new(1, 0));
+        history.remove_layer(&mut map, 0);
+        history.insert_layer(&mut map, 5, ChunkLayer::new());
+        history.commit().unwrap();
+        let after = contents(&map);
+        assert_ne!(before, after);
+
+        let mut reader = map.events_mut().register_reader();
+        history.undo(&mut map).unwrap();
+        assert_eq!(contents(&map), before);
+
+        // Undoing replays the edits in reverse through the tracked map.
+        let events = map.events().read(&mut reader).collect::<Vec<_>>();
+        assert_eq!(events.len(), 4);
+        assert!(matches!(events[0], LatticeEvent::Layer(_)));
+        assert!(matches!(events[1], LatticeEvent::Layer(_)));
+        assert!(matches!(events[2], LatticeEvent::Chunk(_)));
+        assert!(matches!(events[3], LatticeEvent::Chunk(_)));
+
+        history.redo(&mut map).unwrap();
+        assert_eq!(contents(&map), after);
+    }
+
+    #[test]
+    fn rollback_and_bounded_history() {
+        let mut map = TrackedMap::new();
+        let mut history = EditHistory::new(3);
+        for x in 0..5 {
+            history.insert(&mut map, Vector3::new(x, 0, 0), 1);
+        }
+        assert_eq!(history.undo_transactions().count(), 3);
+
+        let before = contents(&map);
+        history.begin();
+        history.begin();
+        history.insert(&mut map, Vector3::new(0, 0, 0), 2);
+        history.commit().unwrap();
+        history.remove(&mut map, Vector3::new(1, 0, 0));
+        history.rollback(&mut map).unwrap();
+        assert_eq!(contents(&map), before);
+        assert!(!history.in_transaction());
+        assert!(history.commit().is_err());
+
+        while history.undo(&mut map).unwrap() {}
+        assert_eq!(contents(&map).len(), 2);
+    }
+
+    #[test]
+    fn undo_removes_implicitly_created_layers() {
+        let mut map = TrackedMap::new();
+        let mut history = EditHistory::default();
+        history.insert(&mut map, Vector3::new(0, 0, 0), 1);
+        let before = contents(&map);
+
+        let mut chunk = Chunk::default();
+        chunk.insert(SubCoords::from_linear(3), 2);
+        history.begin();
+        history.insert(&mut map, Vector3::new(1, 1, 4), 3);
+        history.insert_chunk(&mut map, 7, ChunkCoords::new(-1, 0), chunk);
+        history.commit().unwrap();
+        let after = contents(&map);
+        assert!(map.get_layer(4).is_some());
+        assert!(map.get_layer(7).is_some());
+
+        let mut reader = map.events_mut().register_reader();
+        history.undo(&mut map).unwrap();
+        assert_eq!(contents(&map), before);
+        assert!(map.get_layer(0).is_some());
+        assert!(map.get_layer(4).is_none());
+        assert!(map.get_layer(7).is_none());
+
+        let mut removed = map
+            .events()
+            .read(&mut reader)
+            .filter_map(|event| match event {
+                LatticeEvent::Layer(layer_event) => {
+                    assert!(matches!(layer_event.kind, LayerEventKind::Remove));
+                    Some(layer_event.layer)
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        removed.sort_unstable();
+        assert_eq!(removed, [4, 7]);
+
+        history.redo(&mut map).unwrap();
+        assert_eq!(contents(&map), after);
+    }
+}
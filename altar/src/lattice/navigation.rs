@@ -0,0 +1,946 @@
+//! Navigation over the walkable surfaces of an [`AtomMap`].
+//!
+//! A [`NavGrid`] derives a walkability graph from the hulls of an atom map. Every cell whose hull
+//! has an exposed, upward-facing facet no steeper than the configured maximum slope, and which has
+//! enough free space above it for an agent to stand in, becomes a node. Nodes are connected to the
+//! nodes of neighboring columns (including diagonals, if enabled) when an agent can step up or drop
+//! down between them, so ramps and slopes connect layers naturally. "Up" is the positive Z axis,
+//! across layers.
+//!
+//! The grid supports A* searches between two points ([`NavGrid::find_path`]) and multi-target
+//! [`FlowField`]s, which give every node within some cost of a set of targets the direction to
+//! move in to reach the nearest one. Both work in cell coordinates; [`NavGrid::nearest_node`] finds
+//! the node an agent at some point in the world is standing on.
+//!
+//! The grid listens to the events of the atom map's tracked atoms, and [`NavGrid::update`] only
+//! recalculates the nodes around cells which have changed. Since nodes are derived from hulls, it
+//! should be run after [`AtomMap::update_hulls`].
+
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap, HashSet},
+};
+
+use hv::{prelude::*, script::api::ModuleBuilder};
+use shrev::ReaderId;
+use soft_edge::{Atom, HullFacet};
+
+use crate::{
+    api::{with_loaned, with_loaned_mut},
+    lattice::{
+        atom_map::AtomMap,
+        chunk_map::{ChunkMap, DividedCoords},
+        event::{LatticeEvent, LatticeEventDebouncer},
+    },
+};
+
+/// The offsets of the orthogonal neighbors of a column, followed by the diagonal ones.
+const NEIGHBOR_OFFSETS: [[i32; 2]; 8] = [
+    [1, 0],
+    [0, 1],
+    [-1, 0],
+    [0, -1],
+    [1, 1],
+    [-1, 1],
+    [-1, -1],
+    [1, -1],
+];
+
+/// Movement rules for the agents navigating a [`NavGrid`]. All lengths are in cells.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NavConfig {
+    /// The free space an agent needs above the surface it's standing on.
+    pub agent_height: f32,
+    /// The highest an agent can step up between neighboring cells.
+    pub max_step_height: f32,
+    /// The furthest an agent can drop down between neighboring cells.
+    pub max_drop_height: f32,
+    /// The steepest slope, in radians, an agent can stand on.
+    pub max_slope: f32,
+    /// Whether agents can move diagonally between cells. Diagonal moves are never allowed to cut
+    /// corners: both of the orthogonal moves around the corner must also be possible.
+    pub allow_diagonals: bool,
+}
+
+impl Default for NavConfig {
+    fn default() -> Self {
+        Self {
+            agent_height: 2.,
+            max_step_height: 0.5,
+            max_drop_height: 1.,
+            max_slope: std::f32::consts::FRAC_PI_4,
+            allow_diagonals: true,
+        }
+    }
+}
+
+/// A cell an agent can stand in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NavNode {
+    /// The point on the walkable surface at the center of the cell's column.
+    pub position: Point3<f32>,
+    /// How much free space there is above `position`, or infinity if nothing was found above it
+    /// within the range checked.
+    pub clearance: f32,
+}
+
+/// A path found by [`NavGrid::find_path`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct NavPath {
+    /// The cells of the nodes along the path, starting w/ the start node and ending w/ the goal.
+    pub cells: Vec<Vector3<i32>>,
+    /// The positions of the nodes along the path.
+    pub points: Vec<Point3<f32>>,
+    /// The total cost (length) of the path.
+    pub cost: f32,
+}
+
+/// A cell of a [`FlowField`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FlowCell {
+    /// The cost of reaching the nearest target from this cell.
+    pub cost: f32,
+    /// The next cell along the cheapest path to the nearest target, or `None` if this cell is a
+    /// target.
+    pub next: Option<Vector3<i32>>,
+    /// The normalized direction from this cell's node to the next, or zero if this cell is a
+    /// target.
+    pub direction: Vector3<f32>,
+}
+
+/// The cheapest way to reach the nearest of a set of targets from every node within some cost of
+/// them. Created by [`NavGrid::flow_field`].
+#[derive(Debug, Clone, Default)]
+pub struct FlowField {
+    cells: ChunkMap<FlowCell>,
+}
+
+impl FlowField {
+    /// Get the flow at a node's cell, if it's reachable.
+    pub fn get(&self, coords: Vector3<i32>) -> Option<&FlowCell> {
+        self.cells.get(coords)
+    }
+
+    /// Get the flow at the node an agent at `point` would be standing on, looking in the cell
+    /// containing the point and the two cells below it.
+    pub fn sample(&self, point: &Point3<f32>) -> Option<&FlowCell> {
+        let cell = point.coords.map(|t| t.floor() as i32);
+        (0..3).find_map(|dz| self.cells.get(cell - Vector3::z() * dz))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Vector3<i32>, &FlowCell)> {
+        self.cells.iter()
+    }
+}
+
+/// A walkability graph derived from an [`AtomMap`]. See the [module-level docs](self).
+pub struct NavGrid {
+    config: NavConfig,
+    nodes: ChunkMap<NavNode>,
+    reader_id: ReaderId<LatticeEvent<Atom>>,
+    debouncer: LatticeEventDebouncer<Atom>,
+}
+
+impl std::fmt::Debug for NavGrid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NavGrid")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl NavGrid {
+    /// Build the walkability graph of an atom map, and start listening for changes to it.
+    pub fn new(config: NavConfig, atom_map: &mut AtomMap) -> Self {
+        let mut this = Self {
+            config,
+            nodes: ChunkMap::new(),
+            reader_id: atom_map.atoms_mut().events_mut().register_reader(),
+            debouncer: LatticeEventDebouncer::new(),
+        };
+        this.rebuild(atom_map);
+        this
+    }
+
+    pub fn config(&self) -> &NavConfig {
+        &self.config
+    }
+
+    /// Change the movement rules of the grid, and rebuild it.
+    pub fn set_config(&mut self, config: NavConfig, atom_map: &AtomMap) {
+        self.config = config;
+        self.rebuild(atom_map);
+    }
+
+    /// Clear and rebuild the entire graph.
+    pub fn rebuild(&mut self, atom_map: &AtomMap) {
+        // Everything is about to be rebuilt, so any pending changes can be ignored.
+        atom_map
+            .atoms()
+            .events()
+            .read(&mut self.reader_id)
+            .for_each(|_| ());
+
+        self.nodes = ChunkMap::new();
+        for (coords, _) in atom_map.hulls().iter() {
+            if let Some(node) = self.calculate_node(atom_map, coords) {
+                self.nodes.insert(coords, node);
+            }
+        }
+    }
+
+    /// Recalculate the nodes around any cells of the atom map which have changed since the graph
+    /// was last updated. The hulls of the atom map must already be up to date.
+    pub fn update(&mut self, atom_map: &AtomMap) {
+        self.debouncer
+            .extend(atom_map.atoms().events().read(&mut self.reader_id).copied());
+
+        let mut dirty = HashSet::new();
+        for event in self.debouncer.drain() {
+            match event {
+                LatticeEvent::Slot(slot_event) => {
+                    let divided = DividedCoords {
+                        chunk_coords: slot_event.chunk,
+                        sub_coords: slot_event.sub,
+                    };
+                    dirty.insert(divided.to_world_coords().push(slot_event.layer));
+                }
+                LatticeEvent::Chunk(chunk_event) => {
+                    let layer = chunk_event.layer;
+                    dirty.extend(chunk_event.chunk.world_coords().map(|xy| xy.push(layer)));
+                }
+                LatticeEvent::Layer(layer_event) => {
+                    let index = layer_event.layer;
+                    let atom_chunks = atom_map.atoms().get_layer(index).into_iter();
+                    let node_chunks = self.nodes.get_layer(index).into_iter();
+                    let chunks = atom_chunks
+                        .flat_map(|layer| layer.chunks().map(|(coords, _)| coords))
+                        .chain(
+                            node_chunks.flat_map(|layer| layer.chunks().map(|(coords, _)| coords)),
+                        )
+                        .collect::<HashSet<_>>();
+                    for chunk in chunks {
+                        dirty.extend(chunk.world_coords().map(|xy| xy.push(index)));
+                    }
+                }
+            }
+        }
+
+        // A changed cell can change the hulls of its neighbors, and the clearance of any node
+        // below it within an agent's height.
+        let below = self.config.agent_height.ceil() as i32 + 1;
+        let mut affected = HashSet::new();
+        for coords in dirty {
+            for dz in -below..=1 {
+                for dy in -1..=1 {
+                    for dx in -1..=1 {
+                        affected.insert(coords + Vector3::new(dx, dy, dz));
+                    }
+                }
+            }
+        }
+
+        for coords in affected {
+            match self.calculate_node(atom_map, coords) {
+                Some(node) => self.nodes.insert(coords, node),
+                None => self.nodes.remove(coords),
+            };
+        }
+    }
+
+    /// Get the node at some cell, if the cell is walkable.
+    pub fn node(&self, coords: Vector3<i32>) -> Option<&NavNode> {
+        self.nodes.get(coords)
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = (Vector3<i32>, &NavNode)> {
+        self.nodes.iter()
+    }
+
+    /// Find the node an agent at `point` is standing on: the highest node in the point's column
+    /// which is no higher than a step above the point, and no further than a drop below it.
+    pub fn nearest_node(&self, point: &Point3<f32>) -> Option<Vector3<i32>> {
+        let cell = point.coords.map(|t| t.floor() as i32);
+        let below = self.config.max_drop_height.ceil() as i32 + 1;
+        (-1..=below)
+            .map(|dz| cell - Vector3::z() * dz)
+            .find(|&coords| {
+                self.nodes.get(coords).map_or(false, |node| {
+                    let dh = node.position.z - point.z;
+                    dh <= self.config.max_step_height && -dh <= self.config.max_drop_height
+                })
+            })
+    }
+
+    /// Find the cheapest path between two nodes w/ A*.
+    pub fn find_path(&self, start: Vector3<i32>, goal: Vector3<i32>) -> Option<NavPath> {
+        let goal_position = self.nodes.get(goal)?.position;
+        self.nodes.get(start)?;
+
+        let heuristic = |coords: Vector3<i32>| {
+            nalgebra::distance(&self.nodes.get(coords).unwrap().position, &goal_position)
+        };
+
+        let mut open = BinaryHeap::new();
+        let mut visited: HashMap<Vector3<i32>, (f32, Option<Vector3<i32>>)> = HashMap::new();
+        visited.insert(start, (0., None));
+        open.push(OpenNode::new(heuristic(start), start));
+
+        while let Some(OpenNode {
+            coords: current, ..
+        }) = open.pop()
+        {
+            if current == goal {
+                return Some(self.reconstruct_path(&visited, goal));
+            }
+
+            let cost = visited[&current].0;
+            self.for_each_move_from(current, |next, move_cost| {
+                let next_cost = cost + move_cost;
+                if visited
+                    .get(&next)
+                    .map_or(true, |&(prev, _)| next_cost < prev)
+                {
+                    visited.insert(next, (next_cost, Some(current)));
+                    let estimate = next_cost + heuristic(next);
+                    open.push(OpenNode::new(estimate, next));
+                }
+            });
+        }
+
+        None
+    }
+
+    /// Find the cheapest path between two points in the world, by way of the nodes an agent at
+    /// each point would be standing on.
+    pub fn find_path_between(&self, start: &Point3<f32>, goal: &Point3<f32>) -> Option<NavPath> {
+        self.find_path(self.nearest_node(start)?, self.nearest_node(goal)?)
+    }
+
+    /// Calculate a flow field towards the nearest of a set of target nodes, covering every node
+    /// which can reach a target at a cost of at most `max_cost`. Targets which aren't nodes are
+    /// ignored.
+    pub fn flow_field(
+        &self,
+        targets: impl IntoIterator<Item = Vector3<i32>>,
+        max_cost: f32,
+    ) -> FlowField {
+        let mut field = FlowField::default();
+        let mut open = BinaryHeap::new();
+
+        for target in targets {
+            if self.nodes.get(target).is_some() {
+                field.cells.insert(
+                    target,
+                    FlowCell {
+                        cost: 0.,
+                        next: None,
+                        direction: Vector3::zeros(),
+                    },
+                );
+                open.push(OpenNode::new(0., target));
+            }
+        }
+
+        // Dijkstra's algorithm, backwards: agents move *towards* the targets, so we relax moves
+        // into settled cells rather than out of them.
+        let mut settled = HashSet::new();
+        while let Some(OpenNode {
+            coords: current, ..
+        }) = open.pop()
+        {
+            if !settled.insert(current) {
+                continue;
+            }
+
+            let cost = field.cells.get(current).unwrap().cost;
+            let position = self.nodes.get(current).unwrap().position;
+            self.for_each_move_to(current, |prev, move_cost| {
+                let prev_cost = cost + move_cost;
+                if prev_cost > max_cost
+                    || field
+                        .cells
+                        .get(prev)
+                        .map_or(false, |cell| cell.cost <= prev_cost)
+                {
+                    return;
+                }
+
+                let prev_position = self.nodes.get(prev).unwrap().position;
+                field.cells.insert(
+                    prev,
+                    FlowCell {
+                        cost: prev_cost,
+                        next: Some(current),
+                        direction: (position - prev_position)
+                            .try_normalize(f32::EPSILON)
+                            .unwrap_or_else(Vector3::zeros),
+                    },
+                );
+                open.push(OpenNode::new(prev_cost, prev));
+            });
+        }
+
+        field
+    }
+
+    fn reconstruct_path(
+        &self,
+        visited: &HashMap<Vector3<i32>, (f32, Option<Vector3<i32>>)>,
+        goal: Vector3<i32>,
+    ) -> NavPath {
+        let mut cells = vec![goal];
+        while let Some(prev) = visited[cells.last().unwrap()].1 {
+            cells.push(prev);
+        }
+        cells.reverse();
+
+        NavPath {
+            points: cells
+                .iter()
+                .map(|&coords| self.nodes.get(coords).unwrap().position)
+                .collect(),
+            cost: visited[&goal].0,
+            cells,
+        }
+    }
+
+    /// Calculate the node for a cell, if the cell is walkable.
+    fn calculate_node(&self, atom_map: &AtomMap, coords: Vector3<i32>) -> Option<NavNode> {
+        let hull = atom_map.hulls().get(coords)?;
+        let offset = coords.cast::<f32>();
+        let center = offset.xy().add_scalar(0.5);
+        let min_normal_z = self.config.max_slope.cos();
+
+        // The height of the highest walkable facet at the center of the column.
+        let mut surface = None::<f32>;
+        for facet in hull.facets() {
+            let normal = facet.normal();
+            if normal.z < min_normal_z {
+                continue;
+            }
+
+            let p0 = facet_vertices(&facet)[0] + offset;
+            let height =
+                p0.z - (normal.x * (center.x - p0.x) + normal.y * (center.y - p0.y)) / normal.z;
+            let height = height.clamp(offset.z, offset.z + 1.);
+            surface = Some(surface.map_or(height, |s: f32| s.max(height)));
+        }
+        let height = surface?;
+
+        // The lowest point of anything above the surface, up to an agent's height above it.
+        let above = self.config.agent_height.ceil() as i32 + 1;
+        let ceiling = (1..=above).find_map(|dz| {
+            let coords = coords + Vector3::z() * dz;
+            atom_map.atoms().get(coords)?;
+            let bottom = coords.z as f32;
+            let lowest = atom_map.hulls().get(coords).and_then(|hull| {
+                hull.facets()
+                    .flat_map(|facet| facet_vertices(&facet))
+                    .map(|v| v.z + bottom)
+                    .reduce(f32::min)
+            });
+            Some(lowest.unwrap_or(bottom))
+        });
+
+        let clearance = ceiling.map_or(f32::INFINITY, |ceiling| ceiling - height);
+        (clearance >= self.config.agent_height).then(|| NavNode {
+            position: Point3::new(center.x, center.y, height),
+            clearance,
+        })
+    }
+
+    /// The cost of moving between two nodes in neighboring columns, if an agent can.
+    fn move_cost(&self, from: Vector3<i32>, to: Vector3<i32>) -> Option<f32> {
+        let a = self.nodes.get(from)?;
+        let b = self.nodes.get(to)?;
+        let dh = b.position.z - a.position.z;
+        let config = &self.config;
+
+        // Stepping up needs headroom above the lower node for the step, and likewise for dropping
+        // down.
+        let ok = if dh >= 0. {
+            dh <= config.max_step_height && a.clearance >= config.agent_height + dh
+        } else {
+            -dh <= config.max_drop_height && b.clearance >= config.agent_height - dh
+        };
+
+        ok.then(|| nalgebra::distance(&a.position, &b.position))
+    }
+
+    /// Find the node in a neighboring column an agent can move to, if any.
+    fn find_move(&self, from: Vector3<i32>, offset: [i32; 2]) -> Option<(Vector3<i32>, f32)> {
+        let reach = self.reach();
+        (-reach..=reach)
+            .map(|dz| from + Vector3::new(offset[0], offset[1], dz))
+            .filter_map(|to| Some((to, self.move_cost(from, to)?)))
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+    }
+
+    // Diagonal moves have to be possible both ways around the corner.
+    fn diagonal_allowed(&self, from: Vector3<i32>, offset: [i32; 2]) -> bool {
+        self.find_move(from, [offset[0], 0]).is_some()
+            && self.find_move(from, [0, offset[1]]).is_some()
+    }
+
+    fn for_each_move_from(&self, from: Vector3<i32>, mut f: impl FnMut(Vector3<i32>, f32)) {
+        for &offset in self.neighbor_offsets() {
+            if offset[0] != 0 && offset[1] != 0 && !self.diagonal_allowed(from, offset) {
+                continue;
+            }
+
+            if let Some((to, cost)) = self.find_move(from, offset) {
+                f(to, cost);
+            }
+        }
+    }
+
+    fn for_each_move_to(&self, to: Vector3<i32>, mut f: impl FnMut(Vector3<i32>, f32)) {
+        let reach = self.reach();
+        for &offset in self.neighbor_offsets() {
+            for dz in -reach..=reach {
+                let from = to - Vector3::new(offset[0], offset[1], dz);
+                if self.find_move(from, offset).map(|(c, _)| c) != Some(to) {
+                    continue;
+                }
+
+                if offset[0] != 0 && offset[1] != 0 && !self.diagonal_allowed(from, offset) {
+                    continue;
+                }
+
+                if let Some(cost) = self.move_cost(from, to) {
+                    f(from, cost);
+                }
+            }
+        }
+    }
+
+    fn neighbor_offsets(&self) -> &'static [[i32; 2]] {
+        if self.config.allow_diagonals {
+            &NEIGHBOR_OFFSETS
+        } else {
+            &NEIGHBOR_OFFSETS[..4]
+        }
+    }
+
+    // How many layers up or down a move between neighboring columns can cross.
+    fn reach(&self) -> i32 {
+        self.config
+            .max_step_height
+            .max(self.config.max_drop_height)
+            .ceil() as i32
+            + 1
+    }
+}
+
+// An entry in the open set of a search. Ordered so that the cheapest entry is popped first.
+#[derive(Debug, Clone, Copy)]
+struct OpenNode {
+    cost: f32,
+    coords: Vector3<i32>,
+}
+
+impl OpenNode {
+    fn new(cost: f32, coords: Vector3<i32>) -> Self {
+        Self { cost, coords }
+    }
+}
+
+impl PartialEq for OpenNode {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for OpenNode {}
+
+impl PartialOrd for OpenNode {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OpenNode {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Break ties by coordinates, so searches are deterministic.
+        other
+            .cost
+            .total_cmp(&self.cost)
+            .then_with(|| <[i32; 3]>::from(other.coords).cmp(&<[i32; 3]>::from(self.coords)))
+    }
+}
+
+fn facet_vertices(facet: &HullFacet) -> Vec<Point3<f32>> {
+    match facet {
+        HullFacet::Triangle(vs) => vs.iter().map(|v| v.to_f32()).collect(),
+        HullFacet::Rectangle(vs) => vs.iter().map(|v| v.to_f32()).collect(),
+    }
+}
+
+pub(crate) fn register_functions(builder: &mut ModuleBuilder) -> Result<()> {
+    builder
+        .function(
+            "find_path",
+            |lua, (start, goal): (Vector3<f32>, Vector3<f32>)| {
+                with_loaned(lua, |nav: &NavGrid| {
+                    Ok(nav
+                        .find_path_between(&Point3::from(start), &Point3::from(goal))
+                        .map(|path| {
+                            path.points
+                                .into_iter()
+                                .map(|p| p.coords)
+                                .collect::<Vec<_>>()
+                        }))
+                })
+            },
+        )?
+        .function(
+            "flow_field",
+            |lua, (targets, max_cost): (Vec<Vector3<f32>>, Option<f32>)| {
+                with_loaned(lua, |nav: &NavGrid| {
+                    let targets = targets
+                        .iter()
+                        .filter_map(|&target| nav.nearest_node(&Point3::from(target)))
+                        .collect::<Vec<_>>();
+                    Ok(nav.flow_field(targets, max_cost.unwrap_or(f32::INFINITY)))
+                })
+            },
+        )?
+        .function("is_walkable", |lua, point: Vector3<f32>| {
+            with_loaned(lua, |nav: &NavGrid| {
+                Ok(nav.nearest_node(&Point3::from(point)).is_some())
+            })
+        })?
+        .function("set_nav_config", |lua, config: NavConfig| {
+            with_loaned_mut(lua, |nav: &mut NavGrid| {
+                with_loaned(lua, |atom_map: &AtomMap| {
+                    nav.set_config(config, atom_map);
+                    Ok(())
+                })
+            })
+        })?;
+
+    Ok(())
+}
+
+impl LuaUserData for NavConfig {
+    fn on_metatable_init(table: Type<Self>) {
+        table
+            .add_clone()
+            .add_copy()
+            .add_send()
+            .add_sync()
+            .add::<dyn std::fmt::Debug>();
+    }
+
+    #[allow(clippy::unit_arg)]
+    fn add_fields<'lua, F: LuaUserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("agent_height", |_, this| Ok(this.agent_height));
+        fields.add_field_method_get("max_step_height", |_, this| Ok(this.max_step_height));
+        fields.add_field_method_get("max_drop_height", |_, this| Ok(this.max_drop_height));
+        fields.add_field_method_get("max_slope", |_, this| Ok(this.max_slope));
+        fields.add_field_method_get("allow_diagonals", |_, this| Ok(this.allow_diagonals));
+        fields.add_field_method_set("agent_height", |_, this, agent_height| {
+            Ok(this.agent_height = agent_height)
+        });
+        fields.add_field_method_set("max_step_height", |_, this, max_step_height| {
+            Ok(this.max_step_height = max_step_height)
+        });
+        fields.add_field_method_set("max_drop_height", |_, this, max_drop_height| {
+            Ok(this.max_drop_height = max_drop_height)
+        });
+        fields.add_field_method_set("max_slope", |_, this, max_slope| {
+            Ok(this.max_slope = max_slope)
+        });
+        fields.add_field_method_set("allow_diagonals", |_, this, allow_diagonals| {
+            Ok(this.allow_diagonals = allow_diagonals)
+        });
+    }
+
+    fn add_type_methods<'lua, M: LuaUserDataMethods<'lua, Type<Self>>>(methods: &mut M) {
+        methods.add_function("new", |_, ()| Ok(Self::default()));
+    }
+}
+
+impl LuaUserData for FlowField {
+    fn on_metatable_init(table: Type<Self>) {
+        table
+            .add_clone()
+            .add_send()
+            .add_sync()
+            .add::<dyn std::fmt::Debug>();
+    }
+
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("direction", |_, this, point: Vector3<f32>| {
+            Ok(this.sample(&Point3::from(point)).map(|cell| cell.direction))
+        });
+        methods.add_method("cost", |_, this, point: Vector3<f32>| {
+            Ok(this.sample(&Point3::from(point)).map(|cell| cell.cost))
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::lattice::ChunkCoords;
+
+    /// The atom which fills its whole cell.
+    fn cube() -> Atom {
+        Atom::generator()
+            .find(|atom| {
+                atom.compound_hull()
+                    .facets()
+                    .filter(|facet| matches!(facet, HullFacet::Rectangle(_)))
+                    .count()
+                    == 6
+            })
+            .unwrap()
+    }
+
+    /// An atom whose only upward-facing facets are sloped at 45 degrees, through the center of its
+    /// cell.
+    fn wedge() -> Atom {
+        Atom::generator()
+            .find(|atom| {
+                let mut upward = atom
+                    .compound_hull()
+                    .facets()
+                    .map(|facet| facet.normal().z)
+                    .filter(|&z| z > 0.)
+                    .peekable();
+                upward.peek().is_some() && upward.all(|z| (z - 0.5f32.sqrt()).abs() < 1e-3)
+            })
+            .unwrap()
+    }
+
+    fn atom_map(atoms: &[(Vector3<i32>, Atom)]) -> AtomMap {
+        let mut map = AtomMap::new();
+        for &(coords, atom) in atoms {
+            map.atoms_mut().insert(coords, atom);
+        }
+        map.calculate_hulls();
+        map
+    }
+
+    // A flat floor of nodes at height 1 w/ the given cells left out.
+    fn flat_grid(width: i32, height: i32, holes: &[[i32; 2]]) -> NavGrid {
+        let mut grid = NavGrid::new(NavConfig::default(), &mut AtomMap::new());
+        for y in 0..height {
+            for x in 0..width {
+                if holes.contains(&[x, y]) {
+                    continue;
+                }
+
+                grid.nodes.insert(
+                    Vector3::new(x, y, 0),
+                    NavNode {
+                        position: Point3::new(x as f32 + 0.5, y as f32 + 0.5, 1.),
+                        clearance: f32::INFINITY,
+                    },
+                );
+            }
+        }
+        grid
+    }
+
+    #[test]
+    fn straight_path() {
+        let grid = flat_grid(8, 1, &[]);
+        let path = grid
+            .find_path(Vector3::new(0, 0, 0), Vector3::new(7, 0, 0))
+            .unwrap();
+        assert_eq!(path.cells.len(), 8);
+        assert!((path.cost - 7.).abs() < 1e-4);
+    }
+
+    #[test]
+    fn path_around_wall() {
+        // A wall along x = 3 w/ a gap at y = 4.
+        let wall = [[3, 0], [3, 1], [3, 2], [3, 3]];
+        let grid = flat_grid(7, 5, &wall);
+        let path = grid
+            .find_path(Vector3::new(0, 0, 0), Vector3::new(6, 0, 0))
+            .unwrap();
+        assert!(path.cells.contains(&Vector3::new(3, 4, 0)));
+        assert!(path.cells.iter().all(|c| !wall.contains(&[c.x, c.y])));
+
+        let blocked = flat_grid(7, 4, &wall);
+        assert!(blocked
+            .find_path(Vector3::new(0, 0, 0), Vector3::new(6, 0, 0))
+            .is_none());
+    }
+
+    #[test]
+    fn steps_and_drops() {
+        let mut grid = flat_grid(3, 1, &[]);
+        // Raise the last cell a full layer: too high to step up, but fine to drop down from.
+        grid.nodes.remove(Vector3::new(2, 0, 0));
+        grid.nodes.insert(
+            Vector3::new(2, 0, 1),
+            NavNode {
+                position: Point3::new(2.5, 0.5, 2.),
+                clearance: f32::INFINITY,
+            },
+        );
+
+        assert!(grid
+            .find_path(Vector3::new(0, 0, 0), Vector3::new(2, 0, 1))
+            .is_none());
+        assert!(grid
+            .find_path(Vector3::new(2, 0, 1), Vector3::new(0, 0, 0))
+            .is_some());
+    }
+
+    #[test]
+    fn flow_field_leads_to_nearest_target() {
+        let grid = flat_grid(9, 1, &[]);
+        let field = grid.flow_field([Vector3::new(0, 0, 0), Vector3::new(8, 0, 0)], 3.5);
+
+        assert_eq!(
+            field.get(Vector3::new(2, 0, 0)).unwrap().next,
+            Some(Vector3::new(1, 0, 0))
+        );
+        assert_eq!(
+            field.get(Vector3::new(6, 0, 0)).unwrap().next,
+            Some(Vector3::new(7, 0, 0))
+        );
+        assert!(field.get(Vector3::new(8, 0, 0)).unwrap().next.is_none());
+        // Too far from both targets.
+        assert!(field.get(Vector3::new(4, 0, 0)).is_none());
+
+        let cell = field.sample(&Point3::new(2.2, 0.7, 1.)).unwrap();
+        assert!((cell.direction - -Vector3::x()).norm() < 1e-4);
+    }
+
+    #[test]
+    fn nodes_from_atoms() {
+        let config = NavConfig {
+            max_slope: std::f32::consts::FRAC_PI_3,
+            ..NavConfig::default()
+        };
+        let mut map = atom_map(&[
+            (Vector3::new(0, 0, 0), cube()),
+            (Vector3::new(1, 0, 0), wedge()),
+        ]);
+        let grid = NavGrid::new(config, &mut map);
+
+        let floor = grid.node(Vector3::new(0, 0, 0)).unwrap();
+        assert_eq!(floor.position, Point3::new(0.5, 0.5, 1.));
+        assert_eq!(floor.clearance, f32::INFINITY);
+
+        // The surface of a slope is taken at the center of its column.
+        let slope = grid.node(Vector3::new(1, 0, 0)).unwrap();
+        assert!((slope.position - Point3::new(1.5, 0.5, 0.5)).norm() < 1e-4);
+
+        // Unless it's too steep to stand on.
+        let steep = NavConfig {
+            max_slope: std::f32::consts::FRAC_PI_6,
+            ..config
+        };
+        let steep_grid = NavGrid::new(steep, &mut map);
+        assert!(steep_grid.node(Vector3::new(0, 0, 0)).is_some());
+        assert!(steep_grid.node(Vector3::new(1, 0, 0)).is_none());
+    }
+
+    #[test]
+    fn clearance_under_ceilings() {
+        let mut map = atom_map(&[
+            (Vector3::new(0, 0, 0), cube()),
+            (Vector3::new(0, 0, 2), cube()),
+        ]);
+        let grid = NavGrid::new(NavConfig::default(), &mut map);
+        // One cell of headroom isn't enough for an agent two cells tall...
+        assert!(grid.calculate_node(&map, Vector3::new(0, 0, 0)).is_none());
+
+        // ...but is for one a single cell tall.
+        let short = NavConfig {
+            agent_height: 1.,
+            ..NavConfig::default()
+        };
+        let grid = NavGrid::new(short, &mut map);
+        let node = grid.calculate_node(&map, Vector3::new(0, 0, 0)).unwrap();
+        assert_eq!(node.clearance, 1.);
+        // The ceiling is walkable too, w/ nothing above it.
+        assert_eq!(
+            grid.node(Vector3::new(0, 0, 2)).unwrap().clearance,
+            f32::INFINITY
+        );
+    }
+
+    #[test]
+    fn ramps_connect_layers() {
+        let config = NavConfig {
+            max_step_height: 0.6,
+            max_slope: std::f32::consts::FRAC_PI_3,
+            ..NavConfig::default()
+        };
+        let start = Vector3::new(0, 0, 0);
+        let goal = Vector3::new(2, 0, 1);
+        let mut atoms = vec![(start, cube()), (goal, cube())];
+
+        // A full layer up is too far to step w/o anything in between.
+        let mut map = atom_map(&atoms);
+        assert!(NavGrid::new(config, &mut map)
+            .find_path(start, goal)
+            .is_none());
+
+        atoms.push((Vector3::new(1, 0, 1), wedge()));
+        let mut map = atom_map(&atoms);
+        let path = NavGrid::new(config, &mut map)
+            .find_path(start, goal)
+            .unwrap();
+        assert_eq!(path.cells, vec![start, Vector3::new(1, 0, 1), goal]);
+        let heights = path.points.iter().map(|p| p.z).collect::<Vec<_>>();
+        assert!((heights[1] - 1.5).abs() < 1e-4);
+        assert_eq!(heights[2], 2.);
+    }
+
+    #[test]
+    fn update_matches_rebuild() {
+        let atoms = Atom::generator().collect::<Vec<_>>();
+        let atom_at = |coords: Vector3<i32>, salt: i32| {
+            let i = (coords.x * 7 + coords.y * 13 + coords.z * 5 + salt)
+                .rem_euclid(atoms.len() as i32 * 3);
+            atoms.get(i as usize).copied()
+        };
+
+        let mut map = AtomMap::new();
+        for z in 0..4 {
+            for y in -4..20 {
+                for x in 10..22 {
+                    let coords = Vector3::new(x, y, z);
+                    if let Some(atom) = atom_at(coords, 0) {
+                        map.atoms_mut().insert(coords, atom);
+                    }
+                }
+            }
+        }
+        map.calculate_hulls();
+
+        let config = NavConfig::default();
+        let mut grid = NavGrid::new(config, &mut map);
+
+        // Change some cells, clear some others, and drop a whole chunk.
+        for y in 0..6 {
+            for x in 12..18 {
+                let coords = Vector3::new(x, y, 1 + (x + y) % 2);
+                match atom_at(coords, 1) {
+                    Some(atom) => map.atoms_mut().insert(coords, atom),
+                    None => map.atoms_mut().remove(coords),
+                };
+            }
+        }
+        map.atoms_mut().remove_chunk(2, ChunkCoords::new(1, 0));
+        map.update_hulls();
+        grid.update(&map);
+
+        let fresh = NavGrid::new(config, &mut map);
+        let updated = grid.nodes().collect::<HashMap<_, _>>();
+        let rebuilt = fresh.nodes().collect::<HashMap<_, _>>();
+        assert!(!rebuilt.is_empty());
+        assert_eq!(updated, rebuilt);
+    }
+}
@@ -0,0 +1,408 @@
+//! Rectangular regions of cells, for copying and pasting blocks of a map.
+//!
+//! A [`Region`] is a dense box of cells extracted from a [`ChunkMap`] or [`TrackedMap`] w/
+//! `copy_region`, spanning any number of layers. Regions can be rotated in quarter turns about the
+//! Z axis and mirrored along any axis; cell values which have an orientation of their own (such as
+//! [`Atom`]s) implement [`Orientation`] so that they're remapped along w/ their positions. Regions
+//! are pasted back w/ `paste_region` and a [`PasteMask`] controlling which cells are written.
+//!
+//! Regions are serializable, and can be saved as prefabs w/ [`Region::save`].
+
+use hv::prelude::*;
+use serde::{Deserialize, Serialize};
+use soft_edge::{Atom, HullFacet};
+
+use crate::lattice::{chunk_map::ChunkMap, tracked_map::TrackedMap};
+
+/// An axis to mirror a [`Region`] along.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum MirrorAxis {
+    X,
+    Y,
+    Z,
+}
+
+/// Cell values w/ an orientation, which need to be remapped when a [`Region`] is rotated or
+/// mirrored.
+pub trait Orientation: Copy {
+    /// Rotate a quarter turn counterclockwise about the Z axis, as seen from above.
+    fn rotated_z(self) -> Self;
+
+    /// Mirror along an axis.
+    fn mirrored(self, axis: MirrorAxis) -> Self;
+}
+
+lazy_static::lazy_static! {
+    // Every atom, w/ the corners of the unit cell it occupies as a bitmask.
+    static ref ATOM_CORNERS: Vec<(Atom, u8)> = Atom::generator()
+        .map(|atom| (atom, atom_corner_mask(&atom)))
+        .collect();
+}
+
+// An atom is the convex hull of some of the corners of its cell, so the corners its hull touches
+// identify it uniquely. Corner `(x, y, z)` is bit `x + 2y + 4z`.
+fn atom_corner_mask(atom: &Atom) -> u8 {
+    let mut mask = 0;
+    for facet in atom.compound_hull().facets() {
+        let vertices = match facet {
+            HullFacet::Triangle(vs) => vs.iter().map(|v| v.to_f32()).collect::<Vec<_>>(),
+            HullFacet::Rectangle(vs) => vs.iter().map(|v| v.to_f32()).collect::<Vec<_>>(),
+        };
+
+        for v in vertices {
+            let corner = v.map(|t| t.round() as u8);
+            mask |= 1 << (corner.x + 2 * corner.y + 4 * corner.z);
+        }
+    }
+    mask
+}
+
+// Remap an atom by moving each of its corners. Atoms which somehow don't map onto another atom are
+// left as-is.
+fn transform_atom(atom: Atom, f: impl Fn(Vector3<u8>) -> Vector3<u8>) -> Atom {
+    let mask = match ATOM_CORNERS.iter().find(|(a, _)| *a == atom) {
+        Some(&(_, mask)) => mask,
+        None => return atom,
+    };
+
+    let mut transformed = 0u8;
+    for i in (0..8).filter(|i| mask & (1 << i) != 0) {
+        let corner = f(Vector3::new(i & 1, (i >> 1) & 1, (i >> 2) & 1));
+        transformed |= 1 << (corner.x + 2 * corner.y + 4 * corner.z);
+    }
+
+    ATOM_CORNERS
+        .iter()
+        .find(|&&(_, mask)| mask == transformed)
+        .map_or(atom, |&(a, _)| a)
+}
+
+impl Orientation for Atom {
+    fn rotated_z(self) -> Self {
+        transform_atom(self, |c| Vector3::new(1 - c.y, c.x, c.z))
+    }
+
+    fn mirrored(self, axis: MirrorAxis) -> Self {
+        transform_atom(self, |mut c| {
+            match axis {
+                MirrorAxis::X => c.x = 1 - c.x,
+                MirrorAxis::Y => c.y = 1 - c.y,
+                MirrorAxis::Z => c.z = 1 - c.z,
+            }
+            c
+        })
+    }
+}
+
+/// Which cells of the destination are written when pasting a [`Region`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum PasteMask {
+    /// Write every cell of the region, clearing destination cells where the region is empty.
+    All,
+    /// Only write the occupied cells of the region, leaving the rest of the destination alone.
+    SkipEmpty,
+    /// Only write occupied cells of the region over cells which are already occupied.
+    ReplaceOnly,
+    /// Only write occupied cells of the region into cells which are empty.
+    FillEmpty,
+}
+
+impl Default for PasteMask {
+    fn default() -> Self {
+        Self::SkipEmpty
+    }
+}
+
+impl PasteMask {
+    // What to do w/ a destination cell: `None` to leave it alone, `Some(value)` to set or clear it.
+    fn resolve<T: Copy>(self, existing: Option<T>, new: Option<T>) -> Option<Option<T>> {
+        match self {
+            Self::All => Some(new),
+            Self::SkipEmpty => new.map(Some),
+            Self::ReplaceOnly => existing.and(new).map(Some),
+            Self::FillEmpty => match existing {
+                Some(_) => None,
+                None => new.map(Some),
+            },
+        }
+    }
+}
+
+/// A dense box of cells, w/ its minimum corner at the origin. See the
+/// [module-level docs](self).
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Region<T> {
+    size: [u32; 3],
+    cells: Vec<Option<T>>,
+}
+
+impl<T: Copy> Region<T> {
+    /// Create an empty region of the given size.
+    pub fn new(size: Vector3<u32>) -> Self {
+        Self {
+            size: size.into(),
+            cells: vec![None; size.iter().map(|&t| t as usize).product()],
+        }
+    }
+
+    /// Copy the cells in the box between `mins` and `maxs` (inclusive) out of a map.
+    pub fn from_chunk_map(map: &ChunkMap<T>, mins: Vector3<i32>, maxs: Vector3<i32>) -> Self {
+        let size = (maxs - mins).map(|t| (t + 1).max(0) as u32);
+        let mut region = Self::new(size);
+        for (z, layer) in map.get_layers_in_range(mins.z..=maxs.z) {
+            for y in 0..size.y {
+                for x in 0..size.x {
+                    let coords = mins.xy() + Vector2::new(x as i32, y as i32);
+                    let offset = Vector3::new(x, y, (z - mins.z) as u32);
+                    region.set(offset, layer.get(coords).copied());
+                }
+            }
+        }
+        region
+    }
+
+    pub fn size(&self) -> Vector3<u32> {
+        self.size.into()
+    }
+
+    fn index(&self, offset: Vector3<u32>) -> Option<usize> {
+        let [sx, sy, sz] = self.size;
+        (offset.x < sx && offset.y < sy && offset.z < sz).then(|| {
+            let [x, y, z] = [offset.x, offset.y, offset.z].map(|t| t as usize);
+            x + sx as usize * (y + sy as usize * z)
+        })
+    }
+
+    /// Get the value of a cell, or `None` if it's empty or outside the region.
+    pub fn get(&self, offset: Vector3<u32>) -> Option<T> {
+        self.index(offset).and_then(|i| self.cells[i])
+    }
+
+    /// Set or clear a cell, returning its previous value. Offsets outside of the region are
+    /// ignored.
+    pub fn set(&mut self, offset: Vector3<u32>, value: Option<T>) -> Option<T> {
+        let i = self.index(offset)?;
+        std::mem::replace(&mut self.cells[i], value)
+    }
+
+    /// Iterate over the offsets of every cell in the region and their values.
+    pub fn cells(&self) -> impl Iterator<Item = (Vector3<u32>, Option<T>)> + '_ {
+        let [sx, sy, _] = self.size;
+        self.cells.iter().enumerate().map(move |(i, &value)| {
+            let i = i as u32;
+            let offset = Vector3::new(i % sx, (i / sx) % sy, i / (sx * sy));
+            (offset, value)
+        })
+    }
+
+    /// Iterate over the offsets and values of the occupied cells in the region.
+    pub fn iter(&self) -> impl Iterator<Item = (Vector3<u32>, T)> + '_ {
+        self.cells()
+            .filter_map(|(offset, value)| Some((offset, value?)))
+    }
+
+    // Build a new region of some size by moving every cell of this one, and transforming its value.
+    fn remapped(
+        &self,
+        size: Vector3<u32>,
+        offset_fn: impl Fn(Vector3<u32>) -> Vector3<u32>,
+        value_fn: impl Fn(T) -> T,
+    ) -> Self {
+        let mut region = Self::new(size);
+        for (offset, value) in self.iter() {
+            region.set(offset_fn(offset), Some(value_fn(value)));
+        }
+        region
+    }
+}
+
+impl<T: Orientation> Region<T> {
+    /// Rotate the region some number of quarter turns counterclockwise about the Z axis, as seen
+    /// from above. Negative numbers of turns rotate clockwise.
+    pub fn rotated(&self, quarter_turns: i32) -> Self {
+        let mut region = self.clone();
+        for _ in 0..quarter_turns.rem_euclid(4) {
+            let size = region.size();
+            region = region.remapped(
+                Vector3::new(size.y, size.x, size.z),
+                |o| Vector3::new(size.y - 1 - o.y, o.x, o.z),
+                T::rotated_z,
+            );
+        }
+        region
+    }
+
+    /// Mirror the region along an axis.
+    pub fn mirrored(&self, axis: MirrorAxis) -> Self {
+        let size = self.size();
+        self.remapped(
+            size,
+            |mut o| {
+                match axis {
+                    MirrorAxis::X => o.x = size.x - 1 - o.x,
+                    MirrorAxis::Y => o.y = size.y - 1 - o.y,
+                    MirrorAxis::Z => o.z = size.z - 1 - o.z,
+                }
+                o
+            },
+            |value| value.mirrored(axis),
+        )
+    }
+}
+
+impl<T: Copy> ChunkMap<T> {
+    /// Copy the cells in the box between `mins` and `maxs` (inclusive) into a region.
+    pub fn copy_region(&self, mins: Vector3<i32>, maxs: Vector3<i32>) -> Region<T> {
+        Region::from_chunk_map(self, mins, maxs)
+    }
+
+    /// Paste a region w/ its minimum corner at `origin`.
+    pub fn paste_region(&mut self, region: &Region<T>, origin: Vector3<i32>, mask: PasteMask) {
+        for (offset, new) in region.cells() {
+            let coords = origin + offset.cast::<i32>();
+            match mask.resolve(self.get(coords).copied(), new) {
+                Some(Some(value)) => {
+                    self.insert(coords, value);
+                }
+                Some(None) => {
+                    self.remove(coords);
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+impl<T: Copy + PartialEq + Send + Sync + 'static> TrackedMap<T> {
+    /// Copy the cells in the box between `mins` and `maxs` (inclusive) into a region.
+    pub fn copy_region(&self, mins: Vector3<i32>, maxs: Vector3<i32>) -> Region<T> {
+        Region::from_chunk_map(self.as_chunk_map(), mins, maxs)
+    }
+
+    /// Paste a region w/ its minimum corner at `origin`, emitting events for every cell written.
+    pub fn paste_region(&mut self, region: &Region<T>, origin: Vector3<i32>, mask: PasteMask) {
+        for (offset, new) in region.cells() {
+            let coords = origin + offset.cast::<i32>();
+            match mask.resolve(self.get(coords).copied(), new) {
+                Some(Some(value)) => {
+                    self.insert(coords, value);
+                }
+                Some(None) => {
+                    self.remove(coords);
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    // A cell value which points in one of the four horizontal directions, counterclockwise from +X.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    struct Facing(u8);
+
+    impl Orientation for Facing {
+        fn rotated_z(self) -> Self {
+            Self((self.0 + 1) % 4)
+        }
+
+        fn mirrored(self, axis: MirrorAxis) -> Self {
+            match axis {
+                MirrorAxis::X => Self([2, 1, 0, 3][self.0 as usize]),
+                MirrorAxis::Y => Self([0, 3, 2, 1][self.0 as usize]),
+                MirrorAxis::Z => self,
+            }
+        }
+    }
+
+    #[test]
+    fn copy_and_paste_w_masks() {
+        let mut map = ChunkMap::new();
+        map.insert(Vector3::new(-1, 15, 0), 1);
+        map.insert(Vector3::new(0, 16, 1), 2);
+        map.insert(Vector3::new(1, 16, 1), 3);
+
+        let region = map.copy_region(Vector3::new(-1, 15, 0), Vector3::new(1, 16, 1));
+        assert_eq!(region.size(), Vector3::new(3, 2, 2));
+        assert_eq!(region.iter().count(), 3);
+        assert_eq!(region.get(Vector3::new(1, 1, 1)), Some(2));
+
+        let mut dst = TrackedMap::new();
+        dst.insert(Vector3::new(10, 10, 5), 9);
+        dst.insert(Vector3::new(11, 11, 6), 9);
+        dst.insert(Vector3::new(12, 10, 5), 9);
+
+        let mut all = dst.as_chunk_map().clone();
+        all.paste_region(&region, Vector3::new(10, 10, 5), PasteMask::All);
+        assert_eq!(all.iter().count(), 3);
+        assert_eq!(all.get(Vector3::new(10, 10, 5)), Some(&1));
+
+        let mut skip_empty = dst.as_chunk_map().clone();
+        skip_empty.paste_region(&region, Vector3::new(10, 10, 5), PasteMask::SkipEmpty);
+        assert_eq!(skip_empty.iter().count(), 4);
+        assert_eq!(skip_empty.get(Vector3::new(12, 10, 5)), Some(&9));
+        assert_eq!(skip_empty.get(Vector3::new(11, 11, 6)), Some(&2));
+
+        let mut replace_only = dst.as_chunk_map().clone();
+        replace_only.paste_region(&region, Vector3::new(10, 10, 5), PasteMask::ReplaceOnly);
+        assert_eq!(replace_only.iter().count(), 3);
+        assert_eq!(replace_only.get(Vector3::new(10, 10, 5)), Some(&1));
+        assert_eq!(replace_only.get(Vector3::new(11, 11, 6)), Some(&2));
+
+        let mut reader = dst.events_mut().register_reader();
+        dst.paste_region(&region, Vector3::new(10, 10, 5), PasteMask::FillEmpty);
+        assert_eq!(dst.as_chunk_map().iter().count(), 4);
+        assert_eq!(dst.get(Vector3::new(10, 10, 5)), Some(&9));
+        assert_eq!(dst.get(Vector3::new(12, 11, 6)), Some(&3));
+        assert_eq!(dst.events().read(&mut reader).count(), 1);
+    }
+
+    #[test]
+    fn rotate_and_mirror() {
+        let mut region = Region::new(Vector3::new(3, 2, 1));
+        region.set(Vector3::new(0, 0, 0), Some(Facing(0)));
+        region.set(Vector3::new(2, 1, 0), Some(Facing(1)));
+
+        let rotated = region.rotated(1);
+        assert_eq!(rotated.size(), Vector3::new(2, 3, 1));
+        assert_eq!(rotated.get(Vector3::new(1, 0, 0)), Some(Facing(1)));
+        assert_eq!(rotated.get(Vector3::new(0, 2, 0)), Some(Facing(2)));
+        assert_eq!(region.rotated(-1), region.rotated(3));
+        assert_eq!(region.rotated(4), region);
+
+        let mirrored = region.mirrored(MirrorAxis::X);
+        assert_eq!(mirrored.get(Vector3::new(2, 0, 0)), Some(Facing(2)));
+        assert_eq!(mirrored.get(Vector3::new(0, 1, 0)), Some(Facing(1)));
+        assert_eq!(mirrored.mirrored(MirrorAxis::X), region);
+    }
+
+    #[test]
+    fn atom_orientations() {
+        let atoms = Atom::generator().collect::<Vec<_>>();
+        let rotated = atoms.iter().map(|a| a.rotated_z()).collect::<Vec<_>>();
+        assert!(atoms.iter().all(|a| rotated.contains(a)));
+
+        for &atom in &atoms {
+            let mut turned = atom;
+            for _ in 0..4 {
+                turned = turned.rotated_z();
+            }
+            assert_eq!(turned, atom);
+
+            for axis in [MirrorAxis::X, MirrorAxis::Y, MirrorAxis::Z] {
+                assert_eq!(atom.mirrored(axis).mirrored(axis), atom);
+            }
+        }
+
+        let masks = ATOM_CORNERS
+            .iter()
+            .map(|&(_, mask)| mask)
+            .collect::<HashSet<_>>();
+        assert_eq!(masks.len(), atoms.len());
+    }
+}
@@ -0,0 +1,803 @@
+//! Streaming the chunks of a tracked map in and out of the filesystem around focus points.
+//!
+//! A [`ChunkStreamer`] keeps the chunks of a [`TrackedMap`] near a set of focus points (cameras,
+//! players, etc.) resident, and evicts the rest. Chunks are stored one per file under a root
+//! directory of the [`Filesystem`], in the format of [`write_chunk`]; loading, decoding, encoding,
+//! and saving all happen on a background thread, so updating the streamer never blocks on the
+//! filesystem. Chunks which don't have a file yet are considered empty.
+//!
+//! Chunks are inserted into and removed from the map through the tracked map's API, so loading and
+//! evicting them produces the usual [`ChunkEvent`]s, and anything listening to the map's events (a
+//! [`ColliderMap`], the hulls of an [`AtomMap`], renderers) follows along. The streamer also
+//! listens to the map's events itself, to find out which resident chunks have been modified; dirty
+//! chunks are saved when they're evicted, or when the streamer is explicitly flushed. Chunks which
+//! are still loading are never evicted, so that a chunk modified before its load lands is never
+//! saved over its file w/o the loaded values merged in underneath the modifications.
+//!
+//! [`ChunkEvent`]: crate::lattice::event::ChunkEvent
+//! [`ColliderMap`]: crate::lattice::collider_map::ColliderMap
+//! [`AtomMap`]: crate::lattice::atom_map::AtomMap
+
+use std::{
+    collections::HashMap,
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+};
+
+use crossbeam_queue::SegQueue;
+use hv::{fs::Filesystem, prelude::*};
+use serde::{de::DeserializeOwned, Serialize};
+use shrev::ReaderId;
+use tracing::error;
+
+use crate::lattice::{
+    chunk_map::{Chunk, CHUNK_SIDE_LENGTH},
+    event::{LatticeEvent, LayerEventKind},
+    format::{read_chunk, write_chunk, Compression},
+    tracked_map::TrackedMap,
+    ChunkCoords,
+};
+
+/// Controls which chunks a [`ChunkStreamer`] keeps resident.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StreamingConfig {
+    /// Chunks any part of which is within this horizontal distance (in cells) of a focus point are
+    /// loaded.
+    pub load_radius: f32,
+    /// Chunks no part of which is within this horizontal distance (in cells) of any focus point
+    /// are evicted. Should be larger than `load_radius`, so that chunks on the edge of the loaded
+    /// area aren't repeatedly loaded and evicted as focus points move back and forth.
+    pub unload_radius: f32,
+    /// How many layers above and below each focus point are kept resident.
+    pub layer_radius: i32,
+    /// The compression used when saving chunks.
+    pub compression: Compression,
+}
+
+impl Default for StreamingConfig {
+    fn default() -> Self {
+        Self {
+            load_radius: 64.,
+            unload_radius: 96.,
+            layer_radius: 2,
+            compression: Compression::default(),
+        }
+    }
+}
+
+/// The state of a chunk known to a [`ChunkStreamer`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkStatus {
+    /// The chunk has been requested from the filesystem, but hasn't been loaded yet.
+    Loading,
+    /// The chunk was modified while it was being loaded. Once the load lands, the loaded values
+    /// are merged in underneath the modifications, and the chunk becomes resident and dirty.
+    LoadingModified,
+    /// The chunk is loaded (or there was nothing to load), and may have been modified since.
+    Resident { dirty: bool },
+}
+
+enum Request<T> {
+    Load {
+        layer: i32,
+        coords: ChunkCoords,
+        path: PathBuf,
+    },
+    Save {
+        coords: ChunkCoords,
+        chunk: Chunk<T>,
+        path: PathBuf,
+    },
+    Delete {
+        path: PathBuf,
+    },
+}
+
+struct Loaded<T> {
+    layer: i32,
+    coords: ChunkCoords,
+    result: Result<Option<Chunk<T>>>,
+}
+
+// State shared between a streamer and its background thread.
+struct Shared<T> {
+    fs: Arc<Mutex<Filesystem>>,
+    compression: Compression,
+    requests: SegQueue<Request<T>>,
+    loaded: SegQueue<Loaded<T>>,
+    shutdown: AtomicBool,
+}
+
+impl<T: Serialize + DeserializeOwned + Clone + PartialEq> Shared<T> {
+    fn run(&self) {
+        loop {
+            match self.requests.pop() {
+                Some(request) => self.handle(request),
+                // Only shut down once every outstanding save has been handled.
+                None if self.shutdown.load(Ordering::Acquire) => break,
+                None => thread::park(),
+            }
+        }
+    }
+
+    fn handle(&self, request: Request<T>) {
+        match request {
+            Request::Load {
+                layer,
+                coords,
+                path,
+            } => {
+                let result = self
+                    .load(&path)
+                    .with_context(|| format!("while loading chunk from {:?}", path));
+                self.loaded.push(Loaded {
+                    layer,
+                    coords,
+                    result,
+                });
+            }
+            Request::Save {
+                coords,
+                chunk,
+                path,
+            } => {
+                if let Err(err) = self.save(&path, coords, &chunk) {
+                    error!(error = ?err, "error saving chunk to {:?}: {:#}", path, err);
+                }
+            }
+            Request::Delete { path } => {
+                let result = self.lock_fs().and_then(|mut fs| match fs.is_file(&path) {
+                    true => fs.delete(&path),
+                    false => Ok(()),
+                });
+
+                if let Err(err) = result {
+                    error!(error = ?err, "error deleting chunk at {:?}: {:#}", path, err);
+                }
+            }
+        }
+    }
+
+    fn load(&self, path: &Path) -> Result<Option<Chunk<T>>> {
+        let file = {
+            let mut fs = self.lock_fs()?;
+            if !fs.is_file(path) {
+                return Ok(None);
+            }
+            fs.open(path)?
+        };
+
+        let (_, chunk) = read_chunk(BufReader::new(file))?;
+        Ok(Some(chunk))
+    }
+
+    fn save(&self, path: &Path, coords: ChunkCoords, chunk: &Chunk<T>) -> Result<()> {
+        let file = {
+            let mut fs = self.lock_fs()?;
+            if let Some(parent) = path.parent() {
+                fs.create_dir(parent)?;
+            }
+            fs.create(path)?
+        };
+
+        write_chunk(coords, chunk, BufWriter::new(file), self.compression)
+    }
+
+    fn lock_fs(&self) -> Result<MutexGuard<Filesystem>> {
+        self.fs
+            .lock()
+            .map_err(|_| anyhow!("filesystem mutex poisoned"))
+    }
+}
+
+/// Loads and evicts the chunks of a [`TrackedMap`] around a set of focus points. See the
+/// [module-level docs](self).
+pub struct ChunkStreamer<T: Copy + PartialEq + Send + Sync + 'static> {
+    config: StreamingConfig,
+    root: PathBuf,
+    focus_points: Vec<Point3<f32>>,
+    chunks: HashMap<(i32, ChunkCoords), ChunkStatus>,
+    reader_id: ReaderId<LatticeEvent<T>>,
+    shared: Arc<Shared<T>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl<T: Copy + PartialEq + Send + Sync + 'static> std::fmt::Debug for ChunkStreamer<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChunkStreamer")
+            .field("config", &self.config)
+            .field("root", &self.root)
+            .field("focus_points", &self.focus_points)
+            .field("chunks", &self.chunks.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> ChunkStreamer<T>
+where
+    T: Copy + PartialEq + Send + Sync + Serialize + DeserializeOwned + 'static,
+{
+    /// Start streaming the chunks of a map to and from a directory of the filesystem. Chunks
+    /// already in the map are considered resident and dirty, so they'll be saved when evicted.
+    pub fn new(
+        map: &mut TrackedMap<T>,
+        fs: Arc<Mutex<Filesystem>>,
+        root: impl Into<PathBuf>,
+        config: StreamingConfig,
+    ) -> Result<Self> {
+        let shared = Arc::new(Shared {
+            fs,
+            compression: config.compression,
+            requests: SegQueue::new(),
+            loaded: SegQueue::new(),
+            shutdown: AtomicBool::new(false),
+        });
+
+        let worker = {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name("altar chunk streamer".to_owned())
+                .spawn(move || shared.run())?
+        };
+
+        let chunks = map
+            .as_chunk_map()
+            .layers()
+            .flat_map(|(index, layer)| layer.chunks().map(move |(coords, _)| (index, coords)))
+            .map(|key| (key, ChunkStatus::Resident { dirty: true }))
+            .collect();
+
+        Ok(Self {
+            config,
+            root: root.into(),
+            focus_points: Vec::new(),
+            chunks,
+            reader_id: map.events_mut().register_reader(),
+            shared,
+            worker: Some(worker),
+        })
+    }
+
+    pub fn config(&self) -> &StreamingConfig {
+        &self.config
+    }
+
+    /// Replace the focus points chunks are loaded around. Takes effect on the next update.
+    pub fn set_focus_points(&mut self, points: impl IntoIterator<Item = Point3<f32>>) {
+        self.focus_points.clear();
+        self.focus_points.extend(points);
+    }
+
+    pub fn focus_points(&self) -> &[Point3<f32>] {
+        &self.focus_points
+    }
+
+    /// The state of a chunk, or `None` if it isn't resident or being loaded.
+    pub fn status(&self, layer: i32, coords: ChunkCoords) -> Option<ChunkStatus> {
+        self.chunks.get(&(layer, coords)).copied()
+    }
+
+    /// Whether any chunks are still being loaded.
+    pub fn is_loading(&self) -> bool {
+        self.chunks
+            .values()
+            .any(|status| matches!(status, ChunkStatus::Loading | ChunkStatus::LoadingModified))
+    }
+
+    /// Find modified chunks, insert any chunks which have finished loading into the map, request
+    /// chunks which have come within range of a focus point, and evict (and save, if dirty) chunks
+    /// which have gone out of range of all of them.
+    pub fn update(&mut self, map: &mut TrackedMap<T>) {
+        self.read_events(map);
+        self.insert_loaded(map);
+        self.request_chunks();
+        self.evict_chunks(map);
+
+        // Skip over the events from chunks we just inserted or removed; they aren't modifications.
+        map.events().read(&mut self.reader_id).for_each(|_| ());
+
+        if let Some(worker) = &self.worker {
+            worker.thread().unpark();
+        }
+    }
+
+    /// Save every dirty resident chunk, without evicting anything.
+    pub fn flush(&mut self, map: &TrackedMap<T>) {
+        for (&(layer, coords), status) in &mut self.chunks {
+            if *status != (ChunkStatus::Resident { dirty: true }) {
+                continue;
+            }
+
+            let chunk = map
+                .get_layer(layer)
+                .and_then(|layer| layer.get_chunk(coords));
+            let path = self.root.join(chunk_path(layer, coords));
+            self.shared.requests.push(match chunk {
+                Some(chunk) => Request::Save {
+                    coords,
+                    chunk: chunk.clone(),
+                    path,
+                },
+                None => Request::Delete { path },
+            });
+            *status = ChunkStatus::Resident { dirty: false };
+        }
+
+        if let Some(worker) = &self.worker {
+            worker.thread().unpark();
+        }
+    }
+
+    fn mark_dirty(&mut self, layer: i32, coords: ChunkCoords) {
+        let status = self
+            .chunks
+            .entry((layer, coords))
+            .or_insert(ChunkStatus::Resident { dirty: true });
+        *status = match *status {
+            ChunkStatus::Loading | ChunkStatus::LoadingModified => ChunkStatus::LoadingModified,
+            ChunkStatus::Resident { .. } => ChunkStatus::Resident { dirty: true },
+        };
+    }
+
+    fn read_events(&mut self, map: &TrackedMap<T>) {
+        let mut dirty = Vec::new();
+        for event in map.events().read(&mut self.reader_id) {
+            match event {
+                LatticeEvent::Slot(slot_event) => dirty.push((slot_event.layer, slot_event.chunk)),
+                LatticeEvent::Chunk(chunk_event) => {
+                    dirty.push((chunk_event.layer, chunk_event.chunk))
+                }
+                LatticeEvent::Layer(layer_event) => {
+                    let index = layer_event.layer;
+                    match layer_event.kind {
+                        // Every chunk of an inserted layer is new.
+                        LayerEventKind::Insert => dirty.extend(
+                            map.get_layer(index)
+                                .into_iter()
+                                .flat_map(|layer| layer.chunks().map(|(coords, _)| coords))
+                                .map(|coords| (index, coords)),
+                        ),
+                        // Every chunk of a removed layer needs its file deleted.
+                        LayerEventKind::Remove => dirty.extend(
+                            self.chunks
+                                .keys()
+                                .filter(|&&(layer, _)| layer == index)
+                                .copied(),
+                        ),
+                    }
+                }
+            }
+        }
+
+        for (layer, coords) in dirty {
+            self.mark_dirty(layer, coords);
+        }
+    }
+
+    fn insert_loaded(&mut self, map: &mut TrackedMap<T>) {
+        while let Some(Loaded {
+            layer,
+            coords,
+            result,
+        }) = self.shared.loaded.pop()
+        {
+            let chunk = match result {
+                Ok(chunk) => chunk,
+                Err(err) => {
+                    error!(error = ?err, "error streaming in chunk: {:#}", err);
+                    None
+                }
+            };
+
+            match self.chunks.get(&(layer, coords)) {
+                Some(ChunkStatus::Loading) => {
+                    if let Some(chunk) = chunk {
+                        map.get_or_insert_layer(layer).insert_chunk(coords, chunk);
+                    }
+                    self.chunks
+                        .insert((layer, coords), ChunkStatus::Resident { dirty: false });
+                }
+                // The chunk was modified while it was loading. Fill in whatever wasn't modified
+                // from the loaded chunk, unless the whole chunk has since been removed.
+                Some(ChunkStatus::LoadingModified) => {
+                    if let (Some(chunk), Some(mut layer_mut)) = (chunk, map.get_layer_mut(layer)) {
+                        if let Some(mut tracked) = layer_mut.get_chunk_mut(coords) {
+                            for (sub, &value) in chunk.iter() {
+                                if tracked.get(sub).is_none() {
+                                    tracked.insert(sub, value);
+                                }
+                            }
+                        }
+                    }
+                    self.chunks
+                        .insert((layer, coords), ChunkStatus::Resident { dirty: true });
+                }
+                // Loads are only requested for chunks which aren't known yet, and chunks aren't
+                // forgotten until they've finished loading.
+                Some(ChunkStatus::Resident { .. }) | None => {
+                    unreachable!("loaded a chunk which wasn't loading!")
+                }
+            }
+        }
+    }
+
+    fn request_chunks(&mut self) {
+        let side = CHUNK_SIDE_LENGTH as f32;
+        let radius = self.config.load_radius;
+
+        for point in &self.focus_points {
+            let focus_layer = point.z.floor() as i32;
+            let mins = (point.xy() - Vector2::repeat(radius)).map(|t| (t / side).floor() as i32);
+            let maxs = (point.xy() + Vector2::repeat(radius)).map(|t| (t / side).floor() as i32);
+
+            for layer in
+                focus_layer - self.config.layer_radius..=focus_layer + self.config.layer_radius
+            {
+                for y in mins.y..=maxs.y {
+                    for x in mins.x..=maxs.x {
+                        let coords = ChunkCoords::new(x, y);
+                        if self.chunks.contains_key(&(layer, coords))
+                            || chunk_distance(point, coords) > radius
+                        {
+                            continue;
+                        }
+
+                        self.chunks.insert((layer, coords), ChunkStatus::Loading);
+                        self.shared.requests.push(Request::Load {
+                            layer,
+                            coords,
+                            path: self.root.join(chunk_path(layer, coords)),
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    fn evict_chunks(&mut self, map: &mut TrackedMap<T>) {
+        let config = &self.config;
+        let focus_points = &self.focus_points;
+        let evicted = self
+            .chunks
+            .iter()
+            .filter(|(&(layer, coords), _)| {
+                !focus_points.iter().any(|point| {
+                    (layer - point.z.floor() as i32).abs() <= config.layer_radius
+                        && chunk_distance(point, coords) <= config.unload_radius
+                })
+            })
+            .map(|(&key, &status)| (key, status))
+            .collect::<Vec<_>>();
+
+        for ((layer, coords), status) in evicted {
+            // Wait for loading chunks to land before evicting them; otherwise a chunk modified
+            // while loading would be saved w/o the values it was loading.
+            if let ChunkStatus::Loading | ChunkStatus::LoadingModified = status {
+                continue;
+            }
+
+            self.chunks.remove(&(layer, coords));
+            let chunk = map.remove_chunk(layer, coords);
+
+            if status == (ChunkStatus::Resident { dirty: true }) {
+                let path = self.root.join(chunk_path(layer, coords));
+                self.shared.requests.push(match chunk {
+                    Some(chunk) => Request::Save {
+                        coords,
+                        chunk,
+                        path,
+                    },
+                    None => Request::Delete { path },
+                });
+            }
+        }
+    }
+}
+
+impl<T: Copy + PartialEq + Send + Sync + 'static> Drop for ChunkStreamer<T> {
+    fn drop(&mut self) {
+        // Let the background thread finish any outstanding saves before it exits.
+        self.shared.shutdown.store(true, Ordering::Release);
+        if let Some(worker) = self.worker.take() {
+            worker.thread().unpark();
+            if worker.join().is_err() {
+                error!("chunk streaming thread panicked");
+            }
+        }
+    }
+}
+
+// The path of a chunk's file, relative to the root directory of a streamer.
+fn chunk_path(layer: i32, coords: ChunkCoords) -> PathBuf {
+    Path::new(&layer.to_string()).join(format!("{}_{}.chunk", coords.x, coords.y))
+}
+
+// The horizontal distance from a point to the nearest point of a chunk.
+fn chunk_distance(point: &Point3<f32>, coords: ChunkCoords) -> f32 {
+    let side = CHUNK_SIDE_LENGTH as f32;
+    let mins = coords.cast::<f32>() * side;
+    let maxs = mins.add_scalar(side);
+    let dx = (mins.x - point.x).max(point.x - maxs.x).max(0.);
+    let dy = (mins.y - point.y).max(point.y - maxs.y).max(0.);
+    (dx * dx + dy * dy).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use crate::lattice::chunk_map::DividedCoords;
+
+    const ROOT: &str = "/chunks";
+
+    fn memory_fs() -> Arc<Mutex<Filesystem>> {
+        let mut fs = Filesystem::new();
+        fs.mount_memory();
+        Arc::new(Mutex::new(fs))
+    }
+
+    fn config() -> StreamingConfig {
+        StreamingConfig {
+            load_radius: 8.,
+            unload_radius: 24.,
+            layer_radius: 0,
+            compression: Compression::default(),
+        }
+    }
+
+    fn chunk_file(layer: i32, coords: ChunkCoords) -> PathBuf {
+        Path::new(ROOT).join(chunk_path(layer, coords))
+    }
+
+    /// The values `fill_chunk` puts in a chunk, w/ every third slot left empty.
+    fn expected_values(coords: ChunkCoords) -> Vec<Option<u32>> {
+        coords
+            .world_coords()
+            .enumerate()
+            .map(|(i, xy)| (i % 3 != 0).then(|| (xy.x * 1000 + xy.y) as u32))
+            .collect()
+    }
+
+    fn fill_chunk(map: &mut TrackedMap<u32>, layer: i32, coords: ChunkCoords) {
+        for (xy, value) in coords.world_coords().zip(expected_values(coords)) {
+            if let Some(value) = value {
+                map.insert(xy.push(layer), value);
+            }
+        }
+    }
+
+    fn chunk_values(map: &TrackedMap<u32>, layer: i32, coords: ChunkCoords) -> Vec<Option<u32>> {
+        coords
+            .world_coords()
+            .map(|xy| map.get(xy.push(layer)).copied())
+            .collect()
+    }
+
+    fn file_values(fs: &Mutex<Filesystem>, layer: i32, coords: ChunkCoords) -> Vec<Option<u32>> {
+        let file = fs.lock().unwrap().open(chunk_file(layer, coords)).unwrap();
+        let (read_coords, chunk): (_, Chunk<u32>) = read_chunk(BufReader::new(file)).unwrap();
+        assert_eq!(read_coords, coords);
+        coords
+            .world_coords()
+            .map(|xy| {
+                chunk
+                    .get(DividedCoords::from_world_coords(xy).sub_coords)
+                    .copied()
+            })
+            .collect()
+    }
+
+    /// Update the streamer until `done` returns true, giving up after a while.
+    fn update_until(
+        streamer: &mut ChunkStreamer<u32>,
+        map: &mut TrackedMap<u32>,
+        mut done: impl FnMut(&ChunkStreamer<u32>, &TrackedMap<u32>) -> bool,
+    ) {
+        for _ in 0..1000 {
+            streamer.update(map);
+            if done(streamer, map) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        panic!("timed out waiting on the chunk streamer");
+    }
+
+    fn finished_loading(streamer: &ChunkStreamer<u32>, _: &TrackedMap<u32>) -> bool {
+        !streamer.is_loading()
+    }
+
+    #[test]
+    fn evicted_chunks_reload_unchanged() {
+        let fs = memory_fs();
+        let near = ChunkCoords::new(0, 0);
+        let far = ChunkCoords::new(5, -2);
+        let mut map = TrackedMap::new();
+        fill_chunk(&mut map, 0, near);
+        fill_chunk(&mut map, 0, far);
+
+        let mut streamer = ChunkStreamer::new(&mut map, fs.clone(), ROOT, config()).unwrap();
+
+        // Only the far chunk is beyond the unload radius, so only it is evicted.
+        streamer.set_focus_points([Point3::new(8., 8., 0.5)]);
+        streamer.update(&mut map);
+        assert!(map.get_layer(0).unwrap().get_chunk(far).is_none());
+        assert_eq!(streamer.status(0, far), None);
+        assert_eq!(
+            streamer.status(0, near),
+            Some(ChunkStatus::Resident { dirty: true })
+        );
+
+        // Go and get it back, which evicts the near chunk in turn.
+        streamer.set_focus_points([Point3::new(88., -24., 0.5)]);
+        update_until(&mut streamer, &mut map, finished_loading);
+        assert!(map.get_layer(0).unwrap().get_chunk(near).is_none());
+        assert_eq!(chunk_values(&map, 0, far), expected_values(far));
+        assert_eq!(
+            streamer.status(0, far),
+            Some(ChunkStatus::Resident { dirty: false })
+        );
+
+        streamer.set_focus_points([Point3::new(8., 8., 0.5)]);
+        update_until(&mut streamer, &mut map, finished_loading);
+        assert_eq!(chunk_values(&map, 0, near), expected_values(near));
+        drop(streamer);
+
+        // Chunks which were loaded empty and never modified aren't saved.
+        assert!(!fs
+            .lock()
+            .unwrap()
+            .exists(chunk_file(0, ChunkCoords::new(6, -2))));
+    }
+
+    #[test]
+    fn edits_while_loading_are_merged() {
+        let fs = memory_fs();
+        let coords = ChunkCoords::new(0, 0);
+        let mut map = TrackedMap::new();
+        fill_chunk(&mut map, 0, coords);
+
+        // Get the chunk into the filesystem, and out of the map.
+        {
+            let mut streamer = ChunkStreamer::new(&mut map, fs.clone(), ROOT, config()).unwrap();
+            streamer.set_focus_points([Point3::new(1000., 1000., 0.5)]);
+            streamer.update(&mut map);
+        }
+        assert!(map.get_layer(0).unwrap().get_chunk(coords).is_none());
+
+        let mut streamer = ChunkStreamer::new(&mut map, fs, ROOT, config()).unwrap();
+        streamer.set_focus_points([Point3::new(8., 8., 0.5)]);
+        streamer.update(&mut map);
+        assert_eq!(streamer.status(0, coords), Some(ChunkStatus::Loading));
+
+        // Overwrite a slot which was saved, and fill one which was empty.
+        let saved = Vector3::new(1, 0, 0);
+        let empty = Vector3::new(0, 0, 0);
+        let untouched = Vector3::new(2, 0, 0);
+        map.insert(saved, 1);
+        map.insert(empty, 2);
+
+        update_until(&mut streamer, &mut map, |_, map| {
+            map.get(untouched).is_some()
+        });
+        assert_eq!(map.get(saved), Some(&1));
+        assert_eq!(map.get(empty), Some(&2));
+        assert_eq!(map.get(untouched), Some(&2000));
+        assert_eq!(
+            streamer.status(0, coords),
+            Some(ChunkStatus::Resident { dirty: true })
+        );
+    }
+
+    #[test]
+    fn removing_a_layer_deletes_its_chunks() {
+        let fs = memory_fs();
+        let coords = ChunkCoords::new(0, 0);
+        let mut map = TrackedMap::new();
+        fill_chunk(&mut map, 0, coords);
+        fill_chunk(&mut map, 1, coords);
+
+        let mut config = config();
+        config.layer_radius = 1;
+        let focus = Point3::new(8., 8., 0.5);
+
+        {
+            let mut streamer = ChunkStreamer::new(&mut map, fs.clone(), ROOT, config).unwrap();
+            streamer.set_focus_points([focus]);
+            streamer.update(&mut map);
+            streamer.flush(&map);
+        }
+        assert!(fs.lock().unwrap().is_file(chunk_file(0, coords)));
+        assert!(fs.lock().unwrap().is_file(chunk_file(1, coords)));
+
+        let mut streamer = ChunkStreamer::new(&mut map, fs.clone(), ROOT, config).unwrap();
+        streamer.set_focus_points([focus]);
+        update_until(&mut streamer, &mut map, finished_loading);
+
+        map.remove_layer(1);
+        streamer.update(&mut map);
+        assert_eq!(
+            streamer.status(1, coords),
+            Some(ChunkStatus::Resident { dirty: true })
+        );
+        streamer.flush(&map);
+        drop(streamer);
+
+        assert!(fs.lock().unwrap().is_file(chunk_file(0, coords)));
+        assert!(!fs.lock().unwrap().exists(chunk_file(1, coords)));
+    }
+
+    #[test]
+    fn dropping_the_streamer_finishes_queued_saves() {
+        let fs = memory_fs();
+        let mut map = TrackedMap::new();
+        let chunks = (-2..2)
+            .flat_map(|y| (-2..2).map(move |x| ChunkCoords::new(x, y)))
+            .collect::<Vec<_>>();
+        for &coords in &chunks {
+            fill_chunk(&mut map, 0, coords);
+        }
+
+        // Evict everything, and drop the streamer before its thread has a chance to catch up.
+        let mut streamer = ChunkStreamer::new(&mut map, fs.clone(), ROOT, config()).unwrap();
+        streamer.set_focus_points([Point3::new(1000., 1000., 0.5)]);
+        streamer.update(&mut map);
+        drop(streamer);
+
+        for coords in chunks {
+            assert!(map.get_layer(0).unwrap().get_chunk(coords).is_none());
+            assert_eq!(file_values(&fs, 0, coords), expected_values(coords));
+        }
+    }
+
+    #[test]
+    fn chunks_modified_while_loading_are_not_evicted_until_merged() {
+        let fs = memory_fs();
+        let coords = ChunkCoords::new(0, 0);
+        let mut map = TrackedMap::new();
+        fill_chunk(&mut map, 0, coords);
+
+        {
+            let mut streamer = ChunkStreamer::new(&mut map, fs.clone(), ROOT, config()).unwrap();
+            streamer.set_focus_points([Point3::new(1000., 1000., 0.5)]);
+            streamer.update(&mut map);
+        }
+
+        // Hold the filesystem, so that the load can't land until we let it.
+        let guard = fs.lock().unwrap();
+        let mut streamer = ChunkStreamer::new(&mut map, fs.clone(), ROOT, config()).unwrap();
+        streamer.set_focus_points([Point3::new(8., 8., 0.5)]);
+        streamer.update(&mut map);
+        assert_eq!(streamer.status(0, coords), Some(ChunkStatus::Loading));
+
+        // Modify the chunk and move away from it before it's loaded.
+        let edited = Vector3::new(1, 0, 0);
+        map.insert(edited, 1);
+        streamer.set_focus_points([Point3::new(1000., 1000., 0.5)]);
+        streamer.update(&mut map);
+        assert_eq!(
+            streamer.status(0, coords),
+            Some(ChunkStatus::LoadingModified)
+        );
+        assert_eq!(map.get(edited), Some(&1));
+        drop(guard);
+
+        update_until(&mut streamer, &mut map, |streamer, _| {
+            streamer.status(0, coords).is_none()
+        });
+        assert!(map.get_layer(0).unwrap().get_chunk(coords).is_none());
+        drop(streamer);
+
+        let mut expected = expected_values(coords);
+        expected[1] = Some(1);
+        assert_eq!(file_values(&fs, 0, coords), expected);
+    }
+}
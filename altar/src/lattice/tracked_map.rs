@@ -10,16 +10,16 @@ use crate::lattice::{
     ChunkCoords, SubCoords,
 };
 
-pub struct TrackedChunk<'a, T: Copy + Send + Sync + 'static> {
+pub struct TrackedChunk<'a, T: Copy + PartialEq + Send + Sync + 'static> {
     layer: i32,
     chunk: ChunkCoords,
     chunk_mut: &'a mut Chunk<T>,
     channel: &'a mut EventChannel<LatticeEvent<T>>,
 }
 
-impl<'a, T: Copy + Send + Sync + 'static> TrackedChunk<'a, T> {
+impl<'a, T: Copy + PartialEq + Send + Sync + 'static> TrackedChunk<'a, T> {
     pub fn insert(&mut self, sub: SubCoords, value: T) -> Option<T> {
-        let prev = self.chunk_mut.insert(sub, value);
+        let prev = self.chunk_mut.insert_dedup(sub, value);
         self.channel.single_write(LatticeEvent::Slot(SlotEvent {
             layer: self.layer,
             chunk: self.chunk,
@@ -34,7 +34,7 @@ impl<'a, T: Copy + Send + Sync + 'static> TrackedChunk<'a, T> {
     }
 
     pub fn remove(&mut self, sub: SubCoords) -> Option<T> {
-        let removed = self.chunk_mut.remove(sub);
+        let removed = self.chunk_mut.remove_compact(sub);
 
         if let Some(prev) = removed {
             self.channel.single_write(LatticeEvent::Slot(SlotEvent {
@@ -57,13 +57,13 @@ impl<'a, T: Copy + Send + Sync + 'static> TrackedChunk<'a, T> {
     }
 }
 
-pub struct TrackedLayer<'a, T: Copy + Send + Sync + 'static> {
+pub struct TrackedLayer<'a, T: Copy + PartialEq + Send + Sync + 'static> {
     layer: i32,
     layer_mut: &'a mut ChunkLayer<T>,
     channel: &'a mut EventChannel<LatticeEvent<T>>,
 }
 
-impl<'a, T: Copy + Send + Sync + 'static> TrackedLayer<'a, T> {
+impl<'a, T: Copy + PartialEq + Send + Sync + 'static> TrackedLayer<'a, T> {
     pub fn get_chunk(&self, coords: ChunkCoords) -> Option<&Chunk<T>> {
         self.layer_mut.get_chunk(coords)
     }
@@ -139,18 +139,18 @@ impl<'a, T: Copy + Send + Sync + 'static> TrackedLayer<'a, T> {
 }
 
 #[derive(Debug)]
-pub struct TrackedMap<T: Copy + Send + Sync + 'static> {
+pub struct TrackedMap<T: Copy + PartialEq + Send + Sync + 'static> {
     map: ChunkMap<T>,
     channel: EventChannel<LatticeEvent<T>>,
 }
 
-impl<T: Copy + Send + Sync + 'static> Default for TrackedMap<T> {
+impl<T: Copy + PartialEq + Send + Sync + 'static> Default for TrackedMap<T> {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl<T: Copy + Send + Sync + 'static> TrackedMap<T> {
+impl<T: Copy + PartialEq + Send + Sync + 'static> TrackedMap<T> {
     pub fn new() -> Self {
         Self {
             map: ChunkMap::new(),
@@ -158,6 +158,14 @@ impl<T: Copy + Send + Sync + 'static> TrackedMap<T> {
         }
     }
 
+    /// Start tracking changes to an existing map.
+    pub fn from_chunk_map(map: ChunkMap<T>) -> Self {
+        Self {
+            map,
+            channel: EventChannel::new(),
+        }
+    }
+
     pub fn get_layer(&self, index: i32) -> Option<&ChunkLayer<T>> {
         self.map.get_layer(index)
     }
@@ -185,6 +193,10 @@ impl<T: Copy + Send + Sync + 'static> TrackedMap<T> {
             .insert(coords.xy(), value)
     }
 
+    pub fn remove(&mut self, coords: Vector3<i32>) -> Option<T> {
+        self.get_layer_mut(coords.z)?.remove(coords.xy())
+    }
+
     pub fn insert_chunk(
         &mut self,
         layer: i32,
@@ -242,3 +254,50 @@ impl<T: Copy + Send + Sync + 'static> TrackedMap<T> {
         &mut self.channel
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lattice::chunk_map::ChunkStorageKind;
+
+    fn storage_kind(map: &TrackedMap<u32>) -> ChunkStorageKind {
+        let layer = map.get_layer(0).unwrap();
+        layer
+            .get_chunk(ChunkCoords::new(0, 0))
+            .unwrap()
+            .storage_kind()
+    }
+
+    #[test]
+    fn edits_keep_chunks_compact() {
+        let mut map = TrackedMap::new();
+        for x in 0..16 {
+            for y in 0..16 {
+                map.insert(Vector3::new(x, y, 0), 1u32);
+            }
+        }
+        assert_eq!(storage_kind(&map), ChunkStorageKind::Uniform);
+        map.insert(Vector3::new(0, 0, 0), 2);
+        assert_eq!(storage_kind(&map), ChunkStorageKind::Palette);
+
+        // Mutable access through the underlying map decompresses the chunk; removing slots through
+        // the tracked map compacts it again once enough of them are gone.
+        for (_, value) in map.as_chunk_map_mut().iter_mut() {
+            *value += 1;
+        }
+        assert_eq!(storage_kind(&map), ChunkStorageKind::Dense);
+        map.remove(Vector3::new(0, 0, 0));
+        for x in 0..16 {
+            for y in 1..8 {
+                map.remove(Vector3::new(x, y, 0));
+            }
+        }
+        assert_eq!(storage_kind(&map), ChunkStorageKind::Dense);
+        for x in 0..16 {
+            map.remove(Vector3::new(x, 8, 0));
+        }
+        assert_eq!(map.as_chunk_map().iter().count(), 127);
+        assert_eq!(storage_kind(&map), ChunkStorageKind::Uniform);
+        assert_eq!(map.as_chunk_map().heap_size(), 0);
+    }
+}
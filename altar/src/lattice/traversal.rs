@@ -0,0 +1,566 @@
+//! Ray traversal over the cells of chunk maps and layers.
+//!
+//! [`ChunkMap::traverse`], [`ChunkLayer::traverse`], and [`TrackedMap::traverse`] walk every cell a
+//! ray passes through, in order, using the Amanatides-Woo "fast voxel traversal" algorithm, and
+//! yield the occupied ones as [`TraversalHit`]s. Missing layers and chunks are skipped over in a
+//! single step rather than cell by cell, so long rays through sparse maps stay cheap. Once a ray
+//! has to skip over missing space, its length is clamped to where it leaves the bounds of the
+//! map's chunks, so rays w/ an infinite `max_toi` still end once they've left the map behind.
+//!
+//! Distances are in units of the ray's direction vector, like parry's time of impact; if the
+//! direction is normalized, they're distances in cells.
+
+use hv::prelude::*;
+use parry3d::query::Ray;
+use soft_edge::Axis;
+
+use crate::lattice::{
+    chunk_map::{ChunkLayer, ChunkMap, DividedCoords, CHUNK_SIDE_LENGTH},
+    tracked_map::TrackedMap,
+};
+
+/// An occupied cell found along a ray.
+#[derive(Debug)]
+pub struct TraversalHit<'a, T, C> {
+    /// The coordinates of the cell.
+    pub coords: C,
+    /// The value in the cell.
+    pub value: &'a T,
+    /// The distance along the ray at which it enters the cell. Zero if the ray starts inside it.
+    pub entry: f32,
+    /// The distance along the ray at which it leaves the cell.
+    pub exit: f32,
+    /// The face of the cell the ray entered through, or `None` if the ray starts inside it. For
+    /// layers, this is always on the X or Y axis.
+    pub face: Option<Axis>,
+}
+
+impl<'a, T, C: Clone> Clone for TraversalHit<'a, T, C> {
+    fn clone(&self) -> Self {
+        Self {
+            coords: self.coords.clone(),
+            value: self.value,
+            entry: self.entry,
+            exit: self.exit,
+            face: self.face,
+        }
+    }
+}
+
+// The state of an Amanatides-Woo traversal through an N-dimensional grid of unit cells.
+#[derive(Debug, Clone, Copy)]
+struct Dda<const N: usize> {
+    cell: [i32; N],
+    step: [i32; N],
+    // The distance at which the ray crosses the next cell boundary on each axis.
+    t_max: [f32; N],
+    // The distance between cell boundaries on each axis.
+    t_delta: [f32; N],
+    // The distance at which the ray entered the current cell.
+    t: f32,
+    max_toi: f32,
+    // The axis crossed to enter the current cell.
+    entered: Option<usize>,
+    // Set if the ray will never enter another cell worth visiting.
+    done: bool,
+}
+
+impl<const N: usize> Dda<N> {
+    fn new(origin: [f32; N], dir: [f32; N], max_toi: f32) -> Self {
+        let mut dda = Self {
+            cell: [0; N],
+            step: [0; N],
+            t_max: [f32::INFINITY; N],
+            t_delta: [f32::INFINITY; N],
+            t: 0.,
+            max_toi,
+            entered: None,
+            done: false,
+        };
+
+        for i in 0..N {
+            let cell = origin[i].floor();
+            dda.cell[i] = cell as i32;
+            if dir[i] > 0. {
+                dda.step[i] = 1;
+                dda.t_max[i] = (cell + 1. - origin[i]) / dir[i];
+                dda.t_delta[i] = 1. / dir[i];
+            } else if dir[i] < 0. {
+                dda.step[i] = -1;
+                dda.t_max[i] = (cell - origin[i]) / dir[i];
+                dda.t_delta[i] = -1. / dir[i];
+            }
+        }
+
+        dda
+    }
+
+    fn is_done(&self) -> bool {
+        self.done || self.t > self.max_toi
+    }
+
+    fn exit(&self) -> f32 {
+        self.t_max.iter().copied().fold(self.max_toi, f32::min)
+    }
+
+    fn entered_axis(&self) -> Option<(usize, i32)> {
+        self.entered.map(|i| (i, self.step[i]))
+    }
+
+    fn closest_axis(t: &[f32; N]) -> usize {
+        (1..N).fold(0, |best, i| if t[i] < t[best] { i } else { best })
+    }
+
+    // Move some number of cells along an axis, ending the traversal instead if that would take
+    // the ray out of the range of `i32` cell coordinates.
+    fn advance(&mut self, axis: usize, n: i32) {
+        match self.cell[axis].checked_add(self.step[axis] * n) {
+            Some(cell) => self.cell[axis] = cell,
+            None => self.done = true,
+        }
+        self.t_max[axis] += n as f32 * self.t_delta[axis];
+    }
+
+    // Move into the next cell along the ray.
+    fn step(&mut self) {
+        let axis = Self::closest_axis(&self.t_max);
+        self.t = self.t_max[axis];
+        self.advance(axis, 1);
+        self.entered = Some(axis);
+        // A zero direction never leaves the first cell.
+        self.done |= !self.t.is_finite();
+    }
+
+    // Shorten the ray to end where it leaves a box of cells, given as inclusive/exclusive bounds
+    // on each axis.
+    fn clamp(&mut self, bounds: [(i32, i32); N]) {
+        for (i, (min, max)) in bounds.into_iter().enumerate() {
+            let cell = i64::from(self.cell[i]);
+            // The number of boundaries the ray crosses on this axis before it leaves the box.
+            let crossings = match self.step[i] {
+                0 => {
+                    self.done |= cell < i64::from(min) || cell >= i64::from(max);
+                    continue;
+                }
+                1 => i64::from(max) - cell,
+                _ => cell - i64::from(min) + 1,
+            };
+
+            if crossings <= 0 {
+                // Already outside the box, and heading away from it.
+                self.done = true;
+            } else {
+                let t_exit = self.t_max[i] + (crossings - 1) as f32 * self.t_delta[i];
+                self.max_toi = self.max_toi.min(t_exit);
+            }
+        }
+    }
+
+    // Move into the first cell along the ray outside of a box containing the current cell. The box
+    // is given as inclusive/exclusive cell bounds on each axis, or `None` if unbounded.
+    fn leave(&mut self, bounds: [Option<(i32, i32)>; N]) {
+        let mut crossings = [i32::MAX; N];
+        let mut t_exit = [f32::INFINITY; N];
+        for i in 0..N {
+            if let (Some((min, max)), step) = (bounds[i], self.step[i]) {
+                if step != 0 {
+                    let n = if step > 0 {
+                        max.checked_sub(self.cell[i])
+                    } else {
+                        self.cell[i].checked_sub(min).and_then(|n| n.checked_add(1))
+                    };
+                    crossings[i] = match n {
+                        Some(n) => n,
+                        None => {
+                            self.done = true;
+                            return;
+                        }
+                    };
+                    t_exit[i] = self.t_max[i] + (crossings[i] - 1) as f32 * self.t_delta[i];
+                }
+            }
+        }
+
+        let axis = Self::closest_axis(&t_exit);
+        let t = t_exit[axis];
+        if !t.is_finite() {
+            // The ray never leaves the box.
+            self.done = true;
+            return;
+        }
+
+        for i in (0..N).filter(|&i| i != axis && self.step[i] != 0) {
+            let n = if self.t_max[i] >= t {
+                0
+            } else {
+                let n = ((t - self.t_max[i]) / self.t_delta[i]).floor() as i32 + 1;
+                n.min(crossings[i] - 1)
+            };
+            self.advance(i, n);
+        }
+
+        self.advance(axis, crossings[axis]);
+        self.t = t;
+        self.entered = Some(axis);
+    }
+}
+
+// The face a cell was entered through, given the axis crossed and the direction of the step.
+fn entered_face(coords: Vector3<i32>, entered: Option<(usize, i32)>) -> Option<Axis> {
+    let (axis, step) = entered?;
+    let mut prev = coords;
+    prev[axis] -= step;
+    Axis::from_adjacent_coords(&Point3::from(coords), &Point3::from(prev))
+}
+
+fn chunk_bounds(min: i32) -> Option<(i32, i32)> {
+    Some((min, min + CHUNK_SIDE_LENGTH as i32))
+}
+
+// The inclusive/exclusive cell bounds of every chunk in a layer, or `None` if it has no chunks.
+fn layer_bounds<T>(layer: &ChunkLayer<T>) -> Option<[(i32, i32); 2]> {
+    let side = CHUNK_SIDE_LENGTH as i32;
+    layer.chunks().fold(None, |bounds, (coords, _)| {
+        let [(x0, x1), (y0, y1)] = bounds.unwrap_or([(i32::MAX, i32::MIN); 2]);
+        let (x, y) = (coords.x * side, coords.y * side);
+        Some([
+            (x0.min(x), x1.max(x.saturating_add(side))),
+            (y0.min(y), y1.max(y.saturating_add(side))),
+        ])
+    })
+}
+
+// The inclusive/exclusive cell bounds of every chunk in a map, or `None` if it has no chunks.
+fn map_bounds<T>(map: &ChunkMap<T>) -> Option<[(i32, i32); 3]> {
+    map.layers().fold(None, |bounds, (z, layer)| {
+        let [x, y] = match layer_bounds(layer) {
+            Some(layer_bounds) => layer_bounds,
+            None => return bounds,
+        };
+        let [(x0, x1), (y0, y1), (z0, z1)] = match bounds {
+            Some(bounds) => bounds,
+            None => return Some([x, y, (z, z + 1)]),
+        };
+        Some([
+            (x0.min(x.0), x1.max(x.1)),
+            (y0.min(y.0), y1.max(y.1)),
+            (z0.min(z), z1.max(z + 1)),
+        ])
+    })
+}
+
+/// An iterator over the occupied cells of a [`ChunkMap`] along a ray, created by
+/// [`ChunkMap::traverse`].
+#[derive(Debug, Clone)]
+pub struct MapTraversal<'a, T> {
+    map: &'a ChunkMap<T>,
+    dda: Dda<3>,
+    clamped: bool,
+}
+
+impl<'a, T> MapTraversal<'a, T> {
+    // Clamp the ray to the bounds of the map, the first time it has to skip over missing space.
+    fn clamp_to_map(&mut self) {
+        if !self.clamped {
+            self.clamped = true;
+            match map_bounds(self.map) {
+                Some(bounds) => self.dda.clamp(bounds),
+                None => self.dda.done = true,
+            }
+        }
+    }
+}
+
+impl<'a, T> Iterator for MapTraversal<'a, T> {
+    type Item = TraversalHit<'a, T, Vector3<i32>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.dda.is_done() {
+            let coords = Vector3::from(self.dda.cell);
+            let z = Some((coords.z, coords.z + 1));
+            let layer = match self.map.get_layer(coords.z) {
+                Some(layer) => layer,
+                None => {
+                    self.clamp_to_map();
+                    self.dda.leave([None, None, z]);
+                    continue;
+                }
+            };
+
+            let divided = DividedCoords::from_world_coords(coords.xy());
+            let chunk = match layer.get_chunk(divided.chunk_coords) {
+                Some(chunk) => chunk,
+                None => {
+                    self.clamp_to_map();
+                    let min = *divided.chunk_coords * CHUNK_SIDE_LENGTH as i32;
+                    self.dda
+                        .leave([chunk_bounds(min.x), chunk_bounds(min.y), z]);
+                    continue;
+                }
+            };
+
+            let hit = chunk.get(divided.sub_coords).map(|value| TraversalHit {
+                coords,
+                value,
+                entry: self.dda.t,
+                exit: self.dda.exit(),
+                face: entered_face(coords, self.dda.entered_axis()),
+            });
+            self.dda.step();
+
+            if hit.is_some() {
+                return hit;
+            }
+        }
+
+        None
+    }
+}
+
+/// An iterator over the occupied cells of a [`ChunkLayer`] along a 2D ray, created by
+/// [`ChunkLayer::traverse`].
+#[derive(Debug, Clone)]
+pub struct LayerTraversal<'a, T> {
+    layer: &'a ChunkLayer<T>,
+    dda: Dda<2>,
+    clamped: bool,
+}
+
+impl<'a, T> LayerTraversal<'a, T> {
+    // Clamp the ray to the bounds of the layer, the first time it has to skip over missing space.
+    fn clamp_to_layer(&mut self) {
+        if !self.clamped {
+            self.clamped = true;
+            match layer_bounds(self.layer) {
+                Some(bounds) => self.dda.clamp(bounds),
+                None => self.dda.done = true,
+            }
+        }
+    }
+}
+
+impl<'a, T> Iterator for LayerTraversal<'a, T> {
+    type Item = TraversalHit<'a, T, Vector2<i32>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.dda.is_done() {
+            let coords = Vector2::from(self.dda.cell);
+            let divided = DividedCoords::from_world_coords(coords);
+            let chunk = match self.layer.get_chunk(divided.chunk_coords) {
+                Some(chunk) => chunk,
+                None => {
+                    self.clamp_to_layer();
+                    let min = *divided.chunk_coords * CHUNK_SIDE_LENGTH as i32;
+                    self.dda.leave([chunk_bounds(min.x), chunk_bounds(min.y)]);
+                    continue;
+                }
+            };
+
+            let hit = chunk.get(divided.sub_coords).map(|value| TraversalHit {
+                coords,
+                value,
+                entry: self.dda.t,
+                exit: self.dda.exit(),
+                face: entered_face(coords.push(0), self.dda.entered_axis()),
+            });
+            self.dda.step();
+
+            if hit.is_some() {
+                return hit;
+            }
+        }
+
+        None
+    }
+}
+
+impl<T> ChunkMap<T> {
+    /// Iterate over the occupied cells a ray passes through, in order, up to `max_toi` along it.
+    pub fn traverse(&self, ray: &Ray, max_toi: f32) -> MapTraversal<T> {
+        MapTraversal {
+            map: self,
+            dda: Dda::new(ray.origin.coords.into(), ray.dir.into(), max_toi),
+            clamped: false,
+        }
+    }
+
+    /// Find the first occupied cell along a ray.
+    pub fn cast_ray(&self, ray: &Ray, max_toi: f32) -> Option<TraversalHit<T, Vector3<i32>>> {
+        self.traverse(ray, max_toi).next()
+    }
+}
+
+impl<T> ChunkLayer<T> {
+    /// Iterate over the occupied cells a 2D ray passes through, in order, up to `max_toi` along it.
+    pub fn traverse(
+        &self,
+        origin: &Point2<f32>,
+        dir: &Vector2<f32>,
+        max_toi: f32,
+    ) -> LayerTraversal<T> {
+        LayerTraversal {
+            layer: self,
+            dda: Dda::new(origin.coords.into(), (*dir).into(), max_toi),
+            clamped: false,
+        }
+    }
+
+    /// Find the first occupied cell along a 2D ray.
+    pub fn cast_ray(
+        &self,
+        origin: &Point2<f32>,
+        dir: &Vector2<f32>,
+        max_toi: f32,
+    ) -> Option<TraversalHit<T, Vector2<i32>>> {
+        self.traverse(origin, dir, max_toi).next()
+    }
+}
+
+impl<T: Copy + PartialEq + Send + Sync + 'static> TrackedMap<T> {
+    /// Iterate over the occupied cells a ray passes through, in order, up to `max_toi` along it.
+    pub fn traverse(&self, ray: &Ray, max_toi: f32) -> MapTraversal<T> {
+        self.as_chunk_map().traverse(ray, max_toi)
+    }
+
+    /// Find the first occupied cell along a ray.
+    pub fn cast_ray(&self, ray: &Ray, max_toi: f32) -> Option<TraversalHit<T, Vector3<i32>>> {
+        self.as_chunk_map().cast_ray(ray, max_toi)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Walk every cell w/o skipping, for comparison.
+    fn naive_cells(ray: &Ray, max_toi: f32) -> Vec<[i32; 3]> {
+        let mut dda = Dda::new(ray.origin.coords.into(), ray.dir.into(), max_toi);
+        let mut cells = Vec::new();
+        while !dda.is_done() {
+            cells.push(dda.cell);
+            dda.step();
+        }
+        cells
+    }
+
+    #[test]
+    fn axis_aligned_ray() {
+        let mut map = ChunkMap::new();
+        map.insert(Vector3::new(5, 0, 0), 'a');
+        map.insert(Vector3::new(40, 0, 0), 'b');
+        map.insert(Vector3::new(-3, 0, 0), 'c');
+
+        let ray = Ray::new(Point3::new(0.5, 0.5, 0.5), Vector3::x());
+        let hits = map.traverse(&ray, 100.).collect::<Vec<_>>();
+        assert_eq!(hits.len(), 2);
+        assert_eq!(*hits[0].value, 'a');
+        assert!((hits[0].entry - 4.5).abs() < 1e-5);
+        assert!((hits[0].exit - 5.5).abs() < 1e-5);
+        assert_eq!(hits[0].face, Some(Axis::PosX.opposite()));
+        assert_eq!(*hits[1].value, 'b');
+        assert_eq!(hits[1].coords, Vector3::new(40, 0, 0));
+
+        assert!(map.cast_ray(&ray, 4.).is_none());
+    }
+
+    #[test]
+    fn starting_inside_a_cell() {
+        let mut map = ChunkMap::new();
+        map.insert(Vector3::new(0, 0, 0), ());
+        let ray = Ray::new(
+            Point3::new(0.5, 0.5, 0.5),
+            Vector3::new(1., 2., 3.).normalize(),
+        );
+        let hit = map.cast_ray(&ray, 10.).unwrap();
+        assert_eq!(hit.entry, 0.);
+        assert!(hit.face.is_none());
+    }
+
+    #[test]
+    fn skipping_matches_naive_traversal() {
+        let ray = Ray::new(
+            Point3::new(-20.3, 7.9, -4.2),
+            Vector3::new(3.1, -1.7, 0.9).normalize(),
+        );
+        let cells = naive_cells(&ray, 80.);
+
+        // Occupy every fifth cell along the ray, so most of the chunks and layers it passes
+        // through are missing.
+        let mut map = ChunkMap::new();
+        let occupied = cells.iter().step_by(5).copied().collect::<Vec<_>>();
+        for &cell in &occupied {
+            map.insert(Vector3::from(cell), cell);
+        }
+
+        let hits = map
+            .traverse(&ray, 80.)
+            .map(|hit| *hit.value)
+            .collect::<Vec<_>>();
+        assert_eq!(hits, occupied);
+    }
+
+    #[test]
+    fn layer_traversal() {
+        let mut layer = ChunkLayer::new();
+        layer.insert(Vector2::new(-17, -17), 1);
+        layer.insert(Vector2::new(-33, -33), 2);
+
+        let hits = layer
+            .traverse(&Point2::new(0.5, 0.5), &Vector2::new(-1., -1.), 100.)
+            .map(|hit| *hit.value)
+            .collect::<Vec<_>>();
+        assert_eq!(hits, [1, 2]);
+    }
+
+    #[test]
+    fn infinite_rays_end_once_they_leave_the_map() {
+        let mut map = ChunkMap::new();
+        map.insert(Vector3::new(5, 0, 0), 'a');
+        map.insert(Vector3::new(40, 3, 2), 'b');
+
+        let away = Ray::new(Point3::new(0.5, 0.5, 0.5), -Vector3::x());
+        assert_eq!(map.traverse(&away, f32::INFINITY).count(), 0);
+        let up = Ray::new(
+            Point3::new(0.5, 0.5, 0.5),
+            Vector3::new(0.1, 0.2, 1.).normalize(),
+        );
+        assert_eq!(map.traverse(&up, f32::INFINITY).count(), 0);
+
+        let through = Ray::new(Point3::new(0.5, 0.5, 0.5), Vector3::x());
+        let hits = map.traverse(&through, f32::INFINITY).collect::<Vec<_>>();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(*hits[0].value, 'a');
+
+        let layer = map.get_layer(0).unwrap();
+        let hits = layer
+            .traverse(
+                &Point2::new(0.5, 0.5),
+                &Vector2::new(-1., -0.5),
+                f32::INFINITY,
+            )
+            .count();
+        assert_eq!(hits, 0);
+        assert_eq!(
+            ChunkMap::<()>::new()
+                .traverse(&through, f32::INFINITY)
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn traversal_ends_at_the_edge_of_the_coordinate_space() {
+        let mut dda = Dda::new([0.5], [1.], f32::INFINITY);
+        dda.cell = [i32::MAX - 1];
+        dda.step();
+        assert_eq!(dda.cell, [i32::MAX]);
+        assert!(!dda.is_done());
+        dda.step();
+        assert!(dda.is_done());
+
+        let mut dda = Dda::new([0.5, 0.5], [-1., 0.], f32::INFINITY);
+        dda.cell = [i32::MIN + 3, 0];
+        dda.leave([Some((i32::MIN, i32::MIN + 16)), None]);
+        assert!(dda.is_done());
+    }
+}
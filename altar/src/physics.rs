@@ -1,30 +1,47 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
+    collections::{BTreeMap, HashMap, HashSet},
     ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Sub, SubAssign},
 };
 
 use hv::{
     ecs::{ColumnMut, Entity, PreparedQuery, QueryMarker, Satisfies, SystemContext, With, Without},
-    elastic::{ElasticMut, ElasticRef},
     prelude::*,
-    resources::Resources,
 };
 use parry3d::{
     bounding_volume::{BoundingVolume, AABB},
+    mass_properties::MassProperties,
     partitioning::QBVH,
     query::TOI,
     shape::SharedShape,
 };
+use serde::{Deserialize, Serialize};
 use shrev::{EventChannel, ReaderId};
 use soft_edge::SortedPair;
-use thunderdome::{Arena, Index};
+use tracing::warn;
 
 use crate::{
+    api::with_loaned,
+    command_buffer::CommandPoolResource,
     lattice::atom_map::AtomMap,
     types::{Float, UpdateDt, UpdateTick},
 };
 
-#[derive(Debug, Clone, Copy)]
+mod broad_phase;
+pub mod character;
+pub mod debug;
+pub mod hooks;
+mod island;
+pub mod joint;
+pub mod query;
+pub mod snapshot;
+
+use broad_phase::BroadPhase;
+use hooks::{ContactHooks, ContactModification};
+use island::Islands;
+use joint::{Joint, JointId};
+use query::QueryCollider;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct CompositePosition3 {
     /// Translational component.
     pub translation: Vector3<Float>,
@@ -81,7 +98,7 @@ impl CompositePosition3 {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct CompositeVelocity3 {
     pub linear: Vector3<Float>,
     pub angular: Float,
@@ -187,7 +204,43 @@ impl Collider {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Collision group membership and filtering.
+///
+/// Two bodies interact (generate contacts, perform CCD against each other, and trigger sensors)
+/// only if each one is a member of at least one group accepted by the other's filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CollisionGroups {
+    /// Bitmask of the groups this body is a member of.
+    pub memberships: u32,
+    /// Bitmask of the groups this body is allowed to interact with.
+    pub filter: u32,
+}
+
+impl Default for CollisionGroups {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl CollisionGroups {
+    /// A member of every group, which interacts with every group.
+    pub const ALL: Self = Self::new(u32::MAX, u32::MAX);
+    /// A member of no groups, which interacts with nothing.
+    pub const NONE: Self = Self::new(0, 0);
+
+    pub const fn new(memberships: u32, filter: u32) -> Self {
+        Self {
+            memberships,
+            filter,
+        }
+    }
+
+    pub fn interacts_with(self, other: Self) -> bool {
+        self.memberships & other.filter != 0 && other.memberships & self.filter != 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct PhysicsConfig {
     /// Allowed overlap between objects. Default value is `0.01`.
     pub position_slop: f32,
@@ -213,6 +266,18 @@ pub struct PhysicsConfig {
     ///
     /// Default: `0.1`.
     pub continuous_collision_toi_bias: f32,
+    /// Collision groups of the static lattice, used to filter contacts and CCD between bodies and
+    /// an [`AtomMap`]. Default value is [`CollisionGroups::ALL`].
+    pub lattice_groups: CollisionGroups,
+    /// Bodies moving slower than this are considered to be at rest, and may fall asleep. Default
+    /// value is `0.1`.
+    pub sleep_linear_threshold: f32,
+    /// Bodies rotating slower than this (in radians per second) are considered to be at rest, and
+    /// may fall asleep. Default value is `0.1`.
+    pub sleep_angular_threshold: f32,
+    /// How long, in seconds, every body in an island has to be at rest before the whole island is
+    /// put to sleep. Set to infinity to disable sleeping entirely. Default value is `0.5`.
+    pub time_to_sleep: f32,
 }
 
 impl Default for PhysicsConfig {
@@ -225,16 +290,149 @@ impl Default for PhysicsConfig {
             gravity: Vector3::zeros(),
             continuous_collision_velocity_threshold: 20.0,
             continuous_collision_toi_bias: 0.1,
+            lattice_groups: CollisionGroups::ALL,
+            sleep_linear_threshold: 0.1,
+            sleep_angular_threshold: 0.1,
+            time_to_sleep: 0.5,
         }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct ContactIdEntry {
     pub flipped: bool,
     pub id: ContactId,
 }
 
+/// Friction and restitution of a surface.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Material {
+    pub restitution: f32,
+    pub static_friction: f32,
+    pub dynamic_friction: f32,
+}
+
+impl Material {
+    pub fn new(restitution: f32, static_friction: f32, dynamic_friction: f32) -> Self {
+        Self {
+            restitution,
+            static_friction,
+            dynamic_friction,
+        }
+    }
+
+    /// The material of a contact between two surfaces, or between a surface and the static
+    /// lattice if `other` is `None`.
+    pub fn combine(&self, other: Option<&Self>) -> Self {
+        match other {
+            Some(other) => Self {
+                restitution: self.restitution.min(other.restitution),
+                static_friction: self.static_friction.hypot(other.static_friction),
+                dynamic_friction: self.dynamic_friction.hypot(other.dynamic_friction),
+            },
+            None => *self,
+        }
+    }
+}
+
+/// One of the shapes making up the collider of a [`Physics`] body.
+#[derive(Clone)]
+pub struct ColliderPart {
+    /// Transform of the part relative to the rest of the collider.
+    pub local_tx: Isometry3<f32>,
+    pub shape: SharedShape,
+    /// If `None`, the part uses the friction and restitution of its body.
+    pub material: Option<Material>,
+    /// Sensor parts never collide and don't contribute to the mass of their body. Instead, they
+    /// emit [`PhysicsEvent::TriggerEnter`] and [`PhysicsEvent::TriggerExit`] events like a body
+    /// w/ a [`SensorMarker`] does.
+    pub sensor: bool,
+}
+
+impl ColliderPart {
+    pub fn new(local_tx: Isometry3<f32>, shape: SharedShape) -> Self {
+        Self {
+            local_tx,
+            shape,
+            material: None,
+            sensor: false,
+        }
+    }
+
+    pub fn with_material(self, material: Material) -> Self {
+        Self {
+            material: Some(material),
+            ..self
+        }
+    }
+
+    pub fn with_sensor(self, sensor: bool) -> Self {
+        Self { sensor, ..self }
+    }
+}
+
+impl From<SharedShape> for ColliderPart {
+    fn from(shape: SharedShape) -> Self {
+        Self::new(Isometry3::identity(), shape)
+    }
+}
+
+/// The parts making up the collider of a [`Physics`] body. Can be made from a single shape, a
+/// single [`ColliderPart`], or any number of parts.
+#[derive(Clone)]
+pub struct ColliderParts(pub Vec<ColliderPart>);
+
+impl ColliderParts {
+    /// Check that a [`Physics`] body can be made from these parts: there has to be at least one
+    /// part, and if the solid parts get combined into a compound shape, none of them can be a
+    /// composite shape themselves.
+    pub fn validate(&self) -> Result<()> {
+        ensure!(!self.0.is_empty(), "a collider must have at least one part");
+
+        let mut solid_parts = self.0.iter().filter(|part| !part.sensor);
+        let is_compound = match (solid_parts.next(), solid_parts.next()) {
+            (Some(part), None) => part.local_tx != Isometry3::identity(),
+            (Some(_), Some(_)) => true,
+            (None, _) => false,
+        };
+        ensure!(
+            !is_compound
+                || self
+                    .0
+                    .iter()
+                    .filter(|part| !part.sensor)
+                    .all(|part| part.shape.as_composite_shape().is_none()),
+            "a composite shape can't be one of several parts of a collider"
+        );
+
+        Ok(())
+    }
+}
+
+impl From<SharedShape> for ColliderParts {
+    fn from(shape: SharedShape) -> Self {
+        Self(vec![shape.into()])
+    }
+}
+
+impl From<ColliderPart> for ColliderParts {
+    fn from(part: ColliderPart) -> Self {
+        Self(vec![part])
+    }
+}
+
+impl From<Vec<ColliderPart>> for ColliderParts {
+    fn from(parts: Vec<ColliderPart>) -> Self {
+        Self(parts)
+    }
+}
+
+impl FromIterator<ColliderPart> for ColliderParts {
+    fn from_iter<I: IntoIterator<Item = ColliderPart>>(iter: I) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
 /// Physics information and solver state.
 ///
 /// Contains mass data as well as intermediate states used by the solver.
@@ -254,30 +452,79 @@ pub struct Physics {
     static_friction: f32,
     dynamic_friction: f32,
     gravity_k: f32,
+    linear_damping: f32,
+    angular_damping: f32,
+    groups: CollisionGroups,
+
+    // Forces and torque accumulated since the last update, cleared once they've been applied.
+    force: Vector3<f32>,
+    torque: f32,
+
+    sleeping: bool,
+    sleep_timer: f32,
 
     collider_tx: Isometry3<f32>,
-    collider_shape: SharedShape,
+    parts: Vec<ColliderPart>,
+    // Every non-sensor part combined into one shape, for the broad phase, CCD and scene queries.
+    // `None` if every part is a sensor.
+    collider_shape: Option<SharedShape>,
 
     contacts: Vec<ContactIdEntry>,
 }
 
 impl Physics {
-    pub fn new(collider_shape: SharedShape) -> Self {
-        Self::with_local_tx(collider_shape, Isometry3::identity())
+    pub fn new(collider: impl Into<ColliderParts>) -> Self {
+        Self::with_local_tx(collider, Isometry3::identity())
     }
 
-    pub fn with_local_tx(collider_shape: SharedShape, collider_tx: Isometry3<f32>) -> Self {
+    /// Create a body w/ a collider made of one or more parts, all offset by `collider_tx`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the parts fail [`ColliderParts::validate`]. Parts coming from Lua are validated
+    /// before they get here.
+    pub fn with_local_tx(collider: impl Into<ColliderParts>, collider_tx: Isometry3<f32>) -> Self {
+        let collider = collider.into();
+        if let Err(err) = collider.validate() {
+            panic!("invalid collider: {}", err);
+        }
+        let ColliderParts(parts) = collider;
+
+        let mut solid_parts = parts.iter().filter(|part| !part.sensor).peekable();
+        let collider_shape = match solid_parts.next() {
+            None => None,
+            Some(part)
+                if solid_parts.peek().is_none() && part.local_tx == Isometry3::identity() =>
+            {
+                Some(part.shape.clone())
+            }
+            Some(part) => Some(SharedShape::compound(
+                std::iter::once(part)
+                    .chain(solid_parts)
+                    .map(|part| (part.local_tx, part.shape.clone()))
+                    .collect(),
+            )),
+        };
+
         Self {
             position: CompositePosition3::origin(),
             target: CompositePosition3::origin(),
             velocity: CompositeVelocity3::zero(),
-            mass_data: MassData { inv_mass: 0. },
+            mass_data: MassData::INFINITE,
             restitution: 0.,
             static_friction: 0.,
             dynamic_friction: 0.,
             gravity_k: 1.,
-            collider_shape,
+            linear_damping: 0.,
+            angular_damping: 0.,
+            groups: CollisionGroups::ALL,
+            force: Vector3::zeros(),
+            torque: 0.,
+            sleeping: false,
+            sleep_timer: 0.,
             collider_tx,
+            parts,
+            collider_shape,
             contacts: Vec::new(),
         }
         .with_density(1.)
@@ -285,11 +532,9 @@ impl Physics {
 
     pub fn with_density(self, density: f32) -> Self {
         let mass_data = if density == 0. {
-            MassData { inv_mass: 0. }
+            MassData::INFINITE
         } else {
-            MassData {
-                inv_mass: self.collider_shape.mass_properties(density).inv_mass,
-            }
+            MassData::from_mass_properties(&self.mass_properties(density))
         };
 
         Self { mass_data, ..self }
@@ -297,16 +542,34 @@ impl Physics {
 
     pub fn with_mass(self, mass: f32) -> Self {
         let mass_data = if mass == 0. {
-            MassData { inv_mass: 0. }
+            MassData::INFINITE
         } else {
+            // Scale the unit density mass properties so that the body has the requested mass,
+            // keeping the same distribution of mass.
+            let unit = MassData::from_mass_properties(&self.mass_properties(1.));
+            let k = unit.inv_mass * mass;
             MassData {
                 inv_mass: mass.recip(),
+                inv_inertia: if k > 0. { unit.inv_inertia / k } else { 0. },
             }
         };
 
         Self { mass_data, ..self }
     }
 
+    /// Mass properties of every non-sensor part of the collider put together.
+    fn mass_properties(&self, density: f32) -> MassProperties {
+        self.parts
+            .iter()
+            .filter(|part| !part.sensor)
+            .map(|part| {
+                part.shape
+                    .mass_properties(density)
+                    .transform_by(&part.local_tx)
+            })
+            .sum()
+    }
+
     pub fn with_friction(self, friction: f32) -> Self {
         Self {
             static_friction: friction,
@@ -336,6 +599,176 @@ impl Physics {
         }
     }
 
+    pub fn with_collision_groups(self, groups: CollisionGroups) -> Self {
+        Self { groups, ..self }
+    }
+
+    /// Scale the effect of gravity on this body. Zero disables gravity for it, and negative values
+    /// make it fall upwards.
+    pub fn with_gravity_scale(self, gravity_scale: f32) -> Self {
+        Self {
+            gravity_k: gravity_scale,
+            ..self
+        }
+    }
+
+    /// Damp the linear velocity of this body. Each update, its linear velocity is scaled by
+    /// `1 / (1 + dt * linear_damping)`.
+    pub fn with_linear_damping(self, linear_damping: f32) -> Self {
+        Self {
+            linear_damping,
+            ..self
+        }
+    }
+
+    /// Damp the angular velocity of this body. Each update, its angular velocity is scaled by
+    /// `1 / (1 + dt * angular_damping)`.
+    pub fn with_angular_damping(self, angular_damping: f32) -> Self {
+        Self {
+            angular_damping,
+            ..self
+        }
+    }
+
+    pub fn parts(&self) -> &[ColliderPart] {
+        &self.parts
+    }
+
+    /// The material of the body, used by any of its parts which don't have their own.
+    pub fn material(&self) -> Material {
+        Material::new(
+            self.restitution,
+            self.static_friction,
+            self.dynamic_friction,
+        )
+    }
+
+    fn part_material(&self, part: &ColliderPart) -> Material {
+        part.material.unwrap_or_else(|| self.material())
+    }
+
+    pub fn collision_groups(&self) -> CollisionGroups {
+        self.groups
+    }
+
+    pub fn gravity_scale(&self) -> f32 {
+        self.gravity_k
+    }
+
+    pub fn set_gravity_scale(&mut self, gravity_scale: f32) {
+        self.gravity_k = gravity_scale;
+    }
+
+    pub fn linear_damping(&self) -> f32 {
+        self.linear_damping
+    }
+
+    pub fn set_linear_damping(&mut self, linear_damping: f32) {
+        self.linear_damping = linear_damping;
+    }
+
+    pub fn angular_damping(&self) -> f32 {
+        self.angular_damping
+    }
+
+    pub fn set_angular_damping(&mut self, angular_damping: f32) {
+        self.angular_damping = angular_damping;
+    }
+
+    /// The force accumulated since the last update.
+    pub fn force(&self) -> Vector3<f32> {
+        self.force
+    }
+
+    /// The torque about the Z axis accumulated since the last update.
+    pub fn torque(&self) -> f32 {
+        self.torque
+    }
+
+    /// Apply a force at the body's position until the end of the next update, waking it up.
+    pub fn apply_force(&mut self, force: Vector3<f32>) {
+        self.force += force;
+        self.wake_up();
+    }
+
+    /// Apply a force at a point in world space until the end of the next update, waking the body
+    /// up. Forces off the body's position also produce a torque about the Z axis. The body's
+    /// position is taken to be its position as of the last update.
+    pub fn apply_force_at_point(&mut self, force: Vector3<f32>, point: Point3<f32>) {
+        self.force += force;
+        self.torque += self.perp_dot_from_position(&force, &point);
+        self.wake_up();
+    }
+
+    /// Apply a torque about the Z axis until the end of the next update, waking the body up.
+    pub fn apply_torque(&mut self, torque: f32) {
+        self.torque += torque;
+        self.wake_up();
+    }
+
+    /// Apply an impulse at the body's position, immediately changing its velocity and waking it
+    /// up.
+    pub fn apply_impulse(&mut self, velocity: &mut Velocity, impulse: Vector3<f32>) {
+        velocity.composite.linear += impulse * self.mass_data.inv_mass;
+        self.wake_up();
+    }
+
+    /// Apply an impulse at a point in world space, immediately changing the body's velocity and
+    /// waking it up. Impulses off the body's position also produce an angular impulse about the Z
+    /// axis. The body's position is taken to be its position as of the last update.
+    pub fn apply_impulse_at_point(
+        &mut self,
+        velocity: &mut Velocity,
+        impulse: Vector3<f32>,
+        point: Point3<f32>,
+    ) {
+        let angular_impulse = self.perp_dot_from_position(&impulse, &point);
+        self.apply_impulse(velocity, impulse);
+        self.apply_angular_impulse(velocity, angular_impulse);
+    }
+
+    /// Apply an angular impulse about the Z axis, immediately changing the body's angular velocity
+    /// and waking it up.
+    pub fn apply_angular_impulse(&mut self, velocity: &mut Velocity, impulse: f32) {
+        velocity.composite.angular += impulse * self.mass_data.inv_inertia;
+        self.wake_up();
+    }
+
+    /// Clear any forces and torque accumulated since the last update.
+    pub fn clear_forces(&mut self) {
+        self.force = Vector3::zeros();
+        self.torque = 0.;
+    }
+
+    /// The Z component of `(point - position) x v`, for turning forces and impulses applied at a
+    /// point into torques and angular impulses.
+    fn perp_dot_from_position(&self, v: &Vector3<f32>, point: &Point3<f32>) -> f32 {
+        let r = point.coords - self.position.translation;
+        r.x * v.y - r.y * v.x
+    }
+
+    pub fn set_collision_groups(&mut self, groups: CollisionGroups) {
+        self.groups = groups;
+    }
+
+    /// Whether this body is asleep. Sleeping bodies are skipped by the solver until they're touched
+    /// by an awake body, or their position or velocity is written to.
+    pub fn is_sleeping(&self) -> bool {
+        self.sleeping
+    }
+
+    /// Wake this body up and reset its sleep timer. Any sleeping bodies it's touching will be woken
+    /// during the next update.
+    pub fn wake_up(&mut self) {
+        self.sleeping = false;
+        self.sleep_timer = 0.;
+    }
+
+    fn is_above_sleep_threshold(&self, config: &PhysicsConfig) -> bool {
+        self.velocity.linear.norm_squared() > config.sleep_linear_threshold.powi(2)
+            || self.velocity.angular.abs() > config.sleep_angular_threshold
+    }
+
     fn remove_contact(&mut self, contact_id: ContactId) {
         let i = self
             .contacts
@@ -346,10 +779,28 @@ impl Physics {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct MassData {
     /// 1/M. If zero, the body has infinite mass.
     pub inv_mass: f32,
+    /// 1/I, where I is the moment of inertia about the Z axis. If zero, the body can't be rotated
+    /// by the solver. Only joints, torques and angular impulses currently rotate bodies.
+    pub inv_inertia: f32,
+}
+
+impl MassData {
+    pub const INFINITE: Self = Self {
+        inv_mass: 0.,
+        inv_inertia: 0.,
+    };
+
+    pub fn from_mass_properties(props: &MassProperties) -> Self {
+        let inertia = props.reconstruct_inertia_matrix()[(2, 2)];
+        Self {
+            inv_mass: props.inv_mass,
+            inv_inertia: if inertia > 0. { inertia.recip() } else { 0. },
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -358,23 +809,86 @@ pub struct CcdEnabled;
 #[derive(Debug, Clone, Copy)]
 pub struct KinematicMarker;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Hash)]
+/// Marks a body which is currently asleep.
+///
+/// This marker is inserted and removed by [`update`] (or [`update_scripted`]) through the command
+/// pool to mirror the state of the body's [`Physics`], so that scripts can query for sleeping
+/// bodies. Inserting or removing it by hand does not affect the simulation; use
+/// [`Physics::wake_up`] to wake a body.
+#[derive(Debug, Clone, Copy)]
+pub struct Sleeping;
+
+/// Marks a body as a sensor, or "trigger volume".
+///
+/// Sensors never participate in the contact solver and are ignored by scene queries. Instead, any
+/// overlap between a sensor and a non-sensor body emits a [`PhysicsEvent::TriggerEnter`] when it
+/// begins and a [`PhysicsEvent::TriggerExit`] when it ends. Sensors do not detect the static
+/// lattice.
+#[derive(Debug, Clone, Copy)]
+pub struct SensorMarker;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Hash, Serialize, Deserialize)]
+#[serde(from = "ConstrainedPairRepr", into = "ConstrainedPairRepr")]
 pub enum ConstrainedPair {
-    Dynamic(SortedPair<Entity>),
-    Static(Entity, Vector3<i32>, u32),
+    /// Two bodies, w/ the indices of the collider part of each which is in contact. The first
+    /// part belongs to the first body of the pair.
+    Dynamic(SortedPair<Entity>, [u32; 2]),
+    /// A body and the static lattice: the body, the index of its collider part in contact, the
+    /// coordinates and hull facet of the lattice cell, and the ID of the point within the contact
+    /// manifold between the part and the facet.
+    Static(Entity, u32, Vector3<i32>, u32, u32),
+}
+
+// `SortedPair` isn't serializable, so we go through this instead.
+#[derive(Serialize, Deserialize)]
+enum ConstrainedPairRepr {
+    Dynamic(Entity, Entity, [u32; 2]),
+    Static(Entity, u32, Vector3<i32>, u32, u32),
+}
+
+impl From<ConstrainedPair> for ConstrainedPairRepr {
+    fn from(pair: ConstrainedPair) -> Self {
+        match pair {
+            ConstrainedPair::Dynamic(pair, parts) => Self::Dynamic(pair.0, pair.1, parts),
+            ConstrainedPair::Static(e, part, coords, facet, point) => {
+                Self::Static(e, part, coords, facet, point)
+            }
+        }
+    }
+}
+
+impl From<ConstrainedPairRepr> for ConstrainedPair {
+    fn from(repr: ConstrainedPairRepr) -> Self {
+        match repr {
+            ConstrainedPairRepr::Dynamic(a, b, parts) => {
+                Self::Dynamic(SortedPair::new(a, b), parts)
+            }
+            ConstrainedPairRepr::Static(e, part, coords, facet, point) => {
+                Self::Static(e, part, coords, facet, point)
+            }
+        }
+    }
 }
 
 impl ConstrainedPair {
     pub fn get_participant(self, flipped: bool) -> Entity {
         match self {
-            Self::Dynamic(pair) if !flipped => pair.0,
-            Self::Dynamic(pair) => pair.1,
-            Self::Static(e, _, _) => e,
+            Self::Dynamic(pair, _) if !flipped => pair.0,
+            Self::Dynamic(pair, _) => pair.1,
+            Self::Static(e, ..) => e,
+        }
+    }
+
+    /// The index of the collider part of a participant which is in contact.
+    pub fn get_part(self, flipped: bool) -> u32 {
+        match self {
+            Self::Dynamic(_, parts) => parts[flipped as usize],
+            Self::Static(_, part, ..) => part,
         }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Contact {
     pub normal: UnitVector3<f32>,
     pub tangent1: UnitVector3<f32>,
@@ -412,10 +926,15 @@ impl Contact {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct ContactConstraint {
     pub participants: ConstrainedPair,
     pub contact: Contact,
+    /// The combined material of the collider parts in contact.
+    pub material: Material,
+    /// Target velocity of the second participant's surface relative to the first, as set by a
+    /// contact hook.
+    pub tangent_velocity: Vector3<f32>,
     /// "Lambda" value, used for velocity correction.
     pub normal_impulse: f32,
     pub tangent_impulses: Vector2<f32>,
@@ -425,10 +944,12 @@ pub struct ContactConstraint {
 }
 
 impl ContactConstraint {
-    pub fn new(participants: ConstrainedPair, contact: Contact) -> Self {
+    pub fn new(participants: ConstrainedPair, contact: Contact, material: Material) -> Self {
         Self {
             participants,
             contact,
+            material,
+            tangent_velocity: Vector3::zeros(),
             normal_impulse: 0.,
             tangent_impulses: Vector2::zeros(),
             pseudo_impulse: 0.,
@@ -453,14 +974,15 @@ impl ContactConstraint {
             b.map(|b| b.velocity.linear).unwrap_or_else(Vector3::zeros) - a.velocity.linear;
         let k_n = a.mass_data.inv_mass + b.map(|b| b.mass_data.inv_mass).unwrap_or(0.);
         let v_bias = self.compute_bias_velocity(config, dt);
-        let e = b.map_or(a.restitution, |b| a.restitution.min(b.restitution));
+        let e = self.material.restitution;
 
         (-(1. + e) * delta_v.dot(&self.contact.normal) + v_bias) / k_n
     }
 
     pub fn compute_tangent_impulses(&self, a: &Physics, b: Option<&Physics>) -> Vector2<f32> {
-        let delta_v =
-            b.map(|b| b.velocity.linear).unwrap_or_else(Vector3::zeros) - a.velocity.linear;
+        let delta_v = b.map(|b| b.velocity.linear).unwrap_or_else(Vector3::zeros)
+            + self.tangent_velocity
+            - a.velocity.linear;
         let k_n = a.mass_data.inv_mass + b.map(|b| b.mass_data.inv_mass).unwrap_or(0.);
 
         Vector2::new(
@@ -521,21 +1043,79 @@ impl ContactConstraint {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct ContactId(Index);
+/// Contact IDs are allocated in increasing order, and constraints are solved in order of their IDs,
+/// so that the solver does not depend on anything but the sequence of contacts created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct ContactId(u64);
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Hash)]
 pub enum PhysicsEvent {
     BeginContact(ContactId),
     EndContact(ContactId, ConstrainedPair),
+    /// A body began overlapping a sensor: the sensor's entity, the index of its collider part which
+    /// is being overlapped, and the body's entity.
+    TriggerEnter(Entity, u32, Entity),
+    /// A body stopped overlapping a sensor: the sensor's entity, the index of its collider part
+    /// which was being overlapped, and the body's entity.
+    TriggerExit(Entity, u32, Entity),
+}
+
+/// What became of a contact found during an update, once it went through the contact hooks.
+enum ContactRefresh {
+    /// The contact is new, and its constraint still needs to be added to the contacts of its
+    /// bodies.
+    Created(ContactId),
+    Updated,
+    /// The contact was discarded by a hook. If it had a constraint, the constraint was removed,
+    /// and still needs to be removed from the contacts of its bodies.
+    Discarded(Option<ContactId>),
+}
+
+/// The motion of a CCD-enabled body during the last update.
+#[derive(Debug, Clone, Copy)]
+pub struct CcdSweep {
+    pub entity: Entity,
+    /// The position of the body at the start of the update.
+    pub start: CompositePosition3,
+    /// Where the body would have ended up w/o motion clamping.
+    pub target: CompositePosition3,
+    /// Where the body actually ended up after motion clamping.
+    pub end: CompositePosition3,
 }
 
+/// How far the AABBs stored in the broad phase are loosened, so that bodies moving only slightly
+/// don't need to be reinserted.
+const BROAD_PHASE_MARGIN: Float = 0.1;
+
 pub struct PhysicsPipeline {
-    qbvh: QBVH<u32>,
-    constraints: Arena<ContactConstraint>,
+    broad_phase: BroadPhase,
+    constraints: BTreeMap<ContactId, ContactConstraint>,
+    next_contact_id: u64,
     contacts: HashMap<ConstrainedPair, ContactId>,
+    // Sensor/body overlaps, keyed by `(sensor, sensor part, body)`, w/ the tick they were last
+    // seen.
+    triggers: BTreeMap<(Entity, u32, Entity), u64>,
+    // Swept AABBs of CCD-enabled bodies, rebuilt every update for dynamic-dynamic CCD.
+    ccd_qbvh: QBVH<u32>,
+    ccd_sweeps: Vec<CcdSweep>,
     events: EventChannel<PhysicsEvent>,
 
+    // Colliders as of the end of the last update, for scene queries.
+    query_qbvh: QBVH<u32>,
+    query_colliders: HashMap<u32, QueryCollider>,
+
+    islands: Islands,
+    // The generation of the atom map as of the last update; see `AtomMap::generation`.
+    lattice_generation: u64,
+
+    joints: BTreeMap<JointId, Joint>,
+    next_joint_id: u64,
+    // Pairs of bodies w/ at least one joint between them which disables contacts, w/ the number
+    // of such joints.
+    jointed_pairs: HashMap<SortedPair<Entity>, usize>,
+
+    contact_hooks: ContactHooks,
+
     pub config: PhysicsConfig,
 }
 
@@ -548,11 +1128,23 @@ impl Default for PhysicsPipeline {
 impl PhysicsPipeline {
     pub fn new(config: PhysicsConfig) -> Self {
         Self {
-            qbvh: QBVH::new(),
-            constraints: Arena::new(),
+            broad_phase: BroadPhase::new(BROAD_PHASE_MARGIN),
+            constraints: BTreeMap::new(),
+            next_contact_id: 0,
             contacts: HashMap::new(),
+            triggers: BTreeMap::new(),
+            ccd_qbvh: QBVH::new(),
+            ccd_sweeps: Vec::new(),
             config,
             events: EventChannel::new(),
+            query_qbvh: QBVH::new(),
+            query_colliders: HashMap::new(),
+            islands: Islands::default(),
+            lattice_generation: 0,
+            joints: BTreeMap::new(),
+            next_joint_id: 0,
+            jointed_pairs: HashMap::new(),
+            contact_hooks: ContactHooks::default(),
         }
     }
 
@@ -560,29 +1152,43 @@ impl PhysicsPipeline {
         // Warm start.
         for (_, constraint) in &mut self.constraints {
             let (a, mut b) = match constraint.participants {
-                ConstrainedPair::Dynamic(pair) => {
+                ConstrainedPair::Dynamic(pair, _) => {
                     let ma_ptr = physics.get(pair.0).unwrap() as *mut _;
                     let mb_ptr = physics.get(pair.1).unwrap() as *mut _;
                     unsafe { (&mut *ma_ptr, Some(&mut *mb_ptr)) }
                 }
-                ConstrainedPair::Static(a, _, _) => (physics.get(a).unwrap(), None),
+                ConstrainedPair::Static(a, ..) => (physics.get(a).unwrap(), None),
             };
 
+            if a.sleeping || b.as_deref().map_or(false, |b| b.sleeping) {
+                continue;
+            }
+
             constraint.apply_normal_impulse(constraint.normal_impulse, a, b.as_deref_mut());
             constraint.apply_tangent_impulses(constraint.tangent_impulses, a, b);
         }
 
+        for (_, joint) in &mut self.joints {
+            if let Some((a, b)) = Self::joint_bodies(physics, joint) {
+                joint.warm_start(a, b);
+            }
+        }
+
         for _ in 0..self.config.velocity_iterations {
             for (_, constraint) in &mut self.constraints {
                 let (a, mut b) = match constraint.participants {
-                    ConstrainedPair::Dynamic(pair) => {
+                    ConstrainedPair::Dynamic(pair, _) => {
                         let ma_ptr = physics.get(pair.0).unwrap() as *mut _;
                         let mb_ptr = physics.get(pair.1).unwrap() as *mut _;
                         unsafe { (&mut *ma_ptr, Some(&mut *mb_ptr)) }
                     }
-                    ConstrainedPair::Static(a, _, _) => (physics.get(a).unwrap(), None),
+                    ConstrainedPair::Static(a, ..) => (physics.get(a).unwrap(), None),
                 };
 
+                if a.sleeping || b.as_deref().map_or(false, |b| b.sleeping) {
+                    continue;
+                }
+
                 let corrective =
                     constraint.compute_normal_impulse(a, b.as_deref(), &self.config, dt);
                 let old = constraint.normal_impulse;
@@ -598,21 +1204,22 @@ impl PhysicsPipeline {
                 assert!(!constraint.tangent_impulses.norm_squared().is_nan());
 
                 let jt = constraint.tangent_impulses.norm_squared();
-                let static_mu = b.as_deref().map_or(a.static_friction, |b| {
-                    a.static_friction.hypot(b.static_friction)
-                });
+                let static_mu = constraint.material.static_friction;
                 if jt > 0. && jt >= (constraint.normal_impulse * static_mu).powi(2) {
-                    let dynamic_mu = b.as_deref().map_or(a.dynamic_friction, |b| {
-                        a.dynamic_friction.hypot(b.dynamic_friction)
-                    });
                     constraint.tangent_impulses = constraint.tangent_impulses.normalize()
                         * constraint.normal_impulse
-                        * dynamic_mu;
+                        * constraint.material.dynamic_friction;
                 }
 
                 let delta = constraint.tangent_impulses - old;
                 constraint.apply_tangent_impulses(delta, a, b);
             }
+
+            for (_, joint) in &mut self.joints {
+                if let Some((a, b)) = Self::joint_bodies(physics, joint) {
+                    joint.solve_velocity(a, b, dt);
+                }
+            }
         }
     }
 
@@ -625,14 +1232,18 @@ impl PhysicsPipeline {
         for _ in 0..self.config.position_iterations {
             for (_, constraint) in &mut self.constraints {
                 let (a, b) = match constraint.participants {
-                    ConstrainedPair::Dynamic(pair) => {
+                    ConstrainedPair::Dynamic(pair, _) => {
                         let ma_ptr = physics.get(pair.0).unwrap() as *mut _;
                         let mb_ptr = physics.get(pair.1).unwrap() as *mut _;
                         unsafe { (&mut *ma_ptr, Some(&mut *mb_ptr)) }
                     }
-                    ConstrainedPair::Static(a, _, _) => (physics.get(a).unwrap(), None),
+                    ConstrainedPair::Static(a, ..) => (physics.get(a).unwrap(), None),
                 };
 
+                if a.sleeping || b.as_deref().map_or(false, |b| b.sleeping) {
+                    continue;
+                }
+
                 let corrective =
                     constraint.compute_pseudo_impulse(a, b.as_deref(), &self.config, dt);
                 let old = constraint.pseudo_impulse;
@@ -641,148 +1252,486 @@ impl PhysicsPipeline {
                 let delta = constraint.pseudo_impulse - old;
                 constraint.apply_pseudo_impulse(delta, a, b);
             }
+
+            for (_, joint) in &self.joints {
+                if let Some((a, b)) = Self::joint_bodies(physics, joint) {
+                    joint.solve_position(a, b);
+                }
+            }
         }
     }
 
-    pub fn contact(&self, contact_id: ContactId) -> Option<&ContactConstraint> {
-        self.constraints.get(contact_id.0)
+    /// Get the bodies of a joint for solving, or `None` if they're all asleep. The bodies are
+    /// distinct, since [`PhysicsPipeline::add_joint`] refuses to join a body to itself.
+    fn joint_bodies<'a>(
+        physics: &'a mut ColumnMut<Physics>,
+        joint: &Joint,
+    ) -> Option<(&'a mut Physics, Option<&'a mut Physics>)> {
+        let a = physics.get(joint.body1).unwrap() as *mut Physics;
+        let b = joint
+            .body2
+            .map(|body2| physics.get(body2).unwrap() as *mut Physics);
+        let (a, b) = unsafe { (&mut *a, b.map(|b| &mut *b)) };
+
+        if a.sleeping && b.as_deref().map_or(true, |b| b.sleeping) {
+            None
+        } else {
+            Some((a, b))
+        }
     }
 
-    pub fn register_reader(&mut self) -> ReaderId<PhysicsEvent> {
-        self.events.register_reader()
-    }
+    /// Add a joint, returning its ID. Fails if the joint attaches a body to itself.
+    pub fn add_joint(&mut self, joint: Joint) -> Result<JointId> {
+        ensure!(
+            joint.body2 != Some(joint.body1),
+            "cannot join {:?} to itself",
+            joint.body1
+        );
 
-    pub fn events(&self) -> &EventChannel<PhysicsEvent> {
-        &self.events
+        let joint_id = JointId(self.next_joint_id);
+        self.next_joint_id += 1;
+        self.insert_joint(joint_id, joint);
+        Ok(joint_id)
     }
-}
 
-pub type DynamicBody<Q> = With<Velocity, Without<KinematicMarker, Q>>;
+    fn insert_joint(&mut self, joint_id: JointId, joint: Joint) {
+        if let (Some(body2), false) = (joint.body2, joint.collide_connected) {
+            *self
+                .jointed_pairs
+                .entry(SortedPair::new(joint.body1, body2))
+                .or_default() += 1;
+        }
 
-#[allow(clippy::type_complexity)]
-pub fn update(
-    context: SystemContext,
-    (dt, tick, atom_map, pipeline): (&UpdateDt, &UpdateTick, &AtomMap, &mut PhysicsPipeline),
-    (
-        ref mut all_colliders_query,
-        ref mut dynamic_objects_query,
-        ref mut with_physics_and_dynamic_query,
-        physics_query_marker,
-        ref mut update_target_pos_query,
-        ref mut motion_clamping_query,
-        ref mut all_physics_objects_query,
-    ): &mut (
-        PreparedQuery<&mut Physics>,
-        PreparedQuery<DynamicBody<&mut Physics>>,
-        PreparedQuery<DynamicBody<With<Physics, ()>>>,
-        QueryMarker<&mut Physics>,
-        PreparedQuery<With<Velocity, (&mut Physics, Satisfies<&CcdEnabled>)>>,
-        PreparedQuery<DynamicBody<With<CcdEnabled, With<Physics, ()>>>>,
-        PreparedQuery<(&mut Position, Option<&mut Velocity>, &mut Physics)>,
-    ),
-) {
-    // Copy physics data from the ECS.
-    for (_, (pos, maybe_vel, physics)) in context.prepared_query(all_physics_objects_query).iter() {
-        physics.position = pos.current;
+        self.joints.insert(joint_id, joint);
+    }
 
-        if let Some(vel) = maybe_vel {
-            physics.velocity = vel.composite;
-        } else {
-            physics.velocity = CompositeVelocity3::zero();
+    pub fn remove_joint(&mut self, joint_id: JointId) -> Option<Joint> {
+        let joint = self.joints.remove(&joint_id)?;
+
+        if let (Some(body2), false) = (joint.body2, joint.collide_connected) {
+            let pair = SortedPair::new(joint.body1, body2);
+            let count = self.jointed_pairs.get_mut(&pair).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                self.jointed_pairs.remove(&pair);
+            }
         }
+
+        Some(joint)
     }
 
-    // Rebuild the quadtree.
-    pipeline.qbvh.clear_and_rebuild(
-        context
-            .prepared_query(all_colliders_query)
-            .iter()
-            .map(|(e, physics)| {
-                (
-                    e.id(),
-                    physics
-                        .collider_shape
-                        .compute_aabb(&(physics.collider_tx * physics.position.as_isometry3())),
-                )
-            }),
-        0.01,
-    );
+    pub fn joint(&self, joint_id: JointId) -> Option<&Joint> {
+        self.joints.get(&joint_id)
+    }
 
-    // Detect dynamic-dynamic collisions, collecting constraints.
+    pub fn joints(&self) -> impl Iterator<Item = (JointId, &Joint)> + '_ {
+        self.joints.iter().map(|(&id, joint)| (id, joint))
+    }
+
+    /// Wake every sleeping body reachable from `seeds` through contacts which are current as of
+    /// this tick. Sleeping bodies haven't moved, so the contacts of any body woken here are still
+    /// valid and are kept alive for this tick.
+    fn wake_touching(
+        &mut self,
+        physics: &mut ColumnMut<Physics>,
+        mut seeds: Vec<Entity>,
+        tick: &UpdateTick,
+    ) {
+        let mut jointed = HashMap::<Entity, Vec<Entity>>::new();
+        for (_, joint) in &self.joints {
+            if let Some(body2) = joint.body2 {
+                jointed.entry(joint.body1).or_default().push(body2);
+                jointed.entry(body2).or_default().push(joint.body1);
+            }
+        }
+
+        let mut neighbors = Vec::new();
+        while let Some(e) = seeds.pop() {
+            for entry in &physics.get(e).unwrap().contacts {
+                let constraint = &self.constraints[&entry.id];
+                if let ConstrainedPair::Dynamic(pair, _) = constraint.participants {
+                    if constraint.timestamp == tick.0 {
+                        neighbors.push(if pair.0 == e { pair.1 } else { pair.0 });
+                    }
+                }
+            }
+            neighbors.extend(jointed.get(&e).into_iter().flatten().copied());
+
+            for other_e in neighbors.drain(..) {
+                let other = physics.get(other_e).unwrap();
+                if other.sleeping {
+                    other.wake_up();
+                    for entry in &other.contacts {
+                        self.constraints.get_mut(&entry.id).unwrap().timestamp = tick.0;
+                    }
+                    seeds.push(other_e);
+                }
+            }
+        }
+    }
+
+    /// Refresh the constraint between a pair of colliders found to be in contact this tick,
+    /// creating it if it doesn't exist yet, after running it through the contact hooks.
+    fn refresh_contact(
+        &mut self,
+        pair: ConstrainedPair,
+        contact: Contact,
+        material: Material,
+        lua: Option<&Lua>,
+        tick: &UpdateTick,
+    ) -> ContactRefresh {
+        let existing = self.contacts.get(&pair).copied();
+        let mut modification =
+            ContactModification::new(pair, contact, material, existing.is_none());
+        self.contact_hooks.run(&mut modification, lua);
+
+        if modification.is_discarded() {
+            if let Some(contact_id) = existing {
+                self.contacts.remove(&pair);
+                self.constraints.remove(&contact_id);
+                self.events
+                    .single_write(PhysicsEvent::EndContact(contact_id, pair));
+            }
+
+            return ContactRefresh::Discarded(existing);
+        }
+
+        let (contact_id, refresh) = match existing {
+            Some(contact_id) => (contact_id, ContactRefresh::Updated),
+            None => {
+                let contact_id = ContactId(self.next_contact_id);
+                self.next_contact_id += 1;
+                self.constraints
+                    .insert(contact_id, ContactConstraint::new(pair, contact, material));
+                self.contacts.insert(pair, contact_id);
+                self.events
+                    .single_write(PhysicsEvent::BeginContact(contact_id));
+                (contact_id, ContactRefresh::Created(contact_id))
+            }
+        };
+
+        let constraint = self.constraints.get_mut(&contact_id).unwrap();
+        constraint.contact = contact;
+        constraint.material = modification.material;
+        constraint.tangent_velocity = modification.tangent_velocity;
+        constraint.timestamp = tick.0;
+
+        refresh
+    }
+
+    pub fn contact(&self, contact_id: ContactId) -> Option<&ContactConstraint> {
+        self.constraints.get(&contact_id)
+    }
+
+    /// Every contact constraint, in the order they're solved.
+    pub fn contacts(&self) -> impl Iterator<Item = (ContactId, &ContactConstraint)> + '_ {
+        self.constraints
+            .iter()
+            .map(|(&id, constraint)| (id, constraint))
+    }
+
+    /// The motion of every CCD-enabled body w/ a solid collider during the last update.
+    pub fn ccd_sweeps(&self) -> &[CcdSweep] {
+        &self.ccd_sweeps
+    }
+
+    pub fn register_reader(&mut self) -> ReaderId<PhysicsEvent> {
+        self.events.register_reader()
+    }
+
+    pub fn events(&self) -> &EventChannel<PhysicsEvent> {
+        &self.events
+    }
+}
+
+pub type DynamicBody<Q> = With<Velocity, Without<KinematicMarker, Without<SensorMarker, Q>>>;
+
+pub type UpdateQueries = (
+    PreparedQuery<Without<SensorMarker, &'static mut Physics>>,
+    PreparedQuery<DynamicBody<&'static mut Physics>>,
+    PreparedQuery<DynamicBody<With<Physics, ()>>>,
+    QueryMarker<&'static mut Physics>,
+    PreparedQuery<With<Velocity, (&'static mut Physics, Satisfies<&'static CcdEnabled>)>>,
+    PreparedQuery<DynamicBody<With<CcdEnabled, With<Physics, ()>>>>,
+    PreparedQuery<(
+        &'static mut Position,
+        Option<&'static mut Velocity>,
+        &'static mut Physics,
+    )>,
+    PreparedQuery<(&'static Physics, Satisfies<&'static SensorMarker>)>,
+    PreparedQuery<(&'static Physics, Satisfies<&'static Sleeping>)>,
+);
+
+/// Step the physics simulation. Lua contact hooks aren't run; see [`update_scripted`].
+///
+/// [`Sleeping`] markers are inserted and removed through the command pool, so they're only up to
+/// date once it has been flushed.
+pub fn update(
+    context: SystemContext,
+    resources: (
+        &UpdateDt,
+        &UpdateTick,
+        &AtomMap,
+        &mut PhysicsPipeline,
+        &CommandPoolResource,
+    ),
+    queries: &mut UpdateQueries,
+) {
+    step(context, resources, None, queries);
+}
+
+/// Step the physics simulation, running the Lua contact hook (if any) in the given Lua state. Since
+/// the Lua state can't be shared between threads, this has to be run as a local system.
+pub fn update_scripted(
+    context: SystemContext,
+    resources: (
+        &UpdateDt,
+        &UpdateTick,
+        &AtomMap,
+        &mut PhysicsPipeline,
+        &CommandPoolResource,
+    ),
+    lua: &Lua,
+    queries: &mut UpdateQueries,
+) {
+    step(context, resources, Some(lua), queries);
+}
+
+fn step(
+    context: SystemContext,
+    (dt, tick, atom_map, pipeline, command_pool): (
+        &UpdateDt,
+        &UpdateTick,
+        &AtomMap,
+        &mut PhysicsPipeline,
+        &CommandPoolResource,
+    ),
+    lua: Option<&Lua>,
+    (
+        ref mut all_colliders_query,
+        ref mut dynamic_objects_query,
+        ref mut with_physics_and_dynamic_query,
+        physics_query_marker,
+        ref mut update_target_pos_query,
+        ref mut motion_clamping_query,
+        ref mut all_physics_objects_query,
+        ref mut sensors_query,
+        ref mut sleeping_markers_query,
+    ): &mut UpdateQueries,
+) {
+    // Copy physics data from the ECS, waking any bodies whose position or velocity has been written
+    // to since the last update. Anything written to or moving will also wake the sleeping bodies
+    // it's touching, once we know what it's touching.
+    let mut wake_seeds = Vec::new();
+    for (e, (pos, maybe_vel, physics)) in context.prepared_query(all_physics_objects_query).iter() {
+        let vel = maybe_vel.map_or_else(CompositeVelocity3::zero, |vel| vel.composite);
+        let written = pos.current.translation != physics.position.translation
+            || pos.current.rotation != physics.position.rotation
+            || vel.linear != physics.velocity.linear
+            || vel.angular != physics.velocity.angular;
+
+        physics.position = pos.current;
+        physics.velocity = vel;
+
+        if written {
+            physics.wake_up();
+        }
+
+        if !physics.sleeping && (written || physics.is_above_sleep_threshold(&pipeline.config)) {
+            wake_seeds.push(e);
+        }
+    }
+
+    // Wake any sleeping bodies around the parts of the lattice which changed since the last
+    // update, so that they don't stay asleep in mid-air if the ground under them is removed. A
+    // body resting on the lattice may be up to a contact's prediction distance above it, so the
+    // changed chunks are loosened by a whole cell.
+    let changed_chunks = atom_map
+        .chunks_changed_since(pipeline.lattice_generation)
+        .map(|aabb| aabb.loosened(1.))
+        .collect::<Vec<_>>();
+    pipeline.lattice_generation = atom_map.generation();
+    if !changed_chunks.is_empty() {
+        for (_, physics) in context.prepared_query(all_colliders_query).iter() {
+            let shape = match &physics.collider_shape {
+                Some(shape) if physics.sleeping => shape,
+                _ => continue,
+            };
+
+            let aabb = shape.compute_aabb(&(physics.collider_tx * physics.position.as_isometry3()));
+            if changed_chunks.iter().any(|chunk| chunk.intersects(&aabb)) {
+                physics.wake_up();
+            }
+        }
+    }
+
+    // Bring the broad phase up to date. Colliders are only reinserted if they've left their
+    // fattened AABBs; sleeping bodies can't have moved at all, so they're skipped entirely.
+    pipeline.broad_phase.begin_update();
+    for (e, physics) in context.prepared_query(all_colliders_query).iter() {
+        let shape = match &physics.collider_shape {
+            Some(shape) => shape,
+            None => continue,
+        };
+
+        if physics.sleeping && pipeline.broad_phase.touch(e.id()) {
+            continue;
+        }
+
+        let aabb = shape.compute_aabb(&(physics.collider_tx * physics.position.as_isometry3()));
+        pipeline.broad_phase.update(e.id(), aabb);
+    }
+    pipeline.broad_phase.end_update();
+
+    // Detect dynamic-dynamic collisions, collecting constraints.
     {
         let physics_column_mut = context.column_mut(*physics_query_marker);
         let mut out = Vec::new();
+        // Dynamic bodies which have already had their pairs visited. Each pair of dynamic bodies
+        // is seen from both sides, but should only be handled once, so that contact hooks see each
+        // contact once per update.
+        let mut visited = HashSet::new();
         for (e1, ()) in context
             .prepared_query(with_physics_and_dynamic_query)
             .iter()
         {
+            visited.insert(e1);
             let p1 = unsafe { physics_column_mut.get_unchecked(e1).unwrap() };
             let pos1 = p1.collider_tx * p1.position.as_isometry3();
-            let aabb = p1.collider_shape.compute_aabb(&pos1);
-            pipeline.qbvh.intersect_aabb(&aabb, &mut out);
+            let aabb = match &p1.collider_shape {
+                Some(shape) => shape.compute_aabb(&pos1),
+                None => continue,
+            };
+            pipeline.broad_phase.intersect_aabb(&aabb, &mut out);
+            // The order of the candidates depends on the history of the broad phase; sort them so
+            // that contact IDs are allocated in the same order no matter how the tree looks.
+            out.sort_unstable();
 
             for id in out.drain(..) {
                 if id == e1.id() {
                     continue;
                 }
 
-                let (e2, p2) = unsafe {
-                    let e2 = context.find_entity_from_id(id);
-                    let p2 = physics_column_mut.get_unchecked(e2).unwrap();
-                    (e2, p2)
-                };
+                let e2 = unsafe { context.find_entity_from_id(id) };
+                if visited.contains(&e2) {
+                    continue;
+                }
 
-                let pos2 = p2.collider_tx * p2.position.as_isometry3();
-                let s1 = p1.collider_shape.as_ref();
-                let s2 = p2.collider_shape.as_ref();
+                let p2 = unsafe { physics_column_mut.get_unchecked(e2).unwrap() };
+
+                if (p1.sleeping && p2.sleeping)
+                    || !p1.groups.interacts_with(p2.groups)
+                    || pipeline
+                        .jointed_pairs
+                        .contains_key(&SortedPair::new(e1, e2))
+                {
+                    continue;
+                }
 
-                if let Some(mut c) = parry3d::query::contact(&pos1, s1, &pos2, s2, 0.1).unwrap() {
-                    let sorted_pair = SortedPair::new(e1, e2);
-                    let pair = ConstrainedPair::Dynamic(sorted_pair);
+                let pos2 = p2.collider_tx * p2.position.as_isometry3();
+                let sorted_pair = SortedPair::new(e1, e2);
+                let flip = sorted_pair.0 != e1;
 
-                    if sorted_pair.0 != e1 {
-                        c.flip();
+                for (i, part1) in p1.parts.iter().enumerate() {
+                    if part1.sensor {
+                        continue;
                     }
 
-                    let contact = Contact::new(c.normal1, c.point1, c.point2);
-                    let constraint = match pipeline.contacts.entry(pair) {
-                        Entry::Vacant(vacant) => {
-                            let contact_id = ContactId(
-                                pipeline
-                                    .constraints
-                                    .insert(ContactConstraint::new(pair, contact)),
-                            );
-                            vacant.insert(contact_id);
-                            p1.contacts.push(ContactIdEntry {
-                                flipped: false,
-                                id: contact_id,
-                            });
-                            p2.contacts.push(ContactIdEntry {
-                                flipped: true,
-                                id: contact_id,
-                            });
-                            pipeline
-                                .events
-                                .single_write(PhysicsEvent::BeginContact(contact_id));
-                            &mut pipeline.constraints[contact_id.0]
-                        }
-                        Entry::Occupied(occupied) => {
-                            let contact_id = *occupied.get();
-                            let mut_constraint = &mut pipeline.constraints[contact_id.0];
-                            mut_constraint.contact = contact;
-                            mut_constraint
+                    let part_pos1 = pos1 * part1.local_tx;
+                    let material1 = p1.part_material(part1);
+
+                    for (j, part2) in p2.parts.iter().enumerate() {
+                        if part2.sensor {
+                            continue;
                         }
-                    };
 
-                    constraint.timestamp = tick.0;
+                        let part_pos2 = pos2 * part2.local_tx;
+                        let s1 = part1.shape.as_ref();
+                        let s2 = part2.shape.as_ref();
+                        let mut c =
+                            match parry3d::query::contact(&part_pos1, s1, &part_pos2, s2, 0.1)
+                                .unwrap()
+                            {
+                                Some(c) => c,
+                                None => continue,
+                            };
+
+                        let parts = if flip {
+                            c.flip();
+                            [j as u32, i as u32]
+                        } else {
+                            [i as u32, j as u32]
+                        };
+
+                        let pair = ConstrainedPair::Dynamic(sorted_pair, parts);
+                        let contact = Contact::new(c.normal1, c.point1, c.point2);
+                        let material = material1.combine(Some(&p2.part_material(part2)));
+
+                        match pipeline.refresh_contact(pair, contact, material, lua, tick) {
+                            ContactRefresh::Created(id) => {
+                                p1.contacts.push(ContactIdEntry { flipped: flip, id });
+                                p2.contacts.push(ContactIdEntry { flipped: !flip, id });
+                            }
+                            ContactRefresh::Discarded(Some(id)) => {
+                                p1.remove_contact(id);
+                                p2.remove_contact(id);
+                            }
+                            _ => {}
+                        }
+                    }
                 }
             }
         }
     }
 
-    // Integrate velocities w/ external forces
+    // Wake up sleeping bodies touching awake or moving ones, along w/ the rest of their islands.
+    {
+        let mut physics = context.column_mut(*physics_query_marker);
+
+        // Joints don't outlive their bodies.
+        let dead_joints = pipeline
+            .joints
+            .iter()
+            .filter(|(_, joint)| {
+                physics.get(joint.body1).is_none()
+                    || joint
+                        .body2
+                        .map_or(false, |body2| physics.get(body2).is_none())
+            })
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        for joint_id in dead_joints {
+            pipeline.remove_joint(joint_id);
+        }
+
+        for (e, ()) in context
+            .prepared_query(with_physics_and_dynamic_query)
+            .iter()
+        {
+            if !physics.get(e).unwrap().sleeping {
+                wake_seeds.push(e);
+            }
+        }
+
+        pipeline.wake_touching(&mut physics, wake_seeds, tick);
+    }
+
+    // Integrate velocities w/ external forces, then apply damping.
     for (_, physics) in context.prepared_query(dynamic_objects_query).iter() {
-        physics.velocity.linear += physics.gravity_k * pipeline.config.gravity * dt.0;
+        if physics.sleeping {
+            continue;
+        }
+
+        let MassData {
+            inv_mass,
+            inv_inertia,
+        } = physics.mass_data;
+        physics.velocity.linear +=
+            (physics.gravity_k * pipeline.config.gravity + physics.force * inv_mass) * dt.0;
+        physics.velocity.angular += physics.torque * inv_inertia * dt.0;
+
+        physics.velocity.linear /= 1. + dt.0 * physics.linear_damping;
+        physics.velocity.angular /= 1. + dt.0 * physics.angular_damping;
     }
 
     // Solve likely-violated velocity constraints
@@ -792,7 +1741,8 @@ pub fn update(
     for (_, (physics, ccd_enabled)) in context.prepared_query(update_target_pos_query).iter() {
         physics.target = physics.velocity.integrate(&physics.position, dt.0);
 
-        if !ccd_enabled {
+        // CCD is only done w/ the solid parts of a body, so if it has none, there's nothing to do.
+        if !ccd_enabled || physics.collider_shape.is_none() {
             physics.position = physics.target;
         }
     }
@@ -800,51 +1750,113 @@ pub fn update(
     // Integrate positions w/ newly solved velocities, for bodies w/ CCD, performing motion clamping
     // where applicable.
     //
-    // At current, we only perform CCD against static bodies, and we only perform CCD for bodies
-    // which are above the threshold.
+    // We perform CCD against static bodies and against other dynamic bodies w/ CCD enabled, and we
+    // only perform CCD for bodies which are above the threshold. All times of impact are computed
+    // from the start-of-step positions before any clamping is applied, so that the result doesn't
+    // depend on the order in which bodies are visited.
     {
         let physics_column_mut = context.column_mut(*physics_query_marker);
-        for (e1, ()) in context.prepared_query(motion_clamping_query).iter() {
+        let threshold = pipeline.config.continuous_collision_velocity_threshold;
+
+        // Collect swept AABBs for every CCD-enabled body, fast or not, so that fast bodies can find
+        // slower ones which they might pass through.
+        let mut swept = Vec::new();
+        for (e, ()) in context.prepared_query(motion_clamping_query).iter() {
+            let p = unsafe { physics_column_mut.get_unchecked(e).unwrap() };
+            let shape = match &p.collider_shape {
+                Some(shape) => shape,
+                None => continue,
+            };
+
+            let pos_start_tx = p.collider_tx * p.position.as_isometry3();
+            let pos_end_tx = p.collider_tx * p.target.as_isometry3();
+            let aabb = shape
+                .compute_swept_aabb(&pos_start_tx, &pos_end_tx)
+                .loosened(0.1);
+            swept.push((e, aabb));
+        }
+
+        pipeline
+            .ccd_qbvh
+            .clear_and_rebuild(swept.iter().map(|&(e, aabb)| (e.id(), aabb)), 0.);
+
+        let mut clamps = Vec::with_capacity(swept.len());
+        let mut out = Vec::new();
+        for &(e1, aabb) in &swept {
             let mut min_toi = None::<TOI>;
+            let mut replace_if_sooner = |maybe_new_toi: Option<TOI>| {
+                if let Some(new_toi) = maybe_new_toi {
+                    let do_replace = min_toi.map_or(true, |prev_toi| new_toi.toi < prev_toi.toi);
+                    if do_replace {
+                        min_toi = Some(new_toi);
+                    }
+                }
+            };
 
             let p1 = unsafe { physics_column_mut.get_unchecked(e1).unwrap() };
+            // Only bodies w/ solid parts make it into `swept`.
+            let s1 = p1.collider_shape.as_deref().unwrap();
 
-            if p1.velocity.linear.norm_squared()
-                >= pipeline
-                    .config
-                    .continuous_collision_velocity_threshold
-                    .powi(2)
-            {
+            if p1.velocity.linear.norm_squared() >= threshold.powi(2) {
                 let pos_start_tx = p1.collider_tx * p1.position.as_isometry3();
-                let pos_end_tx = p1.collider_tx * p1.target.as_isometry3();
-                let aabb = p1
-                    .collider_shape
-                    .compute_swept_aabb(&pos_start_tx, &pos_end_tx)
-                    .loosened(0.1);
-
-                for intersection in atom_map.intersect_with(aabb) {
-                    let maybe_new_toi = intersection.shape.time_of_impact(
-                        &intersection.coords,
+
+                if p1.groups.interacts_with(pipeline.config.lattice_groups) {
+                    for intersection in atom_map.intersect_with(aabb) {
+                        replace_if_sooner(intersection.shape.time_of_impact(
+                            &intersection.coords,
+                            &pos_start_tx,
+                            &p1.velocity.linear,
+                            s1,
+                            dt.0,
+                        ));
+                    }
+                }
+
+                // Dynamic-dynamic CCD. Every CCD-enabled body still has its start-of-step
+                // position at this point, since clamping is deferred until all TOIs are known.
+                pipeline.ccd_qbvh.intersect_aabb(&aabb, &mut out);
+                for id in out.drain(..) {
+                    if id == e1.id() {
+                        continue;
+                    }
+
+                    let (e2, p2) = unsafe {
+                        let e2 = context.find_entity_from_id(id);
+                        (e2, physics_column_mut.get_unchecked(e2).unwrap())
+                    };
+
+                    if !p1.groups.interacts_with(p2.groups) {
+                        continue;
+                    }
+
+                    // Parry doesn't support every pair of shapes; if it can't compute a time of
+                    // impact for these, treat it as a miss rather than bringing the update down.
+                    match parry3d::query::time_of_impact(
                         &pos_start_tx,
                         &p1.velocity.linear,
-                        p1.collider_shape.as_ref(),
+                        s1,
+                        &(p2.collider_tx * p2.position.as_isometry3()),
+                        &p2.velocity.linear,
+                        p2.collider_shape.as_deref().unwrap(),
                         dt.0,
-                    );
-
-                    if let Some(new_toi) = maybe_new_toi {
-                        let do_replace =
-                            min_toi.map_or(true, |prev_toi| new_toi.toi < prev_toi.toi);
-                        if do_replace {
-                            min_toi = Some(new_toi);
-                        }
+                    ) {
+                        Ok(toi) => replace_if_sooner(toi),
+                        Err(err) => warn!(
+                            ?err,
+                            "unable to compute time of impact between {:?} and {:?}", e1, e2
+                        ),
                     }
                 }
-
-                // TODO: When/if we do CCD on dynamic-dynamic bodies, it should probably go here.
-                // We're borrowing the ECS world via the column API specifically in order to make
-                // this easy for if/when we do this.
             }
 
+            clamps.push((e1, min_toi));
+        }
+
+        pipeline.ccd_sweeps.clear();
+        for (e1, min_toi) in clamps {
+            let p1 = unsafe { physics_column_mut.get_unchecked(e1).unwrap() };
+            let start = p1.position;
+
             if let Some(toi) = min_toi {
                 let extra =
                     pipeline.config.continuous_collision_toi_bias / p1.velocity.linear.norm();
@@ -854,55 +1866,64 @@ pub fn update(
             } else {
                 p1.position = p1.target;
             }
+
+            pipeline.ccd_sweeps.push(CcdSweep {
+                entity: e1,
+                start,
+                target: p1.target,
+                end: p1.position,
+            });
         }
     }
 
     // Detect dynamic-static collisions, collecting position and velocity constraints
     let mut out = Vec::new();
     for (e, physics) in context.prepared_query(dynamic_objects_query).iter() {
+        if physics.sleeping
+            || !physics
+                .groups
+                .interacts_with(pipeline.config.lattice_groups)
+        {
+            continue;
+        }
+
         let pos_tx = physics.collider_tx * physics.position.as_isometry3();
-        let aabb = physics.collider_shape.compute_aabb(&pos_tx);
-        for intersection in atom_map.intersect_with(aabb) {
-            intersection.shape.contact(
-                &intersection.coords,
-                physics.collider_shape.as_ref(),
-                &pos_tx,
-                0.0,
-                atom_map.edge_filter(),
-                atom_map.vertex_filter(),
-                &mut out,
-            );
+        for (part_index, part) in physics.parts.iter().enumerate() {
+            if part.sensor {
+                continue;
+            }
 
-            for (mut c, feature) in out.drain(..) {
-                c.flip();
-                let pair = ConstrainedPair::Static(e, intersection.coords, feature);
-                let contact = Contact::new(c.normal1, c.point1, c.point2);
-                let constraint = match pipeline.contacts.entry(pair) {
-                    Entry::Vacant(vacant) => {
-                        let contact_id = ContactId(
-                            pipeline
-                                .constraints
-                                .insert(ContactConstraint::new(pair, contact)),
-                        );
-                        vacant.insert(contact_id);
-                        physics.contacts.push(ContactIdEntry {
-                            flipped: false,
-                            id: contact_id,
-                        });
-                        pipeline
-                            .events
-                            .single_write(PhysicsEvent::BeginContact(contact_id));
-                        &mut pipeline.constraints[contact_id.0]
-                    }
-                    Entry::Occupied(occupied) => {
-                        let contact_id = *occupied.get();
-                        let mut_constraint = &mut pipeline.constraints[contact_id.0];
-                        mut_constraint.contact = contact;
-                        mut_constraint
+            let part_pos = pos_tx * part.local_tx;
+            let aabb = part.shape.compute_aabb(&part_pos);
+            let material = physics.part_material(part);
+
+            for intersection in atom_map.intersect_with(aabb) {
+                let (edge_filter, vertex_filter) = atom_map.filters(intersection.coords);
+                intersection.shape.contact_manifolds(
+                    &intersection.coords,
+                    part.shape.as_ref(),
+                    &part_pos,
+                    0.0,
+                    edge_filter,
+                    vertex_filter,
+                    &mut out,
+                );
+
+                // Every point of a manifold gets its own constraint, so that each is warm started
+                // from the impulses it accumulated over previous updates.
+                for (mut c, facet, point) in out.drain(..) {
+                    c.flip();
+                    let coords = intersection.coords;
+                    let pair = ConstrainedPair::Static(e, part_index as u32, coords, facet, point);
+                    let contact = Contact::new(c.normal1, c.point1, c.point2);
+                    match pipeline.refresh_contact(pair, contact, material, lua, tick) {
+                        ContactRefresh::Created(id) => {
+                            physics.contacts.push(ContactIdEntry { flipped: false, id });
+                        }
+                        ContactRefresh::Discarded(Some(id)) => physics.remove_contact(id),
+                        _ => {}
                     }
-                };
-
-                constraint.timestamp = tick.0;
+                }
             }
         }
     }
@@ -912,17 +1933,24 @@ pub fn update(
         // Solve position constraints
         pipeline.solve_positions(&mut physics, dt);
 
-        pipeline.constraints.retain(|id, constraint| {
-            if constraint.timestamp != tick.0 {
+        // Constraints on sleeping bodies aren't updated, but are kept alive until they wake.
+        pipeline.constraints.retain(|&contact_id, constraint| {
+            let asleep = match constraint.participants {
+                ConstrainedPair::Dynamic(pair, _) => {
+                    physics.get(pair.0).unwrap().sleeping || physics.get(pair.1).unwrap().sleeping
+                }
+                ConstrainedPair::Static(e1, ..) => physics.get(e1).unwrap().sleeping,
+            };
+
+            if constraint.timestamp != tick.0 && !asleep {
                 pipeline.contacts.remove(&constraint.participants);
 
-                let contact_id = ContactId(id);
                 match constraint.participants {
-                    ConstrainedPair::Dynamic(pair) => {
+                    ConstrainedPair::Dynamic(pair, _) => {
                         physics.get(pair.0).unwrap().remove_contact(contact_id);
                         physics.get(pair.1).unwrap().remove_contact(contact_id);
                     }
-                    ConstrainedPair::Static(e1, _, _) => {
+                    ConstrainedPair::Static(e1, ..) => {
                         physics.get(e1).unwrap().remove_contact(contact_id);
                     }
                 }
@@ -937,14 +1965,147 @@ pub fn update(
                 true
             }
         });
+
+        // Build islands out of the awake dynamic bodies, and put to sleep any island which has
+        // been at rest for long enough.
+        pipeline.islands.clear();
+        for (e, ()) in context
+            .prepared_query(with_physics_and_dynamic_query)
+            .iter()
+        {
+            let p = physics.get(e).unwrap();
+            if p.sleeping {
+                continue;
+            }
+
+            if p.is_above_sleep_threshold(&pipeline.config) {
+                p.sleep_timer = 0.;
+            } else {
+                p.sleep_timer += dt.0;
+            }
+
+            pipeline.islands.insert(e, p.sleep_timer);
+        }
+
+        for (_, constraint) in &pipeline.constraints {
+            if let ConstrainedPair::Dynamic(pair, _) = constraint.participants {
+                pipeline.islands.union(pair.0, pair.1);
+            }
+        }
+
+        for (_, joint) in &pipeline.joints {
+            if let Some(body2) = joint.body2 {
+                pipeline.islands.union(joint.body1, body2);
+            }
+        }
+
+        for (e, ()) in context
+            .prepared_query(with_physics_and_dynamic_query)
+            .iter()
+        {
+            if pipeline.islands.can_sleep(e, pipeline.config.time_to_sleep) {
+                let p = physics.get(e).unwrap();
+                p.sleeping = true;
+                p.velocity = CompositeVelocity3::zero();
+            }
+        }
     }
 
-    // Copy physics data back to the ECS.
+    // Copy physics data back to the ECS, and clear the forces applied during this update.
     for (_, (pos, maybe_vel, physics)) in context.prepared_query(all_physics_objects_query).iter() {
         if let Some(vel) = maybe_vel {
             pos.current = physics.position;
             vel.composite = physics.velocity;
         }
+
+        physics.clear_forces();
+    }
+
+    pipeline.update_query_colliders(
+        context
+            .prepared_query(all_colliders_query)
+            .iter()
+            .map(|(e, physics)| (e, &*physics)),
+    );
+
+    // Detect overlaps between sensors and everything else, using the final collider positions we
+    // just recorded for scene queries. Every part of a body w/ a `SensorMarker` is a sensor, and
+    // sensor bodies and parts aren't recorded there, so we'll never see sensor/sensor pairs.
+    let mut out = Vec::new();
+    for (sensor, (physics, is_sensor_body)) in context.prepared_query(sensors_query).iter() {
+        let pos = physics.collider_tx * physics.position.as_isometry3();
+        for (part_index, part) in physics.parts.iter().enumerate() {
+            if !is_sensor_body && !part.sensor {
+                continue;
+            }
+
+            let pos1 = pos * part.local_tx;
+            let s1 = part.shape.as_ref();
+            let aabb = s1.compute_aabb(&pos1);
+            pipeline.query_qbvh.intersect_aabb(&aabb, &mut out);
+
+            for id in out.drain(..) {
+                let other = &pipeline.query_colliders[&id];
+                if other.entity == sensor || !physics.groups.interacts_with(other.groups) {
+                    continue;
+                }
+
+                let s2 = other.shape.as_ref();
+                let intersecting =
+                    match parry3d::query::intersection_test(&pos1, s1, &other.position, s2) {
+                        Ok(intersecting) => intersecting,
+                        Err(err) => {
+                            warn!(
+                                ?err,
+                                "unable to test sensor {:?} against {:?}", sensor, other.entity
+                            );
+                            false
+                        }
+                    };
+
+                if intersecting {
+                    let key = (sensor, part_index as u32, other.entity);
+                    if pipeline.triggers.insert(key, tick.0).is_none() {
+                        pipeline.events.single_write(PhysicsEvent::TriggerEnter(
+                            sensor,
+                            part_index as u32,
+                            other.entity,
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    pipeline
+        .triggers
+        .retain(|&(sensor, part, other), timestamp| {
+            if *timestamp != tick.0 {
+                pipeline
+                    .events
+                    .single_write(PhysicsEvent::TriggerExit(sensor, part, other));
+                false
+            } else {
+                true
+            }
+        });
+
+    // Insert and remove `Sleeping` markers to match the sleep state of every body.
+    let mut maybe_buffer = None;
+    for (e, (physics, marked)) in context.prepared_query(sleeping_markers_query).iter() {
+        if physics.sleeping == marked {
+            continue;
+        }
+
+        let buffer = maybe_buffer.get_or_insert_with(|| command_pool.get_buffer());
+        if physics.sleeping {
+            buffer.insert(e, (Sleeping,));
+        } else {
+            buffer.push(move |world, _| {
+                world.remove_one::<Sleeping>(e)?;
+                Ok(())
+            });
+        }
     }
 }
 
@@ -1138,40 +2299,161 @@ impl LuaUserData for Collider {
     }
 }
 
-impl LuaUserData for Physics {
+impl LuaUserData for Material {
     fn on_metatable_init(table: Type<Self>) {
-        table.add_clone().mark_component();
+        table
+            .add_clone()
+            .add_copy()
+            .add_send()
+            .add_sync()
+            .add::<dyn std::fmt::Debug>();
     }
 
+    #[allow(clippy::unit_arg)]
     fn add_fields<'lua, F: LuaUserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("restitution", |_, this| Ok(this.restitution));
         fields.add_field_method_get("static_friction", |_, this| Ok(this.static_friction));
         fields.add_field_method_get("dynamic_friction", |_, this| Ok(this.dynamic_friction));
-        fields.add_field_method_get("gravity_k", |_, this| Ok(this.gravity_k));
-
+        fields.add_field_method_set("restitution", |_, this, restitution| {
+            Ok(this.restitution = restitution)
+        });
         fields.add_field_method_set("static_friction", |_, this, static_friction| {
-            this.static_friction = static_friction;
-            Ok(())
+            Ok(this.static_friction = static_friction)
         });
         fields.add_field_method_set("dynamic_friction", |_, this, dynamic_friction| {
-            this.dynamic_friction = dynamic_friction;
-            Ok(())
-        });
-        fields.add_field_method_set("gravity_k", |_, this, gravity_k| {
-            this.gravity_k = gravity_k;
-            Ok(())
+            Ok(this.dynamic_friction = dynamic_friction)
         });
     }
 
-    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
-        methods.add_function_mut("with_density", |_, (ud, density): (LuaAnyUserData, _)| {
-            let mut this = ud.borrow_mut::<Self>()?;
-            *this = this.clone().with_density(density);
-            drop(this);
-            Ok(ud)
-        });
+    fn add_type_methods<'lua, M: LuaUserDataMethods<'lua, Type<Self>>>(methods: &mut M) {
+        methods.add_function(
+            "new",
+            |_, (restitution, static_friction, dynamic_friction): (f32, f32, Option<f32>)| {
+                let dynamic_friction = dynamic_friction.unwrap_or(static_friction);
+                Ok(Self::new(restitution, static_friction, dynamic_friction))
+            },
+        );
+    }
+}
 
-        methods.add_function_mut("with_friction", |_, (ud, friction): (LuaAnyUserData, _)| {
-            let mut this = ud.borrow_mut::<Self>()?;
+impl LuaUserData for ColliderPart {
+    fn on_metatable_init(table: Type<Self>) {
+        table.add_clone().add_send().add_sync();
+    }
+
+    #[allow(clippy::unit_arg)]
+    fn add_fields<'lua, F: LuaUserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("local_tx", |_, this| Ok(this.local_tx));
+        fields.add_field_method_get("shape", |_, this| Ok(this.shape.clone()));
+        fields.add_field_method_get("material", |_, this| Ok(this.material));
+        fields.add_field_method_get("sensor", |_, this| Ok(this.sensor));
+        fields.add_field_method_set("local_tx", |_, this, local_tx| Ok(this.local_tx = local_tx));
+        fields.add_field_method_set("shape", |_, this, shape| Ok(this.shape = shape));
+        fields.add_field_method_set("material", |_, this, material| Ok(this.material = material));
+        fields.add_field_method_set("sensor", |_, this, sensor| Ok(this.sensor = sensor));
+    }
+
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_function_mut("with_material", |_, (ud, material): (LuaAnyUserData, _)| {
+            let mut this = ud.borrow_mut::<Self>()?;
+            this.material = Some(material);
+            drop(this);
+            Ok(ud)
+        });
+
+        methods.add_function_mut("with_sensor", |_, (ud, sensor): (LuaAnyUserData, _)| {
+            let mut this = ud.borrow_mut::<Self>()?;
+            this.sensor = sensor;
+            drop(this);
+            Ok(ud)
+        });
+    }
+
+    fn add_type_methods<'lua, M: LuaUserDataMethods<'lua, Type<Self>>>(methods: &mut M) {
+        methods.add_function("new", |_, (local_tx, shape)| Ok(Self::new(local_tx, shape)));
+    }
+}
+
+// Lets Lua build a `Physics` from a shape, a single `ColliderPart`, or a table of them.
+impl<'lua> FromLua<'lua> for ColliderParts {
+    fn from_lua(lua_value: LuaValue<'lua>, lua: &'lua Lua) -> LuaResult<Self> {
+        let parts = match lua_value {
+            LuaValue::Table(table) => table
+                .sequence_values::<ColliderPart>()
+                .collect::<LuaResult<Self>>()?,
+            LuaValue::UserData(ud) => {
+                let maybe_part = ud.borrow::<ColliderPart>().ok().map(|part| part.clone());
+                match maybe_part {
+                    Some(part) => Self::from(part),
+                    None => SharedShape::from_lua(LuaValue::UserData(ud), lua).map(Self::from)?,
+                }
+            }
+            other => SharedShape::from_lua(other, lua).map(Self::from)?,
+        };
+        parts.validate().to_lua_err()?;
+        Ok(parts)
+    }
+}
+
+impl LuaUserData for Physics {
+    fn on_metatable_init(table: Type<Self>) {
+        table.add_clone().mark_component();
+    }
+
+    fn add_fields<'lua, F: LuaUserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("static_friction", |_, this| Ok(this.static_friction));
+        fields.add_field_method_get("dynamic_friction", |_, this| Ok(this.dynamic_friction));
+        fields.add_field_method_get("gravity_k", |_, this| Ok(this.gravity_k));
+        fields.add_field_method_get("linear_damping", |_, this| Ok(this.linear_damping));
+        fields.add_field_method_get("angular_damping", |_, this| Ok(this.angular_damping));
+        fields.add_field_method_get("force", |_, this| Ok(this.force));
+        fields.add_field_method_get("torque", |_, this| Ok(this.torque));
+        fields.add_field_method_get("collision_memberships", |_, this| {
+            Ok(this.groups.memberships)
+        });
+        fields.add_field_method_get("collision_filter", |_, this| Ok(this.groups.filter));
+        fields.add_field_method_get("sleeping", |_, this| Ok(this.sleeping));
+
+        fields.add_field_method_set("static_friction", |_, this, static_friction| {
+            this.static_friction = static_friction;
+            Ok(())
+        });
+        fields.add_field_method_set("dynamic_friction", |_, this, dynamic_friction| {
+            this.dynamic_friction = dynamic_friction;
+            Ok(())
+        });
+        fields.add_field_method_set("gravity_k", |_, this, gravity_k| {
+            this.gravity_k = gravity_k;
+            Ok(())
+        });
+        fields.add_field_method_set("linear_damping", |_, this, linear_damping| {
+            this.linear_damping = linear_damping;
+            Ok(())
+        });
+        fields.add_field_method_set("angular_damping", |_, this, angular_damping| {
+            this.angular_damping = angular_damping;
+            Ok(())
+        });
+        fields.add_field_method_set("collision_memberships", |_, this, memberships| {
+            this.groups.memberships = memberships;
+            Ok(())
+        });
+        fields.add_field_method_set("collision_filter", |_, this, filter| {
+            this.groups.filter = filter;
+            Ok(())
+        });
+    }
+
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_function_mut("with_density", |_, (ud, density): (LuaAnyUserData, _)| {
+            let mut this = ud.borrow_mut::<Self>()?;
+            *this = this.clone().with_density(density);
+            drop(this);
+            Ok(ud)
+        });
+
+        methods.add_function_mut("with_friction", |_, (ud, friction): (LuaAnyUserData, _)| {
+            let mut this = ud.borrow_mut::<Self>()?;
             *this = this.clone().with_friction(friction);
             drop(this);
             Ok(ud)
@@ -1207,6 +2489,98 @@ impl LuaUserData for Physics {
             },
         );
 
+        methods.add_function_mut(
+            "with_collision_groups",
+            |_, (ud, memberships, filter): (LuaAnyUserData, u32, u32)| {
+                let mut this = ud.borrow_mut::<Self>()?;
+                *this = this
+                    .clone()
+                    .with_collision_groups(CollisionGroups::new(memberships, filter));
+                drop(this);
+                Ok(ud)
+            },
+        );
+
+        methods.add_function_mut(
+            "with_gravity_scale",
+            |_, (ud, gravity_scale): (LuaAnyUserData, _)| {
+                let mut this = ud.borrow_mut::<Self>()?;
+                *this = this.clone().with_gravity_scale(gravity_scale);
+                drop(this);
+                Ok(ud)
+            },
+        );
+
+        methods.add_function_mut(
+            "with_linear_damping",
+            |_, (ud, linear_damping): (LuaAnyUserData, _)| {
+                let mut this = ud.borrow_mut::<Self>()?;
+                *this = this.clone().with_linear_damping(linear_damping);
+                drop(this);
+                Ok(ud)
+            },
+        );
+
+        methods.add_function_mut(
+            "with_angular_damping",
+            |_, (ud, angular_damping): (LuaAnyUserData, _)| {
+                let mut this = ud.borrow_mut::<Self>()?;
+                *this = this.clone().with_angular_damping(angular_damping);
+                drop(this);
+                Ok(ud)
+            },
+        );
+
+        methods.add_method_mut("wake_up", |_, this, ()| {
+            this.wake_up();
+            Ok(())
+        });
+
+        methods.add_method_mut(
+            "apply_force",
+            |_, this, (force, point): (Vector3<f32>, Option<Vector3<f32>>)| {
+                match point {
+                    Some(point) => this.apply_force_at_point(force, Point3::from(point)),
+                    None => this.apply_force(force),
+                }
+                Ok(())
+            },
+        );
+
+        methods.add_method_mut("apply_torque", |_, this, torque| {
+            this.apply_torque(torque);
+            Ok(())
+        });
+
+        methods.add_method_mut(
+            "apply_impulse",
+            |_,
+             this,
+             (velocity, impulse, point): (LuaAnyUserData, Vector3<f32>, Option<Vector3<f32>>)| {
+                let mut velocity = velocity.borrow_mut::<Velocity>()?;
+                match point {
+                    Some(point) => {
+                        this.apply_impulse_at_point(&mut velocity, impulse, Point3::from(point))
+                    }
+                    None => this.apply_impulse(&mut velocity, impulse),
+                }
+                Ok(())
+            },
+        );
+
+        methods.add_method_mut(
+            "apply_angular_impulse",
+            |_, this, (velocity, impulse): (LuaAnyUserData, f32)| {
+                this.apply_angular_impulse(&mut velocity.borrow_mut::<Velocity>()?, impulse);
+                Ok(())
+            },
+        );
+
+        methods.add_method_mut("clear_forces", |_, this, ()| {
+            this.clear_forces();
+            Ok(())
+        });
+
         methods.add_method_mut(
             "set_friction",
             |_, this, (static_friction, dynamic_friction): (f32, Option<f32>)| {
@@ -1220,54 +2594,36 @@ impl LuaUserData for Physics {
         methods.add_method(
             "max_projected_contact_normal",
             |lua, this, (normal, out): (Vector3<f32>, Option<LuaAnyUserData>)| {
-                let pp_elastic;
-                let res_elastic;
-                let pp_borrow;
-                let res_borrow;
-                let pp_ref;
-                let pp;
-
-                if let Some(pp_ref) = lua.app_data_ref::<ElasticMut<PhysicsPipeline>>() {
-                    pp_elastic = pp_ref;
-                    pp_borrow = pp_elastic.borrow();
-                    pp = &*pp_borrow;
-                } else if let Some(resources) = lua.app_data_ref::<ElasticRef<Resources>>() {
-                    res_elastic = resources;
-                    res_borrow = res_elastic.borrow();
-                    pp_ref = res_borrow.get().to_lua_err()?;
-                    pp = &*pp_ref;
-                } else {
-                    return Err(anyhow!("no physics pipeline loaned to Lua state!")).to_lua_err();
-                }
-
-                let query_normal = UnitVector3::new_normalize(normal);
-                let mut max_projected = None::<f32>;
-                let mut max_normal = None::<Vector3<f32>>;
-
-                for &contact_id_entry in &this.contacts {
-                    let constraint = pp.contact(contact_id_entry.id).expect("invalid contact");
-                    let contact_normal = if contact_id_entry.flipped {
-                        -constraint.contact.normal
-                    } else {
-                        constraint.contact.normal
-                    };
-
-                    let projected = query_normal.dot(&contact_normal);
-                    let replace =
-                        max_projected.map_or(true, |max_projected| projected > max_projected);
-
-                    if replace {
-                        max_projected = Some(projected);
-                        max_normal = Some(contact_normal.into_inner());
+                with_loaned(lua, |pp: &PhysicsPipeline| {
+                    let query_normal = UnitVector3::new_normalize(normal);
+                    let mut max_projected = None::<f32>;
+                    let mut max_normal = None::<Vector3<f32>>;
+
+                    for &contact_id_entry in &this.contacts {
+                        let constraint = pp.contact(contact_id_entry.id).expect("invalid contact");
+                        let contact_normal = if contact_id_entry.flipped {
+                            -constraint.contact.normal
+                        } else {
+                            constraint.contact.normal
+                        };
+
+                        let projected = query_normal.dot(&contact_normal);
+                        let replace =
+                            max_projected.map_or(true, |max_projected| projected > max_projected);
+
+                        if replace {
+                            max_projected = Some(projected);
+                            max_normal = Some(contact_normal.into_inner());
+                        }
                     }
-                }
 
-                if let (Some(contact_normal), Some(out)) = (max_normal, out) {
-                    let mut out = out.borrow_mut::<Vector3<f32>>()?;
-                    *out = contact_normal;
-                }
+                    if let (Some(contact_normal), Some(out)) = (max_normal, out) {
+                        let mut out = out.borrow_mut::<Vector3<f32>>()?;
+                        *out = contact_normal;
+                    }
 
-                Ok(max_projected)
+                    Ok(max_projected)
+                })
             },
         );
     }
@@ -1277,11 +2633,14 @@ impl LuaUserData for Physics {
     }
 
     fn add_type_methods<'lua, M: LuaUserDataMethods<'lua, Type<Self>>>(methods: &mut M) {
-        methods.add_function("new", |_, collider_shape| Ok(Self::new(collider_shape)));
+        methods.add_function("new", |_, collider: ColliderParts| Ok(Self::new(collider)));
 
-        methods.add_function("with_local_tx", |_, (collider_shape, collider_tx)| {
-            Ok(Self::with_local_tx(collider_shape, collider_tx))
-        });
+        methods.add_function(
+            "with_local_tx",
+            |_, (collider, collider_tx): (ColliderParts, _)| {
+                Ok(Self::with_local_tx(collider, collider_tx))
+            },
+        );
     }
 }
 
@@ -1299,6 +2658,16 @@ impl LuaUserData for CcdEnabled {
     }
 }
 
+impl LuaUserData for Sleeping {
+    fn on_metatable_init(table: Type<Self>) {
+        table.mark_component().add_clone().add_copy();
+    }
+
+    fn on_type_metatable_init(table: Type<Type<Self>>) {
+        table.mark_component_type();
+    }
+}
+
 impl LuaUserData for KinematicMarker {
     fn on_metatable_init(table: Type<Self>) {
         table.mark_component().add_clone().add_copy();
@@ -1313,6 +2682,20 @@ impl LuaUserData for KinematicMarker {
     }
 }
 
+impl LuaUserData for SensorMarker {
+    fn on_metatable_init(table: Type<Self>) {
+        table.mark_component().add_clone().add_copy();
+    }
+
+    fn on_type_metatable_init(table: Type<Type<Self>>) {
+        table.mark_component_type();
+    }
+
+    fn add_type_methods<'lua, M: LuaUserDataMethods<'lua, Type<Self>>>(methods: &mut M) {
+        methods.add_function("new", |_, ()| Ok(Self));
+    }
+}
+
 impl LuaUserData for PhysicsPipeline {
     fn on_metatable_init(table: Type<Self>) {
         table.add_send().add_sync();
@@ -1324,22 +2707,30 @@ impl LuaUserData for PhysicsPipeline {
         fields.add_field_method_set("bias_factor", |_, this, bias| {
             Ok(this.config.bias_factor = bias)
         });
+        fields.add_field_method_get("lattice_memberships", |_, this| {
+            Ok(this.config.lattice_groups.memberships)
+        });
+        fields.add_field_method_set("lattice_memberships", |_, this, memberships| {
+            Ok(this.config.lattice_groups.memberships = memberships)
+        });
+        fields.add_field_method_get("lattice_filter", |_, this| {
+            Ok(this.config.lattice_groups.filter)
+        });
+        fields.add_field_method_set("lattice_filter", |_, this, filter| {
+            Ok(this.config.lattice_groups.filter = filter)
+        });
     }
 }
 
 impl<'lua> ToLua<'lua> for ContactId {
     fn to_lua(self, lua: &'lua Lua) -> LuaResult<LuaValue<'lua>> {
-        LuaLightUserData(self.0.to_bits() as *mut _).to_lua(lua)
+        LuaLightUserData(self.0 as *mut _).to_lua(lua)
     }
 }
 
 impl<'lua> FromLua<'lua> for ContactId {
     fn from_lua(lua_value: LuaValue<'lua>, lua: &'lua Lua) -> LuaResult<Self> {
-        LuaLightUserData::from_lua(lua_value, lua).and_then(|lud| {
-            Index::from_bits(lud.0 as _)
-                .map(ContactId)
-                .ok_or_else(|| anyhow!("invalid index!").to_lua_err())
-        })
+        LuaLightUserData::from_lua(lua_value, lua).map(|lud| ContactId(lud.0 as u64))
     }
 }
 
@@ -1416,3 +2807,873 @@ impl Sub for CompositePosition3 {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hv::{
+        ecs::{System, World},
+        resources::Resources,
+    };
+    use soft_edge::{Atom, HullFacet};
+
+    use crate::command_buffer::CommandPool;
+
+    const DT: f32 = 1. / 60.;
+
+    fn cube() -> Atom {
+        Atom::generator()
+            .find(|atom| {
+                atom.compound_hull()
+                    .facets()
+                    .filter(|facet| matches!(facet, HullFacet::Rectangle(_)))
+                    .count()
+                    == 6
+            })
+            .unwrap()
+    }
+
+    fn lattice(cells: impl IntoIterator<Item = Vector3<i32>>) -> AtomMap {
+        let mut map = AtomMap::new();
+        for coords in cells {
+            map.atoms_mut().insert(coords, cube());
+        }
+        map.calculate_hulls();
+        map
+    }
+
+    fn part_at(x: f32, y: f32, z: f32, shape: SharedShape) -> ColliderPart {
+        ColliderPart::new(Isometry3::translation(x, y, z), shape)
+    }
+
+    struct Sim {
+        world: World,
+        resources: Resources,
+        pool: CommandPool,
+        command_pool: CommandPoolResource,
+        atom_map: AtomMap,
+        pipeline: PhysicsPipeline,
+        reader: ReaderId<PhysicsEvent>,
+        tick: u64,
+    }
+
+    impl Sim {
+        fn new(atom_map: AtomMap) -> Self {
+            let mut pipeline = PhysicsPipeline::default();
+            let reader = pipeline.register_reader();
+            Self {
+                world: World::new(),
+                resources: Resources::new(),
+                pool: CommandPool::new(),
+                command_pool: CommandPoolResource::new(),
+                atom_map,
+                pipeline,
+                reader,
+                tick: 0,
+            }
+        }
+
+        fn spawn_static(&mut self, at: Vector3<f32>, physics: Physics) -> Entity {
+            self.world
+                .spawn((Position::new(CompositePosition3::new(at, 0.)), physics))
+        }
+
+        fn spawn_dynamic(
+            &mut self,
+            at: Vector3<f32>,
+            vel: Vector3<f32>,
+            physics: Physics,
+        ) -> Entity {
+            self.world.spawn((
+                Position::new(CompositePosition3::new(at, 0.)),
+                Velocity {
+                    composite: CompositeVelocity3::new(vel, 0.),
+                },
+                physics,
+            ))
+        }
+
+        fn step(&mut self) {
+            let (tick, atom_map, pipeline) = (self.tick, &self.atom_map, &mut self.pipeline);
+            let command_pool = &self.command_pool;
+            command_pool.run_scoped(&self.pool, &mut self.world, &mut self.resources, |world| {
+                update.run(
+                    world,
+                    (
+                        &UpdateDt(DT),
+                        &UpdateTick(tick),
+                        atom_map,
+                        pipeline,
+                        command_pool,
+                    ),
+                )
+            });
+            self.tick += 1;
+        }
+
+        fn steps(&mut self, n: usize) {
+            for _ in 0..n {
+                self.step();
+            }
+        }
+
+        fn events(&mut self) -> Vec<PhysicsEvent> {
+            self.pipeline
+                .events
+                .read(&mut self.reader)
+                .copied()
+                .collect()
+        }
+
+        fn position(&self, e: Entity) -> Vector3<f32> {
+            self.world.get::<Position>(e).unwrap().current.translation
+        }
+
+        fn velocity(&self, e: Entity) -> CompositeVelocity3 {
+            self.world.get::<Velocity>(e).unwrap().composite
+        }
+
+        fn set_velocity(&mut self, e: Entity, linear: Vector3<f32>) {
+            self.world.get_mut::<Velocity>(e).unwrap().composite.linear = linear;
+        }
+
+        /// Whether a body is asleep, checking that its `Sleeping` marker agrees.
+        fn asleep(&self, e: Entity) -> bool {
+            let sleeping = self.world.get::<Physics>(e).unwrap().sleeping;
+            let marked = self.world.get::<Sleeping>(e).is_ok();
+            assert_eq!(marked, sleeping, "stale `Sleeping` marker on {:?}", e);
+            sleeping
+        }
+
+        /// The part of `e` in each of its current contacts, w/ the contact's material.
+        fn contact_parts(&self, e: Entity) -> Vec<(u32, Material)> {
+            let mut parts = self
+                .pipeline
+                .contacts()
+                .flat_map(|(_, constraint)| {
+                    [false, true].into_iter().filter_map(move |flipped| {
+                        (constraint.participants.get_participant(flipped) == e).then(|| {
+                            (
+                                constraint.participants.get_part(flipped),
+                                constraint.material,
+                            )
+                        })
+                    })
+                })
+                .collect::<Vec<_>>();
+            parts.sort_by_key(|&(part, _)| part);
+            parts.dedup_by_key(|&mut (part, _)| part);
+            parts
+        }
+    }
+
+    #[test]
+    fn empty_colliders_are_rejected() {
+        assert!(ColliderParts(Vec::new()).validate().is_err());
+        assert!(ColliderParts::from(SharedShape::ball(1.))
+            .validate()
+            .is_ok());
+
+        let lua = Lua::new();
+        let empty = lua.create_table().unwrap();
+        assert!(ColliderParts::from_lua(LuaValue::Table(empty), &lua).is_err());
+    }
+
+    #[test]
+    fn compound_mass_properties_sum_offset_parts() {
+        let unit_cube = || SharedShape::cuboid(0.5, 0.5, 0.5);
+
+        // A unit cube has a mass of 1 and a moment of inertia of 1/6 about its center.
+        let single = Physics::new(part_at(3., 0., 0., unit_cube()));
+        assert!((single.mass_data.inv_mass - 1.).abs() < 1e-4);
+        assert!((single.mass_data.inv_inertia - 6.).abs() < 1e-3);
+
+        // Two cubes 1 apart have their center of mass halfway between them, so each contributes
+        // 1/6 + 1 * 0.5^2 by the parallel axis theorem. The sensor part adds nothing.
+        let compound = Physics::new(vec![
+            part_at(1., 0., 0., unit_cube()),
+            part_at(2., 0., 0., unit_cube()),
+            part_at(-4., 0., 0., SharedShape::ball(2.)).with_sensor(true),
+        ]);
+        assert!((compound.mass_data.inv_mass.recip() - 2.).abs() < 1e-4);
+        let inertia = compound.mass_data.inv_inertia.recip();
+        assert!(
+            (inertia - 2. * (1. / 6. + 0.25)).abs() < 1e-3,
+            "{}",
+            inertia
+        );
+
+        // Scaling to a given mass keeps the distribution.
+        let heavy = compound.with_mass(8.);
+        assert!((heavy.mass_data.inv_mass.recip() - 8.).abs() < 1e-3);
+        assert!((heavy.mass_data.inv_inertia.recip() - 4. * inertia).abs() < 1e-2);
+    }
+
+    #[test]
+    fn parts_use_their_own_materials() {
+        let mut sim = Sim::new(AtomMap::new());
+        let ground_material = Material::new(1., 0.4, 0.4);
+        sim.spawn_static(
+            Vector3::zeros(),
+            Physics::new(SharedShape::cuboid(10., 10., 0.5))
+                .with_restitution(ground_material.restitution)
+                .with_friction(ground_material.static_friction),
+        );
+
+        let own_material = Material::new(0.8, 0.9, 0.7);
+        let body = Physics::new(vec![
+            part_at(-1., 0., 0., SharedShape::cuboid(0.5, 0.5, 0.5)).with_material(own_material),
+            part_at(1., 0., 0., SharedShape::cuboid(0.5, 0.5, 0.5)),
+        ])
+        .with_restitution(0.5)
+        .with_friction(0.3);
+        let body_material = body.material();
+        let e = sim.spawn_dynamic(Vector3::new(0., 0., 0.99), Vector3::zeros(), body);
+        sim.step();
+
+        assert_eq!(
+            sim.contact_parts(e),
+            vec![
+                (0, own_material.combine(Some(&ground_material))),
+                (1, body_material.combine(Some(&ground_material))),
+            ]
+        );
+    }
+
+    #[test]
+    fn part_restitution_affects_bounces() {
+        let mut sim = Sim::new(AtomMap::new());
+        sim.spawn_static(
+            Vector3::zeros(),
+            Physics::new(SharedShape::cuboid(10., 10., 0.5)).with_restitution(1.),
+        );
+
+        let bouncy =
+            ColliderPart::from(SharedShape::ball(0.5)).with_material(Material::new(1., 0., 0.));
+        let bouncy = sim.spawn_dynamic(
+            Vector3::new(-3., 0., 1.2),
+            Vector3::new(0., 0., -5.),
+            Physics::new(bouncy),
+        );
+        let dull = sim.spawn_dynamic(
+            Vector3::new(3., 0., 1.2),
+            Vector3::new(0., 0., -5.),
+            Physics::new(SharedShape::ball(0.5)),
+        );
+        sim.steps(15);
+
+        assert!(
+            sim.velocity(bouncy).linear.z > 2.5,
+            "{:?}",
+            sim.velocity(bouncy)
+        );
+        assert!(
+            sim.velocity(dull).linear.z.abs() < 1.,
+            "{:?}",
+            sim.velocity(dull)
+        );
+        assert!(sim.position(dull).z > 0.9);
+    }
+
+    #[test]
+    fn events_report_the_parts_involved() {
+        let mut sim = Sim::new(lattice([Vector3::new(2, 0, 0)]));
+
+        // Only the second part is over the cube, so only it touches the lattice.
+        let body = sim.spawn_dynamic(
+            Vector3::new(2.5, 0.5, 1.3),
+            Vector3::new(0., 0., -5.),
+            Physics::new(vec![
+                part_at(-2., 0., 0., SharedShape::ball(0.25)),
+                part_at(0., 0., 0., SharedShape::ball(0.25)),
+            ]),
+        );
+
+        // Only the second part of the sensor overlaps anything.
+        let sensor = sim.spawn_static(
+            Vector3::new(10., 0., 5.),
+            Physics::new(vec![
+                part_at(-5., 0., 0., SharedShape::ball(1.)).with_sensor(true),
+                part_at(5., 0., 0., SharedShape::ball(1.)).with_sensor(true),
+            ]),
+        );
+        let visitor = sim.spawn_dynamic(
+            Vector3::new(15., 0., 5.),
+            Vector3::zeros(),
+            Physics::new(SharedShape::ball(0.5)),
+        );
+
+        let mut began = Vec::new();
+        let mut triggers = Vec::new();
+        for _ in 0..10 {
+            sim.step();
+            for event in sim.events() {
+                match event {
+                    PhysicsEvent::BeginContact(id) => {
+                        let (_, constraint) =
+                            sim.pipeline.contacts().find(|&(c, _)| c == id).unwrap();
+                        began.push(constraint.participants);
+                    }
+                    PhysicsEvent::TriggerEnter(..) => triggers.push(event),
+                    _ => {}
+                }
+            }
+        }
+
+        assert!(!began.is_empty());
+        for participants in began {
+            assert!(
+                matches!(participants, ConstrainedPair::Static(e, 1, ..) if e == body),
+                "{:?}",
+                participants
+            );
+        }
+        assert_eq!(
+            triggers,
+            vec![PhysicsEvent::TriggerEnter(sensor, 1, visitor)]
+        );
+    }
+
+    #[test]
+    fn bodies_at_rest_fall_asleep_after_time_to_sleep() {
+        let mut sim = Sim::new(AtomMap::new());
+        let ball = || Physics::new(SharedShape::ball(0.5));
+        let resting = sim.spawn_dynamic(Vector3::zeros(), Vector3::zeros(), ball());
+        // Slower than the sleep threshold, so it counts as being at rest.
+        let creeping = sim.spawn_dynamic(Vector3::new(10., 0., 0.), Vector3::x() * 0.05, ball());
+        let moving = sim.spawn_dynamic(Vector3::new(-10., 0., 0.), Vector3::x() * 0.2, ball());
+
+        sim.steps(25);
+        assert!(!sim.asleep(resting));
+        assert!(!sim.asleep(creeping));
+
+        sim.steps(10);
+        assert!(sim.asleep(resting));
+        assert!(sim.asleep(creeping));
+        assert_eq!(sim.velocity(creeping).linear, Vector3::zeros());
+        assert!(!sim.asleep(moving));
+
+        sim.steps(60);
+        assert!(!sim.asleep(moving));
+    }
+
+    #[test]
+    fn writing_to_a_body_wakes_it() {
+        let mut sim = Sim::new(AtomMap::new());
+        let e = sim.spawn_dynamic(
+            Vector3::zeros(),
+            Vector3::zeros(),
+            Physics::new(SharedShape::ball(0.5)),
+        );
+        sim.steps(40);
+        assert!(sim.asleep(e));
+
+        sim.set_velocity(e, Vector3::x());
+        sim.step();
+        assert!(!sim.asleep(e));
+        assert!(sim.position(e).x > 0.);
+
+        sim.set_velocity(e, Vector3::zeros());
+        sim.steps(40);
+        assert!(sim.asleep(e));
+
+        sim.world
+            .get_mut::<Position>(e)
+            .unwrap()
+            .current
+            .translation
+            .y = 3.;
+        sim.step();
+        assert!(!sim.asleep(e));
+        assert_eq!(sim.position(e).y, 3.);
+    }
+
+    #[test]
+    fn islands_sleep_and_wake_together() {
+        let mut sim = Sim::new(AtomMap::new());
+        let ball = || Physics::new(SharedShape::ball(0.5));
+
+        // A row of three touching balls, w/ the last one drifting just fast enough to stay awake.
+        // They form a single island, so none of them can fall asleep.
+        let row = [0., 0.99, 1.98]
+            .map(|x| sim.spawn_dynamic(Vector3::new(x, 0., 0.), Vector3::zeros(), ball()));
+        sim.set_velocity(row[2], Vector3::y() * 0.2);
+        // Far enough away to be its own island.
+        let loner = sim.spawn_dynamic(Vector3::new(10., 0., 0.), Vector3::zeros(), ball());
+
+        sim.steps(10);
+        sim.set_velocity(row[2], Vector3::zeros());
+        sim.steps(25);
+        assert!(sim.asleep(loner));
+        assert!(row.iter().all(|&e| !sim.asleep(e)));
+
+        sim.steps(10);
+        assert!(row.iter().all(|&e| sim.asleep(e)));
+
+        // Nudging one end of the row wakes the whole row through its contacts, but not the loner.
+        sim.set_velocity(row[0], -Vector3::x() * 0.5);
+        sim.step();
+        assert!(row.iter().all(|&e| !sim.asleep(e)));
+        assert!(sim.asleep(loner));
+    }
+
+    #[test]
+    fn removing_the_ground_wakes_bodies_resting_on_it() {
+        let floor = |x0| (x0..x0 + 3).flat_map(|x| (0..3).map(move |y| Vector3::new(x, y, 0)));
+        let mut sim = Sim::new(lattice(floor(0).chain(floor(40))));
+        sim.pipeline.config.gravity = Vector3::new(0., 0., -10.);
+
+        let ball = || Physics::new(SharedShape::ball(0.5));
+        let above_removed =
+            sim.spawn_dynamic(Vector3::new(1.5, 1.5, 1.5), Vector3::zeros(), ball());
+        let above_kept = sim.spawn_dynamic(Vector3::new(41.5, 1.5, 1.5), Vector3::zeros(), ball());
+
+        for _ in 0..300 {
+            sim.step();
+            if sim.asleep(above_removed) && sim.asleep(above_kept) {
+                break;
+            }
+        }
+        assert!(sim.asleep(above_removed) && sim.asleep(above_kept));
+
+        for coords in floor(0) {
+            sim.atom_map.atoms_mut().remove(coords);
+        }
+        sim.atom_map.update_hulls();
+        sim.steps(30);
+
+        assert!(!sim.asleep(above_removed));
+        assert!(sim.position(above_removed).z < 1.);
+        assert!(sim.asleep(above_kept));
+        assert!((sim.position(above_kept).z - 1.5).abs() < 0.05);
+    }
+
+    #[test]
+    fn bodies_passing_through_sensors_enter_and_exit_once() {
+        let mut sim = Sim::new(AtomMap::new());
+        let sensor = sim.world.spawn((
+            Position::new(CompositePosition3::new(Vector3::zeros(), 0.)),
+            Physics::new(SharedShape::ball(1.)),
+            SensorMarker,
+        ));
+        let vel = Vector3::new(6., 0., 0.);
+        let body = sim.spawn_dynamic(
+            Vector3::new(-4., 0., 0.),
+            vel,
+            Physics::new(SharedShape::ball(0.5)),
+        );
+
+        let mut events = Vec::new();
+        let mut overlapping = Vec::new();
+        for _ in 0..90 {
+            sim.step();
+            events.extend(sim.events());
+            overlapping.push(sim.position(body).x.abs() < 1.5);
+        }
+
+        assert_eq!(
+            events,
+            vec![
+                PhysicsEvent::TriggerEnter(sensor, 0, body),
+                PhysicsEvent::TriggerExit(sensor, 0, body),
+            ]
+        );
+        // The body passed straight through w/o being pushed around.
+        assert!(overlapping.iter().any(|&overlapping| overlapping));
+        assert!(sim.position(body).x > 1.5);
+        assert_eq!(sim.velocity(body).linear, vel);
+        assert!(sim.contact_parts(body).is_empty());
+    }
+
+    #[test]
+    fn sensor_parts_do_not_push_bodies_around() {
+        let mut sim = Sim::new(AtomMap::new());
+        let wall = sim.spawn_static(
+            Vector3::new(3., 0., 0.),
+            Physics::new(SharedShape::cuboid(0.5, 5., 5.)),
+        );
+
+        // The sensor part leads the way into the wall, and the solid part follows it.
+        let vel = Vector3::new(3., 0., 0.);
+        let body = sim.spawn_dynamic(
+            Vector3::new(-1., 0., 0.),
+            vel,
+            Physics::new(vec![
+                part_at(0., 0., 0., SharedShape::ball(0.5)),
+                part_at(2., 0., 0., SharedShape::ball(0.5)).with_sensor(true),
+            ]),
+        );
+
+        let mut events = Vec::new();
+        for _ in 0..30 {
+            sim.step();
+            events.extend(sim.events());
+        }
+
+        assert_eq!(events, vec![PhysicsEvent::TriggerEnter(body, 1, wall)]);
+        assert_eq!(sim.velocity(body).linear, vel);
+        assert!(sim.contact_parts(body).is_empty());
+
+        // Once the solid part reaches the wall, it stops there.
+        sim.steps(40);
+        assert!(!sim.contact_parts(body).is_empty());
+        assert!(sim.contact_parts(body).iter().all(|&(part, _)| part == 0));
+        assert!(sim.position(body).x < 2.05);
+    }
+
+    #[test]
+    fn sensors_do_not_detect_the_lattice() {
+        let mut sim = Sim::new(lattice([Vector3::new(0, 0, 0), Vector3::new(1, 0, 0)]));
+        sim.world.spawn((
+            Position::new(CompositePosition3::new(Vector3::new(1., 0.5, 0.5), 0.)),
+            Physics::new(SharedShape::ball(1.)),
+            SensorMarker,
+        ));
+
+        // A sensor part moving through the lattice neither detects it nor collides w/ it.
+        let vel = Vector3::new(0., 0., -1.);
+        let body = sim.spawn_dynamic(
+            Vector3::new(0.5, 0.5, 4.),
+            vel,
+            Physics::new(vec![
+                part_at(0., 0., 0., SharedShape::ball(0.25)),
+                part_at(0., 0., -2.5, SharedShape::ball(0.25)).with_sensor(true),
+            ]),
+        );
+
+        let mut events = Vec::new();
+        for _ in 0..60 {
+            sim.step();
+            events.extend(sim.events());
+        }
+
+        assert!(events.is_empty(), "{:?}", events);
+        assert_eq!(sim.velocity(body).linear, vel);
+        assert!(sim.contact_parts(body).is_empty());
+    }
+
+    /// A thin plate, 0.1 thick along X, moving along X fast enough to cross 100 times its own
+    /// thickness every tick.
+    fn spawn_bullet(sim: &mut Sim, at: Vector3<f32>) -> Entity {
+        let bullet = sim.spawn_dynamic(
+            at,
+            Vector3::new(10. / DT, 0., 0.),
+            Physics::new(SharedShape::cuboid(0.05, 0.5, 0.5)),
+        );
+        sim.world.insert_one(bullet, CcdEnabled).unwrap();
+        bullet
+    }
+
+    #[test]
+    fn fast_bodies_do_not_tunnel_through_dynamic_bodies() {
+        let mut sim = Sim::new(AtomMap::new());
+        let bullet = spawn_bullet(&mut sim, Vector3::zeros());
+        // Dynamic bodies only sweep against other dynamic bodies w/ CCD enabled.
+        let target = sim.spawn_dynamic(
+            Vector3::new(5., 0., 0.),
+            Vector3::zeros(),
+            Physics::new(SharedShape::cuboid(0.05, 0.5, 0.5)),
+        );
+        sim.world.insert_one(target, CcdEnabled).unwrap();
+
+        // W/o CCD, the bullet would end up 5 past the target. Instead, it stops where it touches
+        // it, give or take the TOI bias.
+        sim.step();
+        let gap = (sim.position(target).x - 0.05) - (sim.position(bullet).x + 0.05);
+        assert!(gap.abs() <= sim.pipeline.config.position_slop, "{}", gap);
+        assert_eq!(sim.position(target).x, 5.);
+
+        // And then it hits the target, rather than passing through on the next update.
+        sim.steps(10);
+        assert!(sim.position(bullet).x < sim.position(target).x);
+        assert!(sim.velocity(target).linear.x > 0.);
+    }
+
+    #[test]
+    fn fast_bodies_do_not_tunnel_through_the_lattice() {
+        let wall = (-1..2).flat_map(|y| (-1..2).map(move |z| Vector3::new(5, y, z)));
+        let mut sim = Sim::new(lattice(wall));
+        let bullet = spawn_bullet(&mut sim, Vector3::new(0.5, 0.5, 0.5));
+
+        sim.step();
+        let gap = 5. - (sim.position(bullet).x + 0.05);
+        assert!(gap.abs() <= sim.pipeline.config.position_slop, "{}", gap);
+
+        sim.steps(10);
+        assert!(sim.position(bullet).x + 0.05 <= 5. + sim.pipeline.config.position_slop);
+    }
+
+    // Memberships and filters which only accept each other's groups, and a filter for the second
+    // which doesn't accept the first even though the first accepts it.
+    const GROUP_A: CollisionGroups = CollisionGroups::new(0b01, 0b10);
+    const GROUP_B: CollisionGroups = CollisionGroups::new(0b10, 0b01);
+    const REJECTS_A: CollisionGroups = CollisionGroups::new(0b10, 0b10);
+
+    #[test]
+    fn groups_interact_only_if_both_accept_each_other() {
+        assert!(GROUP_A.interacts_with(GROUP_B));
+        assert!(GROUP_B.interacts_with(GROUP_A));
+        assert!(!GROUP_A.interacts_with(REJECTS_A));
+        assert!(!REJECTS_A.interacts_with(GROUP_A));
+        assert!(!GROUP_A.interacts_with(GROUP_A));
+        assert!(CollisionGroups::ALL.interacts_with(GROUP_A));
+        assert!(!CollisionGroups::NONE.interacts_with(CollisionGroups::ALL));
+    }
+
+    #[test]
+    fn groups_filter_contacts() {
+        for (other, interacts) in [(GROUP_B, true), (REJECTS_A, false)] {
+            let mut sim = Sim::new(AtomMap::new());
+            let ball = sim.spawn_dynamic(
+                Vector3::zeros(),
+                Vector3::x(),
+                Physics::new(SharedShape::ball(0.5)).with_collision_groups(GROUP_A),
+            );
+            sim.spawn_static(
+                Vector3::new(1.5, 0., 0.),
+                Physics::new(SharedShape::ball(0.5)).with_collision_groups(other),
+            );
+
+            let mut touched = false;
+            for _ in 0..90 {
+                sim.step();
+                touched |= !sim.contact_parts(ball).is_empty();
+            }
+            assert_eq!(touched, interacts);
+            assert_eq!(sim.position(ball).x > 1., !interacts);
+        }
+    }
+
+    #[test]
+    fn groups_filter_ccd() {
+        for (other, interacts) in [(GROUP_B, true), (REJECTS_A, false)] {
+            let mut sim = Sim::new(AtomMap::new());
+            let bullet = spawn_bullet(&mut sim, Vector3::zeros());
+            sim.world
+                .get_mut::<Physics>(bullet)
+                .unwrap()
+                .set_collision_groups(GROUP_A);
+            let target = sim.spawn_dynamic(
+                Vector3::new(5., 0., 0.),
+                Vector3::zeros(),
+                Physics::new(SharedShape::cuboid(0.05, 0.5, 0.5)).with_collision_groups(other),
+            );
+            sim.world.insert_one(target, CcdEnabled).unwrap();
+
+            sim.step();
+            assert_eq!(sim.position(bullet).x < 5., interacts);
+        }
+    }
+
+    #[test]
+    fn groups_filter_triggers() {
+        for (other, interacts) in [(GROUP_B, true), (REJECTS_A, false)] {
+            let mut sim = Sim::new(AtomMap::new());
+            let sensor = sim.world.spawn((
+                Position::new(CompositePosition3::new(Vector3::zeros(), 0.)),
+                Physics::new(SharedShape::ball(1.)).with_collision_groups(GROUP_A),
+                SensorMarker,
+            ));
+            let body = sim.spawn_dynamic(
+                Vector3::zeros(),
+                Vector3::zeros(),
+                Physics::new(SharedShape::ball(0.5)).with_collision_groups(other),
+            );
+
+            sim.step();
+            let expected = match interacts {
+                true => vec![PhysicsEvent::TriggerEnter(sensor, 0, body)],
+                false => vec![],
+            };
+            assert_eq!(sim.events(), expected);
+        }
+    }
+
+    #[test]
+    fn lattice_groups_filter_lattice_contacts_and_ccd() {
+        let floor = (-2..3).flat_map(|x| (10..13).map(move |y| Vector3::new(x, y, 0)));
+        let wall = (-1..2).flat_map(|y| (-1..2).map(move |z| Vector3::new(5, y, z)));
+        for (lattice_groups, interacts) in [(GROUP_B, true), (REJECTS_A, false)] {
+            let mut sim = Sim::new(lattice(floor.clone().chain(wall.clone())));
+            sim.pipeline.config.lattice_groups = lattice_groups;
+
+            let ball = sim.spawn_dynamic(
+                Vector3::new(0.5, 11.5, 3.),
+                Vector3::new(0., 0., -3.),
+                Physics::new(SharedShape::ball(0.5)).with_collision_groups(GROUP_A),
+            );
+            let bullet = spawn_bullet(&mut sim, Vector3::new(0.5, 0.5, 0.5));
+            sim.world
+                .get_mut::<Physics>(bullet)
+                .unwrap()
+                .set_collision_groups(GROUP_A);
+
+            sim.step();
+            assert_eq!(sim.position(bullet).x < 5., interacts);
+
+            let mut touched = false;
+            for _ in 0..60 {
+                sim.step();
+                touched |= !sim.contact_parts(ball).is_empty();
+            }
+            assert_eq!(touched, interacts);
+            assert_eq!(sim.position(ball).z < 1., !interacts);
+        }
+    }
+
+    /// A unit cube, w/ a mass of 1 and a moment of inertia of 1/6.
+    fn unit_cube_body() -> Physics {
+        Physics::new(SharedShape::cuboid(0.5, 0.5, 0.5))
+    }
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() <= 1e-4 * b.abs().max(1.), "{} != {}", a, b);
+    }
+
+    #[test]
+    fn forces_and_torques_accumulate_for_one_update() {
+        let mut sim = Sim::new(AtomMap::new());
+        let e = sim.spawn_dynamic(Vector3::zeros(), Vector3::zeros(), unit_cube_body());
+
+        // Forces applied before an update are summed, and integrated over that update only.
+        {
+            let mut physics = sim.world.get_mut::<Physics>(e).unwrap();
+            physics.apply_force(Vector3::new(3., 0., 0.));
+            physics.apply_force(Vector3::new(3., 0., 1.5));
+            physics.apply_torque(0.25);
+            physics.apply_torque(0.25);
+        }
+        sim.step();
+        assert_close(sim.velocity(e).linear.x, 6. * DT);
+        assert_close(sim.velocity(e).linear.z, 1.5 * DT);
+        assert_close(sim.velocity(e).angular, 0.5 * 6. * DT);
+        assert_eq!(
+            sim.world.get::<Physics>(e).unwrap().force(),
+            Vector3::zeros()
+        );
+        assert_eq!(sim.world.get::<Physics>(e).unwrap().torque(), 0.);
+
+        sim.step();
+        assert_close(sim.velocity(e).linear.x, 6. * DT);
+        assert_close(sim.velocity(e).angular, 0.5 * 6. * DT);
+
+        // Applying the same force every update accelerates the body steadily.
+        for _ in 0..10 {
+            sim.world
+                .get_mut::<Physics>(e)
+                .unwrap()
+                .apply_force(Vector3::new(6., 0., 0.));
+            sim.step();
+        }
+        assert_close(sim.velocity(e).linear.x, 11. * 6. * DT);
+
+        // A force off the body's position also produces a torque.
+        sim.world
+            .get_mut::<Physics>(e)
+            .unwrap()
+            .apply_force_at_point(
+                Vector3::new(0., 1., 0.),
+                Point3::from(sim.position(e)) + Vector3::x(),
+            );
+        let angular = sim.velocity(e).angular;
+        sim.step();
+        assert_close(sim.velocity(e).angular - angular, 6. * DT);
+    }
+
+    #[test]
+    fn impulses_change_velocities_immediately() {
+        let mut sim = Sim::new(AtomMap::new());
+        let e = sim.spawn_dynamic(
+            Vector3::zeros(),
+            Vector3::zeros(),
+            unit_cube_body().with_mass(2.),
+        );
+        sim.steps(40);
+        assert!(sim.asleep(e));
+
+        {
+            let mut physics = sim.world.get_mut::<Physics>(e).unwrap();
+            let mut velocity = sim.world.get_mut::<Velocity>(e).unwrap();
+            physics.apply_impulse(&mut velocity, Vector3::new(1., 0., 0.));
+            physics.apply_angular_impulse(&mut velocity, 1.);
+            physics.apply_impulse_at_point(
+                &mut velocity,
+                Vector3::new(0., 2., 0.),
+                Point3::new(-1., 0., 0.),
+            );
+            assert!(!physics.is_sleeping());
+            assert_close(velocity.composite.linear.x, 0.5);
+            assert_close(velocity.composite.linear.y, 1.);
+            // The cube's inertia scales w/ its mass, to 1/3; `(-1, 0) x (0, 2)` is -2.
+            assert_close(velocity.composite.angular, 3. * (1. - 2.));
+        }
+
+        sim.step();
+        assert!(!sim.asleep(e));
+        assert_close(sim.position(e).x, 0.5 * DT);
+        assert_close(sim.velocity(e).angular, -3.);
+    }
+
+    #[test]
+    fn damping_divides_velocities_every_update() {
+        let mut sim = Sim::new(AtomMap::new());
+        let e = sim.spawn_dynamic(
+            Vector3::zeros(),
+            Vector3::new(1., -2., 0.5),
+            unit_cube_body()
+                .with_linear_damping(2.)
+                .with_angular_damping(4.),
+        );
+        sim.world.get_mut::<Velocity>(e).unwrap().composite.angular = 3.;
+
+        for n in 1..=10 {
+            sim.step();
+            let linear = (1. + DT * 2.).powi(n).recip();
+            let angular = (1. + DT * 4.).powi(n).recip();
+            let velocity = sim.velocity(e);
+            assert_close(velocity.linear.x, linear);
+            assert_close(velocity.linear.y, -2. * linear);
+            assert_close(velocity.linear.z, 0.5 * linear);
+            assert_close(velocity.angular, 3. * angular);
+        }
+    }
+
+    #[test]
+    fn gravity_is_scaled_per_body() {
+        let mut sim = Sim::new(AtomMap::new());
+        sim.pipeline.config.gravity = Vector3::new(0., 0., -10.);
+        let bodies = [1., 0.5, 0., -1.].map(|k| {
+            let at = Vector3::new(k * 10., 0., 0.);
+            let e = sim.spawn_dynamic(at, Vector3::zeros(), unit_cube_body().with_gravity_scale(k));
+            (e, k)
+        });
+
+        // Forces are added to gravity, and damping is applied after both.
+        let damped = sim.spawn_dynamic(
+            Vector3::new(0., 10., 0.),
+            Vector3::zeros(),
+            unit_cube_body().with_linear_damping(1.),
+        );
+
+        let n = 12;
+        let mut expected = 0.;
+        for _ in 0..n {
+            sim.world
+                .get_mut::<Physics>(damped)
+                .unwrap()
+                .apply_force(Vector3::new(0., 0., 4.));
+            sim.step();
+            expected = (expected + (-10. + 4.) * DT) / (1. + DT);
+        }
+
+        for (e, k) in bodies {
+            assert_close(sim.velocity(e).linear.z, -10. * k * DT * n as f32);
+            // Velocities are integrated before positions.
+            let fallen = -10. * k * DT * DT * (n * (n + 1) / 2) as f32;
+            assert_close(sim.position(e).z, fallen);
+            assert_eq!(sim.velocity(e).linear.xy(), Vector2::zeros());
+        }
+        assert_close(sim.velocity(damped).linear.z, expected);
+    }
+}
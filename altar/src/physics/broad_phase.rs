@@ -0,0 +1,517 @@
+//! A persistent broad phase, kept up to date incrementally instead of being rebuilt every update.
+//!
+//! Colliders are stored in a dynamic AABB tree as "fattened" AABBs, loosened by a margin. A
+//! collider's leaf is only reinserted into the tree when its AABB escapes its fattened AABB, so
+//! colliders which are resting or moving slowly cost next to nothing to keep track of. Since
+//! fattened AABBs are conservative, queries may return a few more candidates than a tree built
+//! from exact AABBs would, but never fewer.
+//!
+//! Colliders are inserted the first time they're updated, and removed at the end of an update in
+//! which they weren't seen; so the contents of the tree follow whatever set of entities the ECS
+//! hands to [`BroadPhase::update`] each tick.
+
+use std::collections::HashMap;
+
+use parry3d::bounding_volume::{BoundingVolume, AABB};
+
+const NULL: usize = usize::MAX;
+
+fn surface_area(aabb: &AABB) -> f32 {
+    let e = aabb.extents();
+    2. * (e.x * e.y + e.y * e.z + e.z * e.x)
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    aabb: AABB,
+    parent: usize,
+    // Both `NULL` for leaves.
+    children: [usize; 2],
+    // Only meaningful for leaves.
+    id: u32,
+    // Zero for leaves.
+    height: u32,
+}
+
+impl Node {
+    fn is_leaf(&self) -> bool {
+        self.children[0] == NULL
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Proxy {
+    node: usize,
+    // The last update this proxy was seen in.
+    generation: u64,
+}
+
+#[derive(Debug)]
+pub(crate) struct BroadPhase {
+    nodes: Vec<Node>,
+    free_nodes: Vec<usize>,
+    root: usize,
+    proxies: HashMap<u32, Proxy>,
+    generation: u64,
+    margin: f32,
+}
+
+impl BroadPhase {
+    pub fn new(margin: f32) -> Self {
+        Self {
+            nodes: Vec::new(),
+            free_nodes: Vec::new(),
+            root: NULL,
+            proxies: HashMap::new(),
+            generation: 0,
+            margin,
+        }
+    }
+
+    /// Remove every collider, so that everything is reinserted on the next update.
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+        self.free_nodes.clear();
+        self.root = NULL;
+        self.proxies.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.proxies.len()
+    }
+
+    /// Start a new update. Any collider which isn't passed to [`BroadPhase::update`] or
+    /// [`BroadPhase::touch`] before [`BroadPhase::end_update`] is called will be removed.
+    pub fn begin_update(&mut self) {
+        self.generation += 1;
+    }
+
+    /// Insert a collider, or update the AABB of one already in the tree. Returns `true` if the
+    /// tree had to be modified.
+    pub fn update(&mut self, id: u32, aabb: AABB) -> bool {
+        let generation = self.generation;
+        if let Some(proxy) = self.proxies.get_mut(&id) {
+            proxy.generation = generation;
+            let node = proxy.node;
+            if self.nodes[node].aabb.contains(&aabb) {
+                return false;
+            }
+
+            self.remove_leaf(node);
+            self.nodes[node].aabb = aabb.loosened(self.margin);
+            self.insert_leaf(node);
+        } else {
+            let node = self.allocate(Node {
+                aabb: aabb.loosened(self.margin),
+                parent: NULL,
+                children: [NULL; 2],
+                id,
+                height: 0,
+            });
+            self.insert_leaf(node);
+            self.proxies.insert(id, Proxy { node, generation });
+        }
+
+        true
+    }
+
+    /// Mark a collider as still present without updating its AABB. Returns `false` if the
+    /// collider isn't in the tree, in which case it must be inserted with [`BroadPhase::update`].
+    pub fn touch(&mut self, id: u32) -> bool {
+        match self.proxies.get_mut(&id) {
+            Some(proxy) => {
+                proxy.generation = self.generation;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Finish an update, removing every collider which wasn't seen since
+    /// [`BroadPhase::begin_update`].
+    pub fn end_update(&mut self) {
+        let generation = self.generation;
+        let mut stale = Vec::new();
+        self.proxies.retain(|_, proxy| {
+            let seen = proxy.generation == generation;
+            if !seen {
+                stale.push(proxy.node);
+            }
+            seen
+        });
+
+        for node in stale {
+            self.remove_leaf(node);
+            self.free_nodes.push(node);
+        }
+    }
+
+    /// Collect the ids of every collider whose fattened AABB intersects `aabb`.
+    pub fn intersect_aabb(&self, aabb: &AABB, out: &mut Vec<u32>) {
+        if self.root == NULL {
+            return;
+        }
+
+        let mut stack = vec![self.root];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if !node.aabb.intersects(aabb) {
+                continue;
+            }
+
+            if node.is_leaf() {
+                out.push(node.id);
+            } else {
+                stack.extend_from_slice(&node.children);
+            }
+        }
+    }
+
+    /// Visit the fattened AABB of every node in the tree, internal nodes included, along w/ whether
+    /// the node is a leaf.
+    pub fn for_each_node(&self, mut f: impl FnMut(&AABB, bool)) {
+        if self.root == NULL {
+            return;
+        }
+
+        let mut stack = vec![self.root];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            f(&node.aabb, node.is_leaf());
+            if !node.is_leaf() {
+                stack.extend_from_slice(&node.children);
+            }
+        }
+    }
+
+    fn allocate(&mut self, node: Node) -> usize {
+        match self.free_nodes.pop() {
+            Some(index) => {
+                self.nodes[index] = node;
+                index
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    fn replace_child(&mut self, parent: usize, old: usize, new: usize) {
+        if parent == NULL {
+            self.root = new;
+        } else {
+            let children = &mut self.nodes[parent].children;
+            let slot = if children[0] == old { 0 } else { 1 };
+            children[slot] = new;
+        }
+    }
+
+    fn insert_leaf(&mut self, leaf: usize) {
+        if self.root == NULL {
+            self.root = leaf;
+            self.nodes[leaf].parent = NULL;
+            return;
+        }
+
+        // Walk down the tree, picking the sibling which minimizes the total surface area added to
+        // the tree.
+        let leaf_aabb = self.nodes[leaf].aabb;
+        let mut index = self.root;
+        while !self.nodes[index].is_leaf() {
+            let node = &self.nodes[index];
+            let area = surface_area(&node.aabb);
+            let combined_area = surface_area(&node.aabb.merged(&leaf_aabb));
+
+            // The cost of making the leaf a sibling of this node, and the minimum cost pushed down
+            // to the children if it goes further down instead.
+            let cost = 2. * combined_area;
+            let inheritance_cost = 2. * (combined_area - area);
+
+            let child_cost = |child: &Node| {
+                let merged_area = surface_area(&child.aabb.merged(&leaf_aabb));
+                if child.is_leaf() {
+                    merged_area + inheritance_cost
+                } else {
+                    merged_area - surface_area(&child.aabb) + inheritance_cost
+                }
+            };
+
+            let [child1, child2] = node.children;
+            let cost1 = child_cost(&self.nodes[child1]);
+            let cost2 = child_cost(&self.nodes[child2]);
+
+            if cost < cost1 && cost < cost2 {
+                break;
+            }
+
+            index = if cost1 < cost2 { child1 } else { child2 };
+        }
+
+        let sibling = index;
+        let old_parent = self.nodes[sibling].parent;
+        let new_parent = self.allocate(Node {
+            aabb: leaf_aabb.merged(&self.nodes[sibling].aabb),
+            parent: old_parent,
+            children: [sibling, leaf],
+            id: 0,
+            height: self.nodes[sibling].height + 1,
+        });
+
+        self.replace_child(old_parent, sibling, new_parent);
+        self.nodes[sibling].parent = new_parent;
+        self.nodes[leaf].parent = new_parent;
+
+        self.fix_upwards(new_parent);
+    }
+
+    /// Unlink a leaf from the tree. The leaf's node isn't freed.
+    fn remove_leaf(&mut self, leaf: usize) {
+        if leaf == self.root {
+            self.root = NULL;
+            return;
+        }
+
+        let parent = self.nodes[leaf].parent;
+        let grandparent = self.nodes[parent].parent;
+        let [child1, child2] = self.nodes[parent].children;
+        let sibling = if child1 == leaf { child2 } else { child1 };
+
+        self.replace_child(grandparent, parent, sibling);
+        self.nodes[sibling].parent = grandparent;
+        self.free_nodes.push(parent);
+
+        if grandparent != NULL {
+            self.fix_upwards(grandparent);
+        }
+    }
+
+    /// Rebalance and refit every node from `index` up to the root.
+    fn fix_upwards(&mut self, mut index: usize) {
+        while index != NULL {
+            index = self.balance(index);
+
+            let [child1, child2] = self.nodes[index].children;
+            let (c1, c2) = (&self.nodes[child1], &self.nodes[child2]);
+            let height = 1 + c1.height.max(c2.height);
+            let aabb = c1.aabb.merged(&c2.aabb);
+
+            let node = &mut self.nodes[index];
+            node.height = height;
+            node.aabb = aabb;
+            index = node.parent;
+        }
+    }
+
+    /// If the subtree rooted at `a` is unbalanced, rotate its taller child up into its place.
+    /// Returns the index of the new root of the subtree.
+    fn balance(&mut self, a: usize) -> usize {
+        if self.nodes[a].is_leaf() || self.nodes[a].height < 2 {
+            return a;
+        }
+
+        let [b, c] = self.nodes[a].children;
+        let balance = self.nodes[c].height as i64 - self.nodes[b].height as i64;
+        if balance > 1 {
+            self.rotate_up(a, c, b)
+        } else if balance < -1 {
+            self.rotate_up(a, b, c)
+        } else {
+            a
+        }
+    }
+
+    /// Swap `a` with its child `c`, giving `a` the shorter of `c`'s children. `b` is the other
+    /// child of `a`.
+    fn rotate_up(&mut self, a: usize, c: usize, b: usize) -> usize {
+        let [f, g] = self.nodes[c].children;
+        let a_parent = self.nodes[a].parent;
+
+        self.nodes[c].parent = a_parent;
+        self.nodes[a].parent = c;
+        self.replace_child(a_parent, a, c);
+
+        let (taller, shorter) = if self.nodes[f].height > self.nodes[g].height {
+            (f, g)
+        } else {
+            (g, f)
+        };
+
+        self.nodes[c].children = [a, taller];
+        self.replace_child(a, c, shorter);
+        self.nodes[shorter].parent = a;
+
+        let a_aabb = self.nodes[b].aabb.merged(&self.nodes[shorter].aabb);
+        let a_height = 1 + self.nodes[b].height.max(self.nodes[shorter].height);
+        self.nodes[a].aabb = a_aabb;
+        self.nodes[a].height = a_height;
+
+        self.nodes[c].aabb = a_aabb.merged(&self.nodes[taller].aabb);
+        self.nodes[c].height = 1 + a_height.max(self.nodes[taller].height);
+
+        c
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+
+    use hv::prelude::*;
+    use parry3d::partitioning::QBVH;
+
+    // A small xorshift generator, so the test is reproducible without pulling in `rand`.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next_f32(&mut self) -> f32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 >> 40) as f32 / (1u64 << 24) as f32
+        }
+
+        fn range(&mut self, min: f32, max: f32) -> f32 {
+            min + (max - min) * self.next_f32()
+        }
+
+        fn vector(&mut self, min: f32, max: f32) -> Vector3<f32> {
+            Vector3::new(
+                self.range(min, max),
+                self.range(min, max),
+                self.range(min, max),
+            )
+        }
+
+        fn aabb(&mut self) -> AABB {
+            let center = Point3::from(self.vector(-50., 50.));
+            AABB::from_half_extents(center, self.vector(0.1, 2.))
+        }
+    }
+
+    fn check_invariants(broad_phase: &BroadPhase) {
+        let mut leaves = 0;
+        let mut stack = Vec::new();
+        if broad_phase.root != NULL {
+            assert_eq!(broad_phase.nodes[broad_phase.root].parent, NULL);
+            stack.push(broad_phase.root);
+        }
+
+        while let Some(index) = stack.pop() {
+            let node = &broad_phase.nodes[index];
+            if node.is_leaf() {
+                assert_eq!(node.height, 0);
+                assert_eq!(broad_phase.proxies[&node.id].node, index);
+                leaves += 1;
+                continue;
+            }
+
+            let [child1, child2] = node.children;
+            let (c1, c2) = (&broad_phase.nodes[child1], &broad_phase.nodes[child2]);
+            assert_eq!(c1.parent, index);
+            assert_eq!(c2.parent, index);
+            assert_eq!(node.height, 1 + c1.height.max(c2.height));
+            assert!(node.aabb.contains(&c1.aabb) && node.aabb.contains(&c2.aabb));
+            stack.extend_from_slice(&node.children);
+        }
+
+        assert_eq!(leaves, broad_phase.len());
+    }
+
+    #[test]
+    fn matches_full_rebuild() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        let mut broad_phase = BroadPhase::new(0.1);
+        let mut aabbs = BTreeMap::new();
+        let mut next_id = 0;
+
+        for _ in 0..200 {
+            aabbs.insert(next_id, rng.aabb());
+            next_id += 1;
+        }
+
+        for _ in 0..50 {
+            // Jitter some colliders, teleport others, and resize a few.
+            for aabb in aabbs.values_mut() {
+                let roll = rng.next_f32();
+                if roll < 0.5 {
+                    let offset = rng.vector(-0.05, 0.05);
+                    *aabb = AABB::new(aabb.mins + offset, aabb.maxs + offset);
+                } else if roll < 0.6 {
+                    *aabb = rng.aabb();
+                } else if roll < 0.65 {
+                    *aabb = AABB::from_half_extents(aabb.center(), rng.vector(0.1, 2.));
+                }
+            }
+
+            // Remove and insert a few.
+            let removed = aabbs
+                .keys()
+                .copied()
+                .filter(|_| rng.next_f32() < 0.05)
+                .collect::<Vec<_>>();
+            for id in removed {
+                aabbs.remove(&id);
+            }
+
+            for _ in 0..10 {
+                aabbs.insert(next_id, rng.aabb());
+                next_id += 1;
+            }
+
+            broad_phase.begin_update();
+            for (&id, aabb) in &aabbs {
+                broad_phase.update(id, *aabb);
+            }
+            broad_phase.end_update();
+            check_invariants(&broad_phase);
+            assert_eq!(broad_phase.len(), aabbs.len());
+
+            let mut qbvh = QBVH::new();
+            qbvh.clear_and_rebuild(aabbs.iter().map(|(&id, &aabb)| (id, aabb)), 0.);
+
+            for _ in 0..20 {
+                let query = rng.aabb();
+
+                let mut from_broad_phase = Vec::new();
+                broad_phase.intersect_aabb(&query, &mut from_broad_phase);
+                from_broad_phase.retain(|id| aabbs[id].intersects(&query));
+                from_broad_phase.sort_unstable();
+
+                let mut from_qbvh = Vec::new();
+                qbvh.intersect_aabb(&query, &mut from_qbvh);
+                from_qbvh.retain(|id| aabbs[id].intersects(&query));
+                from_qbvh.sort_unstable();
+
+                assert_eq!(from_broad_phase, from_qbvh);
+            }
+        }
+    }
+
+    #[test]
+    fn untouched_colliders_are_removed() {
+        let mut broad_phase = BroadPhase::new(0.1);
+        let aabb = AABB::new(Point3::origin(), Point3::new(1., 1., 1.));
+
+        broad_phase.begin_update();
+        assert!(broad_phase.update(0, aabb));
+        assert!(broad_phase.update(1, aabb));
+        broad_phase.end_update();
+
+        broad_phase.begin_update();
+        assert!(!broad_phase.update(0, aabb));
+        broad_phase.end_update();
+        check_invariants(&broad_phase);
+
+        let mut out = Vec::new();
+        broad_phase.intersect_aabb(&aabb, &mut out);
+        assert_eq!(out, vec![0]);
+
+        broad_phase.begin_update();
+        assert!(!broad_phase.touch(1));
+        broad_phase.end_update();
+        assert_eq!(broad_phase.len(), 0);
+    }
+}
@@ -0,0 +1,792 @@
+//! A kinematic character controller.
+//!
+//! A [`CharacterController`] moves a kinematic body by sweeping its collider through the world
+//! instead of simulating it. The body slides along walls, walks up slopes no steeper than a maximum
+//! angle, steps up onto small ledges, stays stuck to the ground when walking down slopes and
+//! stairs, and rides along with any kinematic body it's standing on.
+//!
+//! The controller doesn't move the body directly; [`update`] writes the velocity which takes the
+//! body where it should go into its [`Velocity`] component, and the body is then moved by the
+//! physics update. So `update` should run right before [`physics::update`], and controlled bodies
+//! should have a [`KinematicMarker`]. Gravity, jumping and so on are left to gameplay code, which
+//! should fold them into [`CharacterController::desired_velocity`].
+//!
+//! [`physics::update`]: crate::physics::update
+//! [`KinematicMarker`]: crate::physics::KinematicMarker
+
+use hv::{
+    ecs::{PreparedQuery, QueryMarker, SystemContext, With},
+    prelude::*,
+};
+use parry3d::shape::Shape;
+
+use crate::{
+    lattice::atom_map::AtomMap,
+    physics::{
+        query::{QueryFilter, QueryTarget, ShapeContact, ShapeHit},
+        CompositePosition3, KinematicMarker, Physics, PhysicsPipeline, Position, Velocity,
+    },
+    types::{Float, UpdateDt},
+};
+
+/// Distances shorter than this are treated as no motion at all.
+const MIN_MOTION: Float = 1e-5;
+
+/// How many times the controller will try to push a character out of things it's overlapping
+/// before giving up and moving it anyway.
+const MAX_DEPENETRATION_ITERATIONS: usize = 4;
+
+/// The surface a character is standing on.
+#[derive(Debug, Clone, Copy)]
+pub struct Ground {
+    pub target: QueryTarget,
+    /// World-space point on the ground which the character is standing on.
+    pub point: Point3<Float>,
+    /// Surface normal of the ground, pointing towards the character.
+    pub normal: UnitVector3<Float>,
+}
+
+/// A character controller component. Requires [`Position`], [`Velocity`] and [`Physics`]
+/// components on the same entity.
+#[derive(Debug, Clone, Copy)]
+pub struct CharacterController {
+    /// The direction the character considers to be "up". Default: +Z.
+    pub up: UnitVector3<Float>,
+    /// The steepest slope, in radians, which the character can stand on and walk up. Default: 45
+    /// degrees.
+    pub max_slope: Float,
+    /// The tallest ledge the character will step up onto. Default: `0.25`.
+    pub step_height: Float,
+    /// How far the character may be pulled down to keep it on the ground when walking down slopes
+    /// and stairs. Default: `0.25`.
+    pub snap_distance: Float,
+    /// The gap kept between the character's collider and everything around it. Default: `0.01`.
+    pub skin: Float,
+    /// The maximum number of times the character's motion will be redirected along a surface in a
+    /// single update. Default: `4`.
+    pub max_slides: u32,
+    /// The velocity the character is trying to move at, set by gameplay code. Default: zero.
+    pub desired_velocity: Vector3<Float>,
+
+    ground: Option<Ground>,
+    platform_motion: Vector3<Float>,
+}
+
+impl Default for CharacterController {
+    fn default() -> Self {
+        Self {
+            up: Vector3::z_axis(),
+            max_slope: std::f32::consts::FRAC_PI_4,
+            step_height: 0.25,
+            snap_distance: 0.25,
+            skin: 0.01,
+            max_slides: 4,
+            desired_velocity: Vector3::zeros(),
+            ground: None,
+            platform_motion: Vector3::zeros(),
+        }
+    }
+}
+
+impl CharacterController {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the character was standing on walkable ground at the end of the last update.
+    pub fn is_grounded(&self) -> bool {
+        self.ground.is_some()
+    }
+
+    /// The ground the character was standing on at the end of the last update, if any.
+    pub fn ground(&self) -> Option<&Ground> {
+        self.ground.as_ref()
+    }
+
+    /// Whether a surface with this normal is shallow enough to stand on.
+    pub fn is_walkable(&self, normal: &UnitVector3<Float>) -> bool {
+        normal.dot(&*self.up) >= self.max_slope.cos()
+    }
+}
+
+/// Sweeps the collider of a single character through the world.
+struct Mover<'a> {
+    pipeline: &'a PhysicsPipeline,
+    atom_map: &'a AtomMap,
+    controller: &'a CharacterController,
+    shape: &'a dyn Shape,
+    collider_tx: Isometry3<Float>,
+    rotation: UnitComplex<Float>,
+    filter: QueryFilter,
+}
+
+impl<'a> Mover<'a> {
+    /// The pose of the character's collider when the body is at `translation`.
+    fn pose(&self, translation: Vector3<Float>) -> Isometry3<Float> {
+        let position = CompositePosition3 {
+            translation,
+            rotation: self.rotation,
+        };
+        self.collider_tx * position.as_isometry3()
+    }
+
+    /// Bring a world-space normal into the frame the body is moved in.
+    fn to_body_frame(&self, normal: UnitVector3<Float>) -> UnitVector3<Float> {
+        self.collider_tx.rotation.inverse() * normal
+    }
+
+    /// Sweep the character from `translation` along `motion`, returning the distance it can move
+    /// while staying `skin` away from everything, and what it ran into, if anything.
+    fn sweep(
+        &self,
+        translation: Vector3<Float>,
+        motion: &Vector3<Float>,
+    ) -> (Float, Option<ShapeHit>) {
+        let distance = motion.norm();
+        if distance <= MIN_MOTION {
+            return (0., None);
+        }
+
+        let dir = motion / distance;
+        let maybe_hit = self.pipeline.cast_shape(
+            self.atom_map,
+            &self.pose(translation),
+            &(self.collider_tx.rotation * dir),
+            self.shape,
+            distance + self.controller.skin,
+            &self.filter,
+        );
+
+        match maybe_hit {
+            Some(mut hit) => {
+                hit.normal = self.to_body_frame(hit.normal);
+                // Something we're already touching but moving away from can't stop us.
+                if hit.toi <= 0. && hit.normal.dot(&dir) > 0. {
+                    return (distance, None);
+                }
+
+                let allowed = (hit.toi - self.controller.skin).clamp(0., distance);
+                (allowed, Some(hit))
+            }
+            None => (distance, None),
+        }
+    }
+
+    /// Push the character out of anything it's overlapping, leaving it `skin` away.
+    fn depenetrate(&self, translation: &mut Vector3<Float>, scratch: &mut Vec<ShapeContact>) {
+        let skin = self.controller.skin;
+        for _ in 0..MAX_DEPENETRATION_ITERATIONS {
+            scratch.clear();
+            self.pipeline.contacts_with_shape(
+                self.atom_map,
+                &self.pose(*translation),
+                self.shape,
+                skin,
+                &self.filter,
+                scratch,
+            );
+
+            let deepest = scratch
+                .iter()
+                .filter(|contact| contact.dist < skin * 0.5)
+                .min_by(|a, b| a.dist.total_cmp(&b.dist));
+
+            match deepest {
+                Some(contact) => {
+                    *translation +=
+                        self.to_body_frame(contact.normal).into_inner() * (skin - contact.dist);
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Move the character along `motion`, sliding along anything it runs into and stepping up onto
+    /// ledges if it's on the ground.
+    fn move_and_slide(
+        &self,
+        translation: &mut Vector3<Float>,
+        motion: Vector3<Float>,
+        grounded: bool,
+    ) {
+        let up = self.controller.up.into_inner();
+        let mut remaining = motion;
+        let mut tried_step = false;
+
+        for _ in 0..self.controller.max_slides {
+            let distance = remaining.norm();
+            if distance <= MIN_MOTION {
+                break;
+            }
+
+            let (allowed, maybe_hit) = self.sweep(*translation, &remaining);
+            *translation += remaining * (allowed / distance);
+            let hit = match maybe_hit {
+                Some(hit) if allowed < distance => hit,
+                _ => break,
+            };
+            remaining *= 1. - allowed / distance;
+
+            let mut normal = hit.normal;
+            if !self.controller.is_walkable(&normal) {
+                if grounded && !tried_step {
+                    tried_step = true;
+                    if let Some((stepped, rest)) = self.step_up(*translation, &remaining) {
+                        *translation = stepped;
+                        remaining = rest;
+                        continue;
+                    }
+                }
+
+                // Too steep to walk up; treat it as a vertical wall, unless we're moving down it,
+                // in which case we should slide down it.
+                if remaining.dot(&up) >= 0. {
+                    let flattened = normal.into_inner() - up * normal.dot(&up);
+                    if let Some(flattened) = UnitVector3::try_new(flattened, MIN_MOTION) {
+                        normal = flattened;
+                    }
+                }
+            }
+
+            let into_surface = normal.dot(&remaining);
+            if into_surface < 0. {
+                remaining -= normal.into_inner() * into_surface;
+            }
+        }
+    }
+
+    /// Try to step up onto a ledge: move up by the step height, then along the horizontal part of
+    /// `remaining`, then back down onto walkable ground. Returns the new translation and the motion
+    /// left over if the step succeeds.
+    fn step_up(
+        &self,
+        translation: Vector3<Float>,
+        remaining: &Vector3<Float>,
+    ) -> Option<(Vector3<Float>, Vector3<Float>)> {
+        let up = self.controller.up.into_inner();
+        let horizontal = remaining - up * remaining.dot(&up);
+        let horizontal_distance = horizontal.norm();
+        if self.controller.step_height <= 0. || horizontal_distance <= MIN_MOTION {
+            return None;
+        }
+
+        let (rise, _) = self.sweep(translation, &(up * self.controller.step_height));
+        if rise <= MIN_MOTION {
+            return None;
+        }
+        let raised = translation + up * rise;
+
+        let (advance, _) = self.sweep(raised, &horizontal);
+        if advance <= MIN_MOTION {
+            return None;
+        }
+        let advanced = raised + horizontal * (advance / horizontal_distance);
+
+        let (drop, maybe_ground) = self.sweep(advanced, &(-up * (rise + self.controller.skin)));
+        match maybe_ground {
+            Some(ground) if self.controller.is_walkable(&ground.normal) => Some((
+                advanced - up * drop,
+                horizontal * (1. - advance / horizontal_distance),
+            )),
+            _ => None,
+        }
+    }
+
+    /// Look for walkable ground below the character, pulling it down onto the ground if `snap` is
+    /// set.
+    fn probe_ground(&self, translation: &mut Vector3<Float>, snap: bool) -> Option<Ground> {
+        let up = self.controller.up.into_inner();
+        let probe_distance = if snap {
+            self.controller.snap_distance + self.controller.skin
+        } else {
+            self.controller.skin
+        };
+
+        let (drop, hit) = self.sweep(*translation, &(-up * probe_distance));
+        let hit = hit.filter(|hit| self.controller.is_walkable(&hit.normal))?;
+        if snap {
+            *translation -= up * drop;
+        }
+
+        Some(Ground {
+            target: hit.target,
+            point: hit.point,
+            normal: hit.normal,
+        })
+    }
+}
+
+/// Move every entity with a [`CharacterController`], writing the velocity which will take it to
+/// its new position into its [`Velocity`] component. Run this before [`physics::update`].
+///
+/// [`physics::update`]: crate::physics::update
+#[allow(clippy::type_complexity)]
+pub fn update(
+    context: SystemContext,
+    (dt, atom_map, pipeline): (&UpdateDt, &AtomMap, &PhysicsPipeline),
+    (ref mut riders_query, ref mut controllers_query, platform_query_marker): &mut (
+        PreparedQuery<(&mut CharacterController, &Position)>,
+        PreparedQuery<(&mut CharacterController, &Position, &mut Velocity, &Physics)>,
+        QueryMarker<With<KinematicMarker, (&Position, &Velocity)>>,
+    ),
+) {
+    let dt = dt.0;
+    if dt <= 0. {
+        return;
+    }
+
+    // Work out how far every character standing on a kinematic body will be carried by it. This is
+    // done separately so that platforms' velocities aren't borrowed while we're writing to the
+    // velocities of characters.
+    for (_, (controller, position)) in context.prepared_query(riders_query).iter() {
+        controller.platform_motion = Vector3::zeros();
+        let platform = match controller.ground.and_then(|ground| ground.target.entity()) {
+            Some(platform) => platform,
+            None => continue,
+        };
+
+        let mut platform_query = match context.query_one(*platform_query_marker, platform) {
+            Ok(platform_query) => platform_query,
+            Err(_) => continue,
+        };
+
+        if let Some((platform_pos, platform_vel)) = platform_query.get() {
+            let center = platform_pos.current.translation;
+            let offset = position.current.translation - center;
+            let rotated = UnitComplex::new(platform_vel.composite.angular * dt)
+                .transform_vector(&offset.xy())
+                .push(offset.z);
+            controller.platform_motion = platform_vel.composite.linear * dt + (rotated - offset);
+        }
+    }
+
+    let mut scratch = Vec::new();
+    for (e, (controller, position, velocity, physics)) in
+        context.prepared_query(controllers_query).iter()
+    {
+        let shape = match physics.collider_shape.as_deref() {
+            Some(shape) => shape,
+            None => continue,
+        };
+
+        let mover = Mover {
+            pipeline,
+            atom_map,
+            controller: &*controller,
+            shape,
+            collider_tx: physics.collider_tx,
+            rotation: position.current.rotation,
+            filter: QueryFilter::new().excluding(e).with_groups(physics.groups),
+        };
+
+        let start = position.current.translation;
+        let mut translation = start;
+        let was_grounded = controller.ground.is_some();
+        let moving_up = controller.up.dot(&controller.desired_velocity) > MIN_MOTION;
+
+        mover.depenetrate(&mut translation, &mut scratch);
+
+        let motion = controller.desired_velocity * dt + controller.platform_motion;
+        mover.move_and_slide(&mut translation, motion, was_grounded);
+
+        // A character trying to move up (jumping, say) is never snapped back down or considered
+        // to be on the ground.
+        let ground = if moving_up {
+            None
+        } else {
+            mover.probe_ground(&mut translation, was_grounded)
+        };
+
+        velocity.composite.linear = (translation - start) / dt;
+        controller.ground = ground;
+    }
+}
+
+impl LuaUserData for Ground {
+    fn on_metatable_init(table: Type<Self>) {
+        table
+            .add_clone()
+            .add_copy()
+            .add_send()
+            .add_sync()
+            .add::<dyn std::fmt::Debug>();
+    }
+
+    fn add_fields<'lua, F: LuaUserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("target", |_, this| Ok(this.target));
+        fields.add_field_method_get("point", |_, this| Ok(this.point.coords));
+        fields.add_field_method_get("normal", |_, this| Ok(this.normal.into_inner()));
+    }
+}
+
+impl LuaUserData for CharacterController {
+    fn on_metatable_init(table: Type<Self>) {
+        table
+            .mark_component()
+            .add_clone()
+            .add_copy()
+            .add::<dyn std::fmt::Debug>();
+    }
+
+    #[allow(clippy::unit_arg)]
+    fn add_fields<'lua, F: LuaUserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("up", |_, this| Ok(this.up.into_inner()));
+        fields.add_field_method_get("max_slope", |_, this| Ok(this.max_slope));
+        fields.add_field_method_get("step_height", |_, this| Ok(this.step_height));
+        fields.add_field_method_get("snap_distance", |_, this| Ok(this.snap_distance));
+        fields.add_field_method_get("skin", |_, this| Ok(this.skin));
+        fields.add_field_method_get("max_slides", |_, this| Ok(this.max_slides));
+        fields.add_field_method_get("desired_velocity", |_, this| Ok(this.desired_velocity));
+        fields.add_field_method_get("grounded", |_, this| Ok(this.is_grounded()));
+        fields.add_field_method_get("ground", |_, this| Ok(this.ground));
+
+        fields.add_field_method_set("up", |_, this, up: Vector3<Float>| {
+            this.up = UnitVector3::try_new(up, MIN_MOTION)
+                .ok_or_else(|| anyhow!("up vector must be nonzero"))
+                .to_lua_err()?;
+            Ok(())
+        });
+        fields.add_field_method_set("max_slope", |_, this, max_slope| {
+            Ok(this.max_slope = max_slope)
+        });
+        fields.add_field_method_set("step_height", |_, this, step_height| {
+            Ok(this.step_height = step_height)
+        });
+        fields.add_field_method_set("snap_distance", |_, this, snap_distance| {
+            Ok(this.snap_distance = snap_distance)
+        });
+        fields.add_field_method_set("skin", |_, this, skin| Ok(this.skin = skin));
+        fields.add_field_method_set("max_slides", |_, this, max_slides| {
+            Ok(this.max_slides = max_slides)
+        });
+        fields.add_field_method_set("desired_velocity", |_, this, desired_velocity| {
+            Ok(this.desired_velocity = desired_velocity)
+        });
+    }
+
+    fn on_type_metatable_init(table: Type<Type<Self>>) {
+        table.mark_component_type();
+    }
+
+    fn add_type_methods<'lua, M: LuaUserDataMethods<'lua, Type<Self>>>(methods: &mut M) {
+        methods.add_function("new", |_, ()| Ok(Self::new()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hv::{
+        ecs::{Entity, System, World},
+        resources::Resources,
+    };
+    use parry3d::shape::SharedShape;
+    use soft_edge::{Atom, HullFacet};
+
+    use crate::{
+        command_buffer::{CommandPool, CommandPoolResource},
+        types::UpdateTick,
+    };
+
+    const DT: f32 = 1. / 60.;
+    const RADIUS: f32 = 0.25;
+    /// Fast enough to cover a good fraction of a cell every tick.
+    const SPEED: f32 = 12.;
+
+    /// The atom whose only upward-facing facets have the given normal.
+    fn atom_facing(normal: Vector3<f32>) -> Atom {
+        Atom::generator()
+            .find(|atom| {
+                let mut upward = atom
+                    .compound_hull()
+                    .facets()
+                    .map(|facet| facet.normal())
+                    .filter(|n| n.z > 0.)
+                    .peekable();
+                upward.peek().is_some() && upward.all(|n| (n - normal).norm() < 1e-3)
+            })
+            .unwrap()
+    }
+
+    fn cube() -> Atom {
+        Atom::generator()
+            .find(|atom| {
+                atom.compound_hull()
+                    .facets()
+                    .filter(|facet| matches!(facet, HullFacet::Rectangle(_)))
+                    .count()
+                    == 6
+            })
+            .unwrap()
+    }
+
+    /// A floor of cubes in layer 0 (so its surface is at z = 1) from x = -2 to 12, w/ whatever's
+    /// returned by `column` stacked on top of it at each x.
+    fn terrain(column: impl Fn(i32) -> Vec<(i32, Atom)>) -> AtomMap {
+        let mut map = AtomMap::new();
+        for x in -2..12 {
+            for y in -3..4 {
+                map.atoms_mut().insert(Vector3::new(x, y, 0), cube());
+                for (z, atom) in column(x) {
+                    map.atoms_mut().insert(Vector3::new(x, y, z), atom);
+                }
+            }
+        }
+        map.calculate_hulls();
+        map
+    }
+
+    /// A character resting on a surface at height `z`.
+    fn spawn_character(
+        world: &mut World,
+        x: f32,
+        y: f32,
+        z: f32,
+        controller: CharacterController,
+    ) -> Entity {
+        world.spawn((
+            Position::new(CompositePosition3::translation(
+                x,
+                y,
+                z + RADIUS + controller.skin,
+            )),
+            Velocity {
+                composite: CompositeVelocity3::zero(),
+            },
+            Physics::new(SharedShape::ball(RADIUS)),
+            KinematicMarker,
+            controller,
+        ))
+    }
+
+    struct Sim {
+        world: World,
+        resources: Resources,
+        pool: CommandPool,
+        command_pool: CommandPoolResource,
+        atom_map: AtomMap,
+        pipeline: PhysicsPipeline,
+        tick: u64,
+    }
+
+    impl Sim {
+        fn new(world: World, atom_map: AtomMap) -> Self {
+            let mut sim = Self {
+                world,
+                resources: Resources::new(),
+                pool: CommandPool::new(),
+                command_pool: CommandPoolResource::new(),
+                atom_map,
+                pipeline: PhysicsPipeline::default(),
+                tick: 0,
+            };
+            // Get everything into the scene queries the controller uses.
+            sim.physics_update();
+            sim
+        }
+
+        fn physics_update(&mut self) {
+            let (tick, atom_map, pipeline) = (self.tick, &self.atom_map, &mut self.pipeline);
+            let command_pool = &self.command_pool;
+            command_pool.run_scoped(&self.pool, &mut self.world, &mut self.resources, |world| {
+                crate::physics::update.run(
+                    world,
+                    (
+                        &UpdateDt(DT),
+                        &UpdateTick(tick),
+                        atom_map,
+                        pipeline,
+                        command_pool,
+                    ),
+                )
+            });
+            self.tick += 1;
+        }
+
+        fn step(&mut self) {
+            update.run(&self.world, (&UpdateDt(DT), &self.atom_map, &self.pipeline));
+            self.physics_update();
+        }
+
+        fn position(&self, e: Entity) -> Vector3<f32> {
+            self.world.get::<Position>(e).unwrap().current.translation
+        }
+
+        fn controller(&self, e: Entity) -> CharacterController {
+            *self.world.get::<CharacterController>(e).unwrap()
+        }
+    }
+
+    fn walking(velocity: Vector3<f32>) -> CharacterController {
+        CharacterController {
+            desired_velocity: velocity,
+            ..CharacterController::new()
+        }
+    }
+
+    #[test]
+    fn slides_along_walls() {
+        // A wall two cells tall along x = 5.
+        let map = terrain(|x| match x {
+            5 => vec![(1, cube()), (2, cube())],
+            _ => vec![],
+        });
+        let mut world = World::new();
+        let diagonal = Vector3::new(1., 1., 0.).normalize() * SPEED;
+        let e = spawn_character(&mut world, 3., -2., 1., walking(diagonal));
+        let mut sim = Sim::new(world, map);
+
+        for _ in 0..20 {
+            sim.step();
+        }
+
+        let position = sim.position(e);
+        assert!(position.x <= 5. - RADIUS + 1e-3);
+        assert!(position.x > 4.5);
+        // Sliding along the wall carries on in y, rather than sticking to it.
+        assert!(position.y > 0.5);
+        assert!((position.z - (1. + RADIUS)).abs() < 0.05);
+        assert!(sim.controller(e).is_grounded());
+    }
+
+    #[test]
+    fn climbs_steps_up_to_the_step_height() {
+        // A step one cell tall at x = 5.
+        let step = |x| match x {
+            5..=11 => vec![(1, cube())],
+            _ => vec![],
+        };
+        let run = |step_height| {
+            let mut world = World::new();
+            let controller = CharacterController {
+                step_height,
+                ..walking(Vector3::x() * SPEED)
+            };
+            let e = spawn_character(&mut world, 2., 0.5, 1., controller);
+            let mut sim = Sim::new(world, terrain(step));
+            for _ in 0..30 {
+                sim.step();
+            }
+            (sim.position(e), sim.controller(e).is_grounded())
+        };
+
+        let (climbed, grounded) = run(1.1);
+        assert!(climbed.x > 6.);
+        assert!((climbed.z - (2. + RADIUS)).abs() < 0.05);
+        assert!(grounded);
+
+        let (blocked, grounded) = run(0.9);
+        assert!(blocked.x < 5.);
+        assert!((blocked.z - (1. + RADIUS)).abs() < 0.05);
+        assert!(grounded);
+    }
+
+    #[test]
+    fn only_walks_up_shallow_enough_slopes() {
+        // A 45 degree ramp at x = 5, rising in x onto a raised floor.
+        let ramp = atom_facing(Vector3::new(-1., 0., 1.).normalize());
+        let slope = move |x| match x {
+            5 => vec![(1, ramp)],
+            6..=11 => vec![(1, cube())],
+            _ => vec![],
+        };
+        let run = |max_slope| {
+            let mut world = World::new();
+            let controller = CharacterController {
+                max_slope,
+                ..walking(Vector3::x() * SPEED)
+            };
+            let e = spawn_character(&mut world, 2., 0.5, 1., controller);
+            let mut sim = Sim::new(world, terrain(slope));
+            for _ in 0..30 {
+                sim.step();
+            }
+            sim.position(e)
+        };
+
+        let climbed = run(std::f32::consts::FRAC_PI_3);
+        assert!(climbed.x > 6.5);
+        assert!((climbed.z - (2. + RADIUS)).abs() < 0.05);
+
+        let blocked = run(std::f32::consts::FRAC_PI_6);
+        assert!(blocked.x < 5.5);
+        assert!(blocked.z < 1.5);
+    }
+
+    #[test]
+    fn snaps_to_the_ground_down_drops() {
+        // The floor drops by a cell from x = 5 on.
+        let drop = || {
+            let mut map = AtomMap::new();
+            for x in -2..12 {
+                for y in -3..4 {
+                    let z = if x < 5 { 0 } else { -1 };
+                    map.atoms_mut().insert(Vector3::new(x, y, z), cube());
+                }
+            }
+            map.calculate_hulls();
+            map
+        };
+
+        let run = |snap_distance| {
+            let mut world = World::new();
+            let controller = CharacterController {
+                snap_distance,
+                ..walking(Vector3::x() * SPEED)
+            };
+            // Lined up so that the character is never perched on the very edge of the drop.
+            let e = spawn_character(&mut world, 2.1, 0.5, 1., controller);
+            let mut sim = Sim::new(world, drop());
+            let mut always_grounded = true;
+            for _ in 0..25 {
+                sim.step();
+                always_grounded &= sim.controller(e).is_grounded();
+            }
+            (sim.position(e), always_grounded)
+        };
+
+        let (snapped, always_grounded) = run(1.2);
+        assert!(snapped.x > 6.);
+        assert!((snapped.z - RADIUS).abs() < 0.05);
+        assert!(always_grounded);
+
+        // Without enough snap distance, the character walks straight off the edge.
+        let (floating, always_grounded) = run(0.25);
+        assert!(floating.x > 6.);
+        assert!(floating.z > 1.);
+        assert!(!always_grounded);
+    }
+
+    #[test]
+    fn rides_moving_platforms() {
+        let mut world = World::new();
+        let platform = world.spawn((
+            Position::new(CompositePosition3::translation(0., 0., 0.25)),
+            Velocity {
+                composite: CompositeVelocity3::new(Vector3::new(1., 0.5, 0.), 0.),
+            },
+            Physics::new(SharedShape::cuboid(2., 2., 0.25)),
+            KinematicMarker,
+        ));
+        let e = spawn_character(&mut world, 0.5, 0., 0.5, CharacterController::new());
+        let mut sim = Sim::new(world, AtomMap::new());
+
+        let offset = sim.position(e) - sim.position(platform);
+        for _ in 0..60 {
+            sim.step();
+        }
+
+        let controller = sim.controller(e);
+        assert_eq!(
+            controller.ground().map(|ground| ground.target),
+            Some(QueryTarget::Body(platform))
+        );
+        // The platform has moved a good way, and the character is still where it was on it, give
+        // or take the tick before it first found the platform under it.
+        let platform_position = sim.position(platform);
+        assert!(platform_position.x > 0.9);
+        let drift = sim.position(e) - platform_position - offset;
+        assert!(drift.norm() < 0.05);
+    }
+}
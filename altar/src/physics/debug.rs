@@ -0,0 +1,524 @@
+//! Debug geometry for the physics simulation, generated on the CPU as line segments.
+//!
+//! [`DebugLines`] walks the ECS, the [`PhysicsPipeline`] and the [`AtomMap`] and collects colored
+//! line segments for whichever parts of the simulation are asked for: collider outlines, the AABBs
+//! of the broad phase, contact points and normals, CCD sweeps, and the facets of the lattice's
+//! hulls. The segments can be queued straight into a [`WireframeRenderer`], or inspected directly.
+
+use std::f32::consts::TAU;
+
+use hv::{ecs::World, prelude::*};
+use parry3d::{
+    bounding_volume::{BoundingVolume, AABB},
+    shape::Shape,
+};
+use soft_edge::HullFacet;
+
+use crate::{
+    lattice::atom_map::AtomMap,
+    physics::{Physics, PhysicsPipeline, SensorMarker},
+    render::{
+        wireframe::{LineVertex, WireframeBackend, WireframeRenderer},
+        Color,
+    },
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct DebugColors {
+    pub collider: Color,
+    pub sleeping_collider: Color,
+    pub sensor: Color,
+    pub broad_phase_leaf: Color,
+    pub broad_phase_node: Color,
+    pub contact: Color,
+    pub contact_normal: Color,
+    pub ccd_sweep: Color,
+    pub ccd_clamped: Color,
+    pub hull_facet: Color,
+}
+
+impl Default for DebugColors {
+    fn default() -> Self {
+        Self {
+            collider: Color::GREEN,
+            sleeping_collider: Color::BLUE,
+            sensor: Color::YELLOW,
+            broad_phase_leaf: Color::ORANGE,
+            broad_phase_node: Color::ORANGE.with_alpha(0.25),
+            contact: Color::RED,
+            contact_normal: Color::MAGENTA,
+            ccd_sweep: Color::CYAN,
+            ccd_clamped: Color::RED,
+            hull_facet: Color::WHITE.with_alpha(0.5),
+        }
+    }
+}
+
+/// A list of colored line segments, built up from the state of the physics simulation.
+#[derive(Debug, Clone)]
+pub struct DebugLines {
+    pub colors: DebugColors,
+    /// The length of the line drawn for each contact normal.
+    pub normal_length: f32,
+    /// Half the size of the crosses marking contact points and clamped CCD positions.
+    pub marker_size: f32,
+    /// The number of segments used to approximate circles when outlining round shapes.
+    pub circle_segments: u32,
+    lines: Vec<(LineVertex, LineVertex)>,
+}
+
+impl Default for DebugLines {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DebugLines {
+    pub fn new() -> Self {
+        Self {
+            colors: DebugColors::default(),
+            normal_length: 0.5,
+            marker_size: 0.05,
+            circle_segments: 16,
+            lines: Vec::new(),
+        }
+    }
+
+    /// The segments generated so far.
+    pub fn lines(&self) -> &[(LineVertex, LineVertex)] {
+        &self.lines
+    }
+
+    /// Remove every segment, keeping the allocated memory.
+    pub fn clear(&mut self) {
+        self.lines.clear();
+    }
+
+    /// Queue every segment for drawing.
+    pub fn queue_draw<B: WireframeBackend>(&self, renderer: &mut WireframeRenderer<B>) {
+        for &(a, b) in &self.lines {
+            renderer.queue_draw_line(a, b);
+        }
+    }
+
+    /// Outline the collider parts of every body in the world. Sensor parts and sleeping bodies get
+    /// their own colors.
+    pub fn add_colliders(&mut self, world: &World) {
+        for (_, (physics, sensor_marker)) in
+            world.query::<(&Physics, Option<&SensorMarker>)>().iter()
+        {
+            let pose = physics.collider_tx * physics.position.as_isometry3();
+            for part in &physics.parts {
+                let color = if sensor_marker.is_some() || part.sensor {
+                    self.colors.sensor
+                } else if physics.sleeping {
+                    self.colors.sleeping_collider
+                } else {
+                    self.colors.collider
+                };
+
+                self.add_shape(&(pose * part.local_tx), part.shape.as_ref(), color);
+            }
+        }
+    }
+
+    /// Outline every node of the broad phase's AABB tree. Leaves hold the fattened AABBs of
+    /// individual bodies; internal nodes bound their children.
+    pub fn add_broad_phase(&mut self, pipeline: &PhysicsPipeline) {
+        pipeline.broad_phase.for_each_node(|aabb, leaf| {
+            let color = if leaf {
+                self.colors.broad_phase_leaf
+            } else {
+                self.colors.broad_phase_node
+            };
+            self.add_aabb(aabb, color);
+        });
+    }
+
+    /// Mark the points of every contact constraint, and draw each contact normal from the point on
+    /// the first participant.
+    pub fn add_contacts(&mut self, pipeline: &PhysicsPipeline) {
+        for (_, constraint) in pipeline.contacts() {
+            let contact = &constraint.contact;
+            self.add_marker(&contact.p1, self.colors.contact);
+            self.add_marker(&contact.p2, self.colors.contact);
+            self.add_line(
+                contact.p1,
+                contact.p1 + contact.normal.into_inner() * self.normal_length,
+                self.colors.contact_normal,
+            );
+        }
+    }
+
+    /// Draw the path each CCD-enabled body would have taken during the last update, marking where
+    /// it ended up if its motion was clamped.
+    pub fn add_ccd_sweeps(&mut self, pipeline: &PhysicsPipeline) {
+        for sweep in pipeline.ccd_sweeps() {
+            let start = Point3::from(sweep.start.translation);
+            let target = Point3::from(sweep.target.translation);
+            let end = Point3::from(sweep.end.translation);
+            self.add_line(start, target, self.colors.ccd_sweep);
+
+            if end != target {
+                self.add_marker(&end, self.colors.ccd_clamped);
+            }
+        }
+    }
+
+    /// Outline the facets of the lattice's hulls, optionally only for the cells intersecting some
+    /// region.
+    pub fn add_hulls(&mut self, atom_map: &AtomMap, region: Option<&AABB>) {
+        for (coords, hull) in atom_map.hulls().iter() {
+            let offset = coords.cast::<f32>();
+            let cell = AABB::new(Point3::from(offset), Point3::from(offset.add_scalar(1.)));
+            if region.map_or(false, |region| !region.intersects(&cell)) {
+                continue;
+            }
+
+            for facet in hull.facets() {
+                match facet {
+                    HullFacet::Triangle(vertices) => self.add_loop(
+                        vertices.map(|v| v.to_f32() + offset),
+                        self.colors.hull_facet,
+                    ),
+                    HullFacet::Rectangle(vertices) => self.add_loop(
+                        vertices.map(|v| v.to_f32() + offset),
+                        self.colors.hull_facet,
+                    ),
+                }
+            }
+        }
+    }
+
+    fn add_line(&mut self, a: Point3<f32>, b: Point3<f32>, color: Color) {
+        self.lines.push((
+            LineVertex {
+                pos: a.coords,
+                color,
+            },
+            LineVertex {
+                pos: b.coords,
+                color,
+            },
+        ));
+    }
+
+    fn add_loop<const N: usize>(&mut self, points: [Point3<f32>; N], color: Color) {
+        for (i, &point) in points.iter().enumerate() {
+            self.add_line(point, points[(i + 1) % N], color);
+        }
+    }
+
+    fn add_marker(&mut self, point: &Point3<f32>, color: Color) {
+        for axis in [Vector3::x(), Vector3::y(), Vector3::z()] {
+            let offset = axis * self.marker_size;
+            self.add_line(point - offset, point + offset, color);
+        }
+    }
+
+    fn add_circle(
+        &mut self,
+        center: &Point3<f32>,
+        u: &Vector3<f32>,
+        v: &Vector3<f32>,
+        radius: f32,
+        color: Color,
+    ) {
+        let n = self.circle_segments.max(3);
+        let point = |i: u32| {
+            let theta = TAU * i as f32 / n as f32;
+            center + (u * theta.cos() + v * theta.sin()) * radius
+        };
+
+        for i in 0..n {
+            self.add_line(point(i), point(i + 1), color);
+        }
+    }
+
+    /// Draw the twelve edges of a box, given its corners indexed by bits: x in bit 0, y in bit 1,
+    /// and z in bit 2.
+    fn add_box(&mut self, corners: &[Point3<f32>; 8], color: Color) {
+        for (i, &corner) in corners.iter().enumerate() {
+            for bit in [1, 2, 4] {
+                if i & bit == 0 {
+                    self.add_line(corner, corners[i | bit], color);
+                }
+            }
+        }
+    }
+
+    fn add_aabb(&mut self, aabb: &AABB, color: Color) {
+        self.add_transformed_aabb(&Isometry3::identity(), aabb, color);
+    }
+
+    fn add_transformed_aabb(&mut self, pose: &Isometry3<f32>, aabb: &AABB, color: Color) {
+        let corners = [0, 1, 2, 3, 4, 5, 6, 7].map(|i| {
+            let pick = |bit, min: f32, max: f32| if i & bit == 0 { min } else { max };
+            pose * Point3::new(
+                pick(1, aabb.mins.x, aabb.maxs.x),
+                pick(2, aabb.mins.y, aabb.maxs.y),
+                pick(4, aabb.mins.z, aabb.maxs.z),
+            )
+        });
+        self.add_box(&corners, color);
+    }
+
+    /// Draw a circle at each end of an axis and the four lines joining them, as for cylinders and
+    /// capsules.
+    fn add_round_sides(
+        &mut self,
+        a: &Point3<f32>,
+        b: &Point3<f32>,
+        radius: f32,
+        color: Color,
+    ) -> (Vector3<f32>, Vector3<f32>) {
+        let (u, v) = perpendiculars(&(b - a));
+        self.add_circle(a, &u, &v, radius, color);
+        self.add_circle(b, &u, &v, radius, color);
+        for offset in [u, -u, v, -v] {
+            self.add_line(a + offset * radius, b + offset * radius, color);
+        }
+
+        (u, v)
+    }
+
+    /// Outline a shape. Shapes w/o a specific outline are drawn as their local AABB.
+    fn add_shape(&mut self, pose: &Isometry3<f32>, shape: &dyn Shape, color: Color) {
+        if let Some(cuboid) = shape.as_cuboid() {
+            let he = cuboid.half_extents;
+            self.add_transformed_aabb(pose, &AABB::new((-he).into(), he.into()), color);
+        } else if let Some(ball) = shape.as_ball() {
+            let center = Point3::from(pose.translation.vector);
+            let [x, y, z] = [Vector3::x(), Vector3::y(), Vector3::z()].map(|axis| pose * axis);
+            self.add_circle(&center, &x, &y, ball.radius, color);
+            self.add_circle(&center, &y, &z, ball.radius, color);
+            self.add_circle(&center, &z, &x, ball.radius, color);
+        } else if let Some(capsule) = shape.as_capsule() {
+            let a = pose * capsule.segment.a;
+            let b = pose * capsule.segment.b;
+            let (u, v) = self.add_round_sides(&a, &b, capsule.radius, color);
+            // Half-circles over the caps.
+            let axis = (b - a).try_normalize(1.0e-6).unwrap_or_else(|| u.cross(&v));
+            for (end, dir) in [(a, -axis), (b, axis)] {
+                for side in [u, v] {
+                    self.add_half_circle(&end, &side, &dir, capsule.radius, color);
+                }
+            }
+        } else if let Some(cylinder) = shape.as_cylinder() {
+            let a = pose * Point3::new(0., -cylinder.half_height, 0.);
+            let b = pose * Point3::new(0., cylinder.half_height, 0.);
+            self.add_round_sides(&a, &b, cylinder.radius, color);
+        } else if let Some(cone) = shape.as_cone() {
+            let base = pose * Point3::new(0., -cone.half_height, 0.);
+            let apex = pose * Point3::new(0., cone.half_height, 0.);
+            let (u, v) = perpendiculars(&(apex - base));
+            self.add_circle(&base, &u, &v, cone.radius, color);
+            for offset in [u, -u, v, -v] {
+                self.add_line(base + offset * cone.radius, apex, color);
+            }
+        } else if let Some(segment) = shape.as_segment() {
+            self.add_line(pose * segment.a, pose * segment.b, color);
+        } else if let Some(triangle) = shape.as_triangle() {
+            self.add_loop(
+                [triangle.a, triangle.b, triangle.c].map(|p| pose * p),
+                color,
+            );
+        } else if let Some(trimesh) = shape.as_trimesh() {
+            for triangle in trimesh.triangles() {
+                self.add_loop(
+                    [triangle.a, triangle.b, triangle.c].map(|p| pose * p),
+                    color,
+                );
+            }
+        } else if let Some(compound) = shape.as_compound() {
+            for (sub_pose, sub_shape) in compound.shapes() {
+                self.add_shape(&(pose * sub_pose), sub_shape.as_ref(), color);
+            }
+        } else {
+            self.add_transformed_aabb(pose, &shape.compute_local_aabb(), color);
+        }
+    }
+
+    fn add_half_circle(
+        &mut self,
+        center: &Point3<f32>,
+        side: &Vector3<f32>,
+        dir: &Vector3<f32>,
+        radius: f32,
+        color: Color,
+    ) {
+        let n = (self.circle_segments.max(3) + 1) / 2;
+        let point = |i: u32| {
+            let theta = TAU / 2. * i as f32 / n as f32;
+            center + (side * theta.cos() + dir * theta.sin()) * radius
+        };
+
+        for i in 0..n {
+            self.add_line(point(i), point(i + 1), color);
+        }
+    }
+}
+
+/// Two unit vectors perpendicular to `axis` and to each other.
+fn perpendiculars(axis: &Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    let axis = axis.try_normalize(1.0e-6).unwrap_or_else(Vector3::y);
+    let u = if axis.x.abs() >= 0.57735 {
+        Vector3::new(axis.y, -axis.x, 0.)
+    } else {
+        Vector3::new(0., axis.z, -axis.y)
+    }
+    .normalize();
+
+    (u, axis.cross(&u))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use parry3d::shape::SharedShape;
+
+    use crate::physics::{
+        CcdSweep, ColliderPart, CompositePosition3, ConstrainedPair, Contact, ContactConstraint,
+        ContactId, Material,
+    };
+
+    fn approx(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1.0e-4
+    }
+
+    #[test]
+    fn cuboid_outline() {
+        let mut world = World::new();
+        let mut physics = Physics::new(SharedShape::cuboid(1., 2., 3.));
+        physics.position = CompositePosition3::translation(5., 0., 0.);
+        world.spawn((physics,));
+
+        let mut lines = DebugLines::new();
+        lines.add_colliders(&world);
+
+        assert_eq!(lines.lines().len(), 12);
+        let mut total_length = 0.;
+        for (a, b) in lines.lines() {
+            for v in [a, b] {
+                assert!(approx((v.pos.x - 5.).abs(), 1.));
+                assert!(approx(v.pos.y.abs(), 2.));
+                assert!(approx(v.pos.z.abs(), 3.));
+                assert_eq!(v.color, lines.colors.collider);
+            }
+            total_length += (b.pos - a.pos).norm();
+        }
+        assert!(approx(total_length, 4. * (2. + 4. + 6.)));
+    }
+
+    #[test]
+    fn compound_parts_and_sensors() {
+        let mut world = World::new();
+        let ball = ColliderPart::new(Isometry3::translation(0., 0., 1.), SharedShape::ball(0.5));
+        let sensor = ColliderPart::from(SharedShape::cuboid(1., 1., 1.)).with_sensor(true);
+        world.spawn((Physics::new(vec![ball, sensor]),));
+
+        let mut lines = DebugLines::new();
+        lines.circle_segments = 8;
+        lines.add_colliders(&world);
+
+        let (ball_lines, sensor_lines): (Vec<_>, Vec<_>) = lines
+            .lines()
+            .iter()
+            .partition(|(a, _)| a.color == lines.colors.collider);
+        assert_eq!(ball_lines.len(), 3 * 8);
+        assert_eq!(sensor_lines.len(), 12);
+        assert!(sensor_lines
+            .iter()
+            .all(|(a, b)| a.color == lines.colors.sensor && b.color == lines.colors.sensor));
+
+        for (a, b) in ball_lines {
+            for v in [a, b] {
+                assert!(approx((v.pos - Vector3::new(0., 0., 1.)).norm(), 0.5));
+            }
+        }
+    }
+
+    #[test]
+    fn contact_points_and_normals() {
+        let mut world = World::new();
+        let e = world.spawn(());
+        let mut pipeline = PhysicsPipeline::default();
+        let contact = Contact::new(
+            Vector3::z_axis(),
+            Point3::new(1., 2., 3.),
+            Point3::new(1., 2., 2.9),
+        );
+        pipeline.constraints.insert(
+            ContactId(0),
+            ContactConstraint::new(
+                ConstrainedPair::Static(e, 0, Vector3::zeros(), 0, 0),
+                contact,
+                Material::new(0., 0., 0.),
+            ),
+        );
+
+        let mut lines = DebugLines::new();
+        lines.add_contacts(&pipeline);
+
+        assert_eq!(lines.lines().len(), 7);
+        let normals = lines
+            .lines()
+            .iter()
+            .filter(|(a, _)| a.color == lines.colors.contact_normal)
+            .collect::<Vec<_>>();
+        assert_eq!(normals.len(), 1);
+        let (a, b) = normals[0];
+        assert_eq!(a.pos, contact.p1.coords);
+        assert!(approx((b.pos - a.pos).norm(), lines.normal_length));
+        assert!(approx((b.pos - a.pos).normalize().z, 1.));
+    }
+
+    #[test]
+    fn broad_phase_nodes() {
+        let mut pipeline = PhysicsPipeline::default();
+        pipeline.broad_phase.begin_update();
+        for (id, x) in [(0, 0.), (1, 10.)] {
+            let aabb = AABB::new(Point3::new(x, 0., 0.), Point3::new(x + 1., 1., 1.));
+            pipeline.broad_phase.update(id, aabb);
+        }
+        pipeline.broad_phase.end_update();
+
+        let mut lines = DebugLines::new();
+        lines.add_broad_phase(&pipeline);
+
+        // Two leaves and their parent.
+        assert_eq!(lines.lines().len(), 3 * 12);
+        let leaf_lines = lines
+            .lines()
+            .iter()
+            .filter(|(a, _)| a.color == lines.colors.broad_phase_leaf)
+            .count();
+        assert_eq!(leaf_lines, 2 * 12);
+    }
+
+    #[test]
+    fn clamped_ccd_sweep() {
+        let mut world = World::new();
+        let e = world.spawn(());
+        let mut pipeline = PhysicsPipeline::default();
+        pipeline.ccd_sweeps.push(CcdSweep {
+            entity: e,
+            start: CompositePosition3::translation(0., 0., 0.),
+            target: CompositePosition3::translation(4., 0., 0.),
+            end: CompositePosition3::translation(1., 0., 0.),
+        });
+
+        let mut lines = DebugLines::new();
+        lines.add_ccd_sweeps(&pipeline);
+
+        assert_eq!(lines.lines().len(), 4);
+        let (a, b) = lines.lines()[0];
+        assert_eq!(a.color, lines.colors.ccd_sweep);
+        assert_eq!(a.pos, Vector3::zeros());
+        assert_eq!(b.pos, Vector3::new(4., 0., 0.));
+        assert!(lines.lines()[1..]
+            .iter()
+            .all(|(a, b)| approx(((a.pos + b.pos) / 2. - Vector3::x()).norm(), 0.)));
+    }
+}
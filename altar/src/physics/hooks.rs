@@ -0,0 +1,312 @@
+//! Contact modification hooks, for inspecting and altering contacts before they're solved.
+//!
+//! A hook sees every contact found during an update, whether it's new or persisted from the last
+//! update, before it's handed to the solver. It can discard the contact entirely (one-way
+//! platforms, dropping through floors), override the combined friction and restitution of the
+//! parts in contact, or set a target tangent velocity for the surface (conveyor belts).
+//!
+//! Rust hooks are set w/ [`PhysicsPipeline::set_contact_hook`] and run from [`update`]. Lua hooks
+//! are set w/ [`PhysicsPipeline::set_lua_contact_hook`] (or `altar.physics.set_contact_hook` from
+//! Lua); since the Lua state can't be shared between threads, they only run if the physics are
+//! updated w/ [`update_scripted`], as a local system w/ access to the Lua state. If both are set,
+//! the Rust hook runs first.
+//!
+//! Contacts between sleeping bodies aren't refreshed, and so aren't seen by hooks until the bodies
+//! wake up.
+//!
+//! [`update`]: crate::physics::update
+//! [`update_scripted`]: crate::physics::update_scripted
+
+use hv::{ecs::Entity, prelude::*, script::api::ModuleBuilder};
+use tracing::error;
+
+use crate::{
+    api::with_loaned_mut,
+    physics::{ConstrainedPair, Contact, Material, PhysicsPipeline},
+};
+
+/// A contact about to be handed to the solver, along w/ the parameters a hook may modify.
+#[derive(Debug, Clone, Copy)]
+pub struct ContactModification {
+    participants: ConstrainedPair,
+    contact: Contact,
+    is_new: bool,
+    discarded: bool,
+    /// The combined material of the collider parts in contact.
+    pub material: Material,
+    /// The velocity the surface of the second participant should appear to move at, relative to
+    /// the first participant. Only the component lying in the tangent plane of the contact has any
+    /// effect. For contacts w/ the lattice, the lattice is always the second participant.
+    pub tangent_velocity: Vector3<f32>,
+}
+
+impl ContactModification {
+    pub(crate) fn new(
+        participants: ConstrainedPair,
+        contact: Contact,
+        material: Material,
+        is_new: bool,
+    ) -> Self {
+        Self {
+            participants,
+            contact,
+            is_new,
+            discarded: false,
+            material,
+            tangent_velocity: Vector3::zeros(),
+        }
+    }
+
+    pub fn participants(&self) -> ConstrainedPair {
+        self.participants
+    }
+
+    /// The first body in contact.
+    pub fn body1(&self) -> Entity {
+        self.participants.get_participant(false)
+    }
+
+    /// The second body in contact, or `None` if the first body is in contact w/ the lattice.
+    pub fn body2(&self) -> Option<Entity> {
+        match self.participants {
+            ConstrainedPair::Dynamic(pair, _) => Some(pair.1),
+            ConstrainedPair::Static(..) => None,
+        }
+    }
+
+    /// The contact itself. The normal points from the first participant towards the second.
+    pub fn contact(&self) -> &Contact {
+        &self.contact
+    }
+
+    /// Whether this contact was found for the first time during this update.
+    pub fn is_new(&self) -> bool {
+        self.is_new
+    }
+
+    /// Discard this contact for this update. If the contact was persisted from the last update, it
+    /// ends, and will begin again as a new contact if it's found and kept during a later update.
+    pub fn discard(&mut self) {
+        self.discarded = true;
+    }
+
+    pub fn is_discarded(&self) -> bool {
+        self.discarded
+    }
+}
+
+/// A hook run on every new or persisted contact. Implemented for any suitable closure.
+pub trait ContactHook: Send + Sync {
+    fn modify_contact(&mut self, contact: &mut ContactModification);
+}
+
+impl<F> ContactHook for F
+where
+    F: FnMut(&mut ContactModification) + Send + Sync,
+{
+    fn modify_contact(&mut self, contact: &mut ContactModification) {
+        self(contact)
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct ContactHooks {
+    hook: Option<Box<dyn ContactHook>>,
+    lua_hook: Option<LuaRegistryKey>,
+}
+
+impl ContactHooks {
+    pub(crate) fn run(&mut self, contact: &mut ContactModification, lua: Option<&Lua>) {
+        if let Some(hook) = &mut self.hook {
+            hook.modify_contact(contact);
+        }
+
+        if let (Some(lua), Some(key)) = (lua, &self.lua_hook) {
+            if let Err(err) = Self::run_lua(lua, key, contact) {
+                error!(error = ?err, "error calling Lua contact hook: {:#}", err);
+            }
+        }
+    }
+
+    fn run_lua(lua: &Lua, key: &LuaRegistryKey, contact: &mut ContactModification) -> Result<()> {
+        let hook: LuaFunction = lua.registry_value(key)?;
+        let userdata = lua.create_userdata(*contact)?;
+        hook.call::<_, ()>(userdata.clone())?;
+        *contact = *userdata.borrow::<ContactModification>()?;
+        Ok(())
+    }
+}
+
+impl PhysicsPipeline {
+    /// Set the Rust contact hook, replacing any previously set.
+    pub fn set_contact_hook(&mut self, hook: impl ContactHook + 'static) {
+        self.contact_hooks.hook = Some(Box::new(hook));
+    }
+
+    pub fn clear_contact_hook(&mut self) {
+        self.contact_hooks.hook = None;
+    }
+
+    /// Set or clear the Lua contact hook: a registry key for a function taking a
+    /// `ContactModification`. Only run during [`update_scripted`](crate::physics::update_scripted).
+    pub fn set_lua_contact_hook(&mut self, key: Option<LuaRegistryKey>) {
+        self.contact_hooks.lua_hook = key;
+    }
+}
+
+pub(crate) fn register_functions(builder: &mut ModuleBuilder) -> Result<()> {
+    builder.function("set_contact_hook", |lua, hook: Option<LuaFunction>| {
+        let key = hook.map(|f| lua.create_registry_value(f)).transpose()?;
+        with_loaned_mut(lua, |pipeline: &mut PhysicsPipeline| {
+            pipeline.set_lua_contact_hook(key);
+            Ok(())
+        })
+    })?;
+
+    Ok(())
+}
+
+impl LuaUserData for ContactModification {
+    fn on_metatable_init(table: Type<Self>) {
+        table
+            .add_clone()
+            .add_copy()
+            .add_send()
+            .add_sync()
+            .add::<dyn std::fmt::Debug>();
+    }
+
+    #[allow(clippy::unit_arg)]
+    fn add_fields<'lua, F: LuaUserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("body1", |_, this| Ok(this.body1()));
+        fields.add_field_method_get("body2", |_, this| Ok(this.body2()));
+        fields.add_field_method_get("part1", |_, this| Ok(this.participants.get_part(false)));
+        fields.add_field_method_get("part2", |_, this| match this.participants {
+            ConstrainedPair::Dynamic(_, parts) => Ok(Some(parts[1])),
+            ConstrainedPair::Static(..) => Ok(None),
+        });
+        fields.add_field_method_get("normal", |_, this| Ok(this.contact.normal.into_inner()));
+        fields.add_field_method_get("point1", |_, this| Ok(this.contact.p1.coords));
+        fields.add_field_method_get("point2", |_, this| Ok(this.contact.p2.coords));
+        fields.add_field_method_get("is_new", |_, this| Ok(this.is_new));
+        fields.add_field_method_get("is_discarded", |_, this| Ok(this.discarded));
+        fields.add_field_method_get("material", |_, this| Ok(this.material));
+        fields.add_field_method_set("material", |_, this, material| Ok(this.material = material));
+        fields.add_field_method_get("tangent_velocity", |_, this| Ok(this.tangent_velocity));
+        fields.add_field_method_set("tangent_velocity", |_, this, velocity| {
+            Ok(this.tangent_velocity = velocity)
+        });
+    }
+
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method_mut("discard", |_, this, ()| {
+            this.discard();
+            Ok(())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    use hv::{
+        ecs::{System, World},
+        resources::Resources,
+    };
+    use parry3d::shape::SharedShape;
+
+    use crate::{
+        command_buffer::{CommandPool, CommandPoolResource},
+        lattice::atom_map::AtomMap,
+        physics::{update, CompositePosition3, CompositeVelocity3, Physics, Position, Velocity},
+        types::{UpdateDt, UpdateTick},
+    };
+
+    fn spawn_ball(world: &mut World, x: f32, dynamic: bool) -> Entity {
+        let position = Position::new(CompositePosition3::translation(x, 0., 0.));
+        let physics = Physics::new(SharedShape::ball(0.5));
+        if dynamic {
+            let velocity = Velocity {
+                composite: CompositeVelocity3::zero(),
+            };
+            world.spawn((position, velocity, physics))
+        } else {
+            world.spawn((position, physics))
+        }
+    }
+
+    fn run(world: &mut World, pipeline: &mut PhysicsPipeline, tick: u64) {
+        let atom_map = AtomMap::new();
+        let pool = CommandPool::new();
+        let command_pool = CommandPoolResource::new();
+        command_pool.run_scoped(&pool, world, &mut Resources::new(), |world| {
+            update.run(
+                world,
+                (
+                    &UpdateDt(1. / 60.),
+                    &UpdateTick(tick),
+                    &atom_map,
+                    pipeline,
+                    &command_pool,
+                ),
+            )
+        });
+    }
+
+    #[test]
+    fn hook_sees_each_contact_once() {
+        let mut world = World::new();
+        let a = spawn_ball(&mut world, 0., true);
+        let b = spawn_ball(&mut world, 0.8, true);
+        let c = spawn_ball(&mut world, -0.8, false);
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut pipeline = PhysicsPipeline::default();
+        pipeline.set_contact_hook({
+            let seen = seen.clone();
+            move |contact: &mut ContactModification| {
+                let mut bodies = [contact.body1(), contact.body2().unwrap()];
+                bodies.sort();
+                seen.lock().unwrap().push(bodies);
+            }
+        });
+
+        run(&mut world, &mut pipeline, 0);
+        let mut expected = [[a, b], [a, c]];
+        expected.iter_mut().for_each(|pair| pair.sort());
+        expected.sort();
+        seen.lock().unwrap().sort();
+        assert_eq!(*seen.lock().unwrap(), expected);
+
+        // Whether or not the bodies stay in contact, no pair is seen more than once per update.
+        for tick in 1..10 {
+            seen.lock().unwrap().clear();
+            run(&mut world, &mut pipeline, tick);
+
+            let mut seen = seen.lock().unwrap().clone();
+            let len = seen.len();
+            seen.sort();
+            seen.dedup();
+            assert_eq!(seen.len(), len);
+        }
+    }
+
+    #[test]
+    fn discarded_contacts_stay_discarded() {
+        let mut world = World::new();
+        spawn_ball(&mut world, 0., true);
+        spawn_ball(&mut world, 0.8, true);
+
+        let mut pipeline = PhysicsPipeline::default();
+        pipeline.set_contact_hook(|contact: &mut ContactModification| contact.discard());
+        let mut reader = pipeline.register_reader();
+
+        run(&mut world, &mut pipeline, 0);
+
+        assert_eq!(pipeline.contacts().count(), 0);
+        assert_eq!(pipeline.events().read(&mut reader).count(), 0);
+    }
+}
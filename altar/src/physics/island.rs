@@ -0,0 +1,112 @@
+//! Simulation islands, used to put groups of resting bodies to sleep all at once.
+//!
+//! An island is a set of awake dynamic bodies connected by contacts. Bodies in an island can only
+//! fall asleep together, since a body resting on another which is still moving isn't really at
+//! rest.
+
+use std::collections::HashMap;
+
+use hv::ecs::Entity;
+
+/// A disjoint-set forest over the awake dynamic bodies of a single update.
+#[derive(Debug, Default)]
+pub(crate) struct Islands {
+    indices: HashMap<Entity, usize>,
+    parents: Vec<usize>,
+    // The minimum sleep timer of every body in an island, valid only at the island's root.
+    min_sleep_timers: Vec<f32>,
+}
+
+impl Islands {
+    pub fn clear(&mut self) {
+        self.indices.clear();
+        self.parents.clear();
+        self.min_sleep_timers.clear();
+    }
+
+    /// Add a body as its own single-body island.
+    pub fn insert(&mut self, entity: Entity, sleep_timer: f32) {
+        let index = self.parents.len();
+        self.indices.insert(entity, index);
+        self.parents.push(index);
+        self.min_sleep_timers.push(sleep_timer);
+    }
+
+    fn find(&mut self, mut index: usize) -> usize {
+        // Path halving.
+        while self.parents[index] != index {
+            self.parents[index] = self.parents[self.parents[index]];
+            index = self.parents[index];
+        }
+
+        index
+    }
+
+    /// Merge the islands of two bodies. Does nothing if either body isn't in the forest, which is
+    /// the case for static, kinematic, and sleeping bodies.
+    pub fn union(&mut self, a: Entity, b: Entity) {
+        let (a, b) = match (self.indices.get(&a), self.indices.get(&b)) {
+            (Some(&a), Some(&b)) => (self.find(a), self.find(b)),
+            _ => return,
+        };
+
+        if a != b {
+            self.parents[b] = a;
+            self.min_sleep_timers[a] = self.min_sleep_timers[a].min(self.min_sleep_timers[b]);
+        }
+    }
+
+    /// Whether every body in the island of this body has been at rest for at least
+    /// `time_to_sleep` seconds.
+    pub fn can_sleep(&mut self, entity: Entity, time_to_sleep: f32) -> bool {
+        match self.indices.get(&entity) {
+            Some(&index) => {
+                let root = self.find(index);
+                self.min_sleep_timers[root] >= time_to_sleep
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hv::ecs::World;
+
+    #[test]
+    fn islands_sleep_once_every_body_in_them_can() {
+        let mut world = World::new();
+        let [a, b, c, d] = [(); 4].map(|()| world.spawn(()));
+        let outsider = world.spawn(());
+
+        let mut islands = Islands::default();
+        islands.insert(a, 1.);
+        islands.insert(b, 0.7);
+        islands.insert(c, 0.2);
+        islands.insert(d, 0.6);
+        assert!(islands.can_sleep(a, 0.5));
+        assert!(!islands.can_sleep(c, 0.5));
+
+        // Merging w/ a body which isn't in the forest does nothing.
+        islands.union(a, outsider);
+        assert!(islands.can_sleep(a, 0.5));
+        assert!(!islands.can_sleep(outsider, 0.));
+
+        islands.union(a, b);
+        islands.union(c, d);
+        assert!(islands.can_sleep(b, 0.5));
+        assert!(!islands.can_sleep(d, 0.5));
+
+        // The island is only as rested as its least rested body.
+        islands.union(b, d);
+        for e in [a, b, c, d] {
+            assert!(!islands.can_sleep(e, 0.5));
+            assert!(islands.can_sleep(e, 0.2));
+        }
+
+        islands.clear();
+        assert!(!islands.can_sleep(a, 0.));
+    }
+}
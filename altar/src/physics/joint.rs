@@ -0,0 +1,698 @@
+//! Joints, which constrain the relative motion of two bodies, or of a body and the world.
+//!
+//! Joints live in the [`PhysicsPipeline`] and are solved alongside contacts, in the same velocity
+//! and position iterations. Anchors are given in the local space of the body they're attached to,
+//! or in world space when attached to the world.
+
+use hv::{ecs::Entity, prelude::*, script::api::ModuleBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::with_loaned_mut,
+    physics::{Physics, PhysicsPipeline},
+    types::UpdateDt,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct JointId(pub(super) u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum JointKind {
+    /// Keeps the anchors exactly `length` apart.
+    Distance { length: f32 },
+    /// Keeps the anchors at most `max_length` apart.
+    Rope { max_length: f32 },
+    /// Pins the anchors together, leaving the bodies free to rotate about the Z axis.
+    Revolute,
+    /// Pins the anchors together and locks the rotation of the second body relative to the first
+    /// at `reference_angle`.
+    Fixed { reference_angle: f32 },
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Joint {
+    pub kind: JointKind,
+    pub body1: Entity,
+    pub anchor1: Point3<f32>,
+    /// The second body, or `None` if the joint is attached to the world.
+    pub body2: Option<Entity>,
+    pub anchor2: Point3<f32>,
+    /// Whether contacts should be generated between the two bodies. Default: `false`.
+    pub collide_connected: bool,
+
+    // Accumulated impulses, for warm starting. Distance and rope joints only use the X component
+    // of the linear impulse, as the impulse along the axis between their anchors.
+    linear_impulse: Vector3<f32>,
+    angular_impulse: f32,
+}
+
+impl Joint {
+    pub fn new(
+        kind: JointKind,
+        body1: Entity,
+        anchor1: Point3<f32>,
+        body2: Option<Entity>,
+        anchor2: Point3<f32>,
+    ) -> Self {
+        Self {
+            kind,
+            body1,
+            anchor1,
+            body2,
+            anchor2,
+            collide_connected: false,
+            linear_impulse: Vector3::zeros(),
+            angular_impulse: 0.,
+        }
+    }
+
+    pub fn distance(
+        body1: Entity,
+        anchor1: Point3<f32>,
+        body2: Option<Entity>,
+        anchor2: Point3<f32>,
+        length: f32,
+    ) -> Self {
+        Self::new(
+            JointKind::Distance { length },
+            body1,
+            anchor1,
+            body2,
+            anchor2,
+        )
+    }
+
+    pub fn rope(
+        body1: Entity,
+        anchor1: Point3<f32>,
+        body2: Option<Entity>,
+        anchor2: Point3<f32>,
+        max_length: f32,
+    ) -> Self {
+        Self::new(
+            JointKind::Rope { max_length },
+            body1,
+            anchor1,
+            body2,
+            anchor2,
+        )
+    }
+
+    pub fn revolute(
+        body1: Entity,
+        anchor1: Point3<f32>,
+        body2: Option<Entity>,
+        anchor2: Point3<f32>,
+    ) -> Self {
+        Self::new(JointKind::Revolute, body1, anchor1, body2, anchor2)
+    }
+
+    pub fn fixed(
+        body1: Entity,
+        anchor1: Point3<f32>,
+        body2: Option<Entity>,
+        anchor2: Point3<f32>,
+        reference_angle: f32,
+    ) -> Self {
+        Self::new(
+            JointKind::Fixed { reference_angle },
+            body1,
+            anchor1,
+            body2,
+            anchor2,
+        )
+    }
+
+    pub fn with_collide_connected(self, collide_connected: bool) -> Self {
+        Self {
+            collide_connected,
+            ..self
+        }
+    }
+
+    pub(super) fn warm_start(&self, a: &mut Physics, mut b: Option<&mut Physics>) {
+        let fa = Frame::new(Some(&*a), &self.anchor1);
+        let fb = Frame::new(b.as_deref(), &self.anchor2);
+
+        let linear = match self.kind {
+            JointKind::Distance { .. } | JointKind::Rope { .. } => match fa.axis_to(&fb) {
+                Some((n, _)) => n * self.linear_impulse.x,
+                None => Vector3::zeros(),
+            },
+            JointKind::Revolute | JointKind::Fixed { .. } => self.linear_impulse,
+        };
+
+        apply_velocity_impulse(&linear, &fa, a, &fb, b.as_deref_mut());
+
+        if let JointKind::Fixed { .. } = self.kind {
+            apply_angular_velocity_impulse(self.angular_impulse, &fa, a, &fb, b);
+        }
+    }
+
+    pub(super) fn solve_velocity(
+        &mut self,
+        a: &mut Physics,
+        mut b: Option<&mut Physics>,
+        dt: &UpdateDt,
+    ) {
+        match self.kind {
+            JointKind::Distance { length: limit } | JointKind::Rope { max_length: limit } => {
+                let fa = Frame::new(Some(&*a), &self.anchor1);
+                let fb = Frame::new(b.as_deref(), &self.anchor2);
+                let (n, distance) = match fa.axis_to(&fb) {
+                    Some(axis) => axis,
+                    None => return,
+                };
+
+                let k = effective_mass(&n, &fa, &fb);
+                if k == 0. {
+                    return;
+                }
+
+                let mut cdot = (fb.velocity(b.as_deref()) - fa.velocity(Some(&*a))).dot(&n);
+                let is_rope = matches!(self.kind, JointKind::Rope { .. });
+                let c = distance - limit;
+
+                // A slack rope is allowed to approach its limit, but not to pass it.
+                if is_rope && c < 0. {
+                    cdot += c / dt.0;
+                }
+
+                let mut lambda = -cdot / k;
+                if is_rope {
+                    let old = self.linear_impulse.x;
+                    self.linear_impulse.x = (old + lambda).min(0.);
+                    lambda = self.linear_impulse.x - old;
+                } else {
+                    self.linear_impulse.x += lambda;
+                }
+
+                apply_velocity_impulse(&(n * lambda), &fa, a, &fb, b);
+            }
+            JointKind::Revolute | JointKind::Fixed { .. } => {
+                if let JointKind::Fixed { .. } = self.kind {
+                    let fa = Frame::new(Some(&*a), &self.anchor1);
+                    let fb = Frame::new(b.as_deref(), &self.anchor2);
+                    let k = fa.inv_inertia + fb.inv_inertia;
+                    if k > 0. {
+                        let wb = b.as_deref().map_or(0., |b| b.velocity.angular);
+                        let lambda = -(wb - a.velocity.angular) / k;
+                        self.angular_impulse += lambda;
+                        apply_angular_velocity_impulse(lambda, &fa, a, &fb, b.as_deref_mut());
+                    }
+                }
+
+                for axis in [Vector3::x(), Vector3::y(), Vector3::z()] {
+                    let fa = Frame::new(Some(&*a), &self.anchor1);
+                    let fb = Frame::new(b.as_deref(), &self.anchor2);
+                    let k = effective_mass(&axis, &fa, &fb);
+                    if k == 0. {
+                        continue;
+                    }
+
+                    let cdot = (fb.velocity(b.as_deref()) - fa.velocity(Some(&*a))).dot(&axis);
+                    let lambda = -cdot / k;
+                    self.linear_impulse += axis * lambda;
+                    apply_velocity_impulse(&(axis * lambda), &fa, a, &fb, b.as_deref_mut());
+                }
+            }
+        }
+    }
+
+    pub(super) fn solve_position(&self, a: &mut Physics, mut b: Option<&mut Physics>) {
+        match self.kind {
+            JointKind::Distance { length: limit } | JointKind::Rope { max_length: limit } => {
+                let fa = Frame::new(Some(&*a), &self.anchor1);
+                let fb = Frame::new(b.as_deref(), &self.anchor2);
+                let (n, distance) = match fa.axis_to(&fb) {
+                    Some(axis) => axis,
+                    None => return,
+                };
+
+                let mut c = distance - limit;
+                if let JointKind::Rope { .. } = self.kind {
+                    c = c.max(0.);
+                }
+
+                let k = effective_mass(&n, &fa, &fb);
+                if k > 0. {
+                    apply_position_impulse(&(n * (-c / k)), &fa, a, &fb, b);
+                }
+            }
+            JointKind::Revolute | JointKind::Fixed { .. } => {
+                if let JointKind::Fixed { reference_angle } = self.kind {
+                    let fa = Frame::new(Some(&*a), &self.anchor1);
+                    let fb = Frame::new(b.as_deref(), &self.anchor2);
+                    let k = fa.inv_inertia + fb.inv_inertia;
+                    if k > 0. {
+                        let rb = b
+                            .as_deref()
+                            .map_or_else(UnitComplex::identity, |b| b.position.rotation);
+                        let c = (rb * a.position.rotation.inverse()).angle() - reference_angle;
+                        let c = UnitComplex::new(c).angle();
+                        let lambda = -c / k;
+                        a.position.rotation =
+                            UnitComplex::new(-fa.inv_inertia * lambda) * a.position.rotation;
+                        if let Some(b) = b.as_deref_mut() {
+                            b.position.rotation =
+                                UnitComplex::new(fb.inv_inertia * lambda) * b.position.rotation;
+                        }
+                    }
+                }
+
+                for axis in [Vector3::x(), Vector3::y(), Vector3::z()] {
+                    let fa = Frame::new(Some(&*a), &self.anchor1);
+                    let fb = Frame::new(b.as_deref(), &self.anchor2);
+                    let k = effective_mass(&axis, &fa, &fb);
+                    if k == 0. {
+                        continue;
+                    }
+
+                    let c = (fb.point - fa.point).dot(&axis);
+                    apply_position_impulse(&(axis * (-c / k)), &fa, a, &fb, b.as_deref_mut());
+                }
+            }
+        }
+    }
+}
+
+/// The world-space anchor of one side of a joint, along w/ the mass of the body it's on.
+struct Frame {
+    /// Offset of the anchor from the body's origin.
+    r: Vector3<f32>,
+    point: Point3<f32>,
+    inv_mass: f32,
+    inv_inertia: f32,
+}
+
+impl Frame {
+    fn new(physics: Option<&Physics>, anchor: &Point3<f32>) -> Self {
+        match physics {
+            Some(physics) => {
+                let r = physics.position.transform_vector(&anchor.coords);
+                Self {
+                    r,
+                    point: Point3::from(physics.position.translation + r),
+                    inv_mass: physics.mass_data.inv_mass,
+                    inv_inertia: physics.mass_data.inv_inertia,
+                }
+            }
+            None => Self {
+                r: Vector3::zeros(),
+                point: *anchor,
+                inv_mass: 0.,
+                inv_inertia: 0.,
+            },
+        }
+    }
+
+    /// Velocity of the anchor point.
+    fn velocity(&self, physics: Option<&Physics>) -> Vector3<f32> {
+        physics.map_or_else(Vector3::zeros, |physics| {
+            let w = physics.velocity.angular;
+            physics.velocity.linear + Vector3::new(-w * self.r.y, w * self.r.x, 0.)
+        })
+    }
+
+    /// The unit vector from this anchor to another, and the distance between them. `None` if the
+    /// anchors coincide.
+    fn axis_to(&self, other: &Self) -> Option<(Vector3<f32>, f32)> {
+        let d = other.point - self.point;
+        let distance = d.norm();
+        (distance > f32::EPSILON).then(|| (d / distance, distance))
+    }
+
+    /// The Z component of `r x n`; how much an impulse along `n` at this anchor turns the body.
+    fn moment_arm(&self, n: &Vector3<f32>) -> f32 {
+        self.r.x * n.y - self.r.y * n.x
+    }
+}
+
+fn effective_mass(n: &Vector3<f32>, fa: &Frame, fb: &Frame) -> f32 {
+    fa.inv_mass
+        + fb.inv_mass
+        + fa.inv_inertia * fa.moment_arm(n).powi(2)
+        + fb.inv_inertia * fb.moment_arm(n).powi(2)
+}
+
+/// Apply `impulse` to `b` at its anchor, and its negation to `a`.
+fn apply_velocity_impulse(
+    impulse: &Vector3<f32>,
+    fa: &Frame,
+    a: &mut Physics,
+    fb: &Frame,
+    b: Option<&mut Physics>,
+) {
+    a.velocity.linear -= impulse * fa.inv_mass;
+    a.velocity.angular -= fa.inv_inertia * fa.moment_arm(impulse);
+
+    if let Some(b) = b {
+        b.velocity.linear += impulse * fb.inv_mass;
+        b.velocity.angular += fb.inv_inertia * fb.moment_arm(impulse);
+    }
+}
+
+fn apply_angular_velocity_impulse(
+    impulse: f32,
+    fa: &Frame,
+    a: &mut Physics,
+    fb: &Frame,
+    b: Option<&mut Physics>,
+) {
+    a.velocity.angular -= fa.inv_inertia * impulse;
+
+    if let Some(b) = b {
+        b.velocity.angular += fb.inv_inertia * impulse;
+    }
+}
+
+/// Like [`apply_velocity_impulse`], but moves the bodies directly.
+fn apply_position_impulse(
+    impulse: &Vector3<f32>,
+    fa: &Frame,
+    a: &mut Physics,
+    fb: &Frame,
+    b: Option<&mut Physics>,
+) {
+    a.position.translation -= impulse * fa.inv_mass;
+    a.position.rotation =
+        UnitComplex::new(-fa.inv_inertia * fa.moment_arm(impulse)) * a.position.rotation;
+
+    if let Some(b) = b {
+        b.position.translation += impulse * fb.inv_mass;
+        b.position.rotation =
+            UnitComplex::new(fb.inv_inertia * fb.moment_arm(impulse)) * b.position.rotation;
+    }
+}
+
+pub(crate) fn register_functions(builder: &mut ModuleBuilder) -> Result<()> {
+    builder
+        .function("add_joint", |lua, joint: Joint| {
+            with_loaned_mut(lua, |pipeline: &mut PhysicsPipeline| {
+                pipeline.add_joint(joint).to_lua_err()
+            })
+        })?
+        .function("remove_joint", |lua, joint_id: JointId| {
+            with_loaned_mut(lua, |pipeline: &mut PhysicsPipeline| {
+                Ok(pipeline.remove_joint(joint_id))
+            })
+        })?;
+
+    Ok(())
+}
+
+impl LuaUserData for JointId {
+    fn on_metatable_init(table: Type<Self>) {
+        table
+            .add_clone()
+            .add_copy()
+            .add_send()
+            .add_sync()
+            .add::<dyn std::fmt::Debug>();
+    }
+}
+
+impl LuaUserData for Joint {
+    fn on_metatable_init(table: Type<Self>) {
+        table
+            .add_clone()
+            .add_copy()
+            .add_send()
+            .add_sync()
+            .add::<dyn std::fmt::Debug>();
+    }
+
+    #[allow(clippy::unit_arg)]
+    fn add_fields<'lua, F: LuaUserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("body1", |_, this| Ok(this.body1));
+        fields.add_field_method_get("body2", |_, this| Ok(this.body2));
+        fields.add_field_method_get("anchor1", |_, this| Ok(this.anchor1.coords));
+        fields.add_field_method_get("anchor2", |_, this| Ok(this.anchor2.coords));
+        fields.add_field_method_get("collide_connected", |_, this| Ok(this.collide_connected));
+        fields.add_field_method_set("anchor1", |_, this, anchor: Vector3<f32>| {
+            Ok(this.anchor1 = Point3::from(anchor))
+        });
+        fields.add_field_method_set("anchor2", |_, this, anchor: Vector3<f32>| {
+            Ok(this.anchor2 = Point3::from(anchor))
+        });
+        fields.add_field_method_set("collide_connected", |_, this, collide_connected| {
+            Ok(this.collide_connected = collide_connected)
+        });
+    }
+
+    fn add_type_methods<'lua, M: LuaUserDataMethods<'lua, Type<Self>>>(methods: &mut M) {
+        methods.add_function(
+            "distance",
+            |_,
+             (body1, anchor1, body2, anchor2, length): (
+                Entity,
+                Vector3<f32>,
+                Option<Entity>,
+                Vector3<f32>,
+                f32,
+            )| {
+                Ok(Self::distance(
+                    body1,
+                    Point3::from(anchor1),
+                    body2,
+                    Point3::from(anchor2),
+                    length,
+                ))
+            },
+        );
+
+        methods.add_function(
+            "rope",
+            |_,
+             (body1, anchor1, body2, anchor2, max_length): (
+                Entity,
+                Vector3<f32>,
+                Option<Entity>,
+                Vector3<f32>,
+                f32,
+            )| {
+                Ok(Self::rope(
+                    body1,
+                    Point3::from(anchor1),
+                    body2,
+                    Point3::from(anchor2),
+                    max_length,
+                ))
+            },
+        );
+
+        methods.add_function(
+            "revolute",
+            |_,
+             (body1, anchor1, body2, anchor2): (
+                Entity,
+                Vector3<f32>,
+                Option<Entity>,
+                Vector3<f32>,
+            )| {
+                Ok(Self::revolute(
+                    body1,
+                    Point3::from(anchor1),
+                    body2,
+                    Point3::from(anchor2),
+                ))
+            },
+        );
+
+        methods.add_function(
+            "fixed",
+            |_,
+             (body1, anchor1, body2, anchor2, reference_angle): (
+                Entity,
+                Vector3<f32>,
+                Option<Entity>,
+                Vector3<f32>,
+                Option<f32>,
+            )| {
+                Ok(Self::fixed(
+                    body1,
+                    Point3::from(anchor1),
+                    body2,
+                    Point3::from(anchor2),
+                    reference_angle.unwrap_or(0.),
+                ))
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hv::{
+        ecs::{System, World},
+        resources::Resources,
+    };
+    use parry3d::shape::SharedShape;
+
+    use crate::{
+        command_buffer::{CommandPool, CommandPoolResource},
+        lattice::atom_map::AtomMap,
+        physics::{update, CompositePosition3, CompositeVelocity3, Position, Velocity},
+        types::UpdateTick,
+    };
+
+    const DT: f32 = 1. / 60.;
+
+    fn spawn_ball(world: &mut World, x: f32, y: f32, velocity: CompositeVelocity3) -> Entity {
+        world.spawn((
+            Position::new(CompositePosition3::translation(x, y, 0.)),
+            Velocity {
+                composite: velocity,
+            },
+            Physics::new(SharedShape::ball(0.25)),
+        ))
+    }
+
+    fn run(world: &mut World, pipeline: &mut PhysicsPipeline, ticks: u64) {
+        let atom_map = AtomMap::new();
+        let pool = CommandPool::new();
+        let command_pool = CommandPoolResource::new();
+        let mut resources = Resources::new();
+        for tick in 0..ticks {
+            command_pool.run_scoped(&pool, world, &mut resources, |world| {
+                update.run(
+                    world,
+                    (
+                        &UpdateDt(DT),
+                        &UpdateTick(tick),
+                        &atom_map,
+                        &mut *pipeline,
+                        &command_pool,
+                    ),
+                )
+            });
+        }
+    }
+
+    /// World-space position of an anchor on a body, or the anchor itself for the world.
+    fn anchor_point(world: &World, body: Option<Entity>, anchor: Point3<f32>) -> Point3<f32> {
+        match body {
+            Some(e) => world
+                .get::<Position>(e)
+                .unwrap()
+                .current
+                .transform_point(&anchor),
+            None => anchor,
+        }
+    }
+
+    fn anchor_distance(world: &World, joint: &Joint) -> f32 {
+        let p1 = anchor_point(world, Some(joint.body1), joint.anchor1);
+        let p2 = anchor_point(world, joint.body2, joint.anchor2);
+        (p2 - p1).norm()
+    }
+
+    fn angle(world: &World, e: Entity) -> f32 {
+        world.get::<Position>(e).unwrap().current.rotation.angle()
+    }
+
+    #[test]
+    fn rejects_joining_a_body_to_itself() {
+        let mut world = World::new();
+        let e = spawn_ball(&mut world, 0., 0., CompositeVelocity3::zero());
+        let mut pipeline = PhysicsPipeline::default();
+
+        let joint = Joint::revolute(e, Point3::origin(), Some(e), Point3::new(1., 0., 0.));
+        assert!(pipeline.add_joint(joint).is_err());
+        assert_eq!(pipeline.joints().count(), 0);
+        assert!(pipeline.jointed_pairs.is_empty());
+    }
+
+    #[test]
+    fn distance_joint_keeps_its_length() {
+        let mut world = World::new();
+        let a = spawn_ball(&mut world, 0., 0., CompositeVelocity3::zero());
+        let b = spawn_ball(
+            &mut world,
+            1.5,
+            0.,
+            CompositeVelocity3::new(Vector3::new(2., 3., 0.), 0.),
+        );
+        let mut pipeline = PhysicsPipeline::default();
+        let joint = Joint::distance(a, Point3::origin(), Some(b), Point3::origin(), 1.5);
+        let joint_id = pipeline.add_joint(joint).unwrap();
+
+        run(&mut world, &mut pipeline, 120);
+
+        let joint = *pipeline.joint(joint_id).unwrap();
+        assert!((anchor_distance(&world, &joint) - 1.5).abs() < 0.01);
+        // Both bodies were dragged along, rather than `b` orbiting a fixed `a`.
+        let pos_a = world.get::<Position>(a).unwrap().current.translation;
+        assert!(pos_a.norm() > 0.1);
+    }
+
+    #[test]
+    fn rope_joint_stops_at_its_max_length() {
+        let mut world = World::new();
+        let e = spawn_ball(&mut world, 0., -1., CompositeVelocity3::zero());
+        let mut pipeline = PhysicsPipeline::default();
+        pipeline.config.gravity = Vector3::new(0., -10., 0.);
+        let joint = Joint::rope(e, Point3::origin(), None, Point3::origin(), 2.);
+        let joint_id = pipeline.add_joint(joint).unwrap();
+
+        // Slack at first, so the body falls freely.
+        run(&mut world, &mut pipeline, 10);
+        let joint = *pipeline.joint(joint_id).unwrap();
+        let distance = anchor_distance(&world, &joint);
+        assert!(distance > 1.1 && distance < 2.);
+
+        // Then the rope goes taut and holds it up.
+        run(&mut world, &mut pipeline, 120);
+        let distance = anchor_distance(&world, &joint);
+        assert!(distance <= 2.01 && distance > 1.95, "{}", distance);
+    }
+
+    #[test]
+    fn revolute_joint_pins_its_anchors_together() {
+        let mut world = World::new();
+        let e = spawn_ball(&mut world, 1., 0., CompositeVelocity3::zero());
+        let mut pipeline = PhysicsPipeline::default();
+        pipeline.config.gravity = Vector3::new(0., -10., 0.);
+        let joint = Joint::revolute(e, Point3::new(-1., 0., 0.), None, Point3::origin());
+        let joint_id = pipeline.add_joint(joint).unwrap();
+
+        run(&mut world, &mut pipeline, 30);
+
+        // The body swings about the world anchor like a pendulum, turning as it goes.
+        let joint = *pipeline.joint(joint_id).unwrap();
+        assert!(anchor_distance(&world, &joint) < 0.01);
+        let pos = world.get::<Position>(e).unwrap().current.translation;
+        assert!(pos.y < -0.5);
+        assert!(angle(&world, e) < -0.5);
+    }
+
+    #[test]
+    fn fixed_joint_locks_relative_position_and_angle() {
+        let mut world = World::new();
+        let a = spawn_ball(&mut world, 0., 0., CompositeVelocity3::zero());
+        let b = spawn_ball(
+            &mut world,
+            1.,
+            0.,
+            CompositeVelocity3::new(Vector3::new(0., 2., 0.), 3.),
+        );
+        let mut pipeline = PhysicsPipeline::default();
+        let joint = Joint::fixed(
+            a,
+            Point3::new(0.5, 0., 0.),
+            Some(b),
+            Point3::new(-0.5, 0., 0.),
+            0.,
+        );
+        let joint_id = pipeline.add_joint(joint).unwrap();
+
+        run(&mut world, &mut pipeline, 120);
+
+        let joint = *pipeline.joint(joint_id).unwrap();
+        assert!(anchor_distance(&world, &joint) < 0.01);
+        let relative = UnitComplex::new(angle(&world, b) - angle(&world, a)).angle();
+        assert!(relative.abs() < 0.01, "{}", relative);
+    }
+}
@@ -0,0 +1,795 @@
+//! Scene queries against the physics world.
+//!
+//! Queries are answered against the state of the world as of the end of the last physics update;
+//! they test both the colliders of bodies with [`Physics`] components and the static hulls of an
+//! [`AtomMap`].
+
+use hv::{ecs::Entity, prelude::*, script::api::ModuleBuilder};
+use parry3d::{
+    bounding_volume::{BoundingVolume, AABB},
+    query::{
+        visitors::{BoundingVolumeIntersectionsVisitor, RayIntersectionsVisitor},
+        PointQuery, Ray, RayCast,
+    },
+    shape::{Shape, SharedShape},
+};
+
+use crate::{
+    api::with_loaned,
+    lattice::atom_map::AtomMap,
+    physics::{CollisionGroups, CompositePosition3, Physics, PhysicsPipeline},
+};
+
+/// The thing a scene query hit: either a body, or a cell of the static lattice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum QueryTarget {
+    Body(Entity),
+    Lattice(Vector3<i32>),
+}
+
+impl QueryTarget {
+    pub fn entity(&self) -> Option<Entity> {
+        match self {
+            Self::Body(e) => Some(*e),
+            Self::Lattice(_) => None,
+        }
+    }
+
+    pub fn coords(&self) -> Option<Vector3<i32>> {
+        match self {
+            Self::Body(_) => None,
+            Self::Lattice(coords) => Some(*coords),
+        }
+    }
+}
+
+/// Controls what a scene query is allowed to hit.
+#[derive(Debug, Clone, Copy)]
+pub struct QueryFilter {
+    /// Whether to test against the colliders of bodies. Default: `true`.
+    pub bodies: bool,
+    /// Whether to test against the static hulls of the [`AtomMap`]. Default: `true`.
+    pub lattice: bool,
+    /// A body to ignore; usually the one doing the querying. Default: `None`.
+    pub exclude: Option<Entity>,
+    /// Collision groups of the query itself, tested against those of bodies and the lattice.
+    /// Default: [`CollisionGroups::ALL`].
+    pub groups: CollisionGroups,
+}
+
+impl Default for QueryFilter {
+    fn default() -> Self {
+        Self {
+            bodies: true,
+            lattice: true,
+            exclude: None,
+            groups: CollisionGroups::ALL,
+        }
+    }
+}
+
+impl QueryFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn bodies_only() -> Self {
+        Self {
+            lattice: false,
+            ..Self::default()
+        }
+    }
+
+    pub fn lattice_only() -> Self {
+        Self {
+            bodies: false,
+            ..Self::default()
+        }
+    }
+
+    pub fn excluding(self, entity: Entity) -> Self {
+        Self {
+            exclude: Some(entity),
+            ..self
+        }
+    }
+
+    pub fn with_groups(self, groups: CollisionGroups) -> Self {
+        Self { groups, ..self }
+    }
+
+    fn test_body(&self, collider: &QueryCollider) -> bool {
+        self.bodies
+            && self.exclude != Some(collider.entity)
+            && self.groups.interacts_with(collider.groups)
+    }
+
+    fn test_lattice(&self, pipeline: &PhysicsPipeline) -> bool {
+        self.lattice && self.groups.interacts_with(pipeline.config.lattice_groups)
+    }
+}
+
+/// The result of a ray cast.
+#[derive(Debug, Clone, Copy)]
+pub struct RayHit {
+    pub target: QueryTarget,
+    /// Time of impact, in multiples of the ray's direction vector.
+    pub toi: f32,
+    /// World-space point of impact.
+    pub point: Point3<f32>,
+    /// World-space surface normal at the point of impact.
+    pub normal: Vector3<f32>,
+}
+
+/// The result of a shape cast.
+#[derive(Debug, Clone, Copy)]
+pub struct ShapeHit {
+    pub target: QueryTarget,
+    /// Time of impact, in multiples of the shape's velocity.
+    pub toi: f32,
+    /// World-space point on the target which the cast shape first touches.
+    pub point: Point3<f32>,
+    /// World-space surface normal of the target at the point of impact.
+    pub normal: UnitVector3<f32>,
+}
+
+/// A contact between a query shape and something in the physics world.
+#[derive(Debug, Clone, Copy)]
+pub struct ShapeContact {
+    pub target: QueryTarget,
+    /// World-space point on the target closest to the query shape.
+    pub point: Point3<f32>,
+    /// World-space surface normal of the target, pointing towards the query shape.
+    pub normal: UnitVector3<f32>,
+    /// Distance between the target and the query shape; negative if they're overlapping.
+    pub dist: f32,
+}
+
+/// A snapshot of a body's collider, recorded at the end of a physics update for use in queries.
+#[derive(Clone)]
+pub struct QueryCollider {
+    pub entity: Entity,
+    pub position: Isometry3<f32>,
+    pub shape: SharedShape,
+    pub groups: CollisionGroups,
+}
+
+impl PhysicsPipeline {
+    /// Record the final collider positions of a physics update, for use in scene queries.
+    pub(crate) fn update_query_colliders<'a>(
+        &mut self,
+        bodies: impl IntoIterator<Item = (Entity, &'a Physics)>,
+    ) {
+        self.query_colliders.clear();
+        for (entity, physics) in bodies {
+            // Sensor parts aren't recorded, so bodies made only of sensor parts are skipped.
+            let shape = match &physics.collider_shape {
+                Some(shape) => shape.clone(),
+                None => continue,
+            };
+
+            self.query_colliders.insert(
+                entity.id(),
+                QueryCollider {
+                    entity,
+                    position: physics.collider_tx * physics.position.as_isometry3(),
+                    shape,
+                    groups: physics.groups,
+                },
+            );
+        }
+
+        self.query_qbvh.clear_and_rebuild(
+            self.query_colliders
+                .iter()
+                .map(|(&id, c)| (id, c.shape.compute_aabb(&c.position))),
+            0.01,
+        );
+    }
+
+    /// Find the first thing hit by a ray, up to a distance of `max_toi` times the length of the
+    /// ray's direction.
+    pub fn cast_ray(
+        &self,
+        atom_map: &AtomMap,
+        ray: &Ray,
+        max_toi: f32,
+        filter: &QueryFilter,
+    ) -> Option<RayHit> {
+        let mut best = None::<RayHit>;
+        let mut replace_if_closer = |hit: RayHit| {
+            if best.map_or(true, |prev| hit.toi < prev.toi) {
+                best = Some(hit);
+            }
+        };
+
+        if filter.bodies {
+            let mut leaf_callback = |id: &u32| {
+                let collider = &self.query_colliders[id];
+                if filter.test_body(collider) {
+                    if let Some(hit) = collider.shape.cast_ray_and_get_normal(
+                        &collider.position,
+                        ray,
+                        max_toi,
+                        true,
+                    ) {
+                        replace_if_closer(RayHit {
+                            target: QueryTarget::Body(collider.entity),
+                            toi: hit.toi,
+                            point: ray.point_at(hit.toi),
+                            normal: hit.normal,
+                        });
+                    }
+                }
+
+                true
+            };
+
+            let mut visitor = RayIntersectionsVisitor::new(ray, max_toi, &mut leaf_callback);
+            self.query_qbvh.traverse_depth_first(&mut visitor);
+        }
+
+        if filter.test_lattice(self) {
+            let end = ray.point_at(max_toi);
+            let aabb = AABB::new(ray.origin.inf(&end), ray.origin.sup(&end));
+            for intersection in atom_map.intersect_with(aabb) {
+                if let Some((hit, _)) =
+                    intersection
+                        .shape
+                        .cast_ray(&intersection.coords, ray, max_toi)
+                {
+                    replace_if_closer(RayHit {
+                        target: QueryTarget::Lattice(intersection.coords),
+                        toi: hit.toi,
+                        point: ray.point_at(hit.toi),
+                        normal: hit.normal,
+                    });
+                }
+            }
+        }
+
+        best
+    }
+
+    /// Sweep a shape from `shape_pos` along `shape_vel`, finding the first thing it would hit
+    /// before `max_toi`. Bodies are treated as stationary.
+    pub fn cast_shape(
+        &self,
+        atom_map: &AtomMap,
+        shape_pos: &Isometry3<f32>,
+        shape_vel: &Vector3<f32>,
+        shape: &dyn Shape,
+        max_toi: f32,
+        filter: &QueryFilter,
+    ) -> Option<ShapeHit> {
+        let end_pos = Isometry3::from_parts(
+            Translation3::from(shape_pos.translation.vector + shape_vel * max_toi),
+            shape_pos.rotation,
+        );
+        let aabb = shape.compute_swept_aabb(shape_pos, &end_pos);
+
+        let mut best = None::<ShapeHit>;
+        let mut replace_if_closer = |hit: ShapeHit| {
+            if best.map_or(true, |prev| hit.toi < prev.toi) {
+                best = Some(hit);
+            }
+        };
+
+        if filter.bodies {
+            let mut out = Vec::new();
+            self.query_qbvh.intersect_aabb(&aabb, &mut out);
+            for id in out {
+                let collider = &self.query_colliders[&id];
+                if !filter.test_body(collider) {
+                    continue;
+                }
+
+                let maybe_toi = parry3d::query::time_of_impact(
+                    shape_pos,
+                    shape_vel,
+                    shape,
+                    &collider.position,
+                    &Vector3::zeros(),
+                    collider.shape.as_ref(),
+                    max_toi,
+                );
+
+                if let Ok(Some(toi)) = maybe_toi {
+                    replace_if_closer(ShapeHit {
+                        target: QueryTarget::Body(collider.entity),
+                        toi: toi.toi,
+                        point: collider.position * toi.witness2,
+                        normal: collider.position * toi.normal2,
+                    });
+                }
+            }
+        }
+
+        if filter.test_lattice(self) {
+            for intersection in atom_map.intersect_with(aabb) {
+                let maybe_toi = intersection.shape.time_of_impact(
+                    &intersection.coords,
+                    shape_pos,
+                    shape_vel,
+                    shape,
+                    max_toi,
+                );
+
+                if let Some(toi) = maybe_toi {
+                    let pos1 = Isometry3::from(intersection.coords.cast::<f32>());
+                    replace_if_closer(ShapeHit {
+                        target: QueryTarget::Lattice(intersection.coords),
+                        toi: toi.toi,
+                        point: pos1 * toi.witness1,
+                        normal: toi.normal1,
+                    });
+                }
+            }
+        }
+
+        best
+    }
+
+    /// Find everything within `prediction` of a shape placed at `shape_pos`, including anything it
+    /// overlaps.
+    pub fn contacts_with_shape(
+        &self,
+        atom_map: &AtomMap,
+        shape_pos: &Isometry3<f32>,
+        shape: &dyn Shape,
+        prediction: f32,
+        filter: &QueryFilter,
+        out: &mut Vec<ShapeContact>,
+    ) {
+        let aabb = shape.compute_aabb(shape_pos).loosened(prediction);
+
+        if filter.bodies {
+            let mut ids = Vec::new();
+            self.query_qbvh.intersect_aabb(&aabb, &mut ids);
+            for id in ids {
+                let collider = &self.query_colliders[&id];
+                if !filter.test_body(collider) {
+                    continue;
+                }
+
+                let maybe_contact = parry3d::query::contact(
+                    &collider.position,
+                    collider.shape.as_ref(),
+                    shape_pos,
+                    shape,
+                    prediction,
+                );
+
+                if let Ok(Some(c)) = maybe_contact {
+                    out.push(ShapeContact {
+                        target: QueryTarget::Body(collider.entity),
+                        point: c.point1,
+                        normal: c.normal1,
+                        dist: c.dist,
+                    });
+                }
+            }
+        }
+
+        if filter.test_lattice(self) {
+            let mut contacts = Vec::new();
+            for intersection in atom_map.intersect_with(aabb) {
+                let (edge_filter, vertex_filter) = atom_map.filters(intersection.coords);
+                intersection.shape.contact(
+                    &intersection.coords,
+                    shape,
+                    shape_pos,
+                    prediction,
+                    edge_filter,
+                    vertex_filter,
+                    &mut contacts,
+                );
+
+                out.extend(contacts.drain(..).map(|(c, _)| ShapeContact {
+                    target: QueryTarget::Lattice(intersection.coords),
+                    point: c.point1,
+                    normal: c.normal1,
+                    dist: c.dist,
+                }));
+            }
+        }
+    }
+
+    /// Find everything containing a given point.
+    pub fn intersections_with_point(
+        &self,
+        atom_map: &AtomMap,
+        point: &Point3<f32>,
+        filter: &QueryFilter,
+        out: &mut Vec<QueryTarget>,
+    ) {
+        if filter.bodies {
+            let aabb = AABB::new(*point, *point);
+            let mut leaf_callback = |id: &u32| {
+                let collider = &self.query_colliders[id];
+                if filter.test_body(collider)
+                    && collider.shape.contains_point(&collider.position, point)
+                {
+                    out.push(QueryTarget::Body(collider.entity));
+                }
+
+                true
+            };
+
+            let mut visitor = BoundingVolumeIntersectionsVisitor::new(&aabb, &mut leaf_callback);
+            self.query_qbvh.traverse_depth_first(&mut visitor);
+        }
+
+        if filter.test_lattice(self) {
+            // Hulls are joined across cells, so there's no closed mesh per cell to test against.
+            // Instead we look straight up; if the first facet we see is facing away from us, we're
+            // inside the terrain.
+            let top = match atom_map.shapes().get_layers_in_range(..).last() {
+                Some((top, _)) => top,
+                None => return,
+            };
+            let ray = Ray::new(*point, Vector3::z());
+            let max_toi = (top as f32 - point.z + 2.).max(1.);
+            let up = self.cast_ray(atom_map, &ray, max_toi, &QueryFilter::lattice_only());
+
+            if let Some(hit) = up.filter(|hit| hit.normal.z > 0.) {
+                let cell = point.coords.map(|t| t.floor() as i32);
+                if atom_map.shapes().get(cell).is_some() {
+                    out.push(QueryTarget::Lattice(cell));
+                } else {
+                    out.push(hit.target);
+                }
+            }
+        }
+    }
+
+    /// Find everything whose bounding box overlaps a given AABB.
+    pub fn intersections_with_aabb(
+        &self,
+        atom_map: &AtomMap,
+        aabb: &AABB,
+        filter: &QueryFilter,
+        out: &mut Vec<QueryTarget>,
+    ) {
+        if filter.bodies {
+            let mut ids = Vec::new();
+            self.query_qbvh.intersect_aabb(aabb, &mut ids);
+            out.extend(ids.into_iter().filter_map(|id| {
+                let collider = &self.query_colliders[&id];
+                let overlaps = collider
+                    .shape
+                    .compute_aabb(&collider.position)
+                    .intersects(aabb);
+                (overlaps && filter.test_body(collider)).then(|| QueryTarget::Body(collider.entity))
+            }));
+        }
+
+        if filter.test_lattice(self) {
+            out.extend(atom_map.intersect_with(*aabb).filter_map(|intersection| {
+                let overlaps = intersection
+                    .shape
+                    .compute_aabb(&intersection.coords)
+                    .intersects(aabb);
+                overlaps.then(|| QueryTarget::Lattice(intersection.coords))
+            }));
+        }
+    }
+}
+
+pub(crate) fn register_functions(builder: &mut ModuleBuilder) -> Result<()> {
+    builder
+        .function(
+            "cast_ray",
+            |lua,
+             (origin, dir, max_toi, exclude): (
+                Vector3<f32>,
+                Vector3<f32>,
+                f32,
+                Option<Entity>,
+            )| {
+                let ray = Ray::new(Point3::from(origin), dir);
+                let filter = QueryFilter {
+                    exclude,
+                    ..QueryFilter::default()
+                };
+                let hit = with_loaned(lua, |pipeline: &PhysicsPipeline| {
+                    with_loaned(lua, |atom_map: &AtomMap| {
+                        Ok(pipeline.cast_ray(atom_map, &ray, max_toi, &filter))
+                    })
+                })?;
+                Ok(hit)
+            },
+        )?
+        .function(
+            "cast_shape",
+            |lua,
+             (shape, position, velocity, max_toi, exclude): (
+                SharedShape,
+                CompositePosition3,
+                Vector3<f32>,
+                f32,
+                Option<Entity>,
+            )| {
+                let filter = QueryFilter {
+                    exclude,
+                    ..QueryFilter::default()
+                };
+                let hit = with_loaned(lua, |pipeline: &PhysicsPipeline| {
+                    with_loaned(lua, |atom_map: &AtomMap| {
+                        Ok(pipeline.cast_shape(
+                            atom_map,
+                            &position.as_isometry3(),
+                            &velocity,
+                            shape.as_ref(),
+                            max_toi,
+                            &filter,
+                        ))
+                    })
+                })?;
+                Ok(hit)
+            },
+        )?
+        .function("query_point", |lua, point: Vector3<f32>| {
+            let mut out = Vec::new();
+            with_loaned(lua, |pipeline: &PhysicsPipeline| {
+                with_loaned(lua, |atom_map: &AtomMap| {
+                    pipeline.intersections_with_point(
+                        atom_map,
+                        &Point3::from(point),
+                        &QueryFilter::default(),
+                        &mut out,
+                    );
+                    Ok(())
+                })
+            })?;
+            Ok(out)
+        })?
+        .function(
+            "query_aabb",
+            |lua, (mins, maxs): (Vector3<f32>, Vector3<f32>)| {
+                let aabb = AABB::new(Point3::from(mins), Point3::from(maxs));
+                let mut out = Vec::new();
+                with_loaned(lua, |pipeline: &PhysicsPipeline| {
+                    with_loaned(lua, |atom_map: &AtomMap| {
+                        pipeline.intersections_with_aabb(
+                            atom_map,
+                            &aabb,
+                            &QueryFilter::default(),
+                            &mut out,
+                        );
+                        Ok(())
+                    })
+                })?;
+                Ok(out)
+            },
+        )?;
+
+    Ok(())
+}
+
+impl LuaUserData for QueryTarget {
+    fn on_metatable_init(table: Type<Self>) {
+        table
+            .add_clone()
+            .add_copy()
+            .add_send()
+            .add_sync()
+            .add::<dyn std::fmt::Debug>();
+    }
+
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("entity", |_, this, ()| Ok(this.entity()));
+        methods.add_method("coords", |_, this, ()| {
+            Ok(this.coords().map(|c| (c.x, c.y, c.z)))
+        });
+    }
+}
+
+impl LuaUserData for RayHit {
+    fn on_metatable_init(table: Type<Self>) {
+        table
+            .add_clone()
+            .add_copy()
+            .add_send()
+            .add_sync()
+            .add::<dyn std::fmt::Debug>();
+    }
+
+    fn add_fields<'lua, F: LuaUserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("target", |_, this| Ok(this.target));
+        fields.add_field_method_get("toi", |_, this| Ok(this.toi));
+        fields.add_field_method_get("point", |_, this| Ok(this.point.coords));
+        fields.add_field_method_get("normal", |_, this| Ok(this.normal));
+    }
+}
+
+impl LuaUserData for ShapeHit {
+    fn on_metatable_init(table: Type<Self>) {
+        table
+            .add_clone()
+            .add_copy()
+            .add_send()
+            .add_sync()
+            .add::<dyn std::fmt::Debug>();
+    }
+
+    fn add_fields<'lua, F: LuaUserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("target", |_, this| Ok(this.target));
+        fields.add_field_method_get("toi", |_, this| Ok(this.toi));
+        fields.add_field_method_get("point", |_, this| Ok(this.point.coords));
+        fields.add_field_method_get("normal", |_, this| Ok(this.normal.into_inner()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hv::{
+        ecs::{System, World},
+        resources::Resources,
+    };
+    use parry3d::shape::Ball;
+    use soft_edge::{Atom, HullFacet};
+
+    use crate::{
+        command_buffer::{CommandPool, CommandPoolResource},
+        physics::{update, Position},
+        types::{UpdateDt, UpdateTick},
+    };
+
+    /// A static ball of radius 0.5 at `(2, 0.5, 0.5)`, and a full cube of lattice in the cell at
+    /// `(5, 0, 0)`, both in the path of a ray from `(0, 0.5, 0.5)` along the X axis.
+    fn scene() -> (World, Entity, AtomMap, PhysicsPipeline) {
+        let mut world = World::new();
+        let ball = world.spawn((
+            Position::new(CompositePosition3::translation(2., 0.5, 0.5)),
+            Physics::new(SharedShape::ball(0.5)),
+        ));
+
+        let cube = Atom::generator()
+            .find(|atom| {
+                atom.compound_hull()
+                    .facets()
+                    .filter(|facet| matches!(facet, HullFacet::Rectangle(_)))
+                    .count()
+                    == 6
+            })
+            .unwrap();
+        let mut atom_map = AtomMap::new();
+        atom_map.atoms_mut().insert(Vector3::new(5, 0, 0), cube);
+        atom_map.calculate_hulls();
+
+        // Queries see the world as of the end of the last update.
+        let mut pipeline = PhysicsPipeline::default();
+        let command_pool = CommandPoolResource::new();
+        let resources = &mut Resources::new();
+        command_pool.run_scoped(&CommandPool::new(), &mut world, resources, |world| {
+            update.run(
+                world,
+                (
+                    &UpdateDt(1. / 60.),
+                    &UpdateTick(0),
+                    &atom_map,
+                    &mut pipeline,
+                    &command_pool,
+                ),
+            )
+        });
+
+        (world, ball, atom_map, pipeline)
+    }
+
+    fn ray() -> Ray {
+        Ray::new(Point3::new(0., 0.5, 0.5), Vector3::x())
+    }
+
+    #[test]
+    fn ray_hits_bodies_and_lattice() {
+        let (_world, ball, atom_map, pipeline) = scene();
+        let cast =
+            |max_toi, filter: QueryFilter| pipeline.cast_ray(&atom_map, &ray(), max_toi, &filter);
+
+        let hit = cast(10., QueryFilter::new()).unwrap();
+        assert_eq!(hit.target, QueryTarget::Body(ball));
+        assert!((hit.toi - 1.5).abs() < 1e-4);
+        assert!((hit.normal + Vector3::x()).norm() < 1e-4);
+
+        let lattice = QueryTarget::Lattice(Vector3::new(5, 0, 0));
+        for filter in [
+            QueryFilter::lattice_only(),
+            QueryFilter::new().excluding(ball),
+        ] {
+            let hit = cast(10., filter).unwrap();
+            assert_eq!(hit.target, lattice);
+            assert!((hit.toi - 5.).abs() < 1e-4);
+            assert!((hit.point - Point3::new(5., 0.5, 0.5)).norm() < 1e-4);
+            assert!((hit.normal + Vector3::x()).norm() < 1e-4);
+        }
+
+        assert!(cast(10., QueryFilter::bodies_only().excluding(ball)).is_none());
+        assert!(cast(10., QueryFilter::new().with_groups(CollisionGroups::NONE)).is_none());
+        assert!(cast(1., QueryFilter::new()).is_none());
+    }
+
+    #[test]
+    fn shape_queries_hit_bodies_and_lattice() {
+        let (_world, ball, atom_map, pipeline) = scene();
+        let shape = Ball::new(0.25);
+        let start = Isometry3::translation(0., 0.5, 0.5);
+
+        let hit = pipeline
+            .cast_shape(
+                &atom_map,
+                &start,
+                &Vector3::x(),
+                &shape,
+                10.,
+                &QueryFilter::new(),
+            )
+            .unwrap();
+        assert_eq!(hit.target, QueryTarget::Body(ball));
+        assert!((hit.toi - 1.25).abs() < 1e-3);
+
+        let filter = QueryFilter::new().excluding(ball);
+        let hit = pipeline
+            .cast_shape(&atom_map, &start, &Vector3::x(), &shape, 10., &filter)
+            .unwrap();
+        assert_eq!(hit.target, QueryTarget::Lattice(Vector3::new(5, 0, 0)));
+        assert!((hit.toi - 4.75).abs() < 1e-3);
+
+        // Just short of the ball, and overlapping the cube.
+        let mut contacts = Vec::new();
+        let near_ball = Isometry3::translation(2.9, 0.5, 0.5);
+        pipeline.contacts_with_shape(
+            &atom_map,
+            &near_ball,
+            &shape,
+            0.2,
+            &QueryFilter::new(),
+            &mut contacts,
+        );
+        assert_eq!(contacts.len(), 1);
+        assert_eq!(contacts[0].target, QueryTarget::Body(ball));
+        assert!((contacts[0].dist - 0.15).abs() < 1e-4);
+
+        contacts.clear();
+        pipeline.contacts_with_shape(&atom_map, &near_ball, &shape, 0.2, &filter, &mut contacts);
+        assert!(contacts.is_empty());
+
+        let in_cube = Isometry3::translation(5.1, 0.5, 0.5);
+        pipeline.contacts_with_shape(&atom_map, &in_cube, &shape, 0.2, &filter, &mut contacts);
+        assert!(!contacts.is_empty());
+        assert!(contacts
+            .iter()
+            .all(|c| c.target == QueryTarget::Lattice(Vector3::new(5, 0, 0))));
+
+        let mut targets = Vec::new();
+        let filter = QueryFilter::new();
+        pipeline.intersections_with_point(
+            &atom_map,
+            &Point3::new(2., 0.5, 0.5),
+            &filter,
+            &mut targets,
+        );
+        pipeline.intersections_with_point(
+            &atom_map,
+            &Point3::new(5.5, 0.5, 0.5),
+            &filter,
+            &mut targets,
+        );
+        pipeline.intersections_with_point(
+            &atom_map,
+            &Point3::new(3.5, 0.5, 0.5),
+            &filter,
+            &mut targets,
+        );
+        assert_eq!(
+            targets,
+            [
+                QueryTarget::Body(ball),
+                QueryTarget::Lattice(Vector3::new(5, 0, 0))
+            ]
+        );
+    }
+}
@@ -0,0 +1,340 @@
+//! Snapshots of the full state of the physics simulation, for rollback and save-states.
+//!
+//! Restoring only [`Position`] and [`Velocity`] components isn't enough to reproduce a simulation,
+//! since the solver warm-starts from impulses accumulated over previous updates. A
+//! [`PhysicsSnapshot`] captures everything the solver carries from one update to the next: contact
+//! and joint constraints, sensor overlaps, and the solver state of every [`Physics`] component.
+//! Restoring a snapshot along w/ the `Position` and `Velocity` components from the same moment and
+//! then running the same updates gives bit-identical results.
+//!
+//! Collider shapes are not part of a snapshot; restoring one leaves the shapes of bodies alone.
+//!
+//! [`Position`]: crate::physics::Position
+//! [`Velocity`]: crate::physics::Velocity
+
+use hv::{
+    ecs::{Entity, Without, World},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::physics::{
+    joint::{Joint, JointId},
+    CollisionGroups, CompositePosition3, CompositeVelocity3, ContactConstraint, ContactId,
+    ContactIdEntry, MassData, Physics, PhysicsConfig, PhysicsPipeline, SensorMarker,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PhysicsSnapshot {
+    config: PhysicsConfig,
+    constraints: Vec<(ContactId, ContactConstraint)>,
+    next_contact_id: u64,
+    triggers: Vec<((Entity, u32, Entity), u64)>,
+    joints: Vec<(JointId, Joint)>,
+    next_joint_id: u64,
+    bodies: Vec<(Entity, BodySnapshot)>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BodySnapshot {
+    position: CompositePosition3,
+    target: CompositePosition3,
+    velocity: CompositeVelocity3,
+    mass_data: MassData,
+    restitution: f32,
+    static_friction: f32,
+    dynamic_friction: f32,
+    gravity_k: f32,
+    linear_damping: f32,
+    angular_damping: f32,
+    force: Vector3<f32>,
+    torque: f32,
+    groups: CollisionGroups,
+    sleeping: bool,
+    sleep_timer: f32,
+    collider_tx: Isometry3<f32>,
+    contacts: Vec<ContactIdEntry>,
+}
+
+impl BodySnapshot {
+    fn new(physics: &Physics) -> Self {
+        Self {
+            position: physics.position,
+            target: physics.target,
+            velocity: physics.velocity,
+            mass_data: physics.mass_data,
+            restitution: physics.restitution,
+            static_friction: physics.static_friction,
+            dynamic_friction: physics.dynamic_friction,
+            gravity_k: physics.gravity_k,
+            linear_damping: physics.linear_damping,
+            angular_damping: physics.angular_damping,
+            force: physics.force,
+            torque: physics.torque,
+            groups: physics.groups,
+            sleeping: physics.sleeping,
+            sleep_timer: physics.sleep_timer,
+            collider_tx: physics.collider_tx,
+            contacts: physics.contacts.clone(),
+        }
+    }
+
+    fn restore(&self, physics: &mut Physics) {
+        physics.position = self.position;
+        physics.target = self.target;
+        physics.velocity = self.velocity;
+        physics.mass_data = self.mass_data;
+        physics.restitution = self.restitution;
+        physics.static_friction = self.static_friction;
+        physics.dynamic_friction = self.dynamic_friction;
+        physics.gravity_k = self.gravity_k;
+        physics.linear_damping = self.linear_damping;
+        physics.angular_damping = self.angular_damping;
+        physics.force = self.force;
+        physics.torque = self.torque;
+        physics.groups = self.groups;
+        physics.sleeping = self.sleeping;
+        physics.sleep_timer = self.sleep_timer;
+        physics.collider_tx = self.collider_tx;
+        physics.contacts.clone_from(&self.contacts);
+    }
+}
+
+impl PhysicsPipeline {
+    /// Take a snapshot of the pipeline and of every [`Physics`] component in the world.
+    pub fn snapshot(&self, world: &World) -> PhysicsSnapshot {
+        PhysicsSnapshot {
+            config: self.config,
+            constraints: self
+                .constraints
+                .iter()
+                .map(|(&id, &constraint)| (id, constraint))
+                .collect(),
+            next_contact_id: self.next_contact_id,
+            triggers: self
+                .triggers
+                .iter()
+                .map(|(&key, &timestamp)| (key, timestamp))
+                .collect(),
+            joints: self
+                .joints
+                .iter()
+                .map(|(&id, &joint)| (id, joint))
+                .collect(),
+            next_joint_id: self.next_joint_id,
+            bodies: world
+                .query::<&Physics>()
+                .iter()
+                .map(|(e, physics)| (e, BodySnapshot::new(physics)))
+                .collect(),
+        }
+    }
+
+    /// Restore the pipeline and the [`Physics`] components of the bodies in a snapshot.
+    ///
+    /// Every body in the snapshot must still have a `Physics` component; if any doesn't, an error
+    /// is returned and nothing is modified. Bodies which weren't in the snapshot are left as-is.
+    pub fn restore(&mut self, world: &World, snapshot: &PhysicsSnapshot) -> Result<()> {
+        for &(entity, _) in &snapshot.bodies {
+            world
+                .get::<Physics>(entity)
+                .map_err(|err| anyhow!("cannot restore physics state of {:?}: {}", entity, err))?;
+        }
+
+        for (entity, body) in &snapshot.bodies {
+            body.restore(&mut world.get_mut::<Physics>(*entity)?);
+        }
+
+        self.config = snapshot.config;
+        self.constraints = snapshot.constraints.iter().copied().collect();
+        self.next_contact_id = snapshot.next_contact_id;
+        self.contacts = self
+            .constraints
+            .iter()
+            .map(|(&id, constraint)| (constraint.participants, id))
+            .collect();
+        self.triggers = snapshot.triggers.iter().copied().collect();
+
+        self.joints.clear();
+        self.jointed_pairs.clear();
+        for &(joint_id, joint) in &snapshot.joints {
+            self.insert_joint(joint_id, joint);
+        }
+        self.next_joint_id = snapshot.next_joint_id;
+
+        // Bodies may have been put back somewhere the broad phase doesn't know about without being
+        // woken up, so have it reinsert everything on the next update.
+        self.broad_phase.clear();
+        self.ccd_sweeps.clear();
+
+        self.update_query_colliders(world.query::<Without<SensorMarker, &Physics>>().iter());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::ops::Range;
+
+    use hv::{ecs::System, resources::Resources};
+    use parry3d::shape::SharedShape;
+
+    use crate::{
+        command_buffer::{CommandPool, CommandPoolResource},
+        lattice::atom_map::AtomMap,
+        physics::{update, Position, Velocity},
+        types::{UpdateDt, UpdateTick},
+    };
+
+    const DT: f32 = 1. / 60.;
+
+    fn spawn_body(
+        world: &mut World,
+        translation: Vector3<f32>,
+        velocity: CompositeVelocity3,
+        shape: SharedShape,
+    ) -> Entity {
+        world.spawn((
+            Position::new(CompositePosition3::translation(
+                translation.x,
+                translation.y,
+                translation.z,
+            )),
+            Velocity {
+                composite: velocity,
+            },
+            Physics::new(shape),
+        ))
+    }
+
+    fn run(world: &mut World, pipeline: &mut PhysicsPipeline, ticks: Range<u64>) {
+        let atom_map = AtomMap::new();
+        let pool = CommandPool::new();
+        let command_pool = CommandPoolResource::new();
+        let mut resources = Resources::new();
+        for tick in ticks {
+            command_pool.run_scoped(&pool, world, &mut resources, |world| {
+                update.run(
+                    world,
+                    (
+                        &UpdateDt(DT),
+                        &UpdateTick(tick),
+                        &atom_map,
+                        &mut *pipeline,
+                        &command_pool,
+                    ),
+                )
+            });
+        }
+    }
+
+    /// The exact bits of every position and velocity in the world.
+    fn state_bits(world: &World) -> Vec<(Entity, Vec<u32>)> {
+        let mut state = world
+            .query::<(&Position, Option<&Velocity>)>()
+            .iter()
+            .map(|(e, (position, velocity))| {
+                let mut bits = Vec::new();
+                for p in [position.current, position.previous] {
+                    bits.extend(p.translation.iter().map(|t| t.to_bits()));
+                    bits.push(p.rotation.cos_angle().to_bits());
+                    bits.push(p.rotation.sin_angle().to_bits());
+                }
+                if let Some(velocity) = velocity {
+                    bits.extend(velocity.composite.linear.iter().map(|t| t.to_bits()));
+                    bits.push(velocity.composite.angular.to_bits());
+                }
+                (e, bits)
+            })
+            .collect::<Vec<_>>();
+        state.sort_by_key(|&(e, _)| e);
+        state
+    }
+
+    #[test]
+    fn restoring_a_snapshot_replays_bit_identically() {
+        let mut world = World::new();
+        let mut pipeline = PhysicsPipeline::default();
+        pipeline.config.gravity = Vector3::new(0., -10., 0.);
+
+        // A static floor, a box sliding and tumbling along it, and a ball hanging off the box.
+        world.spawn((
+            Position::new(CompositePosition3::translation(0., -1., 0.)),
+            Physics::new(SharedShape::cuboid(20., 0.5, 1.)),
+        ));
+        let slider = spawn_body(
+            &mut world,
+            Vector3::new(-2., 0.1, 0.),
+            CompositeVelocity3::new(Vector3::new(3., 0., 0.), 1.),
+            SharedShape::cuboid(0.5, 0.5, 0.5),
+        );
+        let hanger = spawn_body(
+            &mut world,
+            Vector3::new(-2., 1.5, 0.),
+            CompositeVelocity3::zero(),
+            SharedShape::ball(0.25),
+        );
+        let bouncer = spawn_body(
+            &mut world,
+            Vector3::new(2., 2., 0.),
+            CompositeVelocity3::new(Vector3::new(-1., 0., 0.), -2.),
+            SharedShape::ball(0.5),
+        );
+        pipeline
+            .add_joint(Joint::distance(
+                slider,
+                Point3::new(0., 0.5, 0.),
+                Some(hanger),
+                Point3::origin(),
+                1.,
+            ))
+            .unwrap();
+        pipeline
+            .add_joint(Joint::rope(
+                bouncer,
+                Point3::origin(),
+                None,
+                Point3::new(2., 3., 0.),
+                1.5,
+            ))
+            .unwrap();
+
+        run(&mut world, &mut pipeline, 0..30);
+
+        // Contacts are warm-started from the impulses accumulated before the snapshot.
+        assert!(pipeline.contacts().count() > 0);
+        let snapshot = pipeline.snapshot(&world);
+        let positions = world
+            .query::<&Position>()
+            .iter()
+            .map(|(e, &position)| (e, position))
+            .collect::<Vec<_>>();
+        let velocities = world
+            .query::<&Velocity>()
+            .iter()
+            .map(|(e, &velocity)| (e, velocity))
+            .collect::<Vec<_>>();
+
+        run(&mut world, &mut pipeline, 30..90);
+        let expected = state_bits(&world);
+        let expected_contacts = pipeline.contacts().map(|(id, _)| id).collect::<Vec<_>>();
+
+        pipeline.restore(&world, &snapshot).unwrap();
+        for (e, position) in positions {
+            *world.get_mut::<Position>(e).unwrap() = position;
+        }
+        for (e, velocity) in velocities {
+            *world.get_mut::<Velocity>(e).unwrap() = velocity;
+        }
+
+        run(&mut world, &mut pipeline, 30..90);
+        assert_eq!(state_bits(&world), expected);
+        assert_eq!(
+            pipeline.contacts().map(|(id, _)| id).collect::<Vec<_>>(),
+            expected_contacts
+        );
+    }
+}
@@ -343,6 +343,14 @@ impl Filesystem {
         self.vfs.push_back(Box::new(physfs));
     }
 
+    /// Adds an empty, writable filesystem which lives entirely in memory. Anything written to it
+    /// is lost when this `Filesystem` is dropped, which makes it handy for tests.
+    pub fn mount_memory(&mut self) {
+        let memfs = vfs::MemoryFs::new();
+        log::trace!("Mounting new in-memory filesystem");
+        self.vfs.push_back(Box::new(memfs));
+    }
+
     /// Adds any object that implements Read + Seek as a zip file.
     ///
     /// Note: This is not intended for system files for the same reasons as
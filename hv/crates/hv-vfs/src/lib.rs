@@ -40,12 +40,12 @@ use anyhow::{anyhow, bail, Error, Result};
 use hv_alchemy::Type;
 use hv_lua::{AnyUserData, UserData, UserDataMethods};
 use std::{
-    collections::VecDeque,
+    collections::{HashMap, VecDeque},
     fmt::{self, Debug, Display},
     fs,
     io::{self, Read, Seek, Write},
     path::{self, Path, PathBuf},
-    sync::RwLock,
+    sync::{Arc, RwLock},
 };
 
 mod path_clean;
@@ -908,6 +908,291 @@ impl Vfs for ZipFs {
     }
 }
 
+/// A writable filesystem which keeps its files in memory, and forgets them when it's dropped.
+/// Mostly useful for tests.
+#[derive(Debug, Default)]
+pub struct MemoryFs {
+    // Keyed by sanitized path; the root directory is the empty path, and is always present.
+    entries: RwLock<HashMap<PathBuf, MemoryEntry>>,
+}
+
+#[derive(Debug, Clone)]
+enum MemoryEntry {
+    Dir,
+    File(Arc<RwLock<Vec<u8>>>),
+}
+
+impl Display for MemoryFs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<MemoryFs>")
+    }
+}
+
+impl MemoryFs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn sanitize(path: &Path) -> Result<PathBuf> {
+        sanitize_path(path).ok_or_else(|| {
+            anyhow!(
+                "Path {:?} is not valid: must be an absolute path with no \
+                 references to parent directories",
+                path
+            )
+        })
+    }
+
+    fn is_dir_in(entries: &HashMap<PathBuf, MemoryEntry>, path: &Path) -> bool {
+        path.as_os_str().is_empty() || matches!(entries.get(path), Some(MemoryEntry::Dir))
+    }
+
+    fn read_entries(&self) -> std::sync::RwLockReadGuard<HashMap<PathBuf, MemoryEntry>> {
+        self.entries.read().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn write_entries(&self) -> std::sync::RwLockWriteGuard<HashMap<PathBuf, MemoryEntry>> {
+        self.entries.write().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// An open file of a [`MemoryFs`]. Writes are visible to every other handle to the same file as
+/// soon as they're made.
+pub struct MemoryFile {
+    data: Arc<RwLock<Vec<u8>>>,
+    pos: u64,
+    read: bool,
+    write: bool,
+    append: bool,
+}
+
+impl Debug for MemoryFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "<MemoryFile>")
+    }
+}
+
+impl io::Read for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.read {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file not opened for reading",
+            ));
+        }
+
+        let data = self.data.read().unwrap_or_else(|err| err.into_inner());
+        let start = (self.pos as usize).min(data.len());
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl io::Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.write && !self.append {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file not opened for writing",
+            ));
+        }
+
+        let mut data = self.data.write().unwrap_or_else(|err| err.into_inner());
+        if self.append {
+            self.pos = data.len() as u64;
+        }
+
+        let start = self.pos as usize;
+        let end = start + buf.len();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[start..end].copy_from_slice(buf);
+        self.pos = end as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl io::Seek for MemoryFile {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let len = self
+            .data
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .len() as i64;
+        let new_pos = match pos {
+            io::SeekFrom::Start(offset) => offset as i64,
+            io::SeekFrom::End(offset) => len + offset,
+            io::SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+
+        if new_pos < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            ));
+        }
+
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct MemoryMetadata {
+    len: u64,
+    is_dir: bool,
+}
+
+impl VMetadata for MemoryMetadata {
+    fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+    fn is_file(&self) -> bool {
+        !self.is_dir
+    }
+    fn len(&self) -> u64 {
+        self.len
+    }
+}
+
+impl Vfs for MemoryFs {
+    fn open_options(&self, path: &Path, open_options: OpenOptions) -> Result<Box<dyn VFile>> {
+        let p = Self::sanitize(path)?;
+        let mut entries = self.write_entries();
+        let data = match entries.get(&p) {
+            Some(MemoryEntry::File(data)) => {
+                if open_options.truncate {
+                    data.write().unwrap_or_else(|err| err.into_inner()).clear();
+                }
+                data.clone()
+            }
+            Some(MemoryEntry::Dir) => bail!("Cannot open {:?}: it is a directory", path),
+            None if open_options.create => {
+                match p.parent() {
+                    Some(parent) if Self::is_dir_in(&entries, parent) => (),
+                    _ => bail!("Cannot create {:?}: parent directory does not exist", path),
+                }
+                let data = Arc::new(RwLock::new(Vec::new()));
+                entries.insert(p, MemoryEntry::File(data.clone()));
+                data
+            }
+            None => bail!("Cannot open {:?}: file does not exist", path),
+        };
+
+        Ok(Box::new(MemoryFile {
+            data,
+            pos: 0,
+            read: open_options.read,
+            write: open_options.write,
+            append: open_options.append,
+        }))
+    }
+
+    fn mkdir(&self, path: &Path) -> Result<()> {
+        let p = Self::sanitize(path)?;
+        let mut entries = self.write_entries();
+        for ancestor in p.ancestors().filter(|a| !a.as_os_str().is_empty()) {
+            if let Some(MemoryEntry::File(_)) = entries.get(ancestor) {
+                bail!(
+                    "Cannot create directory {:?}: {:?} is a file",
+                    path,
+                    ancestor
+                );
+            }
+        }
+
+        for ancestor in p.ancestors().filter(|a| !a.as_os_str().is_empty()) {
+            entries.insert(ancestor.to_owned(), MemoryEntry::Dir);
+        }
+
+        Ok(())
+    }
+
+    fn rm(&self, path: &Path) -> Result<()> {
+        let p = Self::sanitize(path)?;
+        let mut entries = self.write_entries();
+        match entries.get(&p) {
+            Some(MemoryEntry::File(_)) => (),
+            Some(MemoryEntry::Dir) if entries.keys().any(|k| k.parent() == Some(p.as_path())) => {
+                bail!("Cannot remove directory {:?}: it is not empty", path)
+            }
+            Some(MemoryEntry::Dir) => (),
+            None => bail!("Cannot remove {:?}: it does not exist", path),
+        }
+
+        entries.remove(&p);
+        Ok(())
+    }
+
+    fn rmrf(&self, path: &Path) -> Result<()> {
+        let p = Self::sanitize(path)?;
+        let mut entries = self.write_entries();
+        if !entries.contains_key(&p) {
+            bail!("Cannot remove {:?}: it does not exist", path);
+        }
+
+        entries.retain(|k, _| !k.starts_with(&p));
+        Ok(())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        match Self::sanitize(path) {
+            Ok(p) => p.as_os_str().is_empty() || self.read_entries().contains_key(&p),
+            Err(_) => false,
+        }
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Box<dyn VMetadata>> {
+        let p = Self::sanitize(path)?;
+        let entries = self.read_entries();
+        let metadata = match entries.get(&p) {
+            Some(MemoryEntry::File(data)) => MemoryMetadata {
+                len: data.read().unwrap_or_else(|err| err.into_inner()).len() as u64,
+                is_dir: false,
+            },
+            Some(MemoryEntry::Dir) => MemoryMetadata {
+                len: 0,
+                is_dir: true,
+            },
+            None if p.as_os_str().is_empty() => MemoryMetadata {
+                len: 0,
+                is_dir: true,
+            },
+            None => bail!("Cannot get metadata for {:?}: it does not exist", path),
+        };
+
+        Ok(Box::new(metadata))
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Box<dyn Iterator<Item = Result<PathBuf>>>> {
+        let p = Self::sanitize(path)?;
+        let entries = self.read_entries();
+        if !Self::is_dir_in(&entries, &p) {
+            bail!("Cannot read directory {:?}: it is not a directory", path);
+        }
+
+        // As w/ `PhysicalFs`, the paths returned are the given path joined w/ each entry's name.
+        let itr = entries
+            .keys()
+            .filter(|k| k.parent() == Some(p.as_path()))
+            .filter_map(|k| k.file_name())
+            .map(|name| Ok(PathBuf::from(path).join(name)))
+            .collect::<Vec<_>>();
+        Ok(Box::new(itr.into_iter()))
+    }
+
+    fn to_path_buf(&self) -> Option<PathBuf> {
+        None
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1050,6 +1335,81 @@ mod tests {
         assert!(!fs.exists(testdir));
     }
 
+    #[test]
+    fn headless_test_memory_all() {
+        let fs = MemoryFs::new();
+        let testdir = Path::new("/testdir");
+        let f1 = Path::new("/testdir/file1.txt");
+
+        assert!(fs.exists(Path::new("/")));
+        assert!(!fs.exists(testdir));
+        assert!(fs.create(f1).is_err());
+
+        // Create and delete test dir
+        fs.mkdir(testdir).unwrap();
+        assert!(fs.exists(testdir));
+        fs.rm(testdir).unwrap();
+        assert!(!fs.exists(testdir));
+
+        let test_string = "Foo!";
+        fs.mkdir(testdir).unwrap();
+        {
+            let mut f = fs.append(f1).unwrap();
+            let _ = f.write(test_string.as_bytes()).unwrap();
+        }
+        {
+            let mut f = fs.append(f1).unwrap();
+            let _ = f.write(test_string.as_bytes()).unwrap();
+        }
+        {
+            let mut buf = Vec::new();
+            let mut f = fs.open(f1).unwrap();
+            let _ = f.read_to_end(&mut buf).unwrap();
+            assert_eq!(&buf[..], "Foo!Foo!".as_bytes());
+            assert!(f.write(b"nope").is_err());
+        }
+        {
+            // Creating an existing file truncates it.
+            let mut f = fs.create(f1).unwrap();
+            let _ = f.write(test_string.as_bytes()).unwrap();
+        }
+
+        {
+            // Test metadata()
+            let m = fs.metadata(f1).unwrap();
+            assert!(m.is_file());
+            assert!(!m.is_dir());
+            assert_eq!(m.len(), 4);
+
+            let m = fs.metadata(testdir).unwrap();
+            assert!(!m.is_file());
+            assert!(m.is_dir());
+        }
+
+        {
+            // Test read_dir()
+            let r = fs.read_dir(testdir).unwrap();
+            assert_eq!(r.count(), 1);
+            let r = fs.read_dir(testdir).unwrap();
+            for f in r {
+                let fname = f.unwrap();
+                assert!(fs.exists(&fname));
+            }
+        }
+
+        {
+            assert!(fs.rm(testdir).is_err());
+            assert!(fs.exists(f1));
+            fs.rm(f1).unwrap();
+            assert!(!fs.exists(f1));
+        }
+
+        fs.mkdir(Path::new("/testdir/a/b")).unwrap();
+        fs.rmrf(testdir).unwrap();
+        assert!(!fs.exists(testdir));
+        assert!(!fs.exists(Path::new("/testdir/a/b")));
+    }
+
     #[test]
     fn headless_test_zip_files() {
         let mut finished_zip_bytes: io::Cursor<_> = {